@@ -23,68 +23,131 @@
  *
 */
 use clap::Parser;
-use crabseal::files::create_image_dirs;
+use crabseal::cache::FrameCache;
+use crabseal::clutter::{node_clutter_to_mask, node_group_clutter, ClutterModel};
+use crabseal::datasource::open_source;
+use crabseal::fits_index::{default_index_path, IndexMode};
+use crabseal::files::{create_image_dirs, read_bearing_table};
+use crabseal::filter::GroupFilter;
 use crabseal::generators::GeneratorGroups;
-use crabseal::nodes::{node_combine_datum_mask, node_reject_on_no_mask, node_slice_datum_overlap};
+use crabseal::kalman::{KalmanOps, KalmanOutput};
+use crabseal::metrics::TrackRules;
+use crabseal::nodes::{
+    node_combine_datum_mask, node_reject_on_mask, node_reject_on_track_metrics, node_slice_datum_overlap,
+};
+use crabseal::nodes_augment::{node_datum_augment, AugmentOps};
+use crabseal::nodes_background::{
+    node_volume_background, node_volume_subtract_background, BackgroundMethod,
+};
+use crabseal::nodes_channels::{node_datum_channels, parse_channels};
+use crabseal::image::VolumePixel;
+use crabseal::nodes_convert::{node_datum_norm_params, Normalise, PixelOps, PixelType};
+use crabseal::nodes_range::{
+    node_group_range_normalise, node_trackraw_range_normalise, node_volume_range_normalise,
+};
+use crabseal::nodes_time::{node_group_resample, node_trackraw_resample, node_volume_resample, Resample};
 use crabseal::nodes_tracks::{
-    node_group_to_trackraw, node_track_kalman, node_trackraw_interpolate, node_trackraw_overlap,
+    node_group_to_trackraw, node_track_kalman, node_track_smooth, node_trackraw_interpolate_timed,
+    node_trackraw_merge, node_trackraw_overlap, GapPolicy,
 };
 use crabseal::nodes_volumes::{
-    node_group_to_volume, node_trackraw_to_volume, node_volume_resize, node_volume_trim,
+    node_datum_patch, node_group_to_volume_as, node_trackraw_to_volume, node_volume_resize, node_volume_trim,
+    patch_positions, FramePolicy, PatchOps, SectorLabel,
 };
 use crabseal::ops::MovesOps;
-use crabseal::ptypes::VolumeT;
-use crabseal::sinks::{sink_to_npz, sink_to_npz_volume, sink_to_png, sink_to_txt};
+use crabseal::ptypes::{DatumT, TrackRawT, VolumeT};
+use crabseal::frame_source::{FallbackSource, GlfSource, GLF_TIME_TOLERANCE_MS};
+use crabseal::pgdf::{PgdfSource, PointsMode};
+use crabseal::resolver::FitsResolver;
+use crabseal::track::Interpolation;
+use crabseal::stage_cache::StageCache;
+use crabseal::sinks::{
+    sink_rejected_reset, sink_to_metadata, sink_to_npz_as, sink_to_npz_extra, sink_to_npz_extra_as, sink_to_png, sink_to_rejected,
+    sink_to_txt,
+};
 use fern;
 use humantime;
+use image::imageops::FilterType;
 use image::imageops::FilterType::{Lanczos3, Nearest};
-use log::info;
+use log::{info, warn};
 use pbr::ProgressBar;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::Write;
 use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::SystemTime;
-use walkdir::WalkDir;
-
-fn run_pipeline(ops: &MovesOps) {
-    //! Run the basic pipeline
-    let mut img_paths: HashMap<String, PathBuf> = HashMap::new();
 
-    // Write to a cache file or read from it, if it already exits.
-    let cache_path = "crabseal.cache";
-
-    if Path::new(cache_path).exists() {
-        let file = File::open(cache_path).unwrap();
-        let reader = BufReader::new(file);
-
-        for res in reader.lines() {
-            let line = res.unwrap().replace('\n', "");
-            let tokens = line.split(",").collect::<Vec<&str>>();
-            img_paths.insert(tokens[0].to_string(), Path::new(tokens[1]).to_path_buf());
-        }
+/// Cut the patches at the given positions from a datum, or keep the whole datum if patches
+/// aren't being cut.
+///
+/// * `datum` - the DatumT to cut from.
+/// * `positions` - the top left corners of the patches, from patch_positions.
+/// * `patch_ops` - the PatchOps.
+fn cut_datum<T: VolumePixel>(datum: &DatumT<T>, positions: &[(u32, u32)], patch_ops: &PatchOps) -> Vec<DatumT<T>> {
+    if patch_ops.count > 0 {
+        positions.iter().map(|p| node_datum_patch(datum, *p, patch_ops.size)).collect()
     } else {
-        for file in WalkDir::new(&ops.fits_path)
-            .into_iter()
-            .filter_map(|file| file.ok())
-        {
-            if file.metadata().unwrap().is_file() {
-                // This conversion to string from osstr is absolutely stupid!
-                let mut key = file.file_name().to_str().map(|s| s.to_string()).unwrap();
-                key = key.replace(".lz4", "");
-                img_paths.insert(key, file.path().to_path_buf());
-            }
-        }
+        vec![datum.clone()]
+    }
+}
+
+fn run_pipeline(ops: &MovesOps) {
+    //! Run the basic pipeline, at the pixel type the base files are written as.
+    match ops.pixel_ops.pixel_type {
+        PixelType::U8 => run_pipeline_as::<u8>(ops),
+        PixelType::U16 => run_pipeline_as::<u16>(ops),
+        PixelType::F32 => run_pipeline_as::<f32>(ops),
+    }
+}
 
-        // Now write the cache file
-        let mut file = File::create(cache_path).unwrap();
+fn run_pipeline_as<T: VolumePixel>(ops: &MovesOps) {
+    //! Run the basic pipeline, reading the frames and building the volumes as T.
+    // Find the FITS files from the SealHits layout, only using the index for those that aren't
+    // where they should be.
+    let img_paths = FitsResolver::new(&ops.fits_path, &ops.index_path, ops.index_mode);
+    // Decoded frames are shared between the generator, overlapping groups and later runs.
+    // Frames without a FITS are pulled straight out of the GLF recordings, if we have them.
+    let glf_source = ops.glf_path.as_ref().map(|p| GlfSource::new(p, ops.glf_cache_size));
+    let frame_source = FallbackSource {
+        sources: match &glf_source {
+            Some(glfs) => vec![&img_paths, glfs],
+            None => vec![&img_paths],
+        },
+    };
+    let frame_cache = FrameCache::<T>::new(ops.frame_cache_size, ops.frame_cache_path.clone());
+    // Points can be rebuilt from, or checked against, the PGDFs the tracks were saved in.
+    let pgdf_source = match (ops.points_mode, &ops.pgdf_path) {
+        (PointsMode::Db, _) | (_, None) => None,
+        (mode, Some(p)) => Some(PgdfSource::new(p, mode)),
+    };
 
-        for key in img_paths.keys() {
-            write!(file, "{},{}\n", key, img_paths[key].to_str().unwrap()).unwrap();
+    // The volumes of each group depend only on these, so a run that changes nothing else reuses them.
+    let stage_params = format!(
+        "pipeline;fits={};glf={:?};glf_tolerance_ms={};points={};frame_policy={:?};resample={:?};metres_per_pixel={:?};clutter={:?};clutter_frames={};clutter_threshold={};background={:?};background_extra={};width={};dtype={};normalise={}",
+        ops.fits_path.display(),
+        ops.glf_path,
+        GLF_TIME_TOLERANCE_MS,
+        ops.points_mode,
+        ops.frame_policy,
+        ops.resample,
+        ops.metres_per_pixel,
+        ops.clutter,
+        ops.clutter_frames,
+        ops.clutter_threshold,
+        ops.background,
+        ops.background_extra,
+        ops.target_width,
+        ops.pixel_ops.pixel_type,
+        ops.pixel_ops.normalise
+    );
+    let stage_cache: Option<StageCache> = ops.stage_cache_path.as_ref().and_then(|p| match StageCache::new(p, &stage_params) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            warn!("Not using the stage cache at {} - {}", p.display(), e);
+            None
         }
-    }
+    });
 
     // Make sure we have a code_to_class id file for outputting classes
     let code_class_path = ops.out_path.clone().join("code_to_class.csv");
@@ -111,15 +174,34 @@ fn run_pipeline(ops: &MovesOps) {
     let path_test_txt = ops.out_path.clone().join("set_test.txt");
     let path_val_txt = ops.out_path.clone().join("set_val.txt");
 
+    // The bearings of the beams, for the track metrics.
+    let btable = match read_bearing_table() {
+        Ok(btable) => btable,
+        Err(e) => {
+            warn!("Failed to read the bearing table btable.dat - {}", e);
+            return;
+        }
+    };
+
+    // The groups come from the database, or a snapshot of it exported by the snapshot program.
+    let source = match open_source(&ops.snapshot_path, &ops.dbuser, &ops.dbpass, &ops.dbname) {
+        Ok(source) => source,
+        Err(e) => {
+            warn!("Failed to read the snapshot - {}", e);
+            return;
+        }
+    };
+
     let mut generator = GeneratorGroups::new(
-        &ops.dbuser,
-        &ops.dbpass,
-        &ops.dbname,
+        source.as_ref(),
         &ops.sonar_ids,
-        &img_paths,
+        &frame_source,
+        &frame_cache,
+        pgdf_source.as_ref(),
         ops.num_frames as usize,
         ops.dataset_limit as usize,
-        1632,
+        ops.crop_height,
+        ops.group_filter.as_ref(),
         &ops.sqlfilter,
         ops.num_threads,
         &code_to_id,
@@ -144,32 +226,254 @@ fn run_pipeline(ops: &MovesOps) {
         num_groups - num_train - num_test
     );
 
+    let augment_ops = AugmentOps {
+        copies: ops.augment_copies,
+        seed: ops.augment_seed,
+        ..Default::default()
+    };
+
+    let patch_ops = PatchOps {
+        count: ops.patch_count,
+        size: ops.patch_size,
+        positive_ratio: ops.patch_positive,
+        min_mask_fraction: ops.patch_min_mask,
+        min_spacing: ops.patch_spacing,
+        seed: ops.patch_seed,
+    };
+
+    // The long term clutter maps are shared between all the groups.
+    let clutter_model = ops
+        .clutter
+        .and_then(|method| match ClutterModel::<T>::new(&ops.clutter_path, method, ops.clutter_frames) {
+            Ok(model) => Some(model),
+            Err(e) => {
+                warn!("Not removing the clutter, as the clutter maps can't be kept at {} - {}", ops.clutter_path.display(), e);
+                None
+            }
+        });
+
+    // The background removed channel uses the same method as --background, or the median. If
+    // --background has already replaced the raw data, the channel is the raw data as it is.
+    let channel_background = match ops.background {
+        Some(_) if !ops.background_extra => None,
+        method => Some(method.unwrap_or(BackgroundMethod::Median)),
+    };
+    let channel_names: Vec<String> = ops.channels.iter().map(|c| c.to_string()).collect();
+    // Each run records its own rejections.
+    if let Err(e) = sink_rejected_reset(&ops.out_path) {
+        warn!("Failed to reset rejected.csv - {}", e);
+    }
+
+    sink_to_metadata(&ops.out_path, "channels", &(String::from("raw;") + &channel_names.join(";")))
+        .unwrap();
+
+    // Record the pixel type of the base files and how they were normalised.
+    sink_to_metadata(&ops.out_path, "dtype", &ops.pixel_ops.pixel_type.to_string()).unwrap();
+    sink_to_metadata(&ops.out_path, "normalise", &ops.pixel_ops.normalise.to_string()).unwrap();
+
+    // Record the range scale, if the range axis is being normalised.
+    let range_scale = ops.metres_per_pixel.map_or(String::from("native"), |m| m.to_string());
+    sink_to_metadata(&ops.out_path, "metres_per_pixel", &range_scale).unwrap();
+
+    // Record the time base, so a slice of num_frames can be turned back into a duration.
+    let time_base = ops.resample.map_or(String::from("native"), |r| r.to_string());
+    sink_to_metadata(&ops.out_path, "time_base", &time_base).unwrap();
+
+    // Record the number of frames in each datum and what the mask values mean, for validate.
+    sink_to_metadata(&ops.out_path, "num_frames", &ops.num_frames.to_string()).unwrap();
+    sink_to_metadata(&ops.out_path, "labels", "class").unwrap();
+
     let mut pb = ProgressBar::new(num_groups as u64);
     pb.format("╢▌▌░╟");
 
     for group in generator {
         // The pipeline proper - nodes in order.
         assert!(group.points.len() > 0);
-        let track_raw = node_group_to_trackraw(&group);
+        let huid = group.origin.group.huid.clone();
+        let sonar_id = group.origin.sonar_id;
+
+        // The raw track comes from the stage cache too, if an earlier run stored it.
+        let track_raw = match stage_cache.as_ref().and_then(|c| c.get_track(&huid, sonar_id, &group.origin)) {
+            Some(track) if !track.boxes.is_empty() => track,
+            _ => {
+                let track = node_group_to_trackraw(&group);
+
+                if let Some(cache) = &stage_cache {
+                    if let Err(e) = cache.put_track(&huid, sonar_id, &track) {
+                        warn!("Failed to cache the track of {} - {}", huid, e);
+                    }
+                }
+
+                track
+            }
+        };
         assert!(track_raw.boxes.len() > 0);
-        let interp_track = node_trackraw_interpolate(&track_raw);
-        let filled_track = node_trackraw_overlap(&interp_track);
-        let kalman_track = node_track_kalman(&filled_track);
-        let rejected = crabseal::nodes::node_reject_on_trackraw(&kalman_track, ops.reject_rate);
+        let pieces = node_trackraw_interpolate_timed(
+            &track_raw,
+            &group.images,
+            ops.interpolation,
+            ops.max_gap,
+            ops.gap_policy,
+        );
+
+        // Each piece of a split track is filled and smoothed on its own, so nothing crosses a gap.
+        let (reasons, overlap_track_second) = match pieces {
+            Some(pieces) => {
+                let smooth = |t: &TrackRawT| match &ops.kalman {
+                    Some(k) => node_track_smooth(t, &group.images, k),
+                    None => node_track_kalman(t),
+                };
+                let kalman_tracks: Vec<TrackRawT> = pieces.iter().map(|p| smooth(&node_trackraw_overlap(p))).collect();
+                let reasons: Vec<String> =
+                    node_reject_on_track_metrics(&kalman_tracks, &group.images, &ops.track_rules, &btable)
+                        .iter()
+                        .map(|r| r.to_string())
+                        .collect();
+
+                let overlapped: Vec<TrackRawT> = kalman_tracks.iter().map(node_trackraw_overlap).collect();
+                (reasons, node_trackraw_merge(&overlapped))
+            }
+            None => (vec![String::from("gap")], track_raw),
+        };
+
+        // Rejected groups are recorded, with the reasons, for the stats report.
+        let rejected = !reasons.is_empty();
+
+        if rejected {
+            info!("Rejected {} - {}", group.origin.group.huid, reasons.join(","));
+            if let Err(e) = sink_to_rejected(&ops.out_path, &group.origin.group.huid, &reasons.join(";")) {
+                warn!("Failed to record the rejection of {} - {}", group.origin.group.huid, e);
+            }
+        }
 
         if !rejected {
-            let overlap_track_second = node_trackraw_overlap(&kalman_track);
-            let maybe_vol = node_group_to_volume(&group, &img_paths);
+            // Reuse the volumes of an earlier run with the same upstream parameters, if there are any.
+            // A cached group that can't be read is built from its frames, as if it weren't there.
+            let cached_volumes = stage_cache.as_ref().filter(|c| c.contains(&huid, sonar_id)).and_then(|c| {
+                let volumes = c
+                    .get_volumes::<T>(&huid, sonar_id, &group.origin)
+                    .filter(|v| !v.is_empty())
+                    .zip(c.get_masks(&huid, sonar_id, &group.origin));
+
+                if volumes.is_none() {
+                    warn!("Failed to read the cached volumes of {} - reading its frames instead", huid);
+                }
+
+                volumes
+            });
+            let mut maybe_vol = if cached_volumes.is_some() {
+                None
+            } else {
+                node_group_to_volume_as(&group, &frame_source, &frame_cache, ops.frame_policy)
+            };
+
+            // Move the group, track and volume onto the fixed time base, if there is one.
+            let (group, overlap_track_second) = match &ops.resample {
+                Some(resample) => {
+                    maybe_vol = maybe_vol.map(|(v, i)| {
+                        (node_volume_resample(&v, &group.images, resample), node_volume_resample(&i, &group.images, resample))
+                    });
+                    (
+                        node_group_resample(&group, resample),
+                        node_trackraw_resample(&overlap_track_second, &group.images, resample),
+                    )
+                }
+                None => (group, overlap_track_second),
+            };
+
+            // Rescale the range axis of the group and track to a fixed metres per pixel, if asked to.
+            let (group, overlap_track_second) = match ops.metres_per_pixel {
+                Some(mpp) => (
+                    node_group_range_normalise(&group, mpp),
+                    node_trackraw_range_normalise(&overlap_track_second, &group.images, mpp),
+                ),
+                None => (group, overlap_track_second),
+            };
+
+            // The volumes are rescaled after the clutter and background removal, which work at the recorded scale.
+            let range_norm = |volume: VolumeT<T>, filter: FilterType| match ops.metres_per_pixel {
+                Some(mpp) => node_volume_range_normalise(&volume, &group.images, mpp, filter),
+                None => volume,
+            };
+            let range_norm_mask = |volume: VolumeT| match ops.metres_per_pixel {
+                Some(mpp) => node_volume_range_normalise(&volume, &group.images, mpp, Nearest),
+                None => volume,
+            };
+
+            // The raw volume and extras are the slow part, so they come from the stage cache if they can.
+            let stage: Option<(VolumeT<T>, Vec<(String, VolumeT<T>)>, Vec<(String, VolumeT)>)> = match cached_volumes {
+                Some((mut volumes, mut mask_extras)) => {
+                    // The volumes were read before the group was moved, so they take its new origin.
+                    for (_, volume) in volumes.iter_mut() {
+                        volume.origin = Some(group.origin.clone());
+                    }
+
+                    for (_, mask) in mask_extras.iter_mut() {
+                        mask.origin = Some(group.origin.clone());
+                    }
+
+                    let data = volumes.remove(0).1;
+                    Some((data, volumes, mask_extras))
+                }
+                None => maybe_vol.map(|(mut data_volume, ignore_volume)| {
+                    // Extra outputs are written alongside the raw and mask files, with the same slicing.
+                    // The extra masks keep their values as u8; only the data extras take the pixel options.
+                    let mut extras: Vec<(String, VolumeT<T>)> = vec![];
+                    let mut mask_extras: Vec<(String, VolumeT)> = vec![];
+
+                    // Frames that were padded are marked in an ignore mask. Rejection never pads.
+                    if ops.frame_policy != FramePolicy::Reject {
+                        mask_extras.push((String::from("ignore"), node_volume_resize(&range_norm_mask(ignore_volume), ops.target_width, Nearest)));
+                    }
+
+                    // Remove the long term static clutter first, optionally exporting it as a mask.
+                    if let Some(model) = &clutter_model {
+                        if let Some(clutter) = node_group_clutter(&group, model, &img_paths) {
+                            if ops.clutter_threshold > 0.0 {
+                                let clutter_mask = node_clutter_to_mask(&data_volume, &clutter, ops.clutter_threshold);
+                                mask_extras.push((String::from("clutter"), node_volume_resize(&range_norm_mask(clutter_mask), ops.target_width, Nearest)));
+                            }
 
-            if maybe_vol.is_some() {
-                let data_volume = maybe_vol.unwrap();
+                            data_volume = node_volume_subtract_background(&data_volume, &clutter);
+                        }
+                    }
+
+                    // Either replace the raw data with the background removed version, or keep it as an extra output.
+                    if let Some(method) = &ops.background {
+                        let background = node_volume_background(&data_volume, method);
+                        let removed = node_volume_subtract_background(&data_volume, &background);
+
+                        if ops.background_extra {
+                            extras.push((String::from("bgsub"), node_volume_resize(&range_norm(removed, Lanczos3), ops.target_width, Lanczos3)));
+                        } else {
+                            data_volume = removed;
+                        }
+                    }
+
+                    let data_volume = range_norm(data_volume, Lanczos3);
+                    let data_resized = node_volume_resize(&data_volume, ops.target_width, Lanczos3); // Still not sure this is the best?
+
+                    if let Some(cache) = &stage_cache {
+                        let mut volumes: Vec<(&str, &VolumeT<T>)> = vec![("data", &data_resized)];
+                        volumes.extend(extras.iter().map(|(name, extra)| (name.as_str(), extra)));
+                        let masks: Vec<(&str, &VolumeT)> = mask_extras.iter().map(|(name, mask)| (name.as_str(), mask)).collect();
+
+                        if let Err(e) = cache.put_masks(&huid, sonar_id, &masks).and_then(|_| cache.put_volumes(&huid, sonar_id, &volumes)) {
+                            warn!("Failed to cache the volumes of {} - {}", huid, e);
+                        }
+                    }
+
+                    (data_resized, extras, mask_extras)
+                }),
+            };
+
+            if let Some((data_resized, extras, mask_extras)) = stage {
                 let mask_volume = node_trackraw_to_volume(&overlap_track_second, &group);
-                let data_resized = node_volume_resize(&data_volume, ops.target_width, Lanczos3); // Still not sure this is the best?
                 let mask_resized = node_volume_resize(&mask_volume, ops.target_width, Nearest); // Make sure we never get rogue values here.
-                let datum: crabseal::ptypes::DatumT =
+                let datum: DatumT<T> =
                     node_combine_datum_mask(&data_resized, &mask_resized);
 
-                if !node_reject_on_no_mask(&datum) {
+                if !node_reject_on_mask(&datum, ops.mask_border, ops.mask_min_pixels) {
                     // Split the datum and recombine after trim. Do a trim here to make things a bit tighter.
                     let (trim_data, _) = node_volume_trim(
                         &VolumeT {
@@ -187,45 +491,86 @@ fn run_pipeline(ops: &MovesOps) {
                         },
                         &overlap_track_second,
                     );
-                    let (trim_og, _) = node_volume_trim(
-                        &VolumeT {
-                            volume: data_volume.volume,
-                            extents: data_volume.extents,
-                            origin: datum.origin.clone(),
-                        },
-                        &overlap_track_second,
-                    );
 
-                    let datum_trimed: crabseal::ptypes::DatumT =
+                    let datum_trimed: DatumT<T> =
                         node_combine_datum_mask(&trim_data, &trim_mask);
 
                     // Decide which set this goes into.
                     // TODO - we need a proper node/sink or something for this
-                    if count < num_train {
-                        sink_to_png(&datum_trimed, &path_train);
-                        sink_to_txt(&datum_trimed, &path_train_txt);
-                        let slices =
-                            node_slice_datum_overlap(&datum_trimed, ops.num_frames as usize);
-                        if slices.is_some() {
-                            sink_to_npz(slices.unwrap(), &path_train, "");
+                    let (set_path, set_txt) = if count < num_train {
+                        (&path_train, &path_train_txt)
+                    } else if count < num_train + num_test {
+                        (&path_test, &path_test_txt)
+                    } else {
+                        (&path_val, &path_val_txt)
+                    };
+
+                    sink_to_png(&datum_trimed, set_path);
+                    sink_to_txt(&datum_trimed, set_txt);
+
+                    // Either write the whole datum, or balanced patches cut from the same places in it and the extras.
+                    let positions = if patch_ops.count > 0 {
+                        patch_positions(&datum_trimed, &patch_ops)
+                    } else {
+                        vec![]
+                    };
+
+                    // The extra channels are made after the trim, from the final raw volume. The normalisation
+                    // is found once for the whole group, so every piece, slice and augmented copy is scaled alike.
+                    let datum_channels = node_datum_channels(&datum_trimed, &ops.channels, channel_background.as_ref());
+                    let norm_params = node_datum_norm_params(&datum_channels, ops.pixel_ops.normalise);
+
+                    for piece in cut_datum(&datum_channels, &positions, &patch_ops) {
+                        if let Some(slices) = node_slice_datum_overlap(&piece, ops.num_frames as usize) {
+                            sink_to_npz_as(slices, set_path, "", ops.pixel_ops.pixel_type, &norm_params);
                         }
-                    } else if count >= num_train && count < num_train + num_test {
-                        sink_to_png(&datum_trimed, &path_test);
-                        sink_to_txt(&datum_trimed, &path_test_txt);
-                        let slices =
-                            node_slice_datum_overlap(&datum_trimed, ops.num_frames as usize);
-                        if slices.is_some() {
-                            sink_to_npz(slices.unwrap(), &path_test, "");
+                    }
+
+                    // The extra outputs are sliced in the same way as the datum so the files pair up.
+                    for (name, extra) in &extras {
+                        let (trim_extra, _) = node_volume_trim(extra, &overlap_track_second);
+                        let extra_datum = node_combine_datum_mask(&trim_extra, &trim_mask);
+                        let extra_params = node_datum_norm_params(&extra_datum, ops.pixel_ops.normalise);
+
+                        for piece in cut_datum(&extra_datum, &positions, &patch_ops) {
+                            if let Some(extra_slices) = node_slice_datum_overlap(&piece, ops.num_frames as usize) {
+                                sink_to_npz_extra_as(extra_slices, set_path, "", name, ops.pixel_ops.pixel_type, &extra_params);
+                            }
                         }
-                    } else {
-                        sink_to_png(&datum_trimed, &path_val);
-                        sink_to_txt(&datum_trimed, &path_val_txt);
-                        let slices =
-                            node_slice_datum_overlap(&datum_trimed, ops.num_frames as usize);
-                        if slices.is_some() {
-                            sink_to_npz(slices.unwrap(), &path_val, "");
+                    }
+
+                    // The extra masks hold class values, so are written as u8 as they are.
+                    for (name, mask) in &mask_extras {
+                        let (trim_extra, _) = node_volume_trim(mask, &overlap_track_second);
+                        let extra_datum = node_combine_datum_mask(&trim_extra, &trim_mask);
+
+                        for piece in cut_datum(&extra_datum, &positions, &patch_ops) {
+                            if let Some(extra_slices) = node_slice_datum_overlap(&piece, ops.num_frames as usize) {
+                                sink_to_npz_extra(extra_slices, set_path, "", name);
+                            }
                         }
                     }
+
+                    // Augmented copies only ever go into the training set.
+                    if count < num_train {
+                        for piece in cut_datum(&datum_trimed, &positions, &patch_ops) {
+                            for (aidx, aug) in node_datum_augment(&piece, &augment_ops).iter().enumerate() {
+                                let aug_channels = node_datum_channels(aug, &ops.channels, channel_background.as_ref());
+                                let aug_slices = node_slice_datum_overlap(&aug_channels, ops.num_frames as usize);
+                                if let Some(aug_slices) = aug_slices {
+                                    sink_to_npz_as(aug_slices, &path_train, &format!("aug{:02}", aidx), ops.pixel_ops.pixel_type, &norm_params);
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    if let Err(e) = sink_to_rejected(&ops.out_path, &group.origin.group.huid, "mask") {
+                        warn!("Failed to record the rejection of {} - {}", group.origin.group.huid, e);
+                    }
+                }
+            } else {
+                if let Err(e) = sink_to_rejected(&ops.out_path, &group.origin.group.huid, "frames") {
+                    warn!("Failed to record the rejection of {} - {}", group.origin.group.huid, e);
                 }
             }
         }
@@ -233,6 +578,8 @@ fn run_pipeline(ops: &MovesOps) {
         pb.inc();
         count = count + 1;
     }
+
+    info!("Frame cache: {}", frame_cache.stats());
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -248,6 +595,8 @@ struct Args {
     dbuser: String,
     #[arg(long, default_value_t = String::from("kissfromarose"))]
     dbpass: String,
+    #[arg(long, default_value_t = String::from(""))]
+    snapshot: String,
     #[arg(long, default_value_t = 0)]
     width: u32,
     #[arg(short, long, default_value_t = String::from("853,854"))]
@@ -258,12 +607,102 @@ struct Args {
     numframes: u32,
     #[arg(short, long, default_value_t = 6)]
     threads: u32,
+    #[arg(long, default_value_t = String::from(""))]
+    filter: String,
     #[arg(long, default_value_t = String::from("none"))]
     sqlfilter: String,
     #[arg(long, default_value_t = 2)]
     sizefilter: i32,
     #[arg(long, default_value_t = 400.0)]
     rejectrate: f32,
+    #[arg(long, default_value_t = String::from("frame"))]
+    interp: String,
+    #[arg(long, default_value_t = 0.0)]
+    maxgap: f32,
+    #[arg(long, default_value_t = String::from("split"))]
+    gappolicy: String,
+    #[arg(long, default_value_t = String::from("legacy"))]
+    kalman: String,
+    #[arg(long, default_value_t = 100.0)]
+    kalmanq: f32,
+    #[arg(long, default_value_t = 25.0)]
+    kalmanr: f32,
+    #[arg(long, default_value_t = 0.0)]
+    minduration: f32,
+    #[arg(long, default_value_t = 0.0)]
+    maxspeed: f32,
+    #[arg(long, default_value_t = 0.0)]
+    maxturn: f32,
+    #[arg(long, default_value_t = 0.0)]
+    minarea: f32,
+    #[arg(long, default_value_t = 0.0)]
+    maxarea: f32,
+    #[arg(long, default_value_t = 0.0)]
+    maxareastd: f32,
+    #[arg(long, default_value_t = 32)]
+    maskborder: u32,
+    #[arg(long, default_value_t = 50)]
+    maskmin: u32,
+    /// Augmented copies of each training datum to write. Only the base and mask files are
+    /// copied - the extra outputs, such as the ignore, clutter and bgsub files, are not.
+    #[arg(long, default_value_t = 0)]
+    augment: u32,
+    #[arg(long, default_value_t = 0)]
+    augmentseed: u64,
+    #[arg(long, default_value_t = String::from("none"))]
+    background: String,
+    #[arg(long, default_value_t = false)]
+    bgextra: bool,
+    #[arg(long, default_value_t = String::from("none"))]
+    clutter: String,
+    #[arg(long, default_value_t = String::from(""))]
+    clutterpath: String,
+    #[arg(long, default_value_t = String::from("use"))]
+    index: String,
+    #[arg(long, default_value_t = 256)]
+    framecache: usize,
+    #[arg(long, default_value_t = String::from(""))]
+    framecachepath: String,
+    #[arg(long, default_value_t = String::from(""))]
+    glfpath: String,
+    #[arg(long, default_value_t = 4)]
+    glfcache: usize,
+    #[arg(long, default_value_t = String::from("db"))]
+    points: String,
+    #[arg(long, default_value_t = String::from(""))]
+    pgdfpath: String,
+    #[arg(long, default_value_t = String::from(""))]
+    stagecache: String,
+    #[arg(long, default_value_t = String::from(""))]
+    indexpath: String,
+    #[arg(long, default_value_t = 100)]
+    clutterframes: usize,
+    #[arg(long, default_value_t = 0.0)]
+    clutterthreshold: f32,
+    #[arg(long, default_value_t = String::from(""))]
+    channels: String,
+    #[arg(long, default_value_t = String::from("u8"))]
+    dtype: String,
+    #[arg(long, default_value_t = String::from("none"))]
+    normalise: String,
+    #[arg(long, default_value_t = String::from("none"))]
+    resample: String,
+    #[arg(long, default_value_t = 0.0)]
+    metresperpixel: f32,
+    #[arg(long, default_value_t = String::from("reject"))]
+    framepolicy: String,
+    #[arg(long, default_value_t = 0)]
+    patches: usize,
+    #[arg(long, default_value_t = 64)]
+    patchsize: u32,
+    #[arg(long, default_value_t = 0.5)]
+    patchpositive: f32,
+    #[arg(long, default_value_t = 0.0)]
+    patchminmask: f32,
+    #[arg(long, default_value_t = 16.0)]
+    patchspacing: f32,
+    #[arg(long, default_value_t = 0)]
+    patchseed: u64,
 }
 
 fn setup_logger(args: &Args) -> Result<(), fern::InitError> {
@@ -315,6 +754,30 @@ fn main() {
         }
     }
 
+    // The structured filter, from a file or the command line. Raw SQL is the alternative.
+    let mut group_filter: Option<GroupFilter> = None;
+
+    // A snapshot has no database to run SQL against.
+    if sqlfilter.is_some() && !args.snapshot.is_empty() {
+        println!("--sqlfilter can't be used with --snapshot - select the groups of a snapshot with --filter instead");
+        return;
+    }
+
+    if !args.filter.is_empty() {
+        if sqlfilter.is_some() {
+            println!("--filter and --sqlfilter can't be used together");
+            return;
+        }
+
+        match GroupFilter::load(&args.filter) {
+            Ok(f) => group_filter = Some(f),
+            Err(e) => {
+                println!("--filter {}", e);
+                return;
+            }
+        }
+    }
+
     let mut sonarids: Vec<i32> = vec![];
     let splits = args.sonarids.split(",");
 
@@ -323,6 +786,170 @@ fn main() {
         sonarids.push(sonar_id);
     }
 
+    // Background removal method, if any.
+    let background: Option<BackgroundMethod> = match args.background.as_str() {
+        "none" => None,
+        method => match method.parse::<BackgroundMethod>() {
+            Ok(m) => Some(m),
+            Err(e) => {
+                println!("--background {}", e);
+                return;
+            }
+        },
+    };
+
+    // Static clutter removal method, if any. The maps live with the dataset unless told otherwise.
+    let clutter: Option<BackgroundMethod> = match args.clutter.as_str() {
+        "none" => None,
+        method => match method.parse::<BackgroundMethod>() {
+            Ok(m) => Some(m),
+            Err(e) => {
+                println!("--clutter {}", e);
+                return;
+            }
+        },
+    };
+
+    // Extra input channels, as a comma separated list such as diff,bgsub,norm.
+    let channels = match parse_channels(&args.channels) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("--channels {}", e);
+            return;
+        }
+    };
+
+    // The pixel type the raw volume and channels are written as - u8, u16 or f32 - and
+    // how they are normalised first - none, max, minmax or standard.
+    let pixel_type = match args.dtype.parse::<PixelType>() {
+        Ok(t) => t,
+        Err(e) => {
+            println!("--dtype {}", e);
+            return;
+        }
+    };
+
+    let normalise = match args.normalise.parse::<Normalise>() {
+        Ok(n) => n,
+        Err(e) => {
+            println!("--normalise {}", e);
+            return;
+        }
+    };
+
+    if normalise == Normalise::Standard && pixel_type != PixelType::F32 {
+        println!("--normalise standard needs --dtype f32.");
+        return;
+    }
+
+    // Fixed time base, if any - nearest:<hz>, linear:<hz> or stride:<n>.
+    let resample: Option<Resample> = match args.resample.as_str() {
+        "none" => None,
+        method => match method.parse::<Resample>() {
+            Ok(r) => Some(r),
+            Err(e) => {
+                println!("--resample {}", e);
+                return;
+            }
+        },
+    };
+
+    // How to interpolate the tracks - frame, linear, catmull or bspline.
+    let interpolation = match args.interp.parse::<Interpolation>() {
+        Ok(i) => i,
+        Err(e) => {
+            println!("--interp {}", e);
+            return;
+        }
+    };
+
+    // What to do with tracks that have a gap longer than --maxgap.
+    let gap_policy = match args.gappolicy.parse::<GapPolicy>() {
+        Ok(p) => p,
+        Err(e) => {
+            println!("--gappolicy {}", e);
+            return;
+        }
+    };
+
+    // The Kalman smoother output - legacy, predicted, filtered or smoothed.
+    let kalman: Option<KalmanOps> = match args.kalman.as_str() {
+        "legacy" => None,
+        output => match output.parse::<KalmanOutput>() {
+            Ok(o) => Some(KalmanOps {
+                process_noise: args.kalmanq,
+                measurement_noise: args.kalmanr,
+                output: o,
+            }),
+            Err(e) => {
+                println!("--kalman {}", e);
+                return;
+            }
+        },
+    };
+
+    // Track rejection rules in metres and seconds. 0 turns a rule off.
+    let rule = |v: f32| if v > 0.0 { Some(v) } else { None };
+    let track_rules = TrackRules {
+        min_duration: rule(args.minduration),
+        max_speed: rule(args.maxspeed),
+        max_turn_rate: rule(args.maxturn),
+        min_area: rule(args.minarea),
+        max_area: rule(args.maxarea),
+        max_area_std: rule(args.maxareastd),
+        max_gap: None,
+        max_pixel_std: rule(args.rejectrate),
+    };
+
+    // What to do with frames shorter or taller than the crop height.
+    let frame_policy = match args.framepolicy.parse::<FramePolicy>() {
+        Ok(p) => p,
+        Err(e) => {
+            println!("--framepolicy {}", e);
+            return;
+        }
+    };
+
+    // What to do with the FITS index - use, verify, refresh or rebuild.
+    let index_mode = match args.index.parse::<IndexMode>() {
+        Ok(m) => m,
+        Err(e) => {
+            println!("--index {}", e);
+            return;
+        }
+    };
+
+    // Where the points come from - db, pgdf or check.
+    let points_mode = match args.points.parse::<PointsMode>() {
+        Ok(m) => m,
+        Err(e) => {
+            println!("--points {}", e);
+            return;
+        }
+    };
+
+    if points_mode != PointsMode::Db && args.pgdfpath.is_empty() {
+        println!("--points {} needs --pgdfpath", points_mode);
+        return;
+    }
+
+    // The FITS archive is often read only, so the index lives with the caches, or the output.
+    let index_path = if args.indexpath.is_empty() {
+        let index_dir = [&args.framecachepath, &args.stagecache]
+            .into_iter()
+            .find(|p| !p.is_empty())
+            .unwrap_or(&args.outpath);
+        default_index_path(Path::new(&args.fitspath), Path::new(index_dir))
+    } else {
+        PathBuf::from(&args.indexpath)
+    };
+
+    let clutter_path = if args.clutterpath.is_empty() {
+        PathBuf::from(&args.outpath).join("clutter")
+    } else {
+        PathBuf::from(&args.clutterpath)
+    };
+
     let gops = MovesOps {
         target_width: args.width,
         sonar_ids: sonarids,
@@ -330,14 +957,55 @@ fn main() {
         dbuser: args.dbuser,
         dbpass: args.dbpass,
         dbname: args.dbname,
+        snapshot_path: if args.snapshot.is_empty() { None } else { Some(PathBuf::from(&args.snapshot)) },
         fits_path: PathBuf::from(&args.fitspath),
+        index_path,
+        index_mode,
+        frame_cache_size: args.framecache,
+        frame_cache_path: if args.framecachepath.is_empty() { None } else { Some(PathBuf::from(&args.framecachepath)) },
+        glf_path: if args.glfpath.is_empty() { None } else { Some(PathBuf::from(&args.glfpath)) },
+        glf_cache_size: args.glfcache,
+        points_mode,
+        pgdf_path: if args.pgdfpath.is_empty() { None } else { Some(PathBuf::from(&args.pgdfpath)) },
+        stage_cache_path: if args.stagecache.is_empty() { None } else { Some(PathBuf::from(&args.stagecache)) },
         out_path: PathBuf::from(&args.outpath),
         num_frames: args.numframes,
         num_threads: args.threads,
+        group_filter,
         sqlfilter: sqlfilter,
         sector_size: 32,
         crop_height: 1632,
-        reject_rate: args.rejectrate,
+        interpolation,
+        max_gap: if args.maxgap > 0.0 { Some(args.maxgap) } else { None },
+        gap_policy,
+        kalman,
+        track_rules,
+        mask_border: args.maskborder,
+        mask_min_pixels: args.maskmin,
+        augment_copies: args.augment,
+        augment_seed: args.augmentseed,
+        background,
+        background_extra: args.bgextra,
+        clutter,
+        clutter_path,
+        clutter_frames: args.clutterframes,
+        clutter_threshold: args.clutterthreshold,
+        channels,
+        pixel_ops: PixelOps { pixel_type, normalise },
+        resample,
+        frame_policy,
+        patch_count: args.patches,
+        patch_size: args.patchsize,
+        patch_positive: args.patchpositive,
+        patch_min_mask: args.patchminmask,
+        patch_spacing: args.patchspacing,
+        patch_seed: args.patchseed,
+        sector_range_size: 32,
+        sector_extra_sizes: vec![],
+        sector_min_occupancy: 0.0,
+        sector_label: SectorLabel::Class,
+        sector_float: false,
+        metres_per_pixel: if args.metresperpixel > 0.0 { Some(args.metresperpixel) } else { None },
     };
 
     if args.width < 32 {
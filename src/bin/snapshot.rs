@@ -0,0 +1,139 @@
+//! A program that exports the groups a filter selects, with their images, points and
+//! tracks, from the database to a snapshot directory. The pipelines can then build a dataset
+//! from the snapshot with --snapshot, where there is no database.
+//!
+//! The database password is read from SEALHITS_DBPASS, in the environment or a .env file,
+//! unless --dbpass is given.
+//!
+//! Example usage:
+//!     cargo run --release --bin snapshot -- --filter "codes=seal;interact=false" --outpath /data/snapshot
+//!     cargo run --release --bin snapshot -- --sqlfilter /data/dataset/filter.sql --outpath /data/snapshot
+//!
+
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   snapshot.rs - export a snapshot of the database.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+*/
+use clap::Parser;
+use crabseal::datasource::{export_snapshot, DataSource, PgSource};
+use crabseal::filter::GroupFilter;
+use std::env;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, default_value_t = '.'.to_string())]
+    outpath: String,
+    #[arg(long, default_value_t = String::from("sealhits"))]
+    dbname: String,
+    #[arg(long, default_value_t = String::from("sealhits"))]
+    dbuser: String,
+    #[arg(long, default_value_t = String::from(""))]
+    dbpass: String,
+    #[arg(short, long, default_value_t = String::from("853,854"))]
+    sonarids: String,
+    #[arg(short, long, default_value_t = 0)]
+    limit: usize,
+    #[arg(long, default_value_t = String::from(""))]
+    filter: String,
+    #[arg(long, default_value_t = String::from("none"))]
+    sqlfilter: String,
+}
+
+fn main() {
+    let args = Args::parse();
+    let mut sonar_ids: Vec<i32> = vec![];
+
+    for split in args.sonarids.split(",") {
+        match split.parse::<i32>() {
+            Ok(id) => sonar_ids.push(id),
+            Err(e) => {
+                println!("--sonarids {}", e);
+                return;
+            }
+        }
+    }
+
+    let mut group_filter: Option<GroupFilter> = None;
+
+    if !args.filter.is_empty() {
+        if args.sqlfilter != "none" {
+            println!("--filter and --sqlfilter can't be used together");
+            return;
+        }
+
+        match GroupFilter::load(&args.filter) {
+            Ok(f) => group_filter = Some(f),
+            Err(e) => {
+                println!("--filter {}", e);
+                return;
+            }
+        }
+    }
+
+    // Keep the password off the command line and out of the code, if we can.
+    dotenvy::dotenv().ok();
+
+    let dbpass = if args.dbpass.is_empty() {
+        match env::var("SEALHITS_DBPASS") {
+            Ok(p) => p,
+            Err(e) => {
+                println!("--dbpass not given and SEALHITS_DBPASS {}", e);
+                return;
+            }
+        }
+    } else {
+        args.dbpass.clone()
+    };
+
+    let source = PgSource::new(&args.dbuser, &dbpass, &args.dbname);
+
+    // The same groups the pipelines would select - the filter if there is one.
+    let groups = if let Some(filter) = &group_filter {
+        let mut groups = source.groups_filter(filter);
+
+        if args.limit > 0 {
+            groups.truncate(args.limit);
+        }
+
+        groups
+    } else if args.sqlfilter != "none" {
+        match read_to_string(&args.sqlfilter) {
+            Ok(sql) => source.groups_sql(&sql),
+            Err(e) => {
+                println!("--sqlfilter {}", e);
+                return;
+            }
+        }
+    } else if args.limit > 0 {
+        source.groups_limit(args.limit)
+    } else {
+        source.groups()
+    };
+
+    let out_path = PathBuf::from(&args.outpath);
+
+    match export_snapshot(&source, &groups, &sonar_ids, &out_path) {
+        Ok(stats) => println!(
+            "Exported {} groups, {} images, {} points and {} tracks to {}",
+            stats.groups,
+            stats.images,
+            stats.points,
+            stats.tracks,
+            out_path.display()
+        ),
+        Err(e) => println!("Failed to write the snapshot to {}: {}", out_path.display(), e),
+    }
+}
@@ -0,0 +1,52 @@
+//! A program that reads back a generated dataset and reports how many of each class are in
+//! each split, the track lengths, mask coverage, sonar balance and the rejected groups.
+//!
+//! Example usage:
+//!     cargo run --release --bin stats -- --datapath /data/dataset
+//!
+
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   stats.rs - report statistics on a generated dataset.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+*/
+use clap::Parser;
+use crabseal::stats::dataset_stats;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, default_value_t = '.'.to_string())]
+    datapath: String,
+    #[arg(short, long, default_value_t = String::from(""))]
+    outpath: String,
+}
+
+fn main() {
+    let args = Args::parse();
+    let data_path = PathBuf::from(&args.datapath);
+
+    // The report goes alongside the dataset unless asked otherwise.
+    let out_path = if args.outpath.is_empty() {
+        data_path.clone()
+    } else {
+        PathBuf::from(&args.outpath)
+    };
+
+    let stats = dataset_stats(&data_path);
+
+    match stats.write(&out_path) {
+        Ok(_) => print!("{}", stats.to_markdown()),
+        Err(e) => println!("Failed to write the report to {}: {}", out_path.display(), e),
+    }
+}
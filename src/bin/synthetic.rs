@@ -0,0 +1,98 @@
+//! A program that generates synthetic sonar data - frames of speckle with blobs moving
+//! through the sonar fan - as FITS files and a snapshot of matching groups, images, points and
+//! tracks. Run the pipelines on the result with --fitspath <outpath>/fits and
+//! --snapshot <outpath>/snapshot.
+//!
+//! Example usage:
+//!     cargo run --release --bin synthetic -- --outpath /data/synthetic --groups 100
+//!
+
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   synthetic.rs - generate synthetic sonar data.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+*/
+use clap::Parser;
+use crabseal::synthetic::{write_synthetic, SyntheticOps};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, default_value_t = '.'.to_string())]
+    outpath: String,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    #[arg(short, long, default_value_t = 10)]
+    groups: usize,
+    #[arg(short, long, default_value_t = 32)]
+    frames: usize,
+    #[arg(long, default_value_t = 250)]
+    interval: i64,
+    #[arg(long, default_value_t = 854)]
+    sonarid: i32,
+    #[arg(long, default_value_t = 512)]
+    beams: u32,
+    #[arg(long, default_value_t = 1632)]
+    height: u32,
+    #[arg(long, default_value_t = 55.0)]
+    range: f32,
+    #[arg(short, long, default_value_t = 1)]
+    blobs: usize,
+    #[arg(long, default_value_t = 1.5)]
+    blobsize: f32,
+    #[arg(long, default_value_t = 1.5)]
+    speed: f32,
+    #[arg(long, default_value_t = 20.0)]
+    speckle: f32,
+    #[arg(long, default_value_t = 180.0)]
+    brightness: f32,
+    #[arg(long, default_value_t = String::from("seal"))]
+    code: String,
+    #[arg(long, default_value_t = false)]
+    plain: bool,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let ops = SyntheticOps {
+        seed: args.seed,
+        groups: args.groups,
+        frames: args.frames,
+        interval_ms: args.interval,
+        sonar_id: args.sonarid,
+        beams: args.beams,
+        height: args.height,
+        range: args.range,
+        blobs: args.blobs,
+        blob_size: args.blobsize,
+        speed: args.speed,
+        speckle: args.speckle,
+        brightness: args.brightness,
+        code: args.code,
+        lz4: !args.plain,
+        ..SyntheticOps::default()
+    };
+
+    let out_path = PathBuf::from(&args.outpath);
+
+    match write_synthetic(&ops, &out_path) {
+        Ok(_) => println!(
+            "Generated {} groups of {} frames in {}",
+            ops.groups,
+            ops.frames,
+            out_path.display()
+        ),
+        Err(e) => println!("Failed to write synthetic data to {}: {}", out_path.display(), e),
+    }
+}
@@ -0,0 +1,49 @@
+//! A program that checks a generated dataset before training - that every raw file has a
+//! mask of the right shape, the mask values are known classes, no group is in two splits,
+//! every datum has the same number of frames and every file can be read. Exits with a
+//! non-zero status if anything is wrong.
+//!
+//! Example usage:
+//!     cargo run --release --bin validate -- --datapath /data/dataset
+//!
+
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   validate.rs - check a generated dataset.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+*/
+use clap::Parser;
+use crabseal::validate::validate_dataset;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, default_value_t = '.'.to_string())]
+    datapath: String,
+    #[arg(short, long, default_value_t = 0)]
+    numframes: usize,
+}
+
+fn main() {
+    let args = Args::parse();
+    let data_path = PathBuf::from(&args.datapath);
+
+    // 0 means use the number of frames recorded in the metadata.
+    let num_frames = if args.numframes > 0 { Some(args.numframes) } else { None };
+    let report = validate_dataset(&data_path, num_frames);
+    print!("{}", report);
+
+    if !report.is_valid() {
+        std::process::exit(1);
+    }
+}
@@ -16,14 +16,23 @@
  *   
  */
 
-use fitsio::errors::Error as FitsError;
-use lzzzz::lz4f::{WriteCompressor, Preferences};
 use chrono::{DateTime, Utc, Datelike};
 use image::Luma;
-use std::io::prelude::*;
-use std::fs::{File, self};
+use std::fs;
 use std::path::{Path, PathBuf};
-use crate::image::img_to_fits;
+use crate::files::parse_fits_name;
+use crate::frame_source::FrameSource;
+use crate::models::Images;
+use crate::fits::{read_fits_image, write_fits_image};
+use crate::image::VolumePixel;
+use image::imageops::crop_imm;
+use image::ImageBuffer;
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
 
 
 /// Save an image to a directory, compressed.
@@ -37,32 +46,321 @@ pub fn img_to_cache_compressed(
     fits_name: &String,
     fits_time: &DateTime<Utc>,
     img_data: &image::ImageBuffer<Luma<u8>, Vec<u8>>,
-) -> Result<PathBuf, FitsError> {
+) -> std::io::Result<PathBuf> {
     //! Save an img to cache as a compressed fits with lz4
-    // First create a subdir if it doesn't exist already
-    let y = format!("{:02}", fits_time.year());
-    let m = format!("{:02}", fits_time.month());
-    let d = format!("{:02}", fits_time.day());
-    let dpath: PathBuf = cache_path.join(y + "_" + &m + "_" + &d); 
-    
-    if !dpath.exists() {
-        std::fs::create_dir(&dpath)?;
-    }
+    img_to_cache_compressed_as(cache_path, fits_name, fits_time, img_data)
+}
 
-    let fpath: PathBuf = dpath.join(&fits_name);
-    let cpath: PathBuf = dpath.join(fits_name.clone() + ".lz4");
-    let cached = img_to_fits(&fpath, img_data)?;
-    // Now compress
-    // Delete temp file if it exists - ignore error
-    let _ = fs::remove_file(&cpath);
-    let mut stream_in = File::open(cached).unwrap();
-    let mut buf: Vec<u8> = vec![];
-    stream_in.read_to_end(&mut buf)?;
-
-    let mut stream_out = File::create(&cpath).unwrap();
-    let mut w = WriteCompressor::new(&mut stream_out, Preferences::default()).unwrap(); // Ignore error?
-    w.write_all(&buf)?;
-
-    fs::remove_file(fpath)?;
+/// Save an image of any of the volume pixel types to a directory, compressed, in a
+/// directory for its day.
+///
+/// * `cache_path` - the path to the cache directory.
+/// * `fits_name` - filename for the fits file.
+/// * `fits_time` - the time for this fits file.
+/// * `img_data` - The data for the image itself.
+pub fn img_to_cache_compressed_as<T: VolumePixel>(
+    cache_path: &Path,
+    fits_name: &str,
+    fits_time: &DateTime<Utc>,
+    img_data: &ImageBuffer<Luma<T>, Vec<T>>,
+) -> std::io::Result<PathBuf> {
+    let day = format!("{:04}_{:02}_{:02}", fits_time.year(), fits_time.month(), fits_time.day());
+    let dpath: PathBuf = cache_path.join(day);
+    fs::create_dir_all(&dpath)?;
+
+    // Write under a temporary name, then move into place, so two threads caching the same
+    // file never trip over each other and a reader never sees half a file.
+    let tpath: PathBuf = dpath.join(Uuid::new_v4().to_string() + ".tmp.lz4");
+    let cpath: PathBuf = dpath.join(fits_name.to_string() + ".lz4");
+    write_fits_image(&tpath, img_data)?;
+    fs::rename(&tpath, &cpath)?;
     Ok(cpath)
 }
+
+/// The key of a frame in the cache - the FITS name and the crop, if any.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct FrameKey {
+    filename: String,
+    crop: Option<(u32, u32)>,
+}
+
+impl FrameKey {
+    /// The name the frame is stored under on disk. Cropped frames have the crop in the name,
+    /// and frames wider than u8 their pixel type.
+    ///
+    /// * `pixel` - the name of the pixel type.
+    fn disk_name(&self, pixel: &str) -> String {
+        let stem = self.filename.strip_suffix(".fits").unwrap_or(&self.filename);
+        let crop = self.crop.map_or(String::new(), |(w, h)| format!("_{}x{}", w, h));
+        let pixel = if pixel == "u8" { String::new() } else { format!("_{}", pixel) };
+        format!("{}{}{}.fits", stem, crop, pixel)
+    }
+}
+
+/// A decoded frame of any of the volume pixel types.
+type Frame<T> = ImageBuffer<Luma<T>, Vec<T>>;
+
+/// The frames held in memory, with the order they were last used in.
+struct FrameLru<T: VolumePixel> {
+    frames: HashMap<FrameKey, (Arc<Frame<T>>, u64)>,
+    order: BTreeMap<u64, FrameKey>,
+    tick: u64,
+}
+
+/// How well a FrameCache is doing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// Frames found in memory.
+    pub hits: u64,
+    /// Frames found in the on-disk cache.
+    pub disk_hits: u64,
+    /// Frames that had to be decoded from the FITS.
+    pub misses: u64,
+    /// Frames dropped from memory to make room.
+    pub evictions: u64,
+}
+
+impl CacheStats {
+    /// The fraction of requests that didn't need the FITS decoding.
+    pub fn hit_rate(&self) -> f32 {
+        let total = self.hits + self.disk_hits + self.misses;
+
+        if total == 0 {
+            return 0.0;
+        }
+
+        (self.hits + self.disk_hits) as f32 / total as f32
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} memory hits, {} disk hits, {} misses, {} evictions, {:.1}% hit rate",
+            self.hits,
+            self.disk_hits,
+            self.misses,
+            self.evictions,
+            self.hit_rate() * 100.0
+        )
+    }
+}
+
+/// A cache of decoded, optionally cropped, frames, shared between threads. The most recently
+/// used frames are kept in memory, and every decoded frame can also be saved, compressed,
+/// to a directory laid out like the FITS files so later runs needn't decode it again. Frames
+/// are u8 unless asked otherwise, converted from the bit depth they were recorded with.
+pub struct FrameCache<T: VolumePixel = u8> {
+    capacity: usize,
+    disk_path: Option<PathBuf>,
+    lru: Mutex<FrameLru<T>>,
+    hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl<T: VolumePixel> FrameCache<T> {
+    /// Create a new, empty cache.
+    ///
+    /// * `capacity` - the most frames to keep in memory. 0 keeps none.
+    /// * `disk_path` - the directory for the on-disk cache, if any.
+    pub fn new(capacity: usize, disk_path: Option<PathBuf>) -> FrameCache<T> {
+        FrameCache {
+            capacity,
+            disk_path,
+            lru: Mutex::new(FrameLru {
+                frames: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+            hits: AtomicU64::new(0),
+            disk_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Look for a frame in memory, marking it as just used.
+    fn get_memory(&self, key: &FrameKey) -> Option<Arc<Frame<T>>> {
+        let mut lru = self.lru.lock().unwrap();
+        let tick = lru.tick + 1;
+        let (frame, last) = lru.frames.get_mut(key)?;
+        let frame = frame.clone();
+        let last = std::mem::replace(last, tick);
+        lru.order.remove(&last);
+        lru.order.insert(tick, key.clone());
+        lru.tick = tick;
+        Some(frame)
+    }
+
+    /// Keep a frame in memory, dropping the least recently used if there isn't room.
+    fn put_memory(&self, key: FrameKey, frame: Arc<Frame<T>>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut lru = self.lru.lock().unwrap();
+        lru.tick += 1;
+        let tick = lru.tick;
+
+        if let Some((_, last)) = lru.frames.insert(key.clone(), (frame, tick)) {
+            lru.order.remove(&last);
+        }
+
+        lru.order.insert(tick, key);
+
+        while lru.frames.len() > self.capacity {
+            if let Some((_, oldest)) = lru.order.pop_first() {
+                lru.frames.remove(&oldest);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Where a frame lives in the on-disk cache, with the time used for its day directory.
+    fn disk_entry(&self, key: &FrameKey) -> Option<(PathBuf, String, DateTime<Utc>)> {
+        let disk_path = self.disk_path.as_ref()?;
+        let time = parse_fits_name(&key.filename).map(|(t, _)| t.and_utc()).unwrap_or_default();
+        let name = key.disk_name(T::NAME);
+        let day = format!("{:04}_{:02}_{:02}", time.year(), time.month(), time.day());
+        Some((disk_path.join(day).join(name.clone() + ".lz4"), name, time))
+    }
+
+    /// Get a frame, from memory, the on-disk cache, or by decoding the FITS. Cropped frames
+    /// are the top left of the frame, clipped to the frame if it is smaller than the crop,
+    /// and are cut from the whole frame if that is in memory.
+    ///
+    /// * `fits_path` - the path to the FITS, compressed or otherwise.
+    /// * `filename` - the name of the FITS, as in Images.filename.
+    /// * `crop` - the width and height to crop to, if any.
+    pub fn frame(&self, fits_path: &Path, filename: &str, crop: Option<(u32, u32)>) -> Option<Arc<Frame<T>>> {
+        self.cached(filename, crop, || read_fits_image(fits_path).ok().map(|f| f.to_image()))
+    }
+
+    /// Get the frame for an image as frame does, but decoding it with a FrameSource, such as
+    /// the FITS files or the GLFs, on a miss.
+    ///
+    /// * `source` - where to decode the frame from.
+    /// * `image` - the Images object we want the frame for.
+    /// * `crop` - the width and height to crop to, if any.
+    pub fn frame_from(&self, source: &dyn FrameSource, image: &Images, crop: Option<(u32, u32)>) -> Option<Arc<Frame<T>>> {
+        self.cached(&image.filename, crop, || source.read_frame(image).map(|f| f.to_image()))
+    }
+
+    /// Get a frame from memory or the on-disk cache, decoding it on a miss.
+    ///
+    /// * `filename` - the name of the FITS, as in Images.filename.
+    /// * `crop` - the width and height to crop to, if any.
+    /// * `decode` - decodes the whole frame.
+    fn cached(&self, filename: &str, crop: Option<(u32, u32)>, decode: impl FnOnce() -> Option<Frame<T>>) -> Option<Arc<Frame<T>>> {
+        let key = FrameKey {
+            filename: filename.to_string(),
+            crop,
+        };
+
+        if let Some(frame) = self.get_memory(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(frame);
+        }
+
+        // The whole frame may already be in memory, such as when the generator read it for its size.
+        let whole = FrameKey { filename: key.filename.clone(), crop: None };
+
+        if let (Some((w, h)), Some(frame)) = (crop, self.get_memory(&whole)) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            let frame = Arc::new(crop_imm(&*frame, 0, 0, w.min(frame.width()), h.min(frame.height())).to_image());
+            self.put_memory(key, frame.clone());
+            return Some(frame);
+        }
+
+        let disk = self.disk_entry(&key);
+
+        if let Some(frame) = disk.as_ref().and_then(|(path, _, _)| read_fits_image(path).ok()).map(|f| f.to_image()) {
+            self.disk_hits.fetch_add(1, Ordering::Relaxed);
+            let frame = Arc::new(frame);
+            self.put_memory(key, frame.clone());
+            return Some(frame);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let mut frame = decode()?;
+
+        if let Some((w, h)) = crop {
+            frame = crop_imm(&frame, 0, 0, w.min(frame.width()), h.min(frame.height())).to_image();
+        }
+
+        if let Some((_, name, time)) = &disk {
+            let disk_path = self.disk_path.as_ref().unwrap();
+
+            if let Err(e) = img_to_cache_compressed_as(disk_path, name, time, &frame) {
+                warn!("Failed to cache frame {} - {}", name, e);
+            }
+        }
+
+        let frame = Arc::new(frame);
+        self.put_memory(key, frame.clone());
+        Some(frame)
+    }
+
+    /// The hit and miss counts so far.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            disk_hits: self.disk_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::img_to_fits;
+    use image::{GrayImage, Luma};
+
+    #[test]
+    fn test_frame_cache() {
+        let root = std::env::temp_dir().join("crabseal_test_frame_cache");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("fits")).unwrap();
+
+        let names = ["2023_05_28_22_52_42_130_854.fits", "2023_05_28_22_52_43_130_854.fits"];
+        let paths: Vec<PathBuf> = names.iter().map(|n| root.join("fits").join(n)).collect();
+
+        for (i, path) in paths.iter().enumerate() {
+            img_to_fits(path, &GrayImage::from_pixel(8, 6, Luma([i as u8 + 1]))).unwrap();
+        }
+
+        // Room for one frame in memory, with the disk behind it.
+        let cache = FrameCache::<u8>::new(1, Some(root.join("cache")));
+        let frame = cache.frame(&paths[0], names[0], Some((4, 10))).unwrap();
+        assert_eq!(frame.dimensions(), (4, 6));
+        assert_eq!(frame.get_pixel(0, 0)[0], 1);
+        assert!(root.join("cache").join("2023_05_28").join("2023_05_28_22_52_42_130_854_4x10.fits.lz4").exists());
+
+        cache.frame(&paths[0], names[0], Some((4, 10))).unwrap();
+        cache.frame(&paths[1], names[1], None).unwrap();
+        // A crop of a whole frame in memory needs no decoding.
+        assert_eq!(cache.frame(&paths[1], names[1], Some((2, 2))).unwrap().dimensions(), (2, 2));
+        // The first frame was evicted, but is still on disk.
+        cache.frame(&paths[0], names[0], Some((4, 10))).unwrap();
+
+        let stats = cache.stats();
+        assert_eq!(stats, CacheStats { hits: 2, disk_hits: 1, misses: 2, evictions: 3 });
+        assert_eq!(stats.hit_rate(), 0.6);
+
+        // A new cache, as on a later run, finds everything on disk.
+        let later = FrameCache::<u8>::new(0, Some(root.join("cache")));
+        assert_eq!(later.frame(&paths[1], names[1], None).unwrap().get_pixel(7, 5)[0], 2);
+        assert_eq!(later.stats().disk_hits, 1);
+
+        // Wider pixels are cached under names of their own.
+        let wide: FrameCache<u16> = FrameCache::new(0, Some(root.join("cache")));
+        assert_eq!(wide.frame(&paths[0], names[0], None).unwrap().get_pixel(0, 0)[0], 1);
+        assert!(root.join("cache").join("2023_05_28").join("2023_05_28_22_52_42_130_854_u16.fits.lz4").exists());
+    }
+}
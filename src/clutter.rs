@@ -0,0 +1,319 @@
+//! A long term model of the static clutter (seabed, moorings, turbine foundations) seen by
+//! each sonar. One map is built per sonar per day from many FITS frames, then cached on disk.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   clutter.rs - static clutter / seabed maps per sonar per day.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::files::parse_fits_name;
+use crate::fits::{read_fits_image, write_fits_image};
+use crate::image::{ImageVolume, VolumePixel};
+use crate::resolver::FitsLookup;
+use crate::nodes_background::{background_from_frames, fit_background, BackgroundMethod};
+use crate::ptypes::{GroupT, ImageT, VolumeT};
+use chrono::{NaiveDate, NaiveDateTime};
+use image::imageops::{crop_imm, replace};
+use image::{GrayImage, ImageBuffer, Luma};
+use log::{info, warn};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A clutter map of any of the volume pixel types.
+type ClutterMap<T> = ImageBuffer<Luma<T>, Vec<T>>;
+
+/// The maps already loaded, by sonar and day. None records a map that could not be built.
+type ClutterMaps<T> = HashMap<(i32, NaiveDate), Option<Arc<ClutterMap<T>>>>;
+
+/// Builds, caches and hands out the clutter maps. Safe to share between threads. The maps
+/// are u8 unless asked otherwise, built from frames at the bit depth they were recorded with.
+pub struct ClutterModel<T: VolumePixel = u8> {
+    /// Where the maps are kept on disk.
+    cache_path: PathBuf,
+    /// How the frames are combined into a map.
+    method: BackgroundMethod,
+    /// The most frames to read for a single map.
+    max_frames: usize,
+    /// Maps already loaded this run.
+    maps: Mutex<ClutterMaps<T>>,
+}
+
+/// Pick up to `count` items, evenly spaced, from a list.
+///
+/// * `items` - the list to pick from.
+/// * `count` - the most items to pick.
+fn sample_evenly<T: Clone>(items: &[T], count: usize) -> Vec<T> {
+    if items.len() <= count || count == 0 {
+        return items.to_vec();
+    }
+
+    let step = items.len() as f64 / count as f64;
+    (0..count).map(|i| items[(i as f64 * step) as usize].clone()).collect()
+}
+
+/// Combine frames into a clutter map as tall as the tallest frame. Heights wobble by a few
+/// pixels, so the map is built in bands of rows, each from only the frames that reach it.
+///
+/// * `frames` - the frames, at least one.
+/// * `method` - how the frames are combined.
+fn clutter_from_frames<T: VolumePixel>(frames: &[ClutterMap<T>], method: &BackgroundMethod) -> ClutterMap<T> {
+    let width = frames.iter().map(|f| f.width()).min().unwrap();
+    let mut heights: Vec<u32> = frames.iter().map(|f| f.height()).collect();
+    heights.sort_unstable();
+    heights.dedup();
+
+    let mut map = ImageBuffer::from_pixel(width, *heights.last().unwrap(), Luma([T::DEFAULT_MIN_VALUE]));
+    let mut top = 0;
+
+    for bottom in heights {
+        let band: Vec<ClutterMap<T>> = frames
+            .iter()
+            .filter(|f| f.height() >= bottom)
+            .map(|f| crop_imm(f, 0, top, width, bottom - top).to_image())
+            .collect();
+
+        if bottom > top {
+            replace(&mut map, &background_from_frames(&band, method), 0, top as i64);
+        }
+
+        top = bottom;
+    }
+
+    map
+}
+
+impl<T: VolumePixel> ClutterModel<T> {
+    /// Create a new ClutterModel. The cache directory is created if it doesn't exist.
+    ///
+    /// * `cache_path` - the directory to keep the maps in.
+    /// * `method` - how the frames are combined into a map. Median is the most robust.
+    /// * `max_frames` - the most frames, spread over the day, to use for each map.
+    pub fn new(cache_path: &Path, method: BackgroundMethod, max_frames: usize) -> io::Result<ClutterModel<T>> {
+        std::fs::create_dir_all(cache_path)?;
+
+        Ok(ClutterModel {
+            cache_path: cache_path.to_path_buf(),
+            method,
+            max_frames,
+            maps: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The path of the cached map for this sonar and day. The method and the most frames, and
+    /// the pixel type if it isn't u8, are in the name, so maps built with different options can
+    /// share a cache directory.
+    ///
+    /// * `sonar_id` - the sonar.
+    /// * `date` - the day.
+    pub fn map_path(&self, sonar_id: i32, date: &NaiveDate) -> PathBuf {
+        let method = self.method.to_string().replace(':', "-");
+        let pixel = if T::NAME == "u8" { String::new() } else { format!("_{}", T::NAME) };
+
+        self.cache_path.join(format!(
+            "clutter_{}_{}_{}_{}{}.fits",
+            sonar_id,
+            date.format("%Y_%m_%d"),
+            method,
+            self.max_frames,
+            pixel
+        ))
+    }
+
+    /// Build a clutter map from the frames a sonar recorded on a day.
+    ///
+    /// * `sonar_id` - the sonar.
+    /// * `date` - the day.
+    /// * `image_path_cache` - finds the FITS files recorded on the day.
+    pub fn build(&self, sonar_id: i32, date: &NaiveDate, image_path_cache: &dyn FitsLookup) -> Option<ClutterMap<T>> {
+        let mut names: Vec<(NaiveDateTime, PathBuf)> = image_path_cache
+            .day_files(date)
+            .into_iter()
+            .filter_map(|(name, path)| match parse_fits_name(&name) {
+                Some((time, sid)) if sid == sonar_id && time.date() == *date => Some((time, path)),
+                _ => None,
+            })
+            .collect();
+
+        if names.is_empty() {
+            warn!("No frames for sonar {} on {} to build a clutter map.", sonar_id, date);
+            return None;
+        }
+
+        names.sort();
+        let chosen = sample_evenly(&names, self.max_frames);
+        info!("Building clutter map for sonar {} on {} from {} frames.", sonar_id, date, chosen.len());
+
+        let frames: Vec<ClutterMap<T>> =
+            chosen.par_iter().filter_map(|(_, path)| read_fits_image(path).ok()).map(|f| f.to_image()).collect();
+
+        if frames.is_empty() {
+            return None;
+        }
+
+        Some(clutter_from_frames(&frames, &self.method))
+    }
+
+    /// Return the clutter map for a sonar and day, loading it from the cache directory, or
+    /// building and saving it if it isn't there yet.
+    ///
+    /// * `sonar_id` - the sonar.
+    /// * `date` - the day.
+    /// * `image_path_cache` - finds the FITS files recorded on the day.
+    pub fn get(&self, sonar_id: i32, date: &NaiveDate, image_path_cache: &dyn FitsLookup) -> Option<Arc<ClutterMap<T>>> {
+        let key = (sonar_id, *date);
+
+        if let Some(map) = self.maps.lock().unwrap().get(&key) {
+            return map.clone();
+        }
+
+        let path = self.map_path(sonar_id, date);
+
+        let map = match read_fits_image(&path) {
+            Ok(img) => Some(img.to_image()),
+            Err(_) => {
+                let built = self.build(sonar_id, date, image_path_cache);
+
+                if let Some(img) = &built {
+                    if let Err(e) = write_fits_image(&path, img) {
+                        warn!("Failed to save clutter map {} - {}", path.display(), e);
+                    }
+                }
+
+                built
+            }
+        };
+
+        // Two threads may build the same map at once. The result is the same, so it doesn't matter.
+        let map = map.map(Arc::new);
+        self.maps.lock().unwrap().insert(key, map.clone());
+        map
+    }
+}
+
+/// Get the clutter map for a GroupT as an ImageT. The day is taken from the first image.
+///
+/// * `group` - the GroupT we want the clutter for.
+/// * `model` - the ClutterModel.
+/// * `image_path_cache` - finds the FITS files.
+pub fn node_group_clutter<T: VolumePixel>(group: &GroupT, model: &ClutterModel<T>, image_path_cache: &dyn FitsLookup) -> Option<ImageT<T>> {
+    let first = group.images.first()?;
+    let map = model.get(group.origin.sonar_id, &first.time.date_naive(), image_path_cache)?;
+
+    Some(ImageT {
+        image: (*map).clone(),
+        extents: (0, 0, map.width(), map.height()),
+        origin: Some(group.origin.clone()),
+    })
+}
+
+/// Turn a clutter map into a 'static clutter' mask volume, matching the size and depth of a
+/// data volume. Pixels where the clutter is above the threshold are set to 1.
+///
+/// * `volume` - the VolumeT the mask should match.
+/// * `clutter` - the clutter ImageT.
+/// * `threshold` - clutter values above this are counted as static clutter, in the units of
+///   the volume - 0 to 255 for u8, 0 to 65535 for u16.
+pub fn node_clutter_to_mask<T: VolumePixel>(volume: &VolumeT<T>, clutter: &ImageT<T>, threshold: f32) -> VolumeT {
+    let mut mask = ImageVolume(vec![]);
+
+    for frame in &volume.volume.0 {
+        let bg = fit_background(clutter, frame.width(), frame.height());
+        let mut mframe = GrayImage::from_pixel(frame.width(), frame.height(), Luma([0]));
+
+        for (m, b) in mframe.pixels_mut().zip(bg.pixels()) {
+            if b.0[0].into() > threshold {
+                m.0[0] = 1;
+            }
+        }
+
+        mask.0.push(mframe);
+    }
+
+    VolumeT {
+        volume: mask,
+        extents: volume.extents,
+        origin: volume.origin.clone(),
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clutter_map() {
+        assert_eq!(sample_evenly(&[0, 1, 2, 3, 4, 5, 6, 7], 4), vec![0, 2, 4, 6]);
+
+        // The map is as tall as the tallest frame, each row from the frames that reach it.
+        let frames = vec![
+            GrayImage::from_pixel(4, 2, Luma([10])),
+            GrayImage::from_pixel(4, 3, Luma([20])),
+            GrayImage::from_pixel(4, 4, Luma([30])),
+        ];
+        let map = clutter_from_frames(&frames, &BackgroundMethod::Median);
+        assert_eq!(map.dimensions(), (4, 4));
+        assert_eq!(map.get_pixel(0, 1)[0], 20);
+        assert_eq!(map.get_pixel(0, 2)[0], 30);
+        assert_eq!(map.get_pixel(0, 3)[0], 30);
+
+        let date = NaiveDate::from_ymd_opt(2023, 5, 28).unwrap();
+        let model = ClutterModel::<u8>::new(&std::env::temp_dir(), BackgroundMethod::RunningPercentile(0.9), 50).unwrap();
+        assert!(model.map_path(854, &date).ends_with("clutter_854_2023_05_28_percentile-0.9_50.fits"));
+        let model = ClutterModel::<u16>::new(&std::env::temp_dir(), BackgroundMethod::Mean, 50).unwrap();
+        assert!(model.map_path(854, &date).ends_with("clutter_854_2023_05_28_mean_50_u16.fits"));
+    }
+
+    #[test]
+    fn test_clutter_mask() {
+        let volume = VolumeT {
+            volume: ImageVolume(vec![GrayImage::new(4, 2), GrayImage::new(4, 2)]),
+            extents: (0, 0, 4, 2),
+            origin: None,
+        };
+
+        // A map at the original image size, taller than the cropped volume.
+        let clutter = ImageT {
+            image: GrayImage::from_fn(4, 3, |x, _| Luma([x as u8 * 50])),
+            extents: (0, 0, 4, 3),
+            origin: None,
+        };
+
+        let mask = node_clutter_to_mask(&volume, &clutter, 60.0);
+        assert_eq!(mask.volume.0.len(), 2);
+        assert_eq!(mask.volume.0[1].get_pixel(1, 1)[0], 0);
+        assert_eq!(mask.volume.0[1].get_pixel(2, 1)[0], 1);
+
+        // Float volumes take a threshold in their own units, however small.
+        let volume = VolumeT {
+            volume: ImageVolume(vec![ImageBuffer::new(4, 2)]),
+            extents: (0, 0, 4, 2),
+            origin: None,
+        };
+        let clutter = ImageT {
+            image: ImageBuffer::from_fn(4, 2, |x, _| Luma([x as f32 * 0.25])),
+            extents: (0, 0, 4, 2),
+            origin: None,
+        };
+
+        let mask = node_clutter_to_mask::<f32>(&volume, &clutter, 0.3);
+        assert_eq!(mask.volume.0[0].get_pixel(1, 0)[0], 0);
+        assert_eq!(mask.volume.0[0].get_pixel(2, 0)[0], 1);
+
+        // A cache directory that can't be made is an error, not a panic.
+        let file = std::env::temp_dir().join("crabseal_test_clutter_file");
+        std::fs::write(&file, "").unwrap();
+        assert!(ClutterModel::<u8>::new(&file.join("maps"), BackgroundMethod::Median, 50).is_err());
+    }
+}
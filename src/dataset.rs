@@ -0,0 +1,235 @@
+//! Reading a generated dataset back from disk - the NPZ files in each split, the set text
+//! files and the list of rejected groups. Used by the stats and validate programs.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   dataset.rs - read back a generated dataset.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use std::fs::{read_dir, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// The splits a dataset is divided into, as named in the images directory and set files.
+pub const SPLITS: [&str; 3] = ["train", "test", "val"];
+
+/// One NPZ file in a dataset, with the parts of its name pulled apart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatasetFile {
+    /// The full path to the file.
+    pub path: PathBuf,
+    /// The split this file is in.
+    pub split: String,
+    /// The huid of the group it came from.
+    pub huid: String,
+    /// The index of the slice within the datum.
+    pub slice: usize,
+    /// The extents, as x_min, y_min, x_max, y_max.
+    pub extents: (u32, u32, u32, u32),
+    /// The sonar the group came from.
+    pub sonar_id: i32,
+    /// The common suffix, such as aug00. Empty for most files.
+    pub suffix: String,
+    /// What the file holds - base, mask, or the name of an extra output.
+    pub kind: String,
+}
+
+impl DatasetFile {
+    /// The name this file shares with the rest of its datum, without the kind.
+    pub fn stem(&self) -> String {
+        let (x0, y0, x1, y1) = self.extents;
+        format!(
+            "{}_{:02}_{:02}-{:02}-{:02}-{:02}_{}_{}",
+            self.huid, self.slice, x0, y0, x1, y1, self.sonar_id, self.suffix
+        )
+    }
+}
+
+/// Pull apart the name of an NPZ file written by the sinks - huid, slice index, extents,
+/// sonar id, suffix and kind, separated by underscores. Returns None if the name doesn't
+/// match. The huid may itself contain underscores, so the extents are found first.
+///
+/// * `path` - the path to the file.
+/// * `split` - the split the file is in.
+pub fn parse_npz_name(path: &Path, split: &str) -> Option<DatasetFile> {
+    let name = path.file_name()?.to_str()?;
+    let tokens: Vec<&str> = name.strip_suffix(".npz")?.split('_').collect();
+    let eidx = tokens.iter().rposition(|t| t.split('-').count() == 4)?;
+
+    if eidx < 2 || tokens.len() < eidx + 4 {
+        return None;
+    }
+
+    let ext: Vec<u32> = tokens[eidx].split('-').map(|v| v.parse::<u32>()).collect::<Result<_, _>>().ok()?;
+    let slice = tokens[eidx - 1].parse::<usize>().ok()?;
+    let sonar_id = tokens[eidx + 1].parse::<i32>().ok()?;
+    let kind = tokens[eidx + 3..].join("_");
+
+    if kind.is_empty() {
+        return None;
+    }
+
+    Some(DatasetFile {
+        path: path.to_path_buf(),
+        split: split.to_string(),
+        huid: tokens[..eidx - 1].join("_"),
+        slice,
+        extents: (ext[0], ext[1], ext[2], ext[3]),
+        sonar_id,
+        suffix: tokens[eidx + 2].to_string(),
+        kind,
+    })
+}
+
+/// List the NPZ files in every split of a dataset. NPZ files with names we don't recognise are
+/// returned in the second vector. Anything else, such as the PNG previews, is skipped.
+///
+/// * `root` - the root of the dataset.
+pub fn dataset_files(root: &Path) -> (Vec<DatasetFile>, Vec<PathBuf>) {
+    let mut files: Vec<DatasetFile> = vec![];
+    let mut unknown: Vec<PathBuf> = vec![];
+
+    for split in SPLITS {
+        let entries = match read_dir(root.join("images").join(split)) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+
+            if path.extension().is_none_or(|e| e != "npz") {
+                continue;
+            }
+
+            match parse_npz_name(&path, split) {
+                Some(file) => files.push(file),
+                None => unknown.push(path),
+            }
+        }
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    unknown.sort();
+    (files, unknown)
+}
+
+/// An array read back from an NPZ file. u8 and u16 arrays keep their values, f32 arrays
+/// (the occupancy masks, or raw volumes written as f32) are flagged as fractions.
+pub struct NpzArray {
+    pub shape: Vec<u64>,
+    pub values: Vec<f32>,
+    pub fraction: bool,
+}
+
+/// Read an NPZ file written by the sinks. These are single numpy arrays of u8, u16 or f32.
+///
+/// * `path` - the path to the file.
+pub fn read_npz(path: &Path) -> io::Result<NpzArray> {
+    let npy = npyz::NpyFile::new(BufReader::new(File::open(path)?))?;
+    let shape = npy.shape().to_vec();
+
+    let npy = match npy.try_data::<u8>() {
+        Ok(data) => {
+            return Ok(NpzArray {
+                shape,
+                values: data.map(|v| v.map(|v| v as f32)).collect::<io::Result<Vec<f32>>>()?,
+                fraction: false,
+            })
+        }
+        Err(npy) => npy,
+    };
+
+    match npy.try_data::<u16>() {
+        Ok(data) => Ok(NpzArray {
+            shape,
+            values: data.map(|v| v.map(|v| v as f32)).collect::<io::Result<Vec<f32>>>()?,
+            fraction: false,
+        }),
+        Err(npy) => Ok(NpzArray {
+            shape,
+            values: npy.into_vec::<f32>()?,
+            fraction: true,
+        }),
+    }
+}
+
+/// Read the huids listed in the set text file of a split. A missing file gives no huids.
+///
+/// * `root` - the root of the dataset.
+/// * `split` - the split.
+pub fn read_set(root: &Path, split: &str) -> Vec<String> {
+    match File::open(root.join(format!("set_{}.txt", split))) {
+        Ok(file) => BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Read the rejected groups and their reasons. A group rejected for several reasons has
+/// them separated by semicolons.
+///
+/// * `root` - the root of the dataset.
+pub fn read_rejected(root: &Path) -> Vec<(String, Vec<String>)> {
+    match File::open(root.join("rejected.csv")) {
+        Ok(file) => BufReader::new(file)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|l| {
+                let (huid, reasons) = l.split_once(',')?;
+                Some((huid.to_string(), reasons.split(';').map(|r| r.to_string()).collect()))
+            })
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Read a value from the dataset metadata.
+///
+/// * `root` - the root of the dataset.
+/// * `key` - the key to look up.
+pub fn read_metadata(root: &Path, key: &str) -> Option<String> {
+    let file = File::open(root.join("metadata.csv")).ok()?;
+
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .find_map(|l| l.split_once(',').filter(|(k, _)| *k == key).map(|(_, v)| v.to_string()))
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_npz_name() {
+        let file = parse_npz_name(Path::new("a_b_03_00-10-64-74_854__mask.npz"), "train").unwrap();
+        assert_eq!(file.huid, "a_b");
+        assert_eq!(file.slice, 3);
+        assert_eq!(file.extents, (0, 10, 64, 74));
+        assert_eq!(file.sonar_id, 854);
+        assert_eq!(file.suffix, "");
+        assert_eq!(file.kind, "mask");
+        assert_eq!(file.stem() + "_mask.npz", "a_b_03_00-10-64-74_854__mask.npz");
+
+        let extra = parse_npz_name(Path::new("x_00_00-00-32-32_853_aug01_mask_16x16.npz"), "val").unwrap();
+        assert_eq!(extra.suffix, "aug01");
+        assert_eq!(extra.kind, "mask_16x16");
+
+        assert!(parse_npz_name(Path::new("notes.txt"), "train").is_none());
+        assert!(parse_npz_name(Path::new("huid_00_00-00-32-32_854_.npz"), "train").is_none());
+    }
+}
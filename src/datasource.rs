@@ -0,0 +1,667 @@
+//! Where the groups, images, points and tracks come from - the *SealHits* PostgreSQL database,
+//! or a snapshot of the rows a run needs, exported to files so datasets can be built where
+//! there is no database.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   datasource.rs - database and snapshot data sources.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::db::{
+    establish_connection, get_groups, get_groups_filter, get_groups_limit, get_groups_sql, get_images_group,
+    get_points_group_image, get_tracks_group,
+};
+use crate::filter::GroupFilter;
+use crate::models::{Groups, Images, Points, TracksGroups};
+use chrono::{DateTime, SecondsFormat, Utc};
+use diesel::PgConnection;
+use log::info;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// The files of a snapshot, in its directory.
+const GROUPS_FILE: &str = "groups.json";
+const IMAGES_FILE: &str = "images.json";
+const GROUPS_IMAGES_FILE: &str = "groups_images.json";
+const POINTS_FILE: &str = "points.json";
+const TRACKS_FILE: &str = "tracks_groups.json";
+
+/// Everything the pipelines read about the groups. Shared between the generator threads.
+pub trait DataSource: Sync {
+    /// All the groups.
+    fn groups(&self) -> Vec<Groups>;
+
+    /// The first groups, up to a limit.
+    ///
+    /// * `limit` - how many groups.
+    fn groups_limit(&self, limit: usize) -> Vec<Groups>;
+
+    /// The groups an SQL filter selects.
+    ///
+    /// * `sql` - the SQL selecting the groups.
+    fn groups_sql(&self, sql: &str) -> Vec<Groups>;
+
+    /// The groups a filter selects. By default every group is matched against the filter, with
+    /// its images for the sonars.
+    ///
+    /// * `filter` - the filter selecting the groups.
+    fn groups_filter(&self, filter: &GroupFilter) -> Vec<Groups> {
+        self.groups()
+            .into_iter()
+            .filter(|g| filter.matches(g))
+            .filter(|g| {
+                filter.sonar_ids.is_empty()
+                    || filter.sonar_ids.iter().any(|&id| !self.images_group(g.uid, id).is_empty())
+            })
+            .collect()
+    }
+
+    /// The images of a group on a sonar, in time order.
+    ///
+    /// * `group_uid` - the uid of the group.
+    /// * `sonar_id` - the sonar.
+    fn images_group(&self, group_uid: Uuid, sonar_id: i32) -> Vec<Images>;
+
+    /// The points of a group in an image. Could be none.
+    ///
+    /// * `group_uid` - the uid of the group.
+    /// * `image` - the image.
+    fn points_group_image(&self, group_uid: Uuid, image: &Images) -> Vec<Points>;
+
+    /// The tracks of a group, with the PGDFs they were saved in.
+    ///
+    /// * `group_uid` - the uid of the group.
+    fn tracks_group(&self, group_uid: Uuid) -> Vec<TracksGroups>;
+}
+
+/// The *SealHits* PostgreSQL database. Each thread takes a connection of its own, made the
+/// first time it is needed and kept for the next query.
+pub struct PgSource {
+    url: String,
+    connections: Mutex<Vec<PgConnection>>,
+}
+
+impl PgSource {
+    /// Create a new PgSource for a database on localhost. Connects straight away, so a bad
+    /// database fails early.
+    ///
+    /// * `dbuser` - the database user.
+    /// * `dbpass` - the database password.
+    /// * `dbname` - the database name.
+    pub fn new(dbuser: &str, dbpass: &str, dbname: &str) -> PgSource {
+        let url = String::from("postgres://") + dbuser + ":" + dbpass + "@localhost/" + dbname;
+        let connection = establish_connection(url.clone());
+
+        PgSource {
+            url,
+            connections: Mutex::new(vec![connection]),
+        }
+    }
+
+    /// Run a query with a free connection, making one if there are none.
+    ///
+    /// * `query` - the query to run.
+    fn with_connection<R>(&self, query: impl FnOnce(&mut PgConnection) -> R) -> R {
+        let free = self.connections.lock().unwrap().pop();
+        let mut connection = free.unwrap_or_else(|| establish_connection(self.url.clone()));
+        let result = query(&mut connection);
+        self.connections.lock().unwrap().push(connection);
+        result
+    }
+}
+
+impl DataSource for PgSource {
+    fn groups(&self) -> Vec<Groups> {
+        self.with_connection(get_groups)
+    }
+
+    fn groups_limit(&self, limit: usize) -> Vec<Groups> {
+        self.with_connection(|c| get_groups_limit(c, limit as i64))
+    }
+
+    fn groups_sql(&self, sql: &str) -> Vec<Groups> {
+        self.with_connection(|c| get_groups_sql(c, sql.to_string()))
+    }
+
+    fn groups_filter(&self, filter: &GroupFilter) -> Vec<Groups> {
+        self.with_connection(|c| get_groups_filter(c, filter))
+    }
+
+    fn images_group(&self, group_uid: Uuid, sonar_id: i32) -> Vec<Images> {
+        self.with_connection(|c| get_images_group(c, group_uid, sonar_id))
+    }
+
+    fn points_group_image(&self, group_uid: Uuid, image: &Images) -> Vec<Points> {
+        self.with_connection(|c| get_points_group_image(c, group_uid, image.uid))
+    }
+
+    fn tracks_group(&self, group_uid: Uuid) -> Vec<TracksGroups> {
+        self.with_connection(|c| get_tracks_group(c, group_uid))
+    }
+}
+
+/// Builds an invalid data error.
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// A time as it is written to a snapshot - RFC 3339, to the microsecond, like PostgreSQL.
+fn time_str(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// Reads the fields of a snapshot row by name.
+struct Row<'a> {
+    fields: &'a Map<String, Value>,
+}
+
+impl Row<'_> {
+    fn value(&self, name: &str) -> io::Result<&Value> {
+        self.fields.get(name).ok_or(invalid(format!("snapshot row has no {}", name)))
+    }
+
+    fn str(&self, name: &str) -> io::Result<&str> {
+        let value = self.value(name)?;
+        value.as_str().ok_or(invalid(format!("bad {} {} in snapshot", name, value)))
+    }
+
+    fn parse<T: DeserializeOwned>(&self, name: &str) -> io::Result<T> {
+        let value = self.value(name)?;
+        T::deserialize(value).map_err(|_| invalid(format!("bad {} {} in snapshot", name, value)))
+    }
+
+    fn time(&self, name: &str) -> io::Result<DateTime<Utc>> {
+        let value = self.str(name)?;
+        DateTime::parse_from_rfc3339(value)
+            .map(|t| t.with_timezone(&Utc))
+            .map_err(|_| invalid(format!("bad {} '{}' in snapshot", name, value)))
+    }
+}
+
+/// Read every row of a snapshot file - a JSON array of objects, one per row.
+///
+/// * `path` - the snapshot file.
+/// * `parse` - turns a row into the value it holds.
+fn read_rows<T>(path: &Path, parse: impl Fn(&Row) -> io::Result<T>) -> io::Result<Vec<T>> {
+    let rows: Vec<Map<String, Value>> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    rows.iter().map(|fields| parse(&Row { fields })).collect()
+}
+
+/// Write the rows of a snapshot file as a JSON array of objects, one row to a line.
+///
+/// * `path` - the snapshot file.
+/// * `rows` - the fields of each row, as JSON objects.
+fn write_rows(path: &Path, rows: impl Iterator<Item = Value>) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(writer, "[")?;
+
+    for (idx, row) in rows.enumerate() {
+        writeln!(writer, "{}", if idx == 0 { "" } else { "," })?;
+        serde_json::to_writer(&mut writer, &row)?;
+    }
+
+    writeln!(writer, "\n]")?;
+    writer.flush()
+}
+
+/// A snapshot of the rows a run needs, read from a directory of JSON files - one per table,
+/// with points carrying their group_id. Held in memory whole.
+pub struct SnapshotSource {
+    groups: Vec<Groups>,
+    /// The images of each group, in time order.
+    images: HashMap<Uuid, Vec<Images>>,
+    /// The points of each group.
+    points: HashMap<Uuid, Vec<Points>>,
+    /// The tracks of each group.
+    tracks: HashMap<Uuid, Vec<TracksGroups>>,
+}
+
+impl SnapshotSource {
+    /// Read a snapshot.
+    ///
+    /// * `path` - the snapshot directory.
+    pub fn new(path: &Path) -> io::Result<SnapshotSource> {
+        let groups = read_rows(&path.join(GROUPS_FILE), |r| {
+            Ok(Groups {
+                gid: r.parse("gid")?,
+                timestart: r.time("timestart")?,
+                interact: r.parse("interact")?,
+                mammal: r.parse("mammal")?,
+                fish: r.parse("fish")?,
+                bird: r.parse("bird")?,
+                sqlite: r.str("sqlite")?.to_string(),
+                uid: r.parse("uid")?,
+                code: r.str("code")?.to_string(),
+                comment: r.parse("comment")?,
+                timeend: r.time("timeend")?,
+                sqliteid: r.parse("sqliteid")?,
+                split: r.parse("split")?,
+                huid: r.str("huid")?.to_string(),
+            })
+        })?;
+
+        let all_images: HashMap<Uuid, Images> = read_rows(&path.join(IMAGES_FILE), |r| {
+            Ok(Images {
+                filename: r.str("filename")?.to_string(),
+                uid: r.parse("uid")?,
+                hastrack: r.parse("hastrack")?,
+                glf: r.str("glf")?.to_string(),
+                time: r.time("time")?,
+                sonarid: r.parse("sonarid")?,
+                range: r.parse("range")?,
+            })
+        })?
+        .into_iter()
+        .map(|i| (i.uid, i))
+        .collect();
+
+        let mut images: Vec<(Uuid, Images)> = vec![];
+
+        for (image_id, group_id) in read_rows(&path.join(GROUPS_IMAGES_FILE), |r| {
+            Ok((r.parse::<Uuid>("image_id")?, r.parse::<Uuid>("group_id")?))
+        })? {
+            let image = all_images
+                .get(&image_id)
+                .ok_or(invalid(format!("snapshot image {} is missing", image_id)))?;
+            images.push((group_id, image.clone()));
+        }
+
+        let points = read_rows(&path.join(POINTS_FILE), |r| {
+            Ok((
+                r.parse::<Uuid>("group_id")?,
+                Points {
+                    time: r.time("time")?,
+                    sonarid: r.parse("sonarid")?,
+                    minbearing: r.parse("minbearing")?,
+                    maxbearing: r.parse("maxbearing")?,
+                    minrange: r.parse("minrange")?,
+                    maxrange: r.parse("maxrange")?,
+                    track_id: r.parse("track_id")?,
+                    uid: r.parse("uid")?,
+                    peakbearing: r.parse("peakbearing")?,
+                    peakrange: r.parse("peakrange")?,
+                    maxvalue: r.parse("maxvalue")?,
+                    occupancy: r.parse("occupancy")?,
+                    objsize: r.parse("objsize")?,
+                },
+            ))
+        })?;
+
+        let tracks = read_rows(&path.join(TRACKS_FILE), |r| {
+            Ok(TracksGroups {
+                track_pam_id: r.parse("track_pam_id")?,
+                group_id: r.parse("group_id")?,
+                binfile: r.str("binfile")?.to_string(),
+                track_id: r.parse("track_id")?,
+            })
+        })?;
+
+        info!("Read a snapshot of {} groups from {}", groups.len(), path.display());
+        Ok(SnapshotSource::from_rows(groups, images, points, tracks))
+    }
+
+    /// Build a snapshot from rows already in memory.
+    ///
+    /// * `groups` - the groups.
+    /// * `images` - each image, with the uid of a group it belongs to.
+    /// * `points` - each point, with the uid of its group.
+    /// * `tracks` - the tracks of the groups.
+    pub fn from_rows(
+        groups: Vec<Groups>,
+        images: Vec<(Uuid, Images)>,
+        points: Vec<(Uuid, Points)>,
+        tracks: Vec<TracksGroups>,
+    ) -> SnapshotSource {
+        let mut snapshot = SnapshotSource {
+            groups,
+            images: HashMap::new(),
+            points: HashMap::new(),
+            tracks: HashMap::new(),
+        };
+
+        for (group_id, image) in images {
+            snapshot.images.entry(group_id).or_default().push(image);
+        }
+
+        for group_images in snapshot.images.values_mut() {
+            group_images.sort_by_key(|i| i.time);
+        }
+
+        for (group_id, point) in points {
+            snapshot.points.entry(group_id).or_default().push(point);
+        }
+
+        for track in tracks {
+            snapshot.tracks.entry(track.group_id).or_default().push(track);
+        }
+
+        snapshot
+    }
+}
+
+impl DataSource for SnapshotSource {
+    fn groups(&self) -> Vec<Groups> {
+        self.groups.clone()
+    }
+
+    fn groups_limit(&self, limit: usize) -> Vec<Groups> {
+        self.groups.iter().take(limit).cloned().collect()
+    }
+
+    fn groups_sql(&self, _sql: &str) -> Vec<Groups> {
+        // Quietly handing back every group would build a dataset from the wrong groups.
+        panic!("A snapshot can't run an SQL filter - select its groups with a GroupFilter (--filter) instead");
+    }
+
+    fn images_group(&self, group_uid: Uuid, sonar_id: i32) -> Vec<Images> {
+        self.images
+            .get(&group_uid)
+            .map(|images| images.iter().filter(|i| i.sonarid == sonar_id).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn points_group_image(&self, group_uid: Uuid, image: &Images) -> Vec<Points> {
+        // Matched as the database does - same sonar, same time.
+        self.points
+            .get(&group_uid)
+            .map(|points| {
+                points
+                    .iter()
+                    .filter(|p| p.sonarid == image.sonarid && p.time == image.time)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn tracks_group(&self, group_uid: Uuid) -> Vec<TracksGroups> {
+        self.tracks.get(&group_uid).cloned().unwrap_or_default()
+    }
+}
+
+/// How many of each row went into a snapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SnapshotStats {
+    pub groups: usize,
+    pub images: usize,
+    pub points: usize,
+    pub tracks: usize,
+}
+
+/// Export groups, and their images, points and tracks on the given sonars, from a data
+/// source to a snapshot directory.
+///
+/// * `source` - where the rows come from, usually the database.
+/// * `groups` - the groups to export, usually those an SQL filter selects.
+/// * `sonar_ids` - the sonars to export the images and points of.
+/// * `path` - the snapshot directory. Created if needed.
+pub fn export_snapshot(source: &dyn DataSource, groups: &[Groups], sonar_ids: &[i32], path: &Path) -> io::Result<SnapshotStats> {
+    fs::create_dir_all(path)?;
+
+    let mut images: Vec<Images> = vec![];
+    let mut seen: HashSet<Uuid> = HashSet::new();
+    let mut groups_images: Vec<(Uuid, Uuid)> = vec![];
+    let mut points: Vec<(Uuid, Points)> = vec![];
+    let mut tracks: Vec<TracksGroups> = vec![];
+
+    for group in groups {
+        for sonar_id in sonar_ids {
+            for image in source.images_group(group.uid, *sonar_id) {
+                for point in source.points_group_image(group.uid, &image) {
+                    points.push((group.uid, point));
+                }
+
+                groups_images.push((image.uid, group.uid));
+
+                // Images can be shared between groups.
+                if seen.insert(image.uid) {
+                    images.push(image);
+                }
+            }
+        }
+
+        tracks.extend(source.tracks_group(group.uid));
+    }
+
+    write_rows(
+        &path.join(GROUPS_FILE),
+        groups.iter().map(|g| {
+            json!({
+                "gid": g.gid,
+                "timestart": time_str(&g.timestart),
+                "interact": g.interact,
+                "mammal": g.mammal,
+                "fish": g.fish,
+                "bird": g.bird,
+                "sqlite": g.sqlite,
+                "uid": g.uid,
+                "code": g.code,
+                "comment": g.comment,
+                "timeend": time_str(&g.timeend),
+                "sqliteid": g.sqliteid,
+                "split": g.split,
+                "huid": g.huid,
+            })
+        }),
+    )?;
+
+    write_rows(
+        &path.join(IMAGES_FILE),
+        images.iter().map(|i| {
+            json!({
+                "filename": i.filename,
+                "uid": i.uid,
+                "hastrack": i.hastrack,
+                "glf": i.glf,
+                "time": time_str(&i.time),
+                "sonarid": i.sonarid,
+                "range": i.range,
+            })
+        }),
+    )?;
+
+    write_rows(
+        &path.join(GROUPS_IMAGES_FILE),
+        groups_images.iter().map(|(i, g)| json!({"image_id": i, "group_id": g})),
+    )?;
+
+    write_rows(
+        &path.join(POINTS_FILE),
+        points.iter().map(|(g, p)| {
+            json!({
+                "time": time_str(&p.time),
+                "sonarid": p.sonarid,
+                "minbearing": p.minbearing,
+                "maxbearing": p.maxbearing,
+                "minrange": p.minrange,
+                "maxrange": p.maxrange,
+                "peakbearing": p.peakbearing,
+                "peakrange": p.peakrange,
+                "maxvalue": p.maxvalue,
+                "occupancy": p.occupancy,
+                "objsize": p.objsize,
+                "track_id": p.track_id,
+                "uid": p.uid,
+                "group_id": g,
+            })
+        }),
+    )?;
+
+    write_rows(
+        &path.join(TRACKS_FILE),
+        tracks.iter().map(|t| {
+            json!({
+                "track_pam_id": t.track_pam_id,
+                "group_id": t.group_id,
+                "binfile": t.binfile,
+                "track_id": t.track_id,
+            })
+        }),
+    )?;
+
+    Ok(SnapshotStats {
+        groups: groups.len(),
+        images: images.len(),
+        points: points.len(),
+        tracks: tracks.len(),
+    })
+}
+
+/// The data source for a run - a snapshot if given one, the database otherwise.
+///
+/// * `snapshot` - the snapshot directory, if any.
+/// * `dbuser` - the database user.
+/// * `dbpass` - the database password.
+/// * `dbname` - the database name.
+pub fn open_source(snapshot: &Option<PathBuf>, dbuser: &str, dbpass: &str, dbname: &str) -> io::Result<Box<dyn DataSource>> {
+    match snapshot {
+        Some(path) => Ok(Box::new(SnapshotSource::new(path)?)),
+        None => Ok(Box::new(PgSource::new(dbuser, dbpass, dbname))),
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_images;
+
+    /// A data source over fixed rows, standing in for the database.
+    struct FixedSource {
+        groups: Vec<Groups>,
+        images: Vec<Images>,
+        points: Vec<Points>,
+        tracks: Vec<TracksGroups>,
+    }
+
+    impl DataSource for FixedSource {
+        fn groups(&self) -> Vec<Groups> {
+            self.groups.clone()
+        }
+
+        fn groups_limit(&self, limit: usize) -> Vec<Groups> {
+            self.groups.iter().take(limit).cloned().collect()
+        }
+
+        fn groups_sql(&self, _sql: &str) -> Vec<Groups> {
+            self.groups()
+        }
+
+        fn images_group(&self, _group_uid: Uuid, sonar_id: i32) -> Vec<Images> {
+            self.images.iter().filter(|i| i.sonarid == sonar_id).cloned().collect()
+        }
+
+        fn points_group_image(&self, _group_uid: Uuid, image: &Images) -> Vec<Points> {
+            self.points.iter().filter(|p| p.time == image.time).cloned().collect()
+        }
+
+        fn tracks_group(&self, group_uid: Uuid) -> Vec<TracksGroups> {
+            self.tracks.iter().filter(|t| t.group_id == group_uid).cloned().collect()
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut images = test_images(&[0, 100, 200]);
+
+        for (idx, image) in images.iter_mut().enumerate() {
+            image.uid = Uuid::from_u64_pair(1, idx as u64);
+            image.filename = format!("frame{}.fits", idx);
+        }
+
+        let group = |uid: u64, comment: Option<&str>| Groups {
+            gid: uid as i64,
+            timestart: images[0].time,
+            interact: uid == 1,
+            mammal: 1,
+            fish: 0,
+            bird: 0,
+            sqlite: String::from("test.sqlite3"),
+            uid: Uuid::from_u64_pair(2, uid),
+            code: String::from("seal"),
+            comment: comment.map(String::from),
+            timeend: images[2].time,
+            sqliteid: 7,
+            split: 0,
+            huid: format!("group{}", uid),
+        };
+        let point = |time: DateTime<Utc>| Points {
+            time,
+            sonarid: 854,
+            minbearing: 0.125,
+            maxbearing: 0.3,
+            minrange: 10.1,
+            maxrange: 12.0,
+            track_id: Uuid::from_u64_pair(3, 0),
+            uid: Uuid::new_v4(),
+            peakbearing: 0.2,
+            peakrange: 11.0,
+            maxvalue: 200.0,
+            occupancy: 0.5,
+            objsize: 1.5,
+        };
+        let source = FixedSource {
+            groups: vec![group(1, Some("a, \"quoted\" comment")), group(2, None)],
+            images: images.clone(),
+            points: vec![point(images[1].time), point(images[2].time)],
+            tracks: vec![TracksGroups {
+                track_pam_id: 77,
+                group_id: Uuid::from_u64_pair(2, 1),
+                binfile: String::from("20230528/tracks.pgdf"),
+                track_id: Uuid::from_u64_pair(3, 0),
+            }],
+        };
+
+        let path = std::env::temp_dir().join("crabseal_test_snapshot");
+        let _ = std::fs::remove_dir_all(&path);
+        let stats = export_snapshot(&source, &source.groups, &[853, 854], &path).unwrap();
+        // Both groups share the same three images.
+        assert_eq!(stats, SnapshotStats { groups: 2, images: 3, points: 4, tracks: 1 });
+
+        let snapshot = SnapshotSource::new(&path).unwrap();
+        let groups = snapshot.groups();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].comment.as_deref(), Some("a, \"quoted\" comment"));
+        assert!(groups[1].comment.is_none());
+        assert_eq!(groups[1].timeend, images[2].time);
+        assert_eq!(snapshot.groups_limit(1).len(), 1);
+
+        let filter: GroupFilter = "interact = true; sonars = 854".parse().unwrap();
+        assert_eq!(snapshot.groups_filter(&filter)[0].huid, "group1");
+        assert_eq!(snapshot.groups_filter(&filter).len(), 1);
+        assert!(snapshot.groups_filter(&"sonars = 853".parse().unwrap()).is_empty());
+
+        let group_images = snapshot.images_group(groups[0].uid, 854);
+        assert_eq!(group_images.len(), 3);
+        assert_eq!(group_images[2].filename, "frame2.fits");
+        assert!(snapshot.images_group(groups[0].uid, 853).is_empty());
+
+        let points = snapshot.points_group_image(groups[1].uid, &group_images[1]);
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].minbearing, 0.125);
+        assert_eq!(points[0].minrange, 10.1);
+        assert!(snapshot.points_group_image(groups[1].uid, &group_images[0]).is_empty());
+        assert_eq!(snapshot.tracks_group(groups[0].uid)[0].track_pam_id, 77);
+        assert!(snapshot.tracks_group(groups[1].uid).is_empty());
+
+        assert!(SnapshotSource::new(&path.join("missing")).is_err());
+
+        // SQL can't be run against a snapshot, rather than quietly selecting everything.
+        let sql = std::panic::catch_unwind(|| snapshot.groups_sql("SELECT * FROM groups"));
+        assert!(sql.is_err());
+    }
+}
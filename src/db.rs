@@ -18,14 +18,18 @@
  *   https://stackoverflow.com/questions/73559824/how-to-write-multiple-explicit-inner-joins-with-diesel
  */
 
+use crate::filter::{CountRange, GroupFilter};
 use crate::models::Groups;
 use crate::models::Images;
 use crate::models::Points;
+use crate::models::TracksGroups;
 use crate::schema::groups;
 use crate::schema::points;
 use crate::schema::groups_images;
 use crate::schema::images;
-use diesel::pg::PgConnection;
+use crate::schema::tracks_groups;
+use diesel::pg::data_types::PgInterval;
+use diesel::pg::{Pg, PgConnection};
 use diesel::prelude::*;
 use dotenvy::dotenv;
 
@@ -101,7 +105,111 @@ pub fn get_groups_limit(conn: &mut diesel::pg::PgConnection, limit: i64) -> Vec<
 /// * `conn` - the Disel PgConnection object.
 /// * `sql` - the sql string we want to match.
 pub fn get_groups_sql(conn: &mut diesel::pg::PgConnection, sql: String) -> Vec<Groups> {
-    diesel::sql_query(sql).load(conn).expect("Error in get_groups_sql")
+    diesel::sql_query(sql)
+        .load(conn)
+        .unwrap_or_else(|e| panic!("Error in the SQL filter - {}", e))
+}
+
+// The PostgreSQL functions that give the time of day a group starts at, in UTC.
+define_sql_function!(fn timezone(zone: diesel::sql_types::Text, time: diesel::sql_types::Timestamptz) -> diesel::sql_types::Timestamp);
+define_sql_function!(fn to_char(time: diesel::sql_types::Timestamp, format: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
+/// A condition on the groups table, boxed so conditions can be joined with OR.
+type GroupsCondition = Box<dyn BoxableExpression<groups::table, Pg, SqlType = diesel::sql_types::Bool>>;
+
+/// One of the counts of animals in the groups table, boxed so they can be filtered in a loop.
+type GroupsCount = Box<dyn BoxableExpression<groups::table, Pg, SqlType = diesel::sql_types::Integer>>;
+type CountColumn = fn() -> GroupsCount;
+
+/// Join a list of conditions with OR.
+fn any_of(conditions: Vec<GroupsCondition>) -> Option<GroupsCondition> {
+    conditions.into_iter().reduce(|a, b| Box::new(a.or(b)))
+}
+
+/// Return all the groups a GroupFilter selects, in one query.
+///
+/// * `conn` - the Disel PgConnection object.
+/// * `filter` - the filter.
+pub fn get_groups_filter(conn: &mut diesel::pg::PgConnection, filter: &GroupFilter) -> Vec<Groups> {
+    let mut query = groups::table.select(Groups::as_select()).into_boxed();
+
+    if !filter.codes.is_empty() {
+        query = query.filter(groups::code.eq_any(filter.codes.clone()));
+    }
+
+    // Ranges open at both ends select every day, so need no condition.
+    if !filter.dates.iter().any(|d| d.from.is_none() && d.to.is_none()) {
+        let dates = filter.dates.iter().map(|d| {
+            let from = d.from.map(|f| f.and_hms_opt(0, 0, 0).unwrap().and_utc());
+            let end = d.end().map(|e| e.and_hms_opt(0, 0, 0).unwrap().and_utc());
+            let condition: GroupsCondition = match (from, end) {
+                (Some(f), Some(e)) => Box::new(groups::timestart.ge(f).and(groups::timestart.lt(e))),
+                (Some(f), None) => Box::new(groups::timestart.ge(f)),
+                (None, Some(e)) => Box::new(groups::timestart.lt(e)),
+                (None, None) => unreachable!(),
+            };
+            condition
+        });
+
+        if let Some(condition) = any_of(dates.collect()) {
+            query = query.filter(condition);
+        }
+    }
+
+    // Times of day are compared as zero padded text, which sorts the same way.
+    let hours = filter.hours.iter().map(|h| {
+        let time = || to_char(timezone("UTC", groups::timestart), "HH24:MI:SS");
+        let start = h.start.format("%H:%M:%S").to_string();
+        let end = h.end.format("%H:%M:%S").to_string();
+        let condition: GroupsCondition = if h.wraps() {
+            Box::new(time().ge(start).or(time().lt(end)))
+        } else {
+            Box::new(time().ge(start).and(time().lt(end)))
+        };
+        condition
+    });
+
+    if let Some(condition) = any_of(hours.collect()) {
+        query = query.filter(condition);
+    }
+
+    if !filter.sonar_ids.is_empty() {
+        let on_sonars = groups_images::table
+            .inner_join(images::table.on(images::uid.eq(groups_images::image_id)))
+            .filter(images::sonarid.eq_any(filter.sonar_ids.clone()))
+            .select(groups_images::group_id);
+        query = query.filter(groups::uid.eq_any(on_sonars));
+    }
+
+    if let Some(duration) = filter.min_duration {
+        let interval = PgInterval::from_microseconds(duration.as_micros() as i64);
+        query = query.filter(groups::timeend.ge(groups::timestart + interval));
+    }
+
+    if let Some(interact) = filter.interact {
+        query = query.filter(groups::interact.eq(interact));
+    }
+
+    let counts: [(Option<CountRange>, CountColumn); 3] = [
+        (filter.mammal, || Box::new(groups::mammal)),
+        (filter.fish, || Box::new(groups::fish)),
+        (filter.bird, || Box::new(groups::bird)),
+    ];
+
+    for (count, column) in counts {
+        if let Some(min) = count.and_then(|c| c.min) {
+            query = query.filter(column().ge(min));
+        }
+
+        if let Some(max) = count.and_then(|c| c.max) {
+            query = query.filter(column().le(max));
+        }
+    }
+
+    query
+        .order(groups::timestart)
+        .load(conn)
+        .expect("Error loading groups")
 }
 
 
@@ -180,6 +288,21 @@ pub fn get_points_group_image(
     results
 }
 
+/// Get the tracks of a group, with the PGDFs they were saved in.
+///
+/// * `conn` - the Diesel PgConnection object.
+/// * `group_uuid` - the uuid of the group.
+pub fn get_tracks_group(
+    conn: &mut diesel::pg::PgConnection,
+    group_uuid: uuid::Uuid
+) -> Vec<TracksGroups> {
+    tracks_groups::table
+        .filter(tracks_groups::group_id.eq(group_uuid))
+        .select(TracksGroups::as_select())
+        .load(conn)
+        .expect("Error loading tracks for group")
+}
+
 #[cfg(test)]
 mod tests {
     use serial_test::serial;
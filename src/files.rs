@@ -15,22 +15,39 @@
  *   
  */
 
+use crate::resolver::layout_path;
+use chrono::NaiveDateTime;
 use std::collections::HashMap;
-use std::io::{Error, BufReader, ErrorKind, BufRead};
+use std::io::{Error, ErrorKind};
 use std::{fs::create_dir, fs::File, path::Path};
 use std::path::PathBuf;
 
 
-/// Read the bearing table file. Needed to properly convert the raw images.
-pub fn read_bearing_table() -> Result<Vec<f32>, Error> {
-    let file = File::open("btable.dat").unwrap();
+/// The bearing table shipped with crabseal, for when there is no btable.dat to hand.
+const BEARING_TABLE: &str = include_str!("../btable.dat");
 
-    let br: BufReader<File> = BufReader::new(file);
-    br.lines()
-        .map(|line| line.and_then(|v| v.parse().map_err(|e| Error::new(ErrorKind::InvalidData, e))))
+/// Parse a bearing table - one bearing per line, in radians.
+fn parse_bearing_table(text: &str) -> Result<Vec<f32>, Error> {
+    text.lines()
+        .map(|v| v.parse().map_err(|e| Error::new(ErrorKind::InvalidData, e)))
         .collect()
 }
 
+/// The bearing table built into crabseal, the same as the btable.dat at the top of the repository.
+pub fn builtin_bearing_table() -> Vec<f32> {
+    parse_bearing_table(BEARING_TABLE).unwrap()
+}
+
+/// Read the bearing table file. Needed to properly convert the raw images. Uses btable.dat in
+/// the working directory if there is one, or the built in table if not.
+pub fn read_bearing_table() -> Result<Vec<f32>, Error> {
+    match std::fs::read_to_string("btable.dat") {
+        Ok(text) => parse_bearing_table(&text),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(builtin_bearing_table()),
+        Err(e) => Err(e),
+    }
+}
+
 /// Read the file that maps codes to numbers for the classes.
 /// * `map_path` - the path to the class file.
 pub fn read_class_map(map_path: &Path,) -> Result<HashMap::<String, u32>, Box<dyn std::error::Error>> {
@@ -54,6 +71,11 @@ pub fn read_class_map(map_path: &Path,) -> Result<HashMap::<String, u32>, Box<dy
 pub fn fits_in_path(fits_path: &Path, fits_name: &String) -> Option<PathBuf> { 
     // TODO - we should probably check the image size as well to see if it
     // matches what is expected.
+    // Most files are where the SealHits layout says they should be, so look there first.
+    if let Some(path) = layout_path(fits_path, fits_name, None) {
+        return Some(path);
+    }
+
     let compressed: String = fits_name.clone() + ".lz4";
 
     match fast_find(fits_path, &compressed) {
@@ -93,6 +115,20 @@ pub fn fast_find(fits_path: &Path, fits_name: &String) -> Option<PathBuf> {
 }
 
 
+/// Split a *SealHits* FITS filename, such as 2023_05_28_22_52_42_130_854.fits(.lz4), into
+/// the time the frame was taken and the sonar id.
+/// * `fits_name` - the name of the FITS file, with or without a path and .lz4.
+pub fn parse_fits_name(fits_name: &str) -> Option<(NaiveDateTime, i32)> {
+    let name = Path::new(fits_name).file_name()?.to_str()?;
+    let stem = name.strip_suffix(".lz4").unwrap_or(name);
+    let stem = stem.strip_suffix(".fits")?;
+    let (time_part, sonar_part) = stem.rsplit_once('_')?;
+    let sonar_id: i32 = sonar_part.parse().ok()?;
+    let time = NaiveDateTime::parse_from_str(time_part, "%Y_%m_%d_%H_%M_%S_%3f").ok()?;
+    Some((time, sonar_id))
+}
+
+
 /// Create the required directories for each set - train, test and validation.
 /// * `base_path_str` - base directory to make other directories under.
 pub fn create_image_dirs(base_path_str: &String) {
@@ -126,4 +162,26 @@ pub fn _create_dir(dir_path: &PathBuf) {
     } else {
         println!("Directory created successfully");
     }
-}
\ No newline at end of file
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fits_names() {
+        let (time, sonar) = parse_fits_name("fits/2023_05_28/2023_05_28_22_52_42_130_854.fits.lz4").unwrap();
+        assert_eq!(sonar, 854);
+        assert_eq!(time.format("%Y-%m-%d %H:%M:%S%.3f").to_string(), "2023-05-28 22:52:42.130");
+        assert!(parse_fits_name("2023_05_28_22_52_42_130_854.png").is_none());
+    }
+
+    #[test]
+    fn test_bearing_table() {
+        let btable = builtin_bearing_table();
+        assert_eq!(btable.len(), 512);
+        assert!((btable[0] - 1.0471976).abs() < 1e-6);
+        assert!(parse_bearing_table("0.5\nfoo\n").is_err());
+    }
+}
@@ -0,0 +1,431 @@
+//! Selecting groups by what they are rather than by SQL - codes, dates, time of day, sonars,
+//! duration, interaction and counts. A filter is compiled to a Diesel query for the database
+//! (db::get_groups_filter), or matched against the groups of a snapshot.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   filter.rs - structured group filters.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::models::Groups;
+use chrono::{Days, NaiveDate, NaiveTime};
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The format of the times of day in a filter.
+const TIME_FORMAT: &str = "%H:%M";
+
+/// A range of days, both ends included. Either end may be open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateRange {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl DateRange {
+    /// Whether a day falls in the range.
+    ///
+    /// * `date` - the day.
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.from.is_none_or(|f| date >= f) && self.to.is_none_or(|t| date <= t)
+    }
+
+    /// The day after the range ends, if it ends - the first day a query should exclude.
+    pub fn end(&self) -> Option<NaiveDate> {
+        self.to.and_then(|t| t.checked_add_days(Days::new(1)))
+    }
+}
+
+/// A range of times of day in UTC, from the start up to but not including the end. If the end is
+/// before the start the range runs past midnight, so 22:00..04:00 is the night.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HourRange {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl HourRange {
+    /// Whether the range runs past midnight.
+    pub fn wraps(&self) -> bool {
+        self.end < self.start
+    }
+
+    /// Whether a time of day falls in the range.
+    ///
+    /// * `time` - the time of day.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.wraps() {
+            time >= self.start || time < self.end
+        } else {
+            time >= self.start && time < self.end
+        }
+    }
+}
+
+/// A range of counts, both ends included. Either end may be open.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CountRange {
+    pub min: Option<i32>,
+    pub max: Option<i32>,
+}
+
+impl CountRange {
+    /// Whether a count falls in the range.
+    ///
+    /// * `count` - the count.
+    pub fn contains(&self, count: i32) -> bool {
+        self.min.is_none_or(|m| count >= m) && self.max.is_none_or(|m| count <= m)
+    }
+}
+
+/// Which groups to select. Every field that is set must match, and a list matches if any of its
+/// entries does. The default filter selects every group.
+///
+/// A filter is written as `key = value` lines, or separated by semicolons on the command line,
+/// with `#` starting a comment:
+///
+/// ```text
+/// codes = seal, fish
+/// dates = 2023-04-01..2023-05-31, 2023-08-01..
+/// hours = 22:00..04:00
+/// sonars = 854
+/// min_duration = 5s
+/// interact = false
+/// mammal = 1..
+/// fish = 0
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GroupFilter {
+    /// The codes the group may have.
+    pub codes: Vec<String>,
+    /// The days the group may start on.
+    pub dates: Vec<DateRange>,
+    /// The times of day, in UTC, the group may start at.
+    pub hours: Vec<HourRange>,
+    /// The group must have images on one of these sonars.
+    pub sonar_ids: Vec<i32>,
+    /// The shortest group, from its start to its end.
+    pub min_duration: Option<Duration>,
+    /// Whether the animals interact with the turbine.
+    pub interact: Option<bool>,
+    /// How many mammals the group may have.
+    pub mammal: Option<CountRange>,
+    /// How many fish the group may have.
+    pub fish: Option<CountRange>,
+    /// How many birds the group may have.
+    pub bird: Option<CountRange>,
+}
+
+impl GroupFilter {
+    /// Read a filter from a file if there is one at this path, or parse it as a filter if not.
+    ///
+    /// * `spec` - the path to the filter file, or the filter itself.
+    pub fn load(spec: &str) -> Result<GroupFilter, String> {
+        let path = Path::new(spec);
+
+        if path.is_file() {
+            read_to_string(path)
+                .map_err(|e| format!("{} - {}", path.display(), e))?
+                .parse()
+                .map_err(|e| format!("{} - {}", path.display(), e))
+        } else {
+            spec.parse()
+        }
+    }
+
+    /// Whether a group matches everything but the sonars, which need the images of the group.
+    ///
+    /// * `group` - the group.
+    pub fn matches(&self, group: &Groups) -> bool {
+        (self.codes.is_empty() || self.codes.contains(&group.code))
+            && (self.dates.is_empty() || self.dates.iter().any(|d| d.contains(group.timestart.date_naive())))
+            && (self.hours.is_empty() || self.hours.iter().any(|h| h.contains(group.timestart.time())))
+            && self.min_duration.is_none_or(|d| {
+                (group.timeend - group.timestart).to_std().is_ok_and(|length| length >= d)
+            })
+            && self.interact.is_none_or(|i| group.interact == i)
+            && self.mammal.is_none_or(|c| c.contains(group.mammal))
+            && self.fish.is_none_or(|c| c.contains(group.fish))
+            && self.bird.is_none_or(|c| c.contains(group.bird))
+    }
+}
+
+/// Split a list on commas, dropping empty entries.
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(|s| s.trim()).filter(|s| !s.is_empty())
+}
+
+/// Split a range written as `from..to`, where either end may be left out. A single value is a
+/// range from and to itself.
+fn range(value: &str) -> (Option<&str>, Option<&str>) {
+    fn end(s: &str) -> Option<&str> {
+        if s.trim().is_empty() {
+            None
+        } else {
+            Some(s.trim())
+        }
+    }
+
+    match value.split_once("..") {
+        Some((from, to)) => (end(from), end(to)),
+        None => (end(value), end(value)),
+    }
+}
+
+fn parse_date(value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| format!("bad date '{}' - expected YYYY-MM-DD", value))
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value, TIME_FORMAT).map_err(|_| format!("bad time of day '{}' - expected HH:MM", value))
+}
+
+fn parse_count(value: &str) -> Result<CountRange, String> {
+    let count = |s: Option<&str>| {
+        s.map(|s| s.parse::<i32>().map_err(|_| format!("bad count '{}'", s)))
+            .transpose()
+    };
+    let (min, max) = range(value);
+
+    Ok(CountRange {
+        min: count(min)?,
+        max: count(max)?,
+    })
+}
+
+impl FromStr for GroupFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = GroupFilter::default();
+
+        for entry in s.split(['\n', ';']) {
+            let entry = entry.split('#').next().unwrap_or("").trim();
+
+            if entry.is_empty() {
+                continue;
+            }
+
+            let (key, value) = entry
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or(format!("expected key = value, not '{}'", entry))?;
+
+            match key {
+                "codes" => filter.codes = list(value).map(String::from).collect(),
+                "dates" => {
+                    filter.dates = list(value)
+                        .map(|d| {
+                            let (from, to) = range(d);
+                            Ok(DateRange {
+                                from: from.map(parse_date).transpose()?,
+                                to: to.map(parse_date).transpose()?,
+                            })
+                        })
+                        .collect::<Result<_, String>>()?
+                }
+                "hours" => {
+                    filter.hours = list(value)
+                        .map(|h| match h.split_once("..") {
+                            Some((start, end)) => {
+                                let hours = HourRange {
+                                    start: parse_time(start.trim())?,
+                                    end: parse_time(end.trim())?,
+                                };
+
+                                if hours.start == hours.end {
+                                    return Err(format!("empty time of day range '{}'", h));
+                                }
+
+                                Ok(hours)
+                            }
+                            None => Err(format!("bad time of day range '{}' - expected HH:MM..HH:MM", h)),
+                        })
+                        .collect::<Result<_, String>>()?
+                }
+                "sonars" => {
+                    filter.sonar_ids = list(value)
+                        .map(|id| id.parse::<i32>().map_err(|_| format!("bad sonar id '{}'", id)))
+                        .collect::<Result<_, String>>()?
+                }
+                "min_duration" => {
+                    filter.min_duration = Some(
+                        humantime::parse_duration(value)
+                            .map_err(|e| format!("bad duration '{}' - {}", value, e))?,
+                    )
+                }
+                "interact" => {
+                    filter.interact = Some(
+                        value
+                            .parse::<bool>()
+                            .map_err(|_| format!("bad interact '{}' - expected true or false", value))?,
+                    )
+                }
+                "mammal" => filter.mammal = Some(parse_count(value)?),
+                "fish" => filter.fish = Some(parse_count(value)?),
+                "bird" => filter.bird = Some(parse_count(value)?),
+                _ => {
+                    return Err(format!(
+                        "unknown filter key '{}' - expected codes, dates, hours, sonars, min_duration, interact, mammal, fish or bird",
+                        key
+                    ))
+                }
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for GroupFilter {
+    /// Write the filter as it would be parsed, on one line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let end = |s: Option<String>| s.unwrap_or_default();
+        let count = |c: &CountRange| {
+            if c.min.is_some() && c.min == c.max {
+                end(c.min.map(|m| m.to_string()))
+            } else {
+                format!("{}..{}", end(c.min.map(|m| m.to_string())), end(c.max.map(|m| m.to_string())))
+            }
+        };
+        let mut entries: Vec<String> = vec![];
+
+        if !self.codes.is_empty() {
+            entries.push(format!("codes={}", self.codes.join(",")));
+        }
+
+        if !self.dates.is_empty() {
+            let dates: Vec<String> = self
+                .dates
+                .iter()
+                .map(|d| format!("{}..{}", end(d.from.map(|d| d.to_string())), end(d.to.map(|d| d.to_string()))))
+                .collect();
+            entries.push(format!("dates={}", dates.join(",")));
+        }
+
+        if !self.hours.is_empty() {
+            let hours: Vec<String> = self
+                .hours
+                .iter()
+                .map(|h| format!("{}..{}", h.start.format(TIME_FORMAT), h.end.format(TIME_FORMAT)))
+                .collect();
+            entries.push(format!("hours={}", hours.join(",")));
+        }
+
+        if !self.sonar_ids.is_empty() {
+            let ids: Vec<String> = self.sonar_ids.iter().map(|id| id.to_string()).collect();
+            entries.push(format!("sonars={}", ids.join(",")));
+        }
+
+        if let Some(d) = self.min_duration {
+            entries.push(format!("min_duration={}", humantime::format_duration(d)));
+        }
+
+        if let Some(i) = self.interact {
+            entries.push(format!("interact={}", i));
+        }
+
+        for (key, c) in [("mammal", &self.mammal), ("fish", &self.fish), ("bird", &self.bird)] {
+            if let Some(c) = c {
+                entries.push(format!("{}={}", key, count(c)));
+            }
+        }
+
+        write!(f, "{}", entries.join(";"))
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use uuid::Uuid;
+
+    fn group(code: &str, start: (u32, u32, u32), minutes: i64, mammal: i32) -> Groups {
+        let timestart = Utc.with_ymd_and_hms(2023, 5, 28, start.0, start.1, start.2).unwrap();
+
+        Groups {
+            gid: 1,
+            timestart,
+            interact: false,
+            mammal,
+            fish: 0,
+            bird: 0,
+            sqlite: String::from("test.sqlite3"),
+            uid: Uuid::from_u64_pair(0, 1),
+            code: String::from(code),
+            comment: None,
+            timeend: timestart + chrono::Duration::minutes(minutes),
+            sqliteid: 1,
+            split: 0,
+            huid: String::from("group1"),
+        }
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        let filter: GroupFilter = "codes = seal, fish\n\
+            # the spring survey\n\
+            dates = 2023-04-01..2023-05-31, 2023-08-01..\n\
+            hours = 22:00..04:00; sonars = 854\n\
+            min_duration = 5m; interact = false\n\
+            mammal = 1..; fish = 0; bird = ..2"
+            .parse()
+            .unwrap();
+
+        assert_eq!(filter.codes, vec!["seal", "fish"]);
+        assert_eq!(filter.dates.len(), 2);
+        assert_eq!(filter.dates[1].to, None);
+        assert!(filter.hours[0].wraps());
+        assert_eq!(filter.sonar_ids, vec![854]);
+        assert_eq!(filter.min_duration, Some(Duration::from_secs(300)));
+        assert_eq!(filter.interact, Some(false));
+        assert_eq!(filter.mammal, Some(CountRange { min: Some(1), max: None }));
+        assert_eq!(filter.fish, Some(CountRange { min: Some(0), max: Some(0) }));
+        assert_eq!(filter.bird, Some(CountRange { min: None, max: Some(2) }));
+
+        // Written out, it reads back the same.
+        assert_eq!(filter.to_string().parse::<GroupFilter>().unwrap(), filter);
+        assert_eq!("".parse::<GroupFilter>().unwrap(), GroupFilter::default());
+
+        // Typos are caught when the filter is read, not when it runs.
+        assert!("code = seal".parse::<GroupFilter>().is_err());
+        assert!("dates = 2023-13-01".parse::<GroupFilter>().is_err());
+        assert!("hours = 22:00".parse::<GroupFilter>().is_err());
+        assert!("interact = maybe".parse::<GroupFilter>().is_err());
+        assert!("mammal = lots".parse::<GroupFilter>().is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        let seal = group("seal", (23, 30, 0), 10, 1);
+        assert!(GroupFilter::default().matches(&seal));
+
+        let filter: GroupFilter = "codes = seal; hours = 22:00..04:00; dates = 2023-05-28".parse().unwrap();
+        assert!(filter.matches(&seal));
+        assert!(filter.matches(&group("seal", (3, 59, 59), 10, 1)));
+        assert!(!filter.matches(&group("seal", (4, 0, 0), 10, 1)));
+        assert!(!filter.matches(&group("fish", (23, 30, 0), 10, 1)));
+
+        let filter: GroupFilter = "dates = ..2023-05-27, 2023-06-01..".parse().unwrap();
+        assert!(!filter.matches(&seal));
+
+        let filter: GroupFilter = "min_duration = 10m; mammal = 1..2".parse().unwrap();
+        assert!(filter.matches(&seal));
+        assert!(!filter.matches(&group("seal", (23, 30, 0), 9, 1)));
+        assert!(!filter.matches(&group("seal", (23, 30, 0), 10, 3)));
+        assert!(!"interact = true".parse::<GroupFilter>().unwrap().matches(&seal));
+    }
+}
@@ -0,0 +1,426 @@
+//! A small FITS reader in pure Rust. Only the primary HDU of an image is read, which is all
+//! *SealHits* writes, but 8, 16 and 32 bit integer and 32 bit float pixels are handled, as
+//! are BZERO and BSCALE. LZ4 compressed files are decompressed as a stream, in memory.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   fits.rs - pure Rust FITS reading.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::image::VolumePixel;
+use image::{GrayImage, ImageBuffer, Luma};
+use lzzzz::lz4f::{Preferences, ReadDecompressor, WriteCompressor};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+/// FITS files are made of blocks of this many bytes.
+const BLOCK_SIZE: usize = 2880;
+/// Each header card is this many characters.
+const CARD_SIZE: usize = 80;
+
+/// A single channel image with 32 bit float pixels.
+pub type FloatImage = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+/// The pixels of a FITS image, as stored in the file, before BZERO and BSCALE.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FitsData {
+    /// BITPIX 8 - unsigned bytes.
+    U8(Vec<u8>),
+    /// BITPIX 16 - signed 16 bit integers.
+    I16(Vec<i16>),
+    /// BITPIX 32 - signed 32 bit integers.
+    I32(Vec<i32>),
+    /// BITPIX -32 - 32 bit floats.
+    F32(Vec<f32>),
+}
+
+/// The image from the primary HDU of a FITS file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FitsImage {
+    /// NAXIS1 - the number of pixels in a row.
+    pub width: u32,
+    /// NAXIS2 - the number of rows.
+    pub height: u32,
+    /// BZERO - the physical value of a pixel is bzero + bscale * the stored value.
+    pub bzero: f64,
+    /// BSCALE - the scale of the stored values, 1 if they are unscaled.
+    pub bscale: f64,
+    /// The stored pixels, row by row.
+    pub data: FitsData,
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// The value of a header card, without any comment or quotes. None if the card has no value.
+/// Only the keyword must be ASCII - anything else in a value or comment is replaced.
+fn card_value(card: &[u8]) -> Option<String> {
+    if card.len() < 10 || &card[8..10] != b"= " {
+        return None;
+    }
+
+    let value = String::from_utf8_lossy(&card[10..]);
+    let value = value.trim_start();
+
+    if let Some(quoted) = value.strip_prefix('\'') {
+        return Some(quoted.split('\'').next().unwrap_or("").trim_end().to_string());
+    }
+
+    Some(value.split('/').next().unwrap_or("").trim().to_string())
+}
+
+impl FitsImage {
+    /// An 8 bit image, such as a frame pulled from a GLF, as a FitsImage.
+    ///
+    /// * `img` - the image.
+    pub fn from_gray(img: GrayImage) -> FitsImage {
+        FitsImage {
+            width: img.width(),
+            height: img.height(),
+            bzero: 0.0,
+            bscale: 1.0,
+            data: FitsData::U8(img.into_raw()),
+        }
+    }
+
+    /// The physical value of every pixel, with BZERO and BSCALE applied.
+    pub fn physical(&self) -> Vec<f32> {
+        let (zero, scale) = (self.bzero, self.bscale);
+        let convert = |v: f64| (zero + scale * v) as f32;
+
+        match &self.data {
+            FitsData::U8(d) => d.iter().map(|v| convert(*v as f64)).collect(),
+            FitsData::I16(d) => d.iter().map(|v| convert(*v as f64)).collect(),
+            FitsData::I32(d) => d.iter().map(|v| convert(*v as f64)).collect(),
+            FitsData::F32(d) => d.iter().map(|v| convert(*v as f64)).collect(),
+        }
+    }
+
+    /// True if the stored values are the physical values.
+    fn unscaled(&self) -> bool {
+        self.bzero == 0.0 && self.bscale == 1.0
+    }
+
+    /// The image as u8, clamping the physical values to 0 to 255. Plain BITPIX 8 images,
+    /// such as those *SealHits* writes, are copied as they are.
+    pub fn to_gray(&self) -> GrayImage {
+        let pixels: Vec<u8> = match &self.data {
+            FitsData::U8(d) if self.unscaled() => d.clone(),
+            _ => self.physical().iter().map(|v| v.round().clamp(0.0, 255.0) as u8).collect(),
+        };
+
+        GrayImage::from_raw(self.width, self.height, pixels).unwrap()
+    }
+
+    /// The image as u16, clamping the physical values to 0 to 65535. 16 bit sonar data is
+    /// usually stored as BITPIX 16 with a BZERO of 32768.
+    pub fn to_u16(&self) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let pixels: Vec<u16> = self.physical().iter().map(|v| v.round().clamp(0.0, 65535.0) as u16).collect();
+        ImageBuffer::from_raw(self.width, self.height, pixels).unwrap()
+    }
+
+    /// The image as f32 physical values.
+    pub fn to_f32(&self) -> FloatImage {
+        ImageBuffer::from_raw(self.width, self.height, self.physical()).unwrap()
+    }
+
+    /// The image as any of the volume pixel types, rounding and clamping the physical
+    /// values to the range of integer types.
+    pub fn to_image<T: VolumePixel>(&self) -> ImageBuffer<Luma<T>, Vec<T>> {
+        let pixels: Vec<T> = self.physical().into_iter().map(T::from_f32).collect();
+        ImageBuffer::from_raw(self.width, self.height, pixels).unwrap()
+    }
+}
+
+/// Parse a FITS file held in memory, returning the image in the primary HDU. Any axes
+/// after the first two must be 1 in size.
+///
+/// * `bytes` - the whole FITS file.
+pub fn parse_fits(bytes: &[u8]) -> io::Result<FitsImage> {
+    let mut keys: HashMap<String, String> = HashMap::new();
+    let mut header_end: Option<usize> = None;
+
+    for (idx, card) in bytes.chunks_exact(CARD_SIZE).enumerate() {
+        // The keyword is the first 8 bytes, which must be ASCII.
+        let key = &card[..8];
+
+        if !key.is_ascii() {
+            return Err(invalid(format!("header card {} has a keyword that is not ASCII", idx)));
+        }
+
+        let key = std::str::from_utf8(key).unwrap().trim_end();
+
+        if idx == 0 && key != "SIMPLE" {
+            return Err(invalid(String::from("not a FITS file")));
+        }
+
+        if key == "END" {
+            header_end = Some((idx + 1) * CARD_SIZE);
+            break;
+        }
+
+        if let Some(value) = card_value(card) {
+            keys.insert(key.to_string(), value);
+        }
+    }
+
+    let header_end = header_end.ok_or(invalid(String::from("no END to the header")))?;
+    let int_key = |key: &str| -> io::Result<i64> {
+        let value = keys.get(key).ok_or(invalid(format!("no {} in the header", key)))?;
+        value.parse::<i64>().map_err(|_| invalid(format!("bad {} '{}'", key, value)))
+    };
+    let float_key = |key: &str, default: f64| -> io::Result<f64> {
+        match keys.get(key) {
+            Some(value) => value.replace('D', "E").parse::<f64>().map_err(|_| invalid(format!("bad {} '{}'", key, value))),
+            None => Ok(default),
+        }
+    };
+
+    if keys.get("SIMPLE").map(|v| v.as_str()) != Some("T") {
+        return Err(invalid(String::from("not a standard FITS file")));
+    }
+
+    let bitpix = int_key("BITPIX")?;
+    let naxis = int_key("NAXIS")?;
+
+    if naxis < 2 {
+        return Err(invalid(format!("the primary HDU has {} axes, not an image", naxis)));
+    }
+
+    for axis in 3..=naxis {
+        if int_key(&format!("NAXIS{}", axis))? != 1 {
+            return Err(invalid(String::from("only single plane images are supported")));
+        }
+    }
+
+    let width = int_key("NAXIS1")?;
+    let height = int_key("NAXIS2")?;
+
+    if width <= 0 || height <= 0 || width > u32::MAX as i64 || height > u32::MAX as i64 {
+        return Err(invalid(format!("bad image size {} by {}", width, height)));
+    }
+
+    let short = || invalid(String::from("the file is shorter than its header says"));
+    let bytes_per_pixel = (bitpix.unsigned_abs() / 8) as usize;
+    // The data starts on the block after the header. A header claiming more than we can even
+    // address is just as short.
+    let data_start = header_end.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+    let data_end = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|pixels| pixels.checked_mul(bytes_per_pixel))
+        .and_then(|size| size.checked_add(data_start))
+        .ok_or_else(short)?;
+    let raw = bytes.get(data_start..data_end).ok_or_else(short)?;
+
+    // FITS is always big endian.
+    let data = match bitpix {
+        8 => FitsData::U8(raw.to_vec()),
+        16 => FitsData::I16(raw.chunks_exact(2).map(|c| i16::from_be_bytes([c[0], c[1]])).collect()),
+        32 => FitsData::I32(raw.chunks_exact(4).map(|c| i32::from_be_bytes([c[0], c[1], c[2], c[3]])).collect()),
+        -32 => FitsData::F32(raw.chunks_exact(4).map(|c| f32::from_be_bytes([c[0], c[1], c[2], c[3]])).collect()),
+        _ => return Err(invalid(format!("unsupported BITPIX {}", bitpix))),
+    };
+
+    Ok(FitsImage {
+        width: width as u32,
+        height: height as u32,
+        bzero: float_key("BZERO", 0.0)?,
+        bscale: float_key("BSCALE", 1.0)?,
+        data,
+    })
+}
+
+/// Read a FITS file from a stream.
+///
+/// * `reader` - the stream, already decompressed.
+pub fn read_fits_from<R: Read>(mut reader: R) -> io::Result<FitsImage> {
+    let mut bytes: Vec<u8> = vec![];
+    reader.read_to_end(&mut bytes)?;
+    parse_fits(&bytes)
+}
+
+/// Read the image from a FITS file on disk, decompressing it first if the name ends in .lz4.
+///
+/// * `fits_path` - full path to the FITS file, including the .lz4 if compressed.
+pub fn read_fits_image(fits_path: &Path) -> io::Result<FitsImage> {
+    let file = BufReader::new(File::open(fits_path)?);
+
+    match fits_path.extension().and_then(|e| e.to_str()) {
+        Some("lz4") => read_fits_from(ReadDecompressor::new(file)?),
+        _ => read_fits_from(file),
+    }
+}
+
+/// An image of any of the volume pixel types as a FITS file in memory. u8 images are written
+/// as *SealHits* writes them, with BITPIX 8, u16 with BITPIX 16 and a BZERO of 32768, and f32
+/// with BITPIX -32.
+///
+/// * `img` - the image to write.
+pub fn image_to_fits<T: VolumePixel>(img: &ImageBuffer<Luma<T>, Vec<T>>) -> Vec<u8> {
+    let mut cards = vec![
+        format!("{:<8}= {:>20}", "SIMPLE", "T"),
+        format!("{:<8}= {:>20}", "BITPIX", T::BITPIX),
+        format!("{:<8}= {:>20}", "NAXIS", 2),
+        format!("{:<8}= {:>20}", "NAXIS1", img.width()),
+        format!("{:<8}= {:>20}", "NAXIS2", img.height()),
+    ];
+
+    if T::BITPIX == 16 {
+        cards.push(format!("{:<8}= {:>20}", "BZERO", 32768));
+    }
+
+    cards.push(String::from("END"));
+    let mut bytes: Vec<u8> = cards.iter().flat_map(|c| format!("{:<80}", c).into_bytes()).collect();
+    bytes.resize(bytes.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, b' ');
+
+    for v in img.as_raw() {
+        let v: f32 = (*v).into();
+
+        match T::BITPIX {
+            8 => bytes.push(v as u8),
+            16 => bytes.extend(((v as i32 - 32768) as i16).to_be_bytes()),
+            _ => bytes.extend(v.to_be_bytes()),
+        }
+    }
+
+    bytes.resize(bytes.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+    bytes
+}
+
+/// Write an image to a FITS file on disk, compressing it if the name ends in .lz4.
+///
+/// * `fits_path` - full path to the FITS file, including the .lz4 if compressed.
+/// * `img` - the image to write.
+pub fn write_fits_image<T: VolumePixel>(fits_path: &Path, img: &ImageBuffer<Luma<T>, Vec<T>>) -> io::Result<()> {
+    let bytes = image_to_fits(img);
+    let mut file = File::create(fits_path)?;
+
+    match fits_path.extension().and_then(|e| e.to_str()) {
+        Some("lz4") => WriteCompressor::new(&mut file, Preferences::default())?.write_all(&bytes),
+        _ => file.write_all(&bytes),
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::img_to_cache_compressed;
+    use crate::image::img_to_fits;
+    use chrono::DateTime;
+
+    /// Build a FITS file in memory from header cards and big endian data.
+    fn make_fits(cards: &[&str], data: &[u8]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![];
+
+        for card in cards.iter().chain(["END"].iter()) {
+            bytes.extend(format!("{:<80}", card).bytes());
+        }
+
+        bytes.resize(bytes.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, b' ');
+        bytes.extend(data);
+        bytes.resize(bytes.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_fits() {
+        // 16 bit unsigned data is stored signed, with BZERO 32768.
+        let values: Vec<u8> = [-32768i16, 0, 32767, 100].iter().flat_map(|v| v.to_be_bytes()).collect();
+        let fits = make_fits(
+            &["SIMPLE  =                    T", "BITPIX  =                   16", "NAXIS   =                    2",
+              "NAXIS1  =                    2", "NAXIS2  =                    2", "BZERO   =                32768 / offset"],
+            &values,
+        );
+        let image = parse_fits(&fits).unwrap();
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_eq!(image.to_u16().into_raw(), vec![0, 32768, 65535, 32868]);
+        assert_eq!(image.to_gray().into_raw(), vec![0, 255, 255, 255]);
+
+        let values: Vec<u8> = [0.5f32, -1.0, 2.0].iter().flat_map(|v| v.to_be_bytes()).collect();
+        let fits = make_fits(
+            &["SIMPLE  =                    T", "BITPIX  =                  -32", "NAXIS   =                    3",
+              "NAXIS1  =                    3", "NAXIS2  =                    1", "NAXIS3  =                    1",
+              "BSCALE  =                  2.0", "COMMENT a comment"],
+            &values,
+        );
+        let image = parse_fits(&fits).unwrap();
+        assert_eq!(image.to_f32().into_raw(), vec![1.0, -2.0, 4.0]);
+        assert_eq!(image.to_gray().into_raw(), vec![1, 0, 4]);
+        assert_eq!(image.to_image::<u16>().into_raw(), vec![1, 0, 4]);
+
+        let short = make_fits(&["SIMPLE  =                    T", "BITPIX  =                   32", "NAXIS   =                    2",
+                                "NAXIS1  =                 2000", "NAXIS2  =                 2000"], &[]);
+        assert!(parse_fits(&short).is_err());
+        let huge = make_fits(&["SIMPLE  =                    T", "BITPIX  =                  -32", "NAXIS   =                    2",
+                               "NAXIS1  =           4294967295", "NAXIS2  =           4294967295"], &[]);
+        assert!(parse_fits(&huge).unwrap_err().to_string().contains("shorter than its header says"));
+        assert!(parse_fits(b"not a fits file").is_err());
+
+        // Bytes that aren't ASCII are fine in a comment, but not in a keyword.
+        let card = |start: &str, rest: &[u8]| {
+            let mut card = start.as_bytes().to_vec();
+            card.extend(rest);
+            card.resize(CARD_SIZE, b' ');
+            card
+        };
+        let cards = [
+            card("SIMPLE  =                    T", b""), card("BITPIX  =                    8", b""),
+            card("NAXIS   =                    2", b""), card("NAXIS1  =                    1", "/ é".as_bytes()),
+            card("NAXIS2  =                    1", b"/ \xff\xfe"), card("COMMENT ", "é°".as_bytes()),
+            card("END", b""),
+        ];
+        let mut fits: Vec<u8> = cards.concat();
+        fits.resize(BLOCK_SIZE, b' ');
+        fits.extend([9u8]);
+        fits.resize(BLOCK_SIZE * 2, 0);
+        assert_eq!(parse_fits(&fits).unwrap().to_gray().into_raw(), vec![9]);
+
+        fits[CARD_SIZE * 5..CARD_SIZE * 5 + 8].copy_from_slice("KEYé   ".as_bytes());
+        assert!(parse_fits(&fits).is_err());
+    }
+
+    #[test]
+    fn test_read_fits_image() {
+        // Files written through img_to_fits and the cache, plain and compressed, read back the same.
+        let root = std::env::temp_dir().join("crabseal_test_fits");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+
+        let mut img = GrayImage::new(5, 3);
+        img.put_pixel(4, 2, Luma([200]));
+        img.put_pixel(0, 1, Luma([7]));
+        let plain = img_to_fits(&root.join("test.fits"), &img).unwrap();
+        let compressed = img_to_cache_compressed(&root, &String::from("test.fits"), &DateTime::default(), &img).unwrap();
+
+        assert_eq!(read_fits_image(&plain).unwrap().to_gray(), img);
+        assert_eq!(read_fits_image(&compressed).unwrap().to_gray(), img);
+
+        // As do those written directly.
+        write_fits_image(&root.join("rust.fits"), &img).unwrap();
+        write_fits_image(&root.join("rust.fits.lz4"), &img).unwrap();
+        assert_eq!(read_fits_image(&root.join("rust.fits")).unwrap().to_gray(), img);
+        assert_eq!(read_fits_image(&root.join("rust.fits.lz4")).unwrap().to_gray(), img);
+        assert_eq!(FitsImage::from_gray(img.clone()).to_gray(), img);
+
+        // Wider pixels keep their values.
+        let wide = ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(3, 1, vec![0, 300, 65535]).unwrap();
+        write_fits_image(&root.join("wide.fits.lz4"), &wide).unwrap();
+        assert_eq!(read_fits_image(&root.join("wide.fits.lz4")).unwrap().to_u16(), wide);
+
+        let float = FloatImage::from_raw(2, 1, vec![-1.5, 1000.25]).unwrap();
+        write_fits_image(&root.join("float.fits"), &float).unwrap();
+        assert_eq!(read_fits_image(&root.join("float.fits")).unwrap().to_f32(), float);
+    }
+}
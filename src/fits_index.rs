@@ -0,0 +1,526 @@
+//! A persistent index of the FITS files under a fits path. Walking the whole tree takes a
+//! long time, so the name, path, size, modification time and compression of every file is
+//! kept in an index file that can be reused, verified against the disk, refreshed where the
+//! tree has changed, or rebuilt from scratch.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   fits_index.rs - the FITS path index.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::stage_cache::stage_key;
+use csv::{ReaderBuilder, StringRecord, WriterBuilder};
+use log::{info, warn};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, read_dir};
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
+
+/// The first token of an index file, so we don't read something else by mistake.
+const INDEX_MAGIC: &str = "#crabseal-fits-index";
+/// The version of the index file format. Version 2 is written through the csv crate, so
+/// names and paths holding commas or quotes are quoted.
+const INDEX_VERSION: u32 = 2;
+
+/// How a FITS file is stored on disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// A plain .fits file.
+    None,
+    /// A .fits.lz4 file.
+    Lz4,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!("unknown compression '{}' - expected none or lz4", s)),
+        }
+    }
+}
+
+/// One FITS file in the index.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FitsEntry {
+    /// The name of the FITS, without any .lz4, as found in Images.filename.
+    pub name: String,
+    /// The full path to the file.
+    pub path: PathBuf,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The modification time, in seconds since the epoch.
+    pub mtime: u64,
+    /// How the file is stored.
+    pub compression: Compression,
+}
+
+/// Seconds since the epoch of a modification time, or 0 if it can't be read.
+fn epoch_seconds(time: io::Result<SystemTime>) -> u64 {
+    time.ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl FitsEntry {
+    /// Make an entry for a file on disk. Returns None if the file isn't a FITS or can't be
+    /// read.
+    ///
+    /// * `path` - the path to the file.
+    pub fn from_path(path: &Path) -> Option<FitsEntry> {
+        let file_name = path.file_name()?.to_str()?;
+
+        let (name, compression) = match file_name.strip_suffix(".lz4") {
+            Some(n) => (n, Compression::Lz4),
+            None => (file_name, Compression::None),
+        };
+
+        if !name.ends_with(".fits") {
+            return None;
+        }
+
+        let meta = fs::metadata(path).ok()?;
+
+        if !meta.is_file() {
+            return None;
+        }
+
+        Some(FitsEntry {
+            name: name.to_string(),
+            path: path.to_path_buf(),
+            size: meta.len(),
+            mtime: epoch_seconds(meta.modified()),
+            compression,
+        })
+    }
+
+    /// True if the file has gone, or changed size or modification time since it was indexed.
+    pub fn is_stale(&self) -> bool {
+        match fs::metadata(&self.path) {
+            Ok(meta) => meta.len() != self.size || epoch_seconds(meta.modified()) != self.mtime,
+            Err(_) => true,
+        }
+    }
+}
+
+/// What to do with an existing index when opening it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexMode {
+    /// Use the index as it is, building it if there isn't one.
+    Use,
+    /// Check every entry against the disk, refreshing the index if any have changed.
+    Verify,
+    /// Walk again only the directories that have changed since the index was built.
+    Refresh,
+    /// Throw the index away and walk the whole tree again.
+    Rebuild,
+}
+
+impl FromStr for IndexMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "use" => Ok(IndexMode::Use),
+            "verify" => Ok(IndexMode::Verify),
+            "refresh" => Ok(IndexMode::Refresh),
+            "rebuild" => Ok(IndexMode::Rebuild),
+            _ => Err(format!("unknown index mode '{}' - expected use, verify, refresh or rebuild", s)),
+        }
+    }
+}
+
+/// The index of every FITS file under a fits path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FitsIndex {
+    /// The fits path the index covers.
+    pub root: PathBuf,
+    /// When the tree was last walked, in seconds since the epoch.
+    pub built: u64,
+    /// Every FITS file, sorted by path. Plain and .lz4 copies of a FITS are kept separately.
+    pub entries: Vec<FitsEntry>,
+}
+
+/// The default place for the index of a fits path - a file in a directory we can write to,
+/// as the FITS archive is often read only. The name holds a hash of the fits path, so indices
+/// of different fits paths can share the directory.
+///
+/// * `fits_path` - the path to the FITS files.
+/// * `dir` - the directory to keep the index in, such as the cache or output directory.
+pub fn default_index_path(fits_path: &Path, dir: &Path) -> PathBuf {
+    dir.join(format!("crabseal_{}.index", stage_key(&fits_path.display().to_string())))
+}
+
+/// Walk one directory for FITS files.
+fn walk_fits(dir: &Path) -> Vec<FitsEntry> {
+    WalkDir::new(dir)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| FitsEntry::from_path(e.path()))
+        .collect()
+}
+
+/// The files and directories directly under the root. The *SealHits* layout has one
+/// directory per day, so these are walked in parallel.
+fn top_level(root: &Path) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut files: Vec<PathBuf> = vec![];
+    let mut dirs: Vec<PathBuf> = vec![];
+
+    if let Ok(entries) = read_dir(root) {
+        for entry in entries.flatten() {
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => dirs.push(entry.path()),
+                Ok(t) if t.is_file() => files.push(entry.path()),
+                _ => {}
+            }
+        }
+    }
+
+    (files, dirs)
+}
+
+/// True if this directory, or any directory below it, was modified at or after a time. Adding
+/// or removing a file only changes the time of the directory holding it, so every directory
+/// is checked, but the files themselves are not.
+///
+/// * `dir` - the directory.
+/// * `time` - the time, in seconds since the epoch.
+fn modified_since(dir: &Path, time: u64) -> bool {
+    WalkDir::new(dir)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|e| e.file_type().is_dir())
+        .filter_map(|e| e.ok())
+        .any(|e| epoch_seconds(e.metadata().map_err(io::Error::from).and_then(|m| m.modified())) >= time)
+}
+
+/// The directory directly under the root that holds this path, if any.
+fn top_dir(root: &Path, path: &Path) -> Option<PathBuf> {
+    let rel = path.strip_prefix(root).ok()?;
+    let mut parts = rel.components();
+    let first = parts.next()?;
+    parts.next()?;
+    Some(root.join(first))
+}
+
+impl FitsIndex {
+    /// Walk the whole tree under the root, in parallel, and index every FITS file.
+    ///
+    /// * `root` - the fits path.
+    pub fn build(root: &Path) -> FitsIndex {
+        let built = epoch_seconds(Ok(SystemTime::now()));
+        let (files, dirs) = top_level(root);
+        let mut entries: Vec<FitsEntry> = files.iter().filter_map(|f| FitsEntry::from_path(f)).collect();
+        entries.extend(dirs.par_iter().flat_map(|d| walk_fits(d)).collect::<Vec<FitsEntry>>());
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        FitsIndex {
+            root: root.to_path_buf(),
+            built,
+            entries,
+        }
+    }
+
+    /// Read an index file.
+    ///
+    /// * `index_path` - the path to the index file.
+    pub fn load(index_path: &Path) -> io::Result<FitsIndex> {
+        // The header has four fields and the entries five.
+        let mut reader = ReaderBuilder::new().has_headers(false).flexible(true).from_path(index_path)?;
+        let mut records = reader.records();
+        let bad = |what: &str| Error::new(ErrorKind::InvalidData, format!("bad index {}", what));
+        let header: StringRecord = records.next().ok_or(bad("file - it is empty"))??;
+
+        if header.len() != 4 || &header[0] != INDEX_MAGIC {
+            return Err(bad("header"));
+        }
+
+        if header[1].parse::<u32>().ok() != Some(INDEX_VERSION) {
+            return Err(bad("version"));
+        }
+
+        let built = header[2].parse::<u64>().map_err(|_| bad("build time"))?;
+        let mut entries: Vec<FitsEntry> = vec![];
+
+        for record in records {
+            let record = record?;
+
+            if record.len() != 5 {
+                return Err(bad("entry"));
+            }
+
+            entries.push(FitsEntry {
+                name: record[0].to_string(),
+                compression: record[1].parse().map_err(|_| bad("compression"))?,
+                size: record[2].parse().map_err(|_| bad("size"))?,
+                mtime: record[3].parse().map_err(|_| bad("mtime"))?,
+                path: PathBuf::from(&record[4]),
+            });
+        }
+
+        Ok(FitsIndex {
+            root: PathBuf::from(&header[3]),
+            built,
+            entries,
+        })
+    }
+
+    /// Write the index file. It is written alongside and renamed into place, so a run that
+    /// is stopped part way never leaves half an index behind.
+    ///
+    /// * `index_path` - the path to the index file.
+    pub fn save(&self, index_path: &Path) -> io::Result<()> {
+        let tmp_path = index_path.with_extension("tmp");
+
+        if let Some(parent) = index_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        {
+            let mut writer = WriterBuilder::new().has_headers(false).flexible(true).from_path(&tmp_path)?;
+            writer.write_record([
+                INDEX_MAGIC.to_string(),
+                INDEX_VERSION.to_string(),
+                self.built.to_string(),
+                self.root.display().to_string(),
+            ])?;
+
+            for e in &self.entries {
+                writer.write_record([
+                    e.name.clone(),
+                    e.compression.to_string(),
+                    e.size.to_string(),
+                    e.mtime.to_string(),
+                    e.path.display().to_string(),
+                ])?;
+            }
+
+            writer.flush()?;
+        }
+
+        fs::rename(&tmp_path, index_path)
+    }
+
+    /// The entries that no longer match the files on disk.
+    pub fn verify(&self) -> Vec<&FitsEntry> {
+        self.entries.par_iter().filter(|e| e.is_stale()).collect()
+    }
+
+    /// Bring the index up to date without walking the whole tree. Day directories that are
+    /// new, or that have any directory within them modified since the index was built, are
+    /// walked again. Everything
+    /// else is checked against the disk, dropping files that have gone and updating those
+    /// that have changed. Returns the number of entries added, removed or changed.
+    pub fn refresh(&mut self) -> usize {
+        let built = epoch_seconds(Ok(SystemTime::now()));
+        let (files, dirs) = top_level(&self.root);
+        let known: HashSet<PathBuf> = self.entries.iter().filter_map(|e| top_dir(&self.root, &e.path)).collect();
+
+        // Directories whose contents may have changed. Times are in whole seconds, so
+        // anything modified in the second the index was built is walked again too.
+        let changed: Vec<PathBuf> = dirs
+            .par_iter()
+            .filter(|d| !known.contains(*d) || modified_since(d, self.built))
+            .cloned()
+            .collect();
+        let changed_set: HashSet<&PathBuf> = changed.iter().collect();
+
+        // Re-check the entries in directories we aren't walking again.
+        let kept: Vec<FitsEntry> = self
+            .entries
+            .par_iter()
+            .filter(|e| top_dir(&self.root, &e.path).is_some_and(|d| !changed_set.contains(&d)))
+            .filter_map(|e| if e.is_stale() { FitsEntry::from_path(&e.path) } else { Some(e.clone()) })
+            .collect();
+
+        let mut entries = kept;
+        entries.extend(files.iter().filter_map(|f| FitsEntry::from_path(f)));
+        entries.extend(changed.par_iter().flat_map(|d| walk_fits(d)).collect::<Vec<FitsEntry>>());
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let old: HashSet<(&PathBuf, u64, u64)> = self.entries.iter().map(|e| (&e.path, e.size, e.mtime)).collect();
+        let new: HashSet<(&PathBuf, u64, u64)> = entries.iter().map(|e| (&e.path, e.size, e.mtime)).collect();
+        let differences = old.symmetric_difference(&new).map(|(p, _, _)| *p).collect::<HashSet<_>>().len();
+
+        self.entries = entries;
+        self.built = built;
+        differences
+    }
+
+    /// Every entry for a FITS name - a plain and a .lz4 copy may both exist.
+    ///
+    /// * `name` - the name of the FITS, without any .lz4.
+    pub fn find(&self, name: &str) -> Vec<&FitsEntry> {
+        self.entries.iter().filter(|e| e.name == name).collect()
+    }
+
+    /// A map of FITS name to path, as the rest of the pipeline expects. Where both a plain
+    /// and a .lz4 copy exist the .lz4 is used, as fits_in_path does. Duplicates of the same
+    /// kind in different directories are logged, and the first by path is used.
+    pub fn paths(&self) -> HashMap<String, PathBuf> {
+        let mut chosen: HashMap<String, &FitsEntry> = HashMap::new();
+
+        for entry in &self.entries {
+            match chosen.get(&entry.name) {
+                None => {
+                    chosen.insert(entry.name.clone(), entry);
+                }
+                Some(existing) if existing.compression == entry.compression => {
+                    warn!("Duplicate FITS {} at {} and {}", entry.name, existing.path.display(), entry.path.display());
+                }
+                Some(existing) if existing.compression == Compression::None => {
+                    chosen.insert(entry.name.clone(), entry);
+                }
+                Some(_) => {}
+            }
+        }
+
+        chosen.into_iter().map(|(k, v)| (k, v.path.clone())).collect()
+    }
+
+    /// Open the index for a fits path, loading, checking or building it as asked. An index
+    /// that can't be read, or that belongs to a different fits path, is rebuilt. Failing to
+    /// save the index is logged but otherwise ignored - the index is only a speed up.
+    ///
+    /// * `fits_path` - the path to the FITS files.
+    /// * `index_path` - the path to the index file.
+    /// * `mode` - what to do with an existing index.
+    pub fn open(fits_path: &Path, index_path: &Path, mode: IndexMode) -> FitsIndex {
+        let existing = match mode {
+            IndexMode::Rebuild => None,
+            _ => match FitsIndex::load(index_path) {
+                Ok(index) if index.root == fits_path => Some(index),
+                Ok(index) => {
+                    warn!("Index {} is for {}, not {}", index_path.display(), index.root.display(), fits_path.display());
+                    None
+                }
+                Err(e) => {
+                    if index_path.exists() {
+                        warn!("Could not read index {}: {}", index_path.display(), e);
+                    }
+                    None
+                }
+            },
+        };
+
+        let (index, dirty) = match (existing, mode) {
+            (None, _) => {
+                info!("Building FITS index of {}", fits_path.display());
+                (FitsIndex::build(fits_path), true)
+            }
+            (Some(index), IndexMode::Use) => (index, false),
+            (Some(mut index), IndexMode::Verify) => {
+                let stale = index.verify().len();
+
+                if stale > 0 {
+                    warn!("{} of {} indexed FITS have changed, refreshing", stale, index.entries.len());
+                    index.refresh();
+                }
+
+                (index, stale > 0)
+            }
+            (Some(mut index), _) => {
+                let changes = index.refresh();
+                info!("Refreshed FITS index with {} changes", changes);
+                (index, true)
+            }
+        };
+
+        if dirty {
+            if let Err(e) = index.save(index_path) {
+                warn!("Could not save index {}: {}", index_path.display(), e);
+            }
+        }
+
+        info!("FITS index holds {} files", index.entries.len());
+        index
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fits_index() {
+        let root = std::env::temp_dir().join("crabseal_test_fits_index");
+        let _ = fs::remove_dir_all(&root);
+        let _ = fs::remove_dir_all(root.with_extension("cache"));
+        fs::create_dir_all(root.join("2023_05_28")).unwrap();
+        fs::create_dir_all(root.join("2023_05_29").join("nested")).unwrap();
+        fs::write(root.join("2023_05_28").join("a_854.fits"), "plain").unwrap();
+        fs::write(root.join("2023_05_28").join("a_854.fits.lz4"), "lz4").unwrap();
+        fs::write(root.join("2023_05_29").join("b_853.fits.lz4"), "lz4").unwrap();
+        fs::write(root.join("2023_05_29").join("b,\"odd\"_853.fits"), "commas").unwrap();
+        fs::write(root.join("2023_05_29").join("notes.txt"), "not a fits").unwrap();
+
+        // Let the directory times fall behind the build time, which is in whole seconds.
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+
+        // Plain and compressed copies are kept apart, but the lz4 is preferred.
+        let index = FitsIndex::build(&root);
+        assert_eq!(index.entries.len(), 4);
+        assert_eq!(index.find("a_854.fits").len(), 2);
+        let paths = index.paths();
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths["a_854.fits"], root.join("2023_05_28").join("a_854.fits.lz4"));
+
+        // The index goes outside the archive, named for the fits path.
+        let index_path = default_index_path(&root, &root.with_extension("cache"));
+        assert!(index_path.starts_with(root.with_extension("cache")));
+        assert_ne!(index_path, default_index_path(&root.join("2023_05_28"), &root.with_extension("cache")));
+        index.save(&index_path).unwrap();
+        assert_eq!(FitsIndex::load(&index_path).unwrap(), index);
+        assert!(index.verify().is_empty());
+
+        // Remove one file and add two - verify spots the first, refresh picks up all three,
+        // including the one in a directory below a day directory that hasn't itself changed.
+        fs::remove_file(root.join("2023_05_28").join("a_854.fits.lz4")).unwrap();
+        fs::create_dir_all(root.join("2023_05_30")).unwrap();
+        fs::write(root.join("2023_05_30").join("c_854.fits"), "new").unwrap();
+        fs::write(root.join("2023_05_29").join("nested").join("d_854.fits"), "nested").unwrap();
+        assert_eq!(index.verify().len(), 1);
+
+        let mut refreshed = index.clone();
+        assert_eq!(refreshed.refresh(), 3);
+        assert_eq!(refreshed.entries.len(), 5);
+        assert_eq!(refreshed.find("d_854.fits").len(), 1);
+        assert_eq!(refreshed.paths()["a_854.fits"], root.join("2023_05_28").join("a_854.fits"));
+        assert!(refreshed.verify().is_empty());
+
+        // Opening with verify brings the saved index up to date too.
+        let opened = FitsIndex::open(&root, &index_path, IndexMode::Verify);
+        assert_eq!(opened.entries, refreshed.entries);
+        assert_eq!(FitsIndex::load(&index_path).unwrap().entries, refreshed.entries);
+        assert!("sometimes".parse::<IndexMode>().is_err());
+    }
+}
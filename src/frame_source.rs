@@ -0,0 +1,284 @@
+//! Where the frames of a group come from. Frames are usually read from the FITS files
+//! *SealHits* exported, but can be pulled straight out of the Gemini GLF recordings they
+//! were exported from, so recordings that were never exported can still be used.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   frame_source.rs - FITS and GLF frame sources.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::fits::{read_fits_image, FitsImage};
+use crate::models::Images;
+use crate::resolver::{day_dir, FitsLookup};
+use chrono::{DateTime, Duration, Utc};
+use glf::{ImageRecord, GLF};
+use image::GrayImage;
+use log::warn;
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use walkdir::WalkDir;
+
+/// A GLF record is taken to be the frame of an image if their times are this close.
+pub const GLF_TIME_TOLERANCE_MS: i64 = 50;
+
+/// Decodes the frame for an image.
+pub trait FrameSource: Sync {
+    /// The whole frame for this image, at the bit depth it was recorded with. None if it can't
+    /// be found or read.
+    ///
+    /// * `image` - the Images object we want the frame for.
+    fn read_frame(&self, image: &Images) -> Option<FitsImage>;
+}
+
+/// Anything that finds FITS files reads frames from them.
+impl<T: FitsLookup + ?Sized> FrameSource for T {
+    fn read_frame(&self, image: &Images) -> Option<FitsImage> {
+        read_fits_image(&self.lookup(image)?).ok()
+    }
+}
+
+/// Reads each frame from the first source that has it, such as the FITS files with the
+/// GLFs behind them.
+pub struct FallbackSource<'a> {
+    pub sources: Vec<&'a dyn FrameSource>,
+}
+
+impl FrameSource for FallbackSource<'_> {
+    fn read_frame(&self, image: &Images) -> Option<FitsImage> {
+        self.sources.iter().find_map(|s| s.read_frame(image))
+    }
+}
+
+/// A frame pulled from a GLF, with the index of its record in the GLF.
+pub struct GlfFrame {
+    pub index: usize,
+    pub image: GrayImage,
+}
+
+/// The index of the record for a sonar that is closest in time, if it is within the tolerance.
+///
+/// * `records` - the image records of a GLF.
+/// * `sonar_id` - the sonar the frame came from.
+/// * `time` - the time of the frame.
+/// * `tolerance` - how far apart the times may be.
+pub fn closest_record(records: &[ImageRecord], sonar_id: i32, time: &DateTime<Utc>, tolerance: Duration) -> Option<usize> {
+    records
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| r.header.device_id as i32 == sonar_id)
+        .map(|(idx, r)| (idx, (r.header.time - *time).abs()))
+        .filter(|(_, gap)| *gap <= tolerance)
+        .min_by_key(|(_, gap)| *gap)
+        .map(|(idx, _)| idx)
+}
+
+/// Reads frames straight out of the GLF recordings named in Images.glf. A GLF is held in
+/// memory whole once opened, so only the few most recently used are kept open.
+pub struct GlfSource {
+    /// The directory holding the GLFs, either flat or in YYYY_MM_DD directories.
+    root: PathBuf,
+    /// The most GLFs to keep open.
+    capacity: usize,
+    /// The open GLFs, least recently used first.
+    open: Mutex<Vec<(PathBuf, Arc<GLF>)>>,
+    /// Every GLF under the root, by name. Only walked if a GLF isn't where it should be.
+    found: OnceLock<HashMap<String, PathBuf>>,
+}
+
+impl GlfSource {
+    /// Create a new GlfSource.
+    ///
+    /// * `root` - the directory holding the GLFs.
+    /// * `capacity` - the most GLFs to keep open, at least 1.
+    pub fn new(root: &Path, capacity: usize) -> GlfSource {
+        GlfSource {
+            root: root.to_path_buf(),
+            capacity: capacity.max(1),
+            open: Mutex::new(vec![]),
+            found: OnceLock::new(),
+        }
+    }
+
+    /// Find a GLF - at the root, in the day directory of the image, or anywhere below the root.
+    ///
+    /// * `name` - the GLF name, as in Images.glf.
+    /// * `time` - the time of a frame in the GLF.
+    pub fn glf_path(&self, name: &str, time: &DateTime<Utc>) -> Option<PathBuf> {
+        let name = Path::new(name).file_name()?.to_str()?;
+
+        for path in [self.root.join(name), self.root.join(day_dir(&time.date_naive())).join(name)] {
+            if path.exists() {
+                return Some(path);
+            }
+        }
+
+        let found = self.found.get_or_init(|| {
+            WalkDir::new(&self.root)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .filter_map(|e| Some((e.file_name().to_str()?.to_string(), e.path().to_path_buf())))
+                .collect()
+        });
+
+        found.get(name).cloned()
+    }
+
+    /// Open a GLF, or get it from the ones already open.
+    ///
+    /// * `path` - the path to the GLF.
+    fn open_glf(&self, path: &Path) -> Option<Arc<GLF>> {
+        {
+            let mut open = self.open.lock().unwrap();
+
+            if let Some(pos) = open.iter().position(|(p, _)| p == path) {
+                let entry = open.remove(pos);
+                let glf = entry.1.clone();
+                open.push(entry);
+                return Some(glf);
+            }
+        }
+
+        // Decoding is slow, so is done without holding the lock.
+        let glf = match GLF::new(path) {
+            Ok(glf) => Arc::new(glf),
+            Err(e) => {
+                warn!("Failed to open GLF {} - {}", path.display(), e);
+                return None;
+            }
+        };
+
+        let mut open = self.open.lock().unwrap();
+        open.push((path.to_path_buf(), glf.clone()));
+
+        if open.len() > self.capacity {
+            open.remove(0);
+        }
+
+        Some(glf)
+    }
+
+    /// Pull the frame for an image out of its GLF, with its record index.
+    ///
+    /// * `image` - the Images object we want the frame for.
+    pub fn frame(&self, image: &Images) -> Option<GlfFrame> {
+        let path = self.glf_path(&image.glf, &image.time)?;
+        let glf = self.open_glf(&path)?;
+        let tolerance = Duration::milliseconds(GLF_TIME_TOLERANCE_MS);
+        let index = closest_record(&glf.images, image.sonarid, &image.time, tolerance)?;
+
+        // The glf crate unwraps while decoding, so a damaged record panics rather than
+        // returning an error. That shouldn't take the whole pipeline down with it.
+        match panic::catch_unwind(AssertUnwindSafe(|| glf.extract_image(index))) {
+            Ok(Ok(img)) => Some(GlfFrame { index, image: img }),
+            Ok(Err(e)) => {
+                warn!("Failed to extract frame {} from {} - {}", index, path.display(), e);
+                None
+            }
+            Err(_) => {
+                warn!("Failed to extract frame {} from {} - the record is damaged", index, path.display());
+                None
+            }
+        }
+    }
+}
+
+impl FrameSource for GlfSource {
+    fn read_frame(&self, image: &Images) -> Option<FitsImage> {
+        self.frame(image).map(|f| FitsImage::from_gray(f.image))
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::img_to_fits;
+    use crate::test_util::test_images;
+    use glf::CIHeader;
+    use image::Luma;
+
+    /// An image record with only the sonar and time filled in.
+    fn record(sonar_id: u16, time: DateTime<Utc>) -> ImageRecord {
+        let mut header = CIHeader::new();
+        header.device_id = sonar_id;
+        header.time = time;
+
+        ImageRecord {
+            header,
+            version: 0,
+            image_version: 0,
+            range_start: 0,
+            range_end: 0,
+            range_compression: 0,
+            bearing_start: 0,
+            bearing_end: 0,
+            compression_type: 0,
+            data_ptr: 0,
+            data_size: 0,
+            bearing_table: vec![],
+            state_flags: 0,
+            modulation_frequency: 0,
+            beam_form_app: 0.0,
+            db_tx_time: time,
+            ping_flags: 0,
+            sos_at_xd: 0.0,
+            percent_gain: 0,
+            chirp: 0,
+            sonar_type: 0,
+            platform: 0,
+            record_size: 0,
+            image_width: 0,
+            image_height: 0,
+        }
+    }
+
+    #[test]
+    fn test_frame_source() {
+        let images = test_images(&[0, 100, 200]);
+        let time = |ms: i64| images[0].time + Duration::milliseconds(ms);
+        let records = vec![record(854, time(0)), record(853, time(95)), record(854, time(110)), record(854, time(190))];
+        let tolerance = Duration::milliseconds(GLF_TIME_TOLERANCE_MS);
+
+        // The closest record on the right sonar, if it's close enough.
+        assert_eq!(closest_record(&records, 854, &time(0), tolerance), Some(0));
+        assert_eq!(closest_record(&records, 854, &time(100), tolerance), Some(2));
+        assert_eq!(closest_record(&records, 853, &time(100), tolerance), Some(1));
+        assert_eq!(closest_record(&records, 854, &time(300), tolerance), None);
+        assert_eq!(closest_record(&records, 852, &time(0), tolerance), None);
+
+        // GLFs are found at the root, in day directories, or anywhere below.
+        let root = std::env::temp_dir().join("crabseal_test_frame_source");
+        let _ = std::fs::remove_dir_all(&root);
+        let day = root.join(day_dir(&images[0].time.date_naive()));
+        std::fs::create_dir_all(day.join("nested")).unwrap();
+        std::fs::write(root.join("a.glf"), "").unwrap();
+        std::fs::write(day.join("b.glf"), "").unwrap();
+        std::fs::write(day.join("nested").join("c.glf"), "").unwrap();
+
+        let source = GlfSource::new(&root, 2);
+        assert_eq!(source.glf_path("a.glf", &images[0].time), Some(root.join("a.glf")));
+        assert_eq!(source.glf_path("/elsewhere/b.glf", &images[0].time), Some(day.join("b.glf")));
+        assert_eq!(source.glf_path("c.glf", &images[0].time), Some(day.join("nested").join("c.glf")));
+        assert!(source.glf_path("d.glf", &images[0].time).is_none());
+        // An empty file isn't a GLF.
+        assert!(source.read_frame(&Images { glf: String::from("a.glf"), ..images[0].clone() }).is_none());
+
+        // A FITS lookup falls back to the next source when it can't find a frame.
+        img_to_fits(&root.join("frame.fits"), &GrayImage::from_pixel(3, 2, Luma([9]))).unwrap();
+        let paths: HashMap<String, PathBuf> = HashMap::from([(images[0].filename.clone(), root.join("frame.fits"))]);
+        let fallback = FallbackSource { sources: vec![&paths, &source] };
+        assert_eq!(fallback.read_frame(&images[0]).unwrap().to_gray().get_pixel(2, 1)[0], 9);
+        assert!(fallback.read_frame(&Images { filename: String::from("other.fits"), ..images[1].clone() }).is_none());
+    }
+}
@@ -13,15 +13,14 @@
  *   Author - bjb8@st-andrews.ac.uk
  *   
  */
-use crate::db::{
-    establish_connection, get_groups, get_groups_limit, get_groups_sql, get_images_group,
-    get_points_group_image,
-};
-use crate::image::{read_fits, ImageSize};
+use crate::cache::FrameCache;
+use crate::datasource::DataSource;
+use crate::filter::GroupFilter;
+use crate::image::{ImageSize, VolumePixel};
+use crate::frame_source::FrameSource;
 use crate::models::{Groups, Points};
+use crate::pgdf::{check_points, PgdfSource, PointsMode};
 use crate::ptypes::{GroupT, OriginT};
-use diesel::PgConnection;
-use image::{ImageBuffer, Luma};
 use log::{info, warn};
 use pbr::ProgressBar;
 use rand::thread_rng;
@@ -32,6 +31,9 @@ use std::fs;
 use std::path::PathBuf;
 use std::sync::mpsc::{channel, Sender};
 
+/// How far apart the bearings and ranges of the points in the database and the PGDFs may be.
+const PGDF_TOLERANCE: f32 = 0.01;
+
 // Now define some functions over these types. Generators create TypeObjects and nodes consume each TypeObjects
 pub struct GeneratorGroups {
     groupts: Vec<GroupT>,
@@ -44,18 +46,22 @@ pub struct GeneratorGroups {
 /// 
 /// * `group` - the database Groups object we are starting with.
 /// * `sonar_ids` - the sonar ids we are considering.
-/// * `connection` - the Diesel PgConnection object.
+/// * `source` - where the images, points and tracks come from.
 /// * `min_window` - the minimum length of time permitted.
 /// * `crop_height` - the height that all images are cropped to, regardless of source.
-/// * `image_path_cache` - the image_path cache object.
+/// * `frame_source` - decodes the frame for each image, from the FITS files or the GLFs.
+/// * `frame_cache` - the cache of decoded frames.
+/// * `pgdf` - the PGDFs to rebuild or check the points from, if any.
 /// * `code_to_id` - the mapping of codename to number.
-fn gen_group(
+fn gen_group<T: VolumePixel>(
     group: &Groups,
     sonar_ids: &Vec<i32>,
-    connection: &mut PgConnection,
+    source: &dyn DataSource,
     min_window: u32,
     crop_height: u32,
-    image_path_cache: &HashMap<String, PathBuf>,
+    frame_source: &dyn FrameSource,
+    frame_cache: &FrameCache<T>,
+    pgdf: Option<&PgdfSource>,
     code_to_id: &HashMap<String, u8>,
 ) -> Option<GroupT> {
     let guid = group.uid;
@@ -65,15 +71,42 @@ fn gen_group(
         let mut track_start = 0;
         let mut track_end = 0;
 
-        let images = get_images_group(connection, guid, *sonar_id);
+        let images = source.images_group(guid, *sonar_id);
         let mut pp: Vec<Vec<Points>> = vec![];
 
-        for idx in 0..images.len() {
-            let image = &images[idx];
-            let points = get_points_group_image(connection, guid, image.uid);
+        if pgdf.map(|p| p.mode) != Some(PointsMode::Pgdf) {
+            for image in &images {
+                pp.push(source.points_group_image(guid, image));
+            }
+        }
 
-            if points.len() > 0 {
-                pp.push(points);
+        if let Some(pgdf) = pgdf {
+            let tracks = source.tracks_group(guid);
+
+            match pgdf.group_points(&images, &tracks) {
+                Some(rebuilt) => match pgdf.mode {
+                    PointsMode::Pgdf => pp = rebuilt,
+                    PointsMode::Check => {
+                        let check = check_points(&pp, &rebuilt, PGDF_TOLERANCE);
+
+                        if !check.consistent() {
+                            warn!("Group {} on sonar {} points differ from the PGDFs: {}", group.huid, sonar_id, check);
+                        }
+                    }
+                    PointsMode::Db => {}
+                },
+                None => {
+                    warn!("Group {} on sonar {} could not be read from the PGDFs", group.huid, sonar_id);
+
+                    if pgdf.mode == PointsMode::Pgdf {
+                        continue;
+                    }
+                }
+            }
+        }
+
+        for idx in 0..images.len() {
+            if pp[idx].len() > 0 {
                 track_len += 1;
 
                 if track_start == -1 {
@@ -83,8 +116,6 @@ fn gen_group(
                 if track_end < idx as i32 {
                     track_end = idx as i32;
                 }
-            } else {
-                pp.push(vec![]); // Push a blank for this frame
             }
         }
 
@@ -94,13 +125,16 @@ fn gen_group(
 
         if images.len() > 0 && track_end - track_start >= min_window as i32 && track_len > 3 {
             let image = &images[0];
-            let image_path = image_path_cache.get(&image.filename).unwrap();
-            let img_data: ImageBuffer<Luma<u8>, Vec<u8>> = read_fits(&image_path).unwrap();
+            let Some(img_data) = frame_cache.frame_from(frame_source, image, None) else {
+                warn!("Group {} on sonar {} skipped - the first frame could not be read", group.huid, sonar_id);
+                continue;
+            };
 
+            // The size is taken from the first frame only. Height varies between sonar but *occasionally*
+            // is a few pixels off even for the same sonar, so later frames may not match it. Those are
+            // fitted to the crop size frame by frame, with a FramePolicy, in node_group_to_volume_as.
             let img_size = ImageSize {
                 width: img_data.width(), // All sonar are 512
-                // Height varies between sonar but *occasionally* is a few pixels off even for the same sonar.
-                // We *assume* not in this case. That 0 counts for all
                 height: img_data.height(),
             };
 
@@ -126,6 +160,8 @@ fn gen_group(
                 sonar_id: *sonar_id,
                 img_size: img_size,
                 crop_size: crop_size,
+                time_base: None,
+                metres_per_pixel: None,
             };
 
             let ngt = GroupT {
@@ -146,50 +182,56 @@ fn gen_group(
 
 impl GeneratorGroups {
 
-    /// Create a new Generator that produces GroupT objects from our PostgreSQL database.
+    /// Create a new Generator that produces GroupT objects from our PostgreSQL database, or a snapshot of it.
     /// 
-    /// * `dbuser` - the database Groups object we are starting with.
-    /// * `dbpass` - the sonar ids we are considering.
-    /// * `dbname` - the Diesel PgConnection object.
+    /// * `source` - the database or snapshot the groups come from.
     /// * `sonar_ids` - list of sonars to consider.
-    /// * `image_path_cache` - the height that all images are cropped to, regardless of source.
+    /// * `frame_source` - decodes the frame for each image, from the FITS files or the GLFs.
+    /// * `frame_cache` - the cache of decoded frames.
+    /// * `pgdf` - the PGDFs to rebuild or check the points from, if any.
     /// * `minimum_window` - the minimum time window in frames.
     /// * `dataset_limit` - possible limit on the number of GroupT objects to create.
     /// * `crop_height` - the height that all images are cropped to, regardless of source.
+    /// * `group_filter` - the filter selecting the groups, if any. Applied before the limit.
     /// * `sqlfilter` - path to the SQLFilter file.
     /// * `num_threads` - number of threads to use.
     /// * `code_to_id` - the mapping of codename to number.
-    pub fn new(
-        dbuser: &str,
-        dbpass: &str,
-        dbname: &str,
+    pub fn new<T: VolumePixel>(
+        source: &dyn DataSource,
         sonar_ids: &Vec<i32>,
-        image_path_cache: &HashMap<String, PathBuf>,
+        frame_source: &dyn FrameSource,
+        frame_cache: &FrameCache<T>,
+        pgdf: Option<&PgdfSource>,
         minimum_window: usize,
         dataset_limit: usize,
         crop_height: u32,
+        group_filter: Option<&GroupFilter>,
         sqlfilter: &Option<PathBuf>,
         num_threads: u32,
         code_to_id: &HashMap<String, u8>,
     ) -> GeneratorGroups {
-        // Generator groups connects to a database and creates it's own internal groupts
+        // Generator groups reads from a data source and creates it's own internal groupts
         // TODO - should be lazy when pulling from the DB - saves memory.
-        let db_url = String::from("postgres://") + dbuser + ":" + dbpass + "@localhost/" + dbname;
-        let thread_db_url = db_url.clone();
-        let connection = &mut establish_connection(db_url);
         let groups: Vec<Groups>;
         let mut groupts: Vec<GroupT> = vec![];
 
-        if dataset_limit > 0 {
-            groups = get_groups_limit(connection, dataset_limit as i64);
+        if let Some(filter) = group_filter {
+            info!("Selecting groups via the filter {}", filter);
+            groups = source
+                .groups_filter(filter)
+                .into_iter()
+                .take(if dataset_limit > 0 { dataset_limit } else { usize::MAX })
+                .collect();
+        } else if dataset_limit > 0 {
+            groups = source.groups_limit(dataset_limit);
         } else {
             if Option::is_some(sqlfilter) {
                 let sqlquery: String =
                     fs::read_to_string(&mut sqlfilter.as_ref().unwrap()).unwrap(); // TODO - better error handling here!
                 info!("Selecting groups via the SQLFilter file. {}", &sqlquery);
-                groups = get_groups_sql(connection, sqlquery);
+                groups = source.groups_sql(&sqlquery);
             } else {
-                groups = get_groups(connection);
+                groups = source.groups();
             }
         }
 
@@ -237,22 +279,21 @@ impl GeneratorGroups {
         pool.scoped(|scoped| {
             for _t in 0..num_threads {
                 let cslice: &[Groups] = &gsplit[_t as usize];
-                let tconn = thread_db_url.clone();
                 let tx: Sender<_> = tx.clone();
 
                 // Now execute the threads
                 scoped.execute(move || {
                     // Perform the group processing
-                    let thread_conn = &mut establish_connection(tconn);
-
                     for group in cslice {
                         let ogroup = gen_group(
                             group,
                             sonar_ids,
-                            thread_conn,
+                            source,
                             min_window as u32,
                             crop_height,
-                            image_path_cache,
+                            frame_source,
+                            frame_cache,
+                            pgdf,
                             code_to_id,
                         );
                         let _ = tx.send(ogroup);
@@ -317,6 +358,7 @@ impl Iterator for GeneratorGroups {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::datasource::PgSource;
     use std::{
         fs::File,
         io::{BufRead, BufReader},
@@ -325,7 +367,7 @@ mod tests {
         panic,
         str::FromStr,
     };
-    use walkdir::WalkDir;
+    use crate::fits_index::FitsIndex;
     use postgres::{Client, NoTls};
     use serial_test::serial;
 
@@ -390,35 +432,9 @@ mod tests {
             d.push("fits");
             let fits_path = d.as_os_str();
             let dataset_limit = 10;
-            let mut img_paths: HashMap<String, PathBuf> = HashMap::new();
+            let img_paths: HashMap<String, PathBuf> = FitsIndex::build(Path::new(fits_path)).paths();
             let mut code_to_id: HashMap<String, u8> = HashMap::new();
 
-            // Write to a cache file or read from it, if it already exits.
-            let cache_path = "crabseal.cache";
-
-            if Path::new(cache_path).exists() {
-                let file = File::open(cache_path).unwrap();
-                let reader = BufReader::new(file);
-
-                for res in reader.lines() {
-                    let line = res.unwrap().replace('\n', "");
-                    let tokens = line.split(",").collect::<Vec<&str>>();
-                    img_paths.insert(tokens[0].to_string(), Path::new(tokens[1]).to_path_buf());
-                }
-            } else {
-                for file in WalkDir::new(fits_path)
-                    .into_iter()
-                    .filter_map(|file| file.ok())
-                {
-                    if file.metadata().unwrap().is_file() {
-                        // This conversion to string from osstr is absolutely stupid!
-                        let mut key = file.file_name().to_str().map(|s| s.to_string()).unwrap();
-                        key = key.replace(".lz4", "");
-                        img_paths.insert(key, file.path().to_path_buf());
-                    }
-                }
-            }
-
             let code_class_path = "code_to_class.csv";
 
             if Path::new(code_class_path).exists() {
@@ -433,14 +449,15 @@ mod tests {
             }
 
             let mut generator = GeneratorGroups::new(
-                pg_user.as_str(),
-                pg_pass.as_str(),
-                "testseals",
+                &PgSource::new(pg_user.as_str(), pg_pass.as_str(), "testseals"),
                 &sonar_ids,
                 &img_paths,
+                &FrameCache::<u8>::new(0, None),
+                None,
                 4,
                 dataset_limit,
                 1632,
+                None,
                 &None,
                 4,
                 &code_to_id,
@@ -14,35 +14,66 @@
  *   Author - bjb8@st-andrews.ac.uk
  *   
  */
-use lzzzz::lz4f::ReadDecompressor;
-use std::collections::HashMap;
-use std::fs;
-use std::io::Read;
 use std::path::PathBuf;
-use std::{assert, fs::File, path::Path};
-use uuid::Uuid;
+use std::{assert, path::Path};
+use crate::fits::{read_fits_image, write_fits_image};
 use crate::models::{Groups, Images};
-use fitsio::errors::Error as FitsError;
-use fitsio::hdu::HduInfo;
-use fitsio::{errors::check_status, sys, FileOpenMode, FitsFile};
-use image::{GrayImage, ImageBuffer, Luma};
-use libc;
+use crate::resolver::FitsLookup;
+use image::{ImageBuffer, Luma, Primitive};
 use log::info;
 
-// Type alias for a 3D image volume
+/// The pixel types a volume can hold and be written out as - u8, u16 and f32.
+pub trait VolumePixel: Primitive + Into<f32> + npyz::AutoSerialize + Send + Sync + 'static {
+    /// The name of the type, as given to --dtype.
+    const NAME: &'static str;
+    /// The FITS BITPIX this type is written as.
+    const BITPIX: i32;
+
+    /// Convert a value to this type, rounding and clamping to its range for integer types.
+    fn from_f32(value: f32) -> Self;
+}
+
+impl VolumePixel for u8 {
+    const NAME: &'static str = "u8";
+    const BITPIX: i32 = 8;
+
+    fn from_f32(value: f32) -> Self {
+        value.round().clamp(0.0, 255.0) as u8
+    }
+}
+
+impl VolumePixel for u16 {
+    const NAME: &'static str = "u16";
+    const BITPIX: i32 = 16;
+
+    fn from_f32(value: f32) -> Self {
+        value.round().clamp(0.0, 65535.0) as u16
+    }
+}
+
+impl VolumePixel for f32 {
+    const NAME: &'static str = "f32";
+    const BITPIX: i32 = -32;
+
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+}
+
+// Type alias for a 3D image volume. The pixels are u8 unless asked otherwise.
 #[derive(Clone)]
-pub struct ImageVolume(pub Vec<ImageBuffer<Luma<u8>, Vec<u8>>>);
+pub struct ImageVolume<T: Primitive = u8>(pub Vec<ImageBuffer<Luma<T>, Vec<T>>>);
 
 // The iterator type for the ImageVolume
 #[derive(Clone)]
-pub struct ImageVolumeIntoIterator {
-    img_vol: ImageVolume,
+pub struct ImageVolumeIntoIterator<T: Primitive = u8> {
+    img_vol: ImageVolume<T>,
     index: u32, // Hopefully big enough
 }
 
-impl IntoIterator for ImageVolume {
-    type Item = u8;
-    type IntoIter = ImageVolumeIntoIterator;
+impl<T: Primitive> IntoIterator for ImageVolume<T> {
+    type Item = T;
+    type IntoIter = ImageVolumeIntoIterator<T>;
 
     fn into_iter(self) -> Self::IntoIter {
         ImageVolumeIntoIterator {
@@ -52,11 +83,11 @@ impl IntoIterator for ImageVolume {
     }
 }
 
-impl Iterator for ImageVolumeIntoIterator {
-    type Item = u8;
+impl<T: Primitive> Iterator for ImageVolumeIntoIterator<T> {
+    type Item = T;
 
     // It's depth, height, width progression
-    fn next(&mut self) -> Option<u8> {
+    fn next(&mut self) -> Option<T> {
         let depth = self.img_vol.0.len() as u32; // Weird we have to use the 0 here :/ It's because we can't really type alias
         let height = self.img_vol.0[0].height();
         let width = self.img_vol.0[0].width();
@@ -92,19 +123,19 @@ pub struct ImageSize {
 /// Check that all these images can be loaded correctly.
 ///
 /// * `group_images` - list of Images objects.
-/// * `img_path_cache` - cache of image paths.
+/// * `img_path_cache` - finds the FITS for each image.
 pub fn check_all_images(
     group_images: &Vec<Images>,
-    img_path_cache: &HashMap<String, PathBuf>,
+    img_path_cache: &dyn FitsLookup,
 ) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
     // Loop through all the images found for this group, making sure each image, compressed or otherwise, exists.
     let mut paths: Vec<PathBuf> = vec![];
     assert!(group_images.len() > 0);
 
     for image in group_images {
-        match img_path_cache.get(&image.filename) {
+        match img_path_cache.lookup(image) {
             Some(path) => {
-                paths.push(path.clone());
+                paths.push(path);
             }
             None => {
                 return Err(format!(
@@ -123,18 +154,15 @@ pub fn check_all_images(
 ///
 /// * `group` - the Groups object we are interested in.
 /// * `group_image` - the Images object we want.
-/// * `image_path_cache` - the cache of image paths.
+/// * `image_path_cache` - finds the FITS for each image.
 pub fn read_group_image(
     group: &Groups,
     group_image: &Images,
-    image_path_cache: &HashMap<String, PathBuf>,
+    image_path_cache: &dyn FitsLookup,
 ) -> Result<image::ImageBuffer<Luma<u8>, Vec<u8>>, Box<dyn std::error::Error>> {
     // Go through each group, creating our cropped images for ingest.
     info!("Reading group image details for: {}", group.uid);
-    let fitspath: PathBuf = image_path_cache
-        .get(&group_image.filename)
-        .unwrap()
-        .to_path_buf();
+    let fitspath: PathBuf = image_path_cache.lookup(group_image).unwrap();
 
     // Load one of the images in the group to get hold of sizes
     let mut image_size = ImageSize {
@@ -167,97 +195,14 @@ pub fn read_group_image(
     Ok(rimg)
 }
 
-/// Read a FITS image from disk.
+/// Read a FITS image from disk as u8. Pixels that aren't 8 bit are clamped to 0 to 255;
+/// use crate::fits::read_fits_image to get at the full range.
 ///
 /// * `fits_path` - full path to the FITS file, including the .lz4 if compressed.
 pub fn read_fits(
     fits_path: &Path,
 ) -> Result<image::ImageBuffer<Luma<u8>, Vec<u8>>, Box<dyn std::error::Error>> {
-    //! Read a FITS file but check if we are passing in an LZ4 path and decompress if we do.
-    // TODO - should be an Option!
-    // TODO - code duplication here
-    let mut width: u32 = 1;
-    let mut height: u32 = 1;
-
-    // info!("Reading FITS {} for track.", fits_path.to_str().unwrap());
-
-    let extension: String;
-
-    match fits_path.extension() {
-        Some(ext) => {
-            extension = String::from(ext.to_str().unwrap());
-        }
-        None => {
-            return Err(Box::from("Failed to get extension."));
-        }
-    }
-
-    if extension == "lz4" {
-        let mut stream = File::open(fits_path)?;
-        let mut r = ReadDecompressor::new(&mut stream)?;
-        let mut decompressed = Vec::new();
-        let mut num_bytes = r.read_to_end(&mut decompressed)?;
-        let mut ptr = decompressed.as_ptr();
-        let tuid = Uuid::new_v4().to_string();
-        let t_filename = std::ffi::CString::new(tuid + ".fits").unwrap();
-        let mut fptr = std::ptr::null_mut();
-        let mut status = 0;
-
-        unsafe {
-            sys::ffomem(
-                &mut fptr as *mut *mut _,
-                t_filename.as_ptr(),
-                sys::READONLY as _,
-                &mut ptr as *const _ as *mut *mut libc::c_void,
-                &mut num_bytes as *mut _,
-                0,
-                None,
-                &mut status,
-            );
-        }
-
-        check_status(status)?;
-
-        let mut fits_file = unsafe { FitsFile::from_raw(fptr, FileOpenMode::READONLY) }?;
-        //fits_file.pretty_print().expect("pretty printing fits file");
-        let hdu = fits_file.primary_hdu().unwrap();
-
-        // Assumption that this is a u8 - it is for our data but still
-        let image_data: Vec<u8> = hdu.read_image(&mut fits_file).unwrap();
-        if let HduInfo::ImageInfo { shape, .. } = hdu.info {
-            width = shape[1] as u32;
-            height = shape[0] as u32;
-
-            match GrayImage::from_vec(width, height, image_data) {
-                Some(img) => {
-                    return Ok(img);
-                }
-                None => {
-                    return Err(Box::from("Failed to make GrayImage from LZ4 in read_fits."));
-                }
-            }
-        }
-    }
-
-    let mut fits_file = FitsFile::open(fits_path)?;
-    let hdu = fits_file.primary_hdu()?;
-
-    // Assumption that this is a u8 - it is for our data but still
-    let image_data: Vec<u8> = hdu.read_image(&mut fits_file)?;
-
-    if let HduInfo::ImageInfo { shape, .. } = hdu.info {
-        width = shape[1] as u32;
-        height = shape[0] as u32;
-    }
-
-    match GrayImage::from_vec(width, height, image_data) {
-        Some(img) => {
-            return Ok(img);
-        }
-        None => {
-            return Err(Box::from("Failed to make GrayImage in read_fits."));
-        }
-    }
+    Ok(read_fits_image(fits_path)?.to_gray())
 }
 
 /// Save an image to a FITS file
@@ -267,22 +212,10 @@ pub fn read_fits(
 pub fn img_to_fits(
     fits_path: &Path,
     img_data: &image::ImageBuffer<Luma<u8>, Vec<u8>>,
-) -> Result<PathBuf, FitsError> {
+) -> std::io::Result<PathBuf> {
     //! Given an ImageBuffer, write this out to a fits file. If the file already exists,
-    //! it is removed
-    let _ = fs::remove_file(fits_path); // Ignore errors
-    let mut fptr = FitsFile::create(fits_path).open()?;
-
-    let hdu = fptr.hdu(0)?;
-    hdu.resize(
-        &mut fptr,
-        &[
-            img_data.dimensions().1 as usize,
-            img_data.dimensions().0 as usize,
-        ],
-    )?;
-    let hdu2 = fptr.hdu(0)?;
-    hdu2.write_image(&mut fptr, img_data)?;
+    //! it is replaced
+    write_fits_image(fits_path, img_data)?;
     Ok(fits_path.to_path_buf())
 }
 
@@ -298,55 +231,36 @@ pub fn width_from_height(height: u32) -> u32 {
 ///
 /// * `mask` - the image volume that represents a mask
 pub fn reject_mask(mask: &ImageVolume) -> bool {
-    let border = 32;
-    let mut total: u32 = 0;
-
-    for i in 0..mask.0.len() {
-        let frame: &ImageBuffer<Luma<u8>, Vec<u8>> = &mask.0[i];
-
-        for y in border..frame.height() - border {
-            for x in border..frame.width() - border {
-                let pixel = frame.get_pixel(x, y);
-                if pixel.0[0] > 0 {
-                    total += 1;
-                }
-            }
-        }
-    }
-
-    if total > 50 {
-        // TODO - Arbitrary but for now, better than just a single pixel
-        return false;
-    }
-
-    return true;
+    reject_mask_sized(mask, 32, 50)
 }
 
 /// Reject a mask ImageVolume. Rejects a mask with fewer than 1 pixel set to one.
 ///
 /// * `mask` - the image volume that represents a mask
 pub fn reject_mask_tiny(mask: &ImageVolume) -> bool {
-    let mut total: u32 = 0;
+    reject_mask_sized(mask, 0, 1)
+}
 
-    for i in 0..mask.0.len() {
-        let frame: &ImageBuffer<Luma<u8>, Vec<u8>> = &mask.0[i];
+/// Reject a mask ImageVolume. Rejects a mask with no more than min_pixels pixels set, not
+/// counting those within border pixels of the edge of each frame.
+///
+/// * `mask` - the image volume that represents a mask
+/// * `border` - the width of the border to ignore, in pixels.
+/// * `min_pixels` - the mask must have more pixels set than this.
+pub fn reject_mask_sized(mask: &ImageVolume, border: u32, min_pixels: u32) -> bool {
+    let mut total: u32 = 0;
 
-        for y in 0..frame.height() {
-            for x in 0..frame.width() {
-                let pixel = frame.get_pixel(x, y);
-                if pixel.0[0] > 0 {
+    for frame in &mask.0 {
+        for y in border..frame.height().saturating_sub(border) {
+            for x in border..frame.width().saturating_sub(border) {
+                if frame.get_pixel(x, y).0[0] > 0 {
                     total += 1;
                 }
             }
         }
     }
 
-    if total > 1 {
-        // TODO - Arbitrary
-        return false;
-    }
-
-    return true;
+    total <= min_pixels
 }
 
 // *** TESTS ***
@@ -0,0 +1,248 @@
+//! A Kalman filter and Rauch-Tung-Striebel smoother for box tracks, with tunable noise and
+//! a time step taken from when each image was recorded.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   kalman.rs - Kalman filtering and RTS smoothing of tracks.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::bbs::{FrameBoxRaw, RawBox};
+use crate::image::ImageSize;
+use crate::track::one_frame_one_box;
+use nalgebra::{SMatrix, SVector};
+use std::str::FromStr;
+
+/// The state - centre x, centre y, width and height, then the rate of change of each.
+type State = SVector<f32, 8>;
+type Cov = SMatrix<f32, 8, 8>;
+type Measure = SVector<f32, 4>;
+
+/// Which estimate of the track to output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KalmanOutput {
+    /// The prediction for each frame from the frames before it, before seeing its box.
+    Predicted,
+    /// The forward filter estimate, using the frames up to and including this one.
+    Filtered,
+    /// The smoothed estimate from the backward pass, using every frame.
+    Smoothed,
+}
+
+impl FromStr for KalmanOutput {
+    type Err = String;
+
+    /// Parse an output from the command line - predicted, filtered or smoothed.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "predicted" => Ok(KalmanOutput::Predicted),
+            "filtered" => Ok(KalmanOutput::Filtered),
+            "smoothed" => Ok(KalmanOutput::Smoothed),
+            _ => Err(format!("Unknown Kalman output {}", s)),
+        }
+    }
+}
+
+/// The options for the Kalman smoother.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KalmanOps {
+    /// How much the velocity may wander, in pixels squared per second cubed.
+    pub process_noise: f32,
+    /// How far the annotated boxes are off, as a variance in pixels squared.
+    pub measurement_noise: f32,
+    /// Which estimate to output.
+    pub output: KalmanOutput,
+}
+
+impl Default for KalmanOps {
+    fn default() -> Self {
+        KalmanOps {
+            process_noise: 100.0,
+            measurement_noise: 25.0,
+            output: KalmanOutput::Smoothed,
+        }
+    }
+}
+
+/// The constant velocity transition over dt seconds.
+fn transition(dt: f32) -> Cov {
+    let mut f = Cov::identity();
+
+    for i in 0..4 {
+        f[(i, i + 4)] = dt;
+    }
+
+    f
+}
+
+/// The process noise over dt seconds, from a white noise acceleration.
+fn process(dt: f32, q: f32) -> Cov {
+    let mut p = Cov::zeros();
+
+    for i in 0..4 {
+        p[(i, i)] = q * dt.powi(3) / 3.0;
+        p[(i, i + 4)] = q * dt.powi(2) / 2.0;
+        p[(i + 4, i)] = q * dt.powi(2) / 2.0;
+        p[(i + 4, i + 4)] = q * dt;
+    }
+
+    p
+}
+
+fn to_measure(bbox: &RawBox) -> Measure {
+    Measure::new(
+        (bbox.x_min + bbox.x_max) as f32 / 2.0,
+        (bbox.y_min + bbox.y_max) as f32 / 2.0,
+        (bbox.x_max - bbox.x_min) as f32,
+        (bbox.y_max - bbox.y_min) as f32,
+    )
+}
+
+fn to_box(state: &State, img_size: &ImageSize) -> RawBox {
+    let (w, h) = (state[2].max(1.0) / 2.0, state[3].max(1.0) / 2.0);
+
+    RawBox {
+        x_min: ((state[0] - w).round() as i32).max(0),
+        y_min: ((state[1] - h).round() as i32).max(0),
+        x_max: ((state[0] + w).round() as i32).min(img_size.width as i32 - 1),
+        y_max: ((state[1] + h).round() as i32).min(img_size.height as i32 - 1),
+    }
+}
+
+/// Filter, and optionally smooth, a track with a constant velocity Kalman filter. Every frame
+/// from the first box to the last gets a box, with missing frames filled by the prediction.
+///
+/// * `frames` - a vector of FrameBoxRaw to smooth.
+/// * `times` - the time of each frame, in seconds.
+/// * `img_size` - the size of image we are working within.
+/// * `ops` - the noise and output options.
+pub fn kalman_smooth_track(frames: &Vec<FrameBoxRaw>, times: &[f32], img_size: &ImageSize, ops: &KalmanOps) -> Vec<FrameBoxRaw> {
+    let (boxes, frame_numbers) = one_frame_one_box(frames, img_size);
+
+    if frame_numbers.is_empty() {
+        return frames.clone();
+    }
+
+    let minf = frame_numbers[0];
+    let maxf = *frame_numbers.last().unwrap();
+    let mut by_frame: Vec<Option<RawBox>> = vec![None; (maxf - minf + 1) as usize];
+
+    for (f, b) in frame_numbers.iter().zip(boxes.iter()) {
+        by_frame[(f - minf) as usize] = *b;
+    }
+
+    let r = ops.measurement_noise;
+    let mut h = SMatrix::<f32, 4, 8>::zeros();
+
+    for i in 0..4 {
+        h[(i, i)] = 1.0;
+    }
+
+    // Start on the first box, standing still but with a wide spread on the velocity.
+    let first = to_measure(&by_frame[0].unwrap());
+    let mut x = State::zeros();
+    x.fixed_rows_mut::<4>(0).copy_from(&first);
+    let mut p = Cov::from_diagonal(&State::from_iterator([r, r, r, r, 100.0 * r, 100.0 * r, 100.0 * r, 100.0 * r]));
+
+    let mut predicted: Vec<(State, Cov)> = vec![];
+    let mut filtered: Vec<(State, Cov)> = vec![];
+    let mut transitions: Vec<Cov> = vec![];
+
+    for (idx, bbox) in by_frame.iter().enumerate() {
+        let frame = minf as usize + idx;
+        let dt = if idx == 0 { 0.0 } else { (times[frame] - times[frame - 1]).max(0.0) };
+        let f = transition(dt);
+        x = f * x;
+        p = f * p * f.transpose() + process(dt, ops.process_noise);
+        predicted.push((x, p));
+        transitions.push(f);
+
+        if let Some(bbox) = bbox {
+            let s = h * p * h.transpose() + SMatrix::<f32, 4, 4>::identity() * r;
+
+            if let Some(s_inv) = s.try_inverse() {
+                let k = p * h.transpose() * s_inv;
+                x += k * (to_measure(bbox) - h * x);
+                p = (Cov::identity() - k * h) * p;
+            }
+        }
+
+        filtered.push((x, p));
+    }
+
+    let states: Vec<State> = match ops.output {
+        KalmanOutput::Predicted => predicted.iter().map(|(x, _)| *x).collect(),
+        KalmanOutput::Filtered => filtered.iter().map(|(x, _)| *x).collect(),
+        KalmanOutput::Smoothed => {
+            // The Rauch-Tung-Striebel pass, from the last frame back to the first.
+            let n = filtered.len();
+            let mut smoothed = filtered.clone();
+
+            for k in (0..n - 1).rev() {
+                let (xf, pf) = filtered[k];
+                let (xp, pp) = predicted[k + 1];
+                let f = transitions[k + 1];
+
+                if let Some(pp_inv) = pp.try_inverse() {
+                    let c = pf * f.transpose() * pp_inv;
+                    let (xs, ps) = smoothed[k + 1];
+                    smoothed[k] = (xf + c * (xs - xp), pf + c * (ps - pp) * c.transpose());
+                }
+            }
+
+            smoothed.iter().map(|(x, _)| *x).collect()
+        }
+    };
+
+    states
+        .iter()
+        .enumerate()
+        .map(|(idx, state)| FrameBoxRaw {
+            frame: minf + idx as u32,
+            bbox: to_box(state, img_size),
+        })
+        .collect()
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kalman_smooth() {
+        // A box moving steadily right at 100 pixels a second, with frame 5 missing.
+        let times: Vec<f32> = (0..10).map(|i| i as f32 * 0.1).collect();
+        let img_size = ImageSize { width: 400, height: 400 };
+        let frames: Vec<FrameBoxRaw> = (0..10)
+            .filter(|i| *i != 5)
+            .map(|i| FrameBoxRaw {
+                frame: i,
+                bbox: RawBox { x_min: i as i32 * 10, y_min: 50, x_max: i as i32 * 10 + 20, y_max: 70 },
+            })
+            .collect();
+
+        assert_eq!("filtered".parse::<KalmanOutput>(), Ok(KalmanOutput::Filtered));
+        assert!("rts".parse::<KalmanOutput>().is_err());
+
+        let ops = KalmanOps::default();
+        let smoothed = kalman_smooth_track(&frames, &times, &img_size, &ops);
+        assert_eq!(smoothed.len(), 10);
+        assert!((smoothed[5].bbox.x_min - 50).abs() <= 2);
+        assert!((smoothed[9].bbox.x_max - 110).abs() <= 2);
+
+        // The prediction lags behind the box on the second frame, as it starts standing still.
+        let predicted = kalman_smooth_track(&frames, &times, &img_size, &KalmanOps { output: KalmanOutput::Predicted, ..ops });
+        let filtered = kalman_smooth_track(&frames, &times, &img_size, &KalmanOps { output: KalmanOutput::Filtered, ..ops });
+        assert_eq!(predicted[1].bbox.x_min, 0);
+        assert!(filtered[1].bbox.x_min > predicted[1].bbox.x_min);
+        assert!((smoothed[1].bbox.x_min - 10).abs() <= (filtered[1].bbox.x_min - 10).abs());
+    }
+}
@@ -22,18 +22,41 @@
 pub mod bbs;
 pub mod cache;
 pub mod classdata;
+pub mod clutter;
 pub mod constants;
+pub mod dataset;
+pub mod datasource;
 pub mod db;
 pub mod files;
+pub mod filter;
+pub mod frame_source;
+pub mod fits;
+pub mod fits_index;
 pub mod generators;
 pub mod groups;
 pub mod image;
+pub mod kalman;
+pub mod metrics;
 pub mod models;
 pub mod nodes;
+pub mod nodes_augment;
+pub mod nodes_background;
+pub mod nodes_channels;
+pub mod nodes_convert;
+pub mod nodes_range;
+pub mod nodes_time;
 pub mod nodes_tracks;
 pub mod nodes_volumes;
 pub mod ops;
+pub mod pgdf;
 pub mod ptypes;
+pub mod resolver;
 pub mod schema;
 pub mod sinks;
+pub mod stage_cache;
+pub mod stats;
+pub mod synthetic;
+#[cfg(test)]
+pub(crate) mod test_util;
 pub mod track;
+pub mod validate;
@@ -0,0 +1,290 @@
+//! Track metrics in physical units. RawBox tracks are turned back into metres and seconds
+//! using the bearing table, the range of each image and the image times, so tracks can be
+//! described and rejected in terms of speed, turning, size and gaps.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   metrics.rs - physical track metrics and rejection rules.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::models::Images;
+use crate::nodes_time::image_seconds;
+use crate::ptypes::TrackRawT;
+use std::f32::consts::PI;
+use std::fmt;
+
+/// A box of a track, in metres from the sonar, at a time in seconds from the first image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TrackPoint {
+    /// The frame this box is on.
+    pub frame: u32,
+    /// Seconds since the first image of the group.
+    pub time: f32,
+    /// Metres across, to starboard of the sonar.
+    pub x: f32,
+    /// Metres out, along the centre of the fan.
+    pub y: f32,
+    /// The width of the box across the fan, in metres, at its centre distance.
+    pub width: f32,
+    /// The depth of the box in range, in metres.
+    pub height: f32,
+}
+
+/// Summary numbers for a track, in metres and seconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TrackMetrics {
+    /// The number of boxes.
+    pub boxes: usize,
+    /// Seconds from the first box to the last.
+    pub duration: f32,
+    /// Metres per second, averaged over the whole track.
+    pub mean_speed: f32,
+    /// The fastest move between neighbouring boxes, in metres per second.
+    pub max_speed: f32,
+    /// The average change of heading, in degrees per second.
+    pub turn_rate: f32,
+    /// The average box area, in square metres.
+    pub mean_area: f32,
+    /// The standard deviation of the box area, in square metres.
+    pub area_std: f32,
+    /// The longest time between neighbouring boxes, in seconds.
+    pub max_gap: f32,
+}
+
+/// Why a track was rejected.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejectReason {
+    TooShort,
+    TooFast,
+    TooTwisty,
+    TooSmall,
+    TooBig,
+    SizeVaries,
+    GapTooLong,
+    /// The box moves or changes size too erratically, in pixels - the original --rejectrate.
+    Erratic,
+}
+
+impl fmt::Display for RejectReason {
+    /// The reason, as recorded in the logs and the rejection list.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectReason::TooShort => write!(f, "too_short"),
+            RejectReason::TooFast => write!(f, "too_fast"),
+            RejectReason::TooTwisty => write!(f, "too_twisty"),
+            RejectReason::TooSmall => write!(f, "too_small"),
+            RejectReason::TooBig => write!(f, "too_big"),
+            RejectReason::SizeVaries => write!(f, "size_varies"),
+            RejectReason::GapTooLong => write!(f, "gap_too_long"),
+            RejectReason::Erratic => write!(f, "reject_rate"),
+        }
+    }
+}
+
+/// Limits on the metrics of a track. A rule that is None is not checked, so the default
+/// rejects nothing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TrackRules {
+    /// The shortest track, in seconds.
+    pub min_duration: Option<f32>,
+    /// The fastest mean speed, in metres per second.
+    pub max_speed: Option<f32>,
+    /// The highest mean turn rate, in degrees per second.
+    pub max_turn_rate: Option<f32>,
+    /// The smallest mean box area, in square metres.
+    pub min_area: Option<f32>,
+    /// The largest mean box area, in square metres.
+    pub max_area: Option<f32>,
+    /// The largest standard deviation of the box area, in square metres.
+    pub max_area_std: Option<f32>,
+    /// The longest gap between boxes, in seconds.
+    pub max_gap: Option<f32>,
+    /// The largest standard deviation of the distance moved and of the box area between
+    /// frames, in pixels. Checked on each piece of a split track by node_reject_on_trackraw,
+    /// rather than on the metrics.
+    pub max_pixel_std: Option<f32>,
+}
+
+/// The bearing of a raw pixel column, in radians, from the bearing table.
+fn column_bearing(x: f32, width: u32, btable: &[f32]) -> f32 {
+    let idx = (x / width as f32 * btable.len() as f32) as usize;
+    btable[idx.min(btable.len() - 1)]
+}
+
+/// Convert a TrackRawT into metres and seconds. Boxes are in the raw (fan shaped) image, so
+/// the columns map to bearings through the bearing table and the rows to distances through
+/// the range of each image, or the fixed metres per pixel if the track was range normalised.
+///
+/// * `track` - the TrackRawT, with frames indexing into images.
+/// * `images` - the images of the group.
+/// * `btable` - the bearing table.
+pub fn track_to_metres(track: &TrackRawT, images: &[Images], btable: &[f32]) -> Vec<TrackPoint> {
+    let origin = match &track.origin {
+        Some(o) => o,
+        None => return vec![],
+    };
+
+    let times = image_seconds(images);
+    let mut points: Vec<TrackPoint> = track
+        .boxes
+        .iter()
+        .map(|b| {
+            let image = &images[b.frame as usize];
+            let metres_per_row = origin
+                .metres_per_pixel
+                .unwrap_or(image.range as f32 / origin.img_size.height as f32);
+            let width = origin.img_size.width;
+            // Bearings run from starboard to port across the table, so the bearing of the
+            // right edge is the smaller.
+            let bearing_min = column_bearing(b.bbox.x_max as f32, width, btable);
+            let bearing_max = column_bearing(b.bbox.x_min as f32, width, btable);
+            let bearing = (bearing_min + bearing_max) / 2.0;
+            let distance = (b.bbox.y_min + b.bbox.y_max) as f32 / 2.0 * metres_per_row;
+
+            TrackPoint {
+                frame: b.frame,
+                time: times[b.frame as usize] as f32,
+                x: distance * bearing.sin(),
+                y: distance * bearing.cos(),
+                width: distance * (bearing_max - bearing_min).abs(),
+                height: (b.bbox.y_max - b.bbox.y_min) as f32 * metres_per_row,
+            }
+        })
+        .collect();
+
+    points.sort_by_key(|p| p.frame);
+    points
+}
+
+/// Work out the metrics of a track already in metres and seconds.
+///
+/// * `points` - the track, in frame order.
+pub fn track_metrics(points: &[TrackPoint]) -> TrackMetrics {
+    if points.is_empty() {
+        return TrackMetrics::default();
+    }
+
+    let mut distance = 0.0;
+    let mut max_speed: f32 = 0.0;
+    let mut max_gap: f32 = 0.0;
+    let mut turning = 0.0;
+    let mut turn_time = 0.0;
+    let mut last_heading: Option<(f32, f32)> = None;
+
+    for pair in points.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+        let dt = b.time - a.time;
+        let (dx, dy) = (b.x - a.x, b.y - a.y);
+        let step = (dx * dx + dy * dy).sqrt();
+        distance += step;
+        max_gap = max_gap.max(dt);
+
+        if dt > 0.0 {
+            max_speed = max_speed.max(step / dt);
+        }
+
+        // Headings are too noisy to mean anything when the box has barely moved.
+        if step > 0.01 {
+            let heading = dy.atan2(dx);
+
+            if let Some((last, last_time)) = last_heading {
+                let mut change = (heading - last).abs();
+
+                if change > PI {
+                    change = 2.0 * PI - change;
+                }
+
+                turning += change.to_degrees();
+                turn_time += b.time - last_time;
+            }
+
+            last_heading = Some((heading, b.time));
+        }
+    }
+
+    let duration = points.last().unwrap().time - points[0].time;
+    let areas: Vec<f32> = points.iter().map(|p| p.width * p.height).collect();
+    let mean_area = areas.iter().sum::<f32>() / areas.len() as f32;
+    let area_var = areas.iter().map(|a| (a - mean_area) * (a - mean_area)).sum::<f32>() / areas.len() as f32;
+
+    TrackMetrics {
+        boxes: points.len(),
+        duration,
+        mean_speed: if duration > 0.0 { distance / duration } else { 0.0 },
+        max_speed,
+        turn_rate: if turn_time > 0.0 { turning / turn_time } else { 0.0 },
+        mean_area,
+        area_std: area_var.sqrt(),
+        max_gap,
+    }
+}
+
+/// Check the metrics of a track against the rules, returning every rule it breaks.
+///
+/// * `metrics` - the metrics of the track.
+/// * `rules` - the rules to check.
+pub fn reject_reasons(metrics: &TrackMetrics, rules: &TrackRules) -> Vec<RejectReason> {
+    let checks = [
+        (rules.min_duration.is_some_and(|v| metrics.duration < v), RejectReason::TooShort),
+        (rules.max_speed.is_some_and(|v| metrics.mean_speed > v), RejectReason::TooFast),
+        (rules.max_turn_rate.is_some_and(|v| metrics.turn_rate > v), RejectReason::TooTwisty),
+        (rules.min_area.is_some_and(|v| metrics.mean_area < v), RejectReason::TooSmall),
+        (rules.max_area.is_some_and(|v| metrics.mean_area > v), RejectReason::TooBig),
+        (rules.max_area_std.is_some_and(|v| metrics.area_std > v), RejectReason::SizeVaries),
+        (rules.max_gap.is_some_and(|v| metrics.max_gap > v), RejectReason::GapTooLong),
+    ];
+
+    checks.iter().filter(|(broken, _)| *broken).map(|(_, reason)| *reason).collect()
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbs::{FrameBoxRaw, RawBox};
+    use crate::test_util::test_origin;
+    use crate::test_util::test_images;
+
+    #[test]
+    fn test_track_metrics() {
+        // A flat bearing table, so every column points straight out from the sonar.
+        let btable = vec![0.0f32; 4];
+        // 100 rows over 55m of range. The box moves 20 rows (11m) each second.
+        let images = test_images(&[0, 1000, 2000, 4000]);
+        let boxes = [(0, 0), (1, 20), (3, 60)]
+            .iter()
+            .map(|(f, y)| FrameBoxRaw { frame: *f, bbox: RawBox { x_min: 0, y_min: *y, x_max: 2, y_max: y + 10 } })
+            .collect();
+        let track = TrackRawT::new(boxes, Some(test_origin()));
+
+        let points = track_to_metres(&track, &images, &btable);
+        assert_eq!(points.len(), 3);
+        assert!((points[1].y - 13.75).abs() < 1e-3);
+        assert!((points[1].height - 5.5).abs() < 1e-3);
+
+        let metrics = track_metrics(&points);
+        assert_eq!(metrics.duration, 4.0);
+        assert!((metrics.mean_speed - 8.25).abs() < 1e-3);
+        assert!((metrics.max_speed - 11.0).abs() < 1e-3);
+        assert_eq!(metrics.max_gap, 3.0);
+        assert_eq!(metrics.turn_rate, 0.0);
+
+        assert!(reject_reasons(&metrics, &TrackRules::default()).is_empty());
+        let rules = TrackRules {
+            max_speed: Some(5.0),
+            max_gap: Some(2.0),
+            min_duration: Some(1.0),
+            ..Default::default()
+        };
+        assert_eq!(reject_reasons(&metrics, &rules), vec![RejectReason::TooFast, RejectReason::GapTooLong]);
+        assert_eq!(RejectReason::GapTooLong.to_string(), "gap_too_long");
+    }
+}
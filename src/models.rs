@@ -91,7 +91,7 @@ pub struct Points {
 
 
 /// This TracksGroups object links Tracks to Groups. 
-#[derive(Queryable, Selectable)]
+#[derive(Queryable, Selectable, Clone)]
 #[diesel(table_name = crate::schema::tracks_groups)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct TracksGroups {
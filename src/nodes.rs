@@ -14,14 +14,17 @@
  *   
  */
 extern crate nalgebra as na;
-use crate::image::{reject_mask, reject_mask_tiny};
+use crate::image::{reject_mask, reject_mask_sized, reject_mask_tiny};
+use crate::metrics::{reject_reasons, track_metrics, track_to_metres, RejectReason, TrackRules};
+use crate::nodes_tracks::node_trackraw_merge;
 use crate::{
     bbs::Area,
     bbs::RefChange,
     image::ImageVolume,
     models::{Images, Points},
-    ptypes::{DatumT, GroupT, SlicedDatumT, TrackRawT, VolumeT},
+    ptypes::{ChannelT, DatumT, GroupT, SlicedDatumT, TrackRawT, VolumeT},
 };
+use crate::image::VolumePixel;
 
 /// Split a volume into smaller, overlapping volumes with random placement.
 ///
@@ -61,7 +64,7 @@ pub fn node_trim_group(group: &GroupT) -> GroupT {
 ///
 /// * `data` - the data VolumeT.
 /// * `mask` - the mask VolumeT.
-pub fn node_combine_datum_mask(data: &VolumeT, mask: &VolumeT) -> DatumT {
+pub fn node_combine_datum_mask<T: VolumePixel>(data: &VolumeT<T>, mask: &VolumeT) -> DatumT<T> {
     //! Combine the two halve of the datum
     assert!(data.volume.0[0].width() == mask.volume.0[0].width());
     assert!(data.volume.0[0].height() == mask.volume.0[0].height());
@@ -72,21 +75,53 @@ pub fn node_combine_datum_mask(data: &VolumeT, mask: &VolumeT) -> DatumT {
 ///
 /// * `data` - the data VolumeT.
 /// * `mask` - the mask VolumeT.
-pub fn node_combine_datum_sector(data: &VolumeT, mask: &VolumeT) -> DatumT {
+pub fn node_combine_datum_sector<T: VolumePixel>(data: &VolumeT<T>, mask: &VolumeT) -> DatumT<T> {
     //! Combine the two halve of the datum
     assert!(data.volume.0[0].width() > mask.volume.0[0].width());
     assert!(data.volume.0[0].height() > mask.volume.0[0].height());
     DatumT::new(data, mask)
 }
 
+/// Cut the same frames out of each of the extra channels of a DatumT.
+///
+/// * `datum` - the DatumT being sliced.
+/// * `start` - the first frame of the slice.
+/// * `window` - the length of the slice in number of frames.
+fn slice_channels<T: VolumePixel>(datum: &DatumT<T>, start: usize, window: usize) -> Vec<ChannelT<T>> {
+    datum
+        .channels
+        .iter()
+        .map(|c| ChannelT {
+            name: c.name.clone(),
+            volume: ImageVolume(c.volume.0[start..start + window].to_vec()),
+        })
+        .collect()
+}
+
+/// Add an extra input channel to a DatumT. The channel must match the raw volume in size and depth.
+///
+/// * `datum` - the DatumT to add to.
+/// * `name` - the name of the channel.
+/// * `channel` - the channel VolumeT.
+pub fn node_datum_add_channel<T: VolumePixel>(datum: &DatumT<T>, name: &str, channel: &VolumeT<T>) -> DatumT<T> {
+    assert!(datum.raw.0.len() == channel.volume.0.len());
+    assert!(datum.raw.0[0].dimensions() == channel.volume.0[0].dimensions());
+    let mut new_datum = datum.clone();
+    new_datum.channels.push(ChannelT {
+        name: String::from(name),
+        volume: channel.volume.clone(),
+    });
+    new_datum
+}
+
 /// Slice a DatumT into shorter DatumTs
 ///
 /// * `data` - the data VolumeT to slice.
 /// * `window` - the length of the slices in number of frames.
-pub fn node_slice_datum(datum: &DatumT, window: usize) -> Option<SlicedDatumT> {
+pub fn node_slice_datum<T: VolumePixel>(datum: &DatumT<T>, window: usize) -> Option<SlicedDatumT<T>> {
     //! Slice the datum up into parts based on window size. This node does not overlap nodes
     //! We return a sliced type that we can then pass on to another node.
-    let mut slices: Vec<DatumT> = vec![];
+    let mut slices: Vec<DatumT<T>> = vec![];
 
     if datum.mask.0.len() != datum.raw.0.len() || datum.mask.0.len() < window {
         return None;
@@ -116,6 +151,7 @@ pub fn node_slice_datum(datum: &DatumT, window: usize) -> Option<SlicedDatumT> {
             mask: nmask,
             origin: datum.origin.clone(),
             extents: datum.extents,
+            channels: slice_channels(datum, sidx, window),
         };
 
         slices.push(newd);
@@ -129,11 +165,11 @@ pub fn node_slice_datum(datum: &DatumT, window: usize) -> Option<SlicedDatumT> {
 ///
 /// * `data` - the data VolumeT to slice.
 /// * `window` - the length of the slices in number of frames.
-pub fn node_slice_datum_overlap(datum: &DatumT, window: usize) -> Option<SlicedDatumT> {
+pub fn node_slice_datum_overlap<T: VolumePixel>(datum: &DatumT<T>, window: usize) -> Option<SlicedDatumT<T>> {
     //! Slice the datum up into parts based on window size. This node allows overlapping slices.
     //! We return a sliced type that we can then pass on to another node.
     //! We add the slices together, then shift each one until we match the size required
-    let mut slices: Vec<DatumT> = vec![];
+    let mut slices: Vec<DatumT<T>> = vec![];
 
     if datum.mask.0.len() != datum.raw.0.len() || datum.mask.0.len() < window {
         return None;
@@ -192,6 +228,7 @@ pub fn node_slice_datum_overlap(datum: &DatumT, window: usize) -> Option<SlicedD
             mask: nmask,
             origin: datum.origin.clone(),
             extents: datum.extents,
+            channels: slice_channels(datum, pos, window),
         };
 
         slices.push(newd);
@@ -217,12 +254,17 @@ pub fn node_reject_on_trackraw(track: &TrackRawT, reject_rate: f32) -> bool {
 
     // TODO - same reject_rate for area AND position?
 
+    // A track with no boxes has nothing to learn from.
+    if track.boxes.is_empty() {
+        return true;
+    }
+
     // Start with dists.
     let mut dists: Vec<f32> = vec![];
 
-    for idx in 0..track.boxes.len() - 1 {
-        let bbox = track.boxes[idx].bbox;
-        let nbox = track.boxes[idx + 1].bbox;
+    for pair in track.boxes.windows(2) {
+        let bbox = pair[0].bbox;
+        let nbox = pair[1].bbox;
         let (bx, by) = bbox.centre();
         let (nx, ny) = nbox.centre();
 
@@ -279,7 +321,7 @@ pub fn node_reject_on_trackraw(track: &TrackRawT, reject_rate: f32) -> bool {
 /// Returns true if this DatumT should be rejected.
 ///
 /// * `datum` - the DatumT to reject.
-pub fn node_reject_on_no_mask(datum: &DatumT) -> bool {
+pub fn node_reject_on_no_mask<T: VolumePixel>(datum: &DatumT<T>) -> bool {
     // Reject a datum if it has no mask in it (can happen post split)
     reject_mask(&datum.mask)
 }
@@ -288,16 +330,52 @@ pub fn node_reject_on_no_mask(datum: &DatumT) -> bool {
 /// Returns true if this DatumT should be rejected.
 ///
 /// * `datum` - the DatumT to reject.
-pub fn node_reject_on_no_mask_tiny(datum: &DatumT) -> bool {
+pub fn node_reject_on_no_mask_tiny<T: VolumePixel>(datum: &DatumT<T>) -> bool {
     reject_mask_tiny(&datum.mask)
 }
 
+/// Reject if this datum has a bad mask, with the thresholds given.
+/// Returns true if this DatumT should be rejected.
+///
+/// * `datum` - the DatumT to reject.
+/// * `border` - the width of the border of each frame to ignore, in pixels.
+/// * `min_pixels` - the mask must have more pixels set than this.
+pub fn node_reject_on_mask<T: VolumePixel>(datum: &DatumT<T>, border: u32, min_pixels: u32) -> bool {
+    reject_mask_sized(&datum.mask, border, min_pixels)
+}
+
+/// Reject a track on its speed, turning, size and gaps in metres and seconds, and on how
+/// erratic each of its pieces is in pixels. Returns the rules the track breaks - empty if it
+/// should be kept.
+///
+/// * `pieces` - the pieces of the track, with frames indexing into images.
+/// * `images` - the images of the group.
+/// * `rules` - the limits on the track.
+/// * `btable` - the bearing table, from read_bearing_table.
+pub fn node_reject_on_track_metrics(
+    pieces: &[TrackRawT],
+    images: &[Images],
+    rules: &TrackRules,
+    btable: &[f32],
+) -> Vec<RejectReason> {
+    let points = track_to_metres(&node_trackraw_merge(pieces), images, btable);
+    let mut reasons = reject_reasons(&track_metrics(&points), rules);
+
+    if rules.max_pixel_std.is_some_and(|v| pieces.iter().any(|p| node_reject_on_trackraw(p, v))) {
+        reasons.push(RejectReason::Erratic);
+    }
+
+    reasons
+}
+
 #[cfg(test)]
 mod tests {
     // TODO - these tests are super inefficient! Setup and teardown needs to be split out as the DB stuff
     // needs to be done just once and always, even if tests fail. Don't need to keep doing it. Would allow
     // us to run in parallel.
     use super::*;
+    use crate::bbs::{FrameBoxRaw, RawBox};
+    use crate::datasource::PgSource;
     use crate::sinks::sink_to_png;
     use crate::{generators::GeneratorGroups, sinks::sink_to_npz};
     use image::imageops::FilterType::Lanczos3;
@@ -313,7 +391,8 @@ mod tests {
         path::Path,
         str::FromStr,
     };
-    use walkdir::WalkDir;
+    use crate::cache::FrameCache;
+    use crate::fits_index::FitsIndex;
 
     use crate::nodes_tracks::{
         node_group_to_trackraw, node_track_kalman, node_trackraw_interpolate, node_trackraw_overlap,
@@ -359,19 +438,7 @@ mod tests {
         client.close().unwrap();
 
         // Start with a generator
-        let mut img_paths: HashMap<String, PathBuf> = HashMap::new();
-
-        for file in WalkDir::new(fits_path)
-            .into_iter()
-            .filter_map(|file| file.ok())
-        {
-            if file.metadata().unwrap().is_file() {
-                // This conversion to string from osstr is absolutely stupid!
-                let mut key = file.file_name().to_str().map(|s| s.to_string()).unwrap();
-                key = key.replace(".lz4", "");
-                img_paths.insert(key, file.path().to_path_buf());
-            }
-        }
+        let img_paths: HashMap<String, PathBuf> = FitsIndex::build(Path::new(fits_path)).paths();
 
         let mut code_to_id: HashMap<String, u8> = HashMap::new();
         let code_class_path = "code_to_class.csv";
@@ -401,6 +468,28 @@ mod tests {
         client.batch_execute("drop user testseals;").unwrap();
     }
 
+    #[test]
+    fn test_reject_on_trackraw() {
+        let frame_box = |frame: u32, x: i32| FrameBoxRaw {
+            frame,
+            bbox: RawBox { x_min: x, y_min: 50, x_max: x + 20, y_max: 70 },
+        };
+
+        assert!(node_reject_on_trackraw(&TrackRawT::new(vec![], None), 400.0));
+        assert!(!node_reject_on_trackraw(&TrackRawT::new(vec![frame_box(0, 10)], None), 400.0));
+
+        let steady: Vec<FrameBoxRaw> = (0..8).map(|i| frame_box(i, i as i32 * 10)).collect();
+        assert!(!node_reject_on_trackraw(&TrackRawT::new(steady, None), 400.0));
+
+        // The pixel rule is one of the TrackRules, checked on every piece.
+        let steady = || TrackRawT::new((0..4).map(|i| frame_box(i, i as i32 * 10)).collect(), None);
+        let jumpy = TrackRawT::new([0, 5, 60, 61].iter().enumerate().map(|(i, x)| frame_box(i as u32, x * 10)).collect(), None);
+        let rules = TrackRules { max_pixel_std: Some(400.0), ..Default::default() };
+        let images = crate::test_util::test_images(&[0, 100, 200, 300]);
+        assert!(node_reject_on_track_metrics(&[steady()], &images, &rules, &[0.0]).is_empty());
+        assert_eq!(node_reject_on_track_metrics(&[steady(), jumpy], &images, &rules, &[0.0]), vec![RejectReason::Erratic]);
+    }
+
     #[test]
     #[serial]
     fn test_nodes() {
@@ -417,14 +506,15 @@ mod tests {
 
         run_test(|| {
             let generator = GeneratorGroups::new(
-                dbuser,
-                dbpass,
-                dbname,
+                &PgSource::new(dbuser, dbpass, dbname),
                 &sonar_ids,
                 &img_paths,
+                &FrameCache::<u8>::new(0, None),
+                None,
                 minimum_window,
                 dataset_limit,
                 1632,
+                None,
                 &None,
                 4,
                 &code_to_id,
@@ -483,14 +573,15 @@ mod tests {
 
         run_test(|| {
             let generator = GeneratorGroups::new(
-                dbuser,
-                dbpass,
-                dbname,
+                &PgSource::new(dbuser, dbpass, dbname),
                 &sonar_ids,
                 &img_paths,
+                &FrameCache::<u8>::new(0, None),
+                None,
                 minimum_window,
                 dataset_limit,
                 1632,
+                None,
                 &None,
                 6,
                 &code_to_id,
@@ -550,14 +641,15 @@ mod tests {
 
         run_test(|| {
             let generator = GeneratorGroups::new(
-                dbuser,
-                dbpass,
-                dbname,
+                &PgSource::new(dbuser, dbpass, dbname),
                 &sonar_ids,
                 &img_paths,
+                &FrameCache::<u8>::new(0, None),
+                None,
                 minimum_window,
                 dataset_limit,
                 1632,
+                None,
                 &None,
                 6,
                 &code_to_id,
@@ -624,14 +716,15 @@ mod tests {
 
         run_test(|| {
             let generator = GeneratorGroups::new(
-                dbuser,
-                dbpass,
-                dbname,
+                &PgSource::new(dbuser, dbpass, dbname),
                 &sonar_ids,
                 &img_paths,
+                &FrameCache::<u8>::new(0, None),
+                None,
                 minimum_window,
                 dataset_limit,
                 1632,
+                None,
                 &None,
                 6,
                 &code_to_id,
@@ -0,0 +1,502 @@
+//! Node functions that augment a DatumT, working on the raw and mask volumes together so
+//! the two halves always stay aligned.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   nodes_augment.rs - data augmentation nodes.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::{
+    image::{ImageVolume, VolumePixel},
+    ptypes::DatumT,
+};
+use image::imageops::flip_horizontal;
+use image::{GrayImage, ImageBuffer, Luma, Primitive};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::VecDeque;
+
+/// The options for the augmentation nodes. Each copy made by node_datum_augment draws its
+/// own random choices from these ranges.
+#[derive(Clone)]
+pub struct AugmentOps {
+    /// How many augmented copies to make of each datum. 0 means no augmentation.
+    pub copies: u32,
+    /// The seed for the random number generator. Combined with the group huid so each datum is repeatable.
+    pub seed: u64,
+    /// Probability of mirroring across the sonar centreline.
+    pub mirror_prob: f32,
+    /// Probability of reversing the order of the frames.
+    pub reverse_prob: f32,
+    /// Range of the multiplicative gain (min, max). A range the wrong way round is swapped.
+    pub gain_range: (f32, f32),
+    /// Range of the contrast stretch about the volume mean (min, max). A range the wrong way
+    /// round is swapped.
+    pub contrast_range: (f32, f32),
+    /// Standard deviation of the multiplicative speckle noise. 0 turns it off.
+    pub speckle_sigma: f32,
+    /// Maximum shift in bearing (x) and range (y), in mask pixels, either way. The sign is ignored.
+    pub max_shift: (i32, i32),
+    /// Maximum amount each side of a mask box can move, in mask pixels. Boxes are redrawn with
+    /// a single class value, so this should be 0 for masks that hold anything else, such as
+    /// sector occupancy.
+    pub box_jitter: i32,
+}
+
+impl Default for AugmentOps {
+    fn default() -> AugmentOps {
+        AugmentOps {
+            copies: 0,
+            seed: 0,
+            mirror_prob: 0.5,
+            reverse_prob: 0.5,
+            gain_range: (0.8, 1.2),
+            contrast_range: (0.8, 1.2),
+            speckle_sigma: 0.1,
+            max_shift: (8, 8),
+            box_jitter: 2,
+        }
+    }
+}
+
+/// Create the random number generator for a datum. The seed is mixed with the group huid,
+/// sonar and extents so the same datum always gets the same augmentations, regardless of
+/// the order the datums are processed in.
+///
+/// * `datum` - the DatumT we are about to augment.
+/// * `seed` - the base seed from the AugmentOps.
+pub fn augment_rng<T: VolumePixel>(datum: &DatumT<T>, seed: u64) -> StdRng {
+    // FNV-1a - stable between runs, unlike the std hasher.
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut mix = |bytes: &[u8]| {
+        for b in bytes {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+
+    if let Some(origin) = &datum.origin {
+        mix(origin.group.huid.as_bytes());
+        mix(&origin.sonar_id.to_le_bytes());
+    }
+
+    mix(&datum.extents.0.to_le_bytes());
+    mix(&datum.extents.1.to_le_bytes());
+    StdRng::seed_from_u64(seed ^ hash)
+}
+
+/// Mirror the raw, channel and mask volumes across the sonar centreline (the middle beam).
+/// The extents are mirrored too, so they still say where in the (mirrored) frame the datum is.
+///
+/// * `datum` - the DatumT to mirror.
+pub fn node_datum_mirror<T: VolumePixel>(datum: &DatumT<T>) -> DatumT<T> {
+    let mut new_datum = datum.clone();
+
+    if let Some(origin) = &datum.origin {
+        let (ex, _, ew, _) = datum.extents;
+        new_datum.extents.0 = origin.img_size.width.max(ex + ew) - (ex + ew);
+    }
+
+    new_datum.raw = ImageVolume(datum.raw.0.iter().map(flip_horizontal).collect());
+    new_datum.mask = ImageVolume(datum.mask.0.iter().map(flip_horizontal).collect());
+
+    for channel in new_datum.channels.iter_mut() {
+        channel.volume = ImageVolume(channel.volume.0.iter().map(flip_horizontal).collect());
+    }
+
+    new_datum
+}
+
+/// Reverse the order of the frames in all the volumes, so the animal moves backwards in time.
+/// Channels that depend on the frame order, such as the frame difference, should be added
+/// after augmentation.
+///
+/// * `datum` - the DatumT to reverse.
+pub fn node_datum_reverse<T: VolumePixel>(datum: &DatumT<T>) -> DatumT<T> {
+    let mut new_datum = datum.clone();
+    new_datum.raw.0.reverse();
+    new_datum.mask.0.reverse();
+
+    for channel in new_datum.channels.iter_mut() {
+        channel.volume.0.reverse();
+    }
+
+    new_datum
+}
+
+/// Change the gain and contrast of the raw volume. The mask and any channels are left alone.
+///
+/// * `datum` - the DatumT to change.
+/// * `gain` - multiplicative gain applied after the contrast change.
+/// * `contrast` - contrast stretch about the mean of the raw volume.
+pub fn node_datum_gain<T: VolumePixel>(datum: &DatumT<T>, gain: f32, contrast: f32) -> DatumT<T> {
+    let mut new_datum = datum.clone();
+    let mut total: f64 = 0.0;
+    let mut count: f64 = 0.0;
+
+    for frame in &datum.raw.0 {
+        for pixel in frame.pixels() {
+            total += pixel.0[0].into() as f64;
+            count += 1.0;
+        }
+    }
+
+    let mean = if count > 0.0 { (total / count) as f32 } else { 0.0 };
+
+    for frame in new_datum.raw.0.iter_mut() {
+        for pixel in frame.pixels_mut() {
+            let v = ((pixel.0[0].into() - mean) * contrast + mean) * gain;
+            pixel.0[0] = T::from_f32(v);
+        }
+    }
+
+    new_datum
+}
+
+/// Add multiplicative speckle noise to the raw volume. The mask and any channels are left alone.
+///
+/// * `datum` - the DatumT to change.
+/// * `sigma` - standard deviation of the noise.
+/// * `rng` - the random number generator to draw from.
+pub fn node_datum_speckle<T: VolumePixel>(datum: &DatumT<T>, sigma: f32, rng: &mut StdRng) -> DatumT<T> {
+    let mut new_datum = datum.clone();
+
+    for frame in new_datum.raw.0.iter_mut() {
+        for pixel in frame.pixels_mut() {
+            // Box-Muller for a normally distributed sample.
+            let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+            let u2: f32 = rng.gen();
+            let n = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos();
+            pixel.0[0] = T::from_f32(pixel.0[0].into() * (1.0 + sigma * n));
+        }
+    }
+
+    new_datum
+}
+
+/// Shift a volume by dx, dy pixels, filling the space left behind with zeros.
+fn shift_volume<T: Primitive>(vol: &ImageVolume<T>, dx: i32, dy: i32) -> ImageVolume<T> {
+    let mut new_vol = ImageVolume(vec![]);
+
+    for frame in &vol.0 {
+        let mut nframe = ImageBuffer::from_pixel(frame.width(), frame.height(), Luma([T::DEFAULT_MIN_VALUE]));
+
+        for (x, y, pixel) in frame.enumerate_pixels() {
+            let nx = x as i32 + dx;
+            let ny = y as i32 + dy;
+
+            if nx >= 0 && ny >= 0 && nx < frame.width() as i32 && ny < frame.height() as i32 {
+                nframe.put_pixel(nx as u32, ny as u32, *pixel);
+            }
+        }
+
+        new_vol.0.push(nframe);
+    }
+
+    new_vol
+}
+
+/// Shift the datum in bearing (x) and range (y). The shift is given in mask pixels and scaled
+/// up for the raw volume and channels, so sectored masks stay aligned with their data.
+///
+/// * `datum` - the DatumT to shift.
+/// * `dx` - shift in bearing, in mask pixels.
+/// * `dy` - shift in range, in mask pixels.
+pub fn node_datum_shift<T: VolumePixel>(datum: &DatumT<T>, dx: i32, dy: i32) -> DatumT<T> {
+    let mut new_datum = datum.clone();
+
+    if datum.mask.0.is_empty() || datum.raw.0.is_empty() {
+        return new_datum;
+    }
+
+    let rx = datum.raw.0[0].width() as f32 / datum.mask.0[0].width() as f32;
+    let ry = datum.raw.0[0].height() as f32 / datum.mask.0[0].height() as f32;
+    let rdx = (dx as f32 * rx).round() as i32;
+    let rdy = (dy as f32 * ry).round() as i32;
+    new_datum.raw = shift_volume(&datum.raw, rdx, rdy);
+    new_datum.mask = shift_volume(&datum.mask, dx, dy);
+
+    for channel in new_datum.channels.iter_mut() {
+        channel.volume = shift_volume(&channel.volume, rdx, rdy);
+    }
+
+    new_datum
+}
+
+/// Move both sides of a box along one axis by up to `jitter` pixels, keeping them inside
+/// 0 to `limit` - 1. The far side is drawn from a range that starts at the new near side, so
+/// the box can never turn inside out.
+///
+/// * `low` - the near side of the box.
+/// * `high` - the far side of the box, no less than `low`.
+/// * `jitter` - the maximum movement of each side, above 0.
+/// * `limit` - the size of the frame along this axis.
+/// * `rng` - the random number generator to draw from.
+fn jitter_sides(low: i32, high: i32, jitter: i32, limit: i32, rng: &mut StdRng) -> (i32, i32) {
+    let new_low = (low + rng.gen_range(-jitter..=jitter)).clamp(0, limit - 1);
+    let new_high = high + rng.gen_range((new_low - high).max(-jitter)..=jitter);
+    (new_low, new_high.clamp(new_low, limit - 1))
+}
+
+/// The box (x_min, y_min, x_max, y_max) and class of each connected group of non-zero pixels
+/// in a mask frame. Pixels touching at the corners are connected.
+///
+/// * `frame` - the mask frame.
+fn mask_boxes(frame: &GrayImage) -> Vec<(i32, i32, i32, i32, u8)> {
+    let (w, h) = (frame.width() as i32, frame.height() as i32);
+    let mut seen = vec![false; (w * h) as usize];
+    let mut boxes = vec![];
+
+    for (x, y, pixel) in frame.enumerate_pixels() {
+        let (x, y) = (x as i32, y as i32);
+
+        if pixel.0[0] == 0 || seen[(y * w + x) as usize] {
+            continue;
+        }
+
+        let mut b = (x, y, x, y, pixel.0[0]);
+        let mut queue = VecDeque::from([(x, y)]);
+        seen[(y * w + x) as usize] = true;
+
+        while let Some((cx, cy)) = queue.pop_front() {
+            b = (b.0.min(cx), b.1.min(cy), b.2.max(cx), b.3.max(cy), b.4.max(frame.get_pixel(cx as u32, cy as u32).0[0]));
+
+            for (nx, ny) in (-1..=1).flat_map(|dy| (-1..=1).map(move |dx| (cx + dx, cy + dy))) {
+                if nx < 0 || ny < 0 || nx >= w || ny >= h || seen[(ny * w + nx) as usize] {
+                    continue;
+                }
+
+                if frame.get_pixel(nx as u32, ny as u32).0[0] > 0 {
+                    seen[(ny * w + nx) as usize] = true;
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+
+        boxes.push(b);
+    }
+
+    boxes
+}
+
+/// Jitter the boxes in each mask frame. Each connected group of non-zero mask pixels is a box
+/// of its own; each side is moved by up to `jitter` pixels and the box is redrawn with its
+/// class value. Where boxes end up overlapping, the later one wins.
+///
+/// * `datum` - the DatumT to jitter.
+/// * `jitter` - the maximum movement of each side, in mask pixels.
+/// * `rng` - the random number generator to draw from.
+pub fn node_datum_box_jitter<T: VolumePixel>(datum: &DatumT<T>, jitter: i32, rng: &mut StdRng) -> DatumT<T> {
+    let mut new_datum = datum.clone();
+
+    if jitter <= 0 {
+        return new_datum;
+    }
+
+    for frame in new_datum.mask.0.iter_mut() {
+        let boxes = mask_boxes(frame);
+
+        // Nothing in this frame to jitter.
+        if boxes.is_empty() {
+            continue;
+        }
+
+        let w = frame.width() as i32;
+        let h = frame.height() as i32;
+        frame.pixels_mut().for_each(|p| p.0[0] = 0);
+
+        for (x_min, y_min, x_max, y_max, classid) in boxes {
+            let (nx_min, nx_max) = jitter_sides(x_min, x_max, jitter, w, rng);
+            let (ny_min, ny_max) = jitter_sides(y_min, y_max, jitter, h, rng);
+
+            for y in ny_min..=ny_max {
+                for x in nx_min..=nx_max {
+                    frame.put_pixel(x as u32, y as u32, Luma([classid]));
+                }
+            }
+        }
+    }
+
+    new_datum
+}
+
+/// Draw a value between the two ends of a range, whichever way round they are given. The
+/// AugmentOps fields are public, so nothing else stops a min above the max.
+///
+/// * `rng` - the random number generator to draw from.
+/// * `range` - the two ends of the range.
+fn gen_between(rng: &mut StdRng, range: (f32, f32)) -> f32 {
+    let (low, high) = (range.0.min(range.1), range.0.max(range.1));
+    rng.gen_range(low..=high)
+}
+
+/// Create a number of augmented copies of a DatumT. Each copy draws its own mirror, reversal,
+/// gain, contrast, speckle, shift and box jitter from the AugmentOps. The original is not
+/// included in the returned list.
+///
+/// * `datum` - the DatumT to augment.
+/// * `ops` - the augmentation options.
+pub fn node_datum_augment<T: VolumePixel>(datum: &DatumT<T>, ops: &AugmentOps) -> Vec<DatumT<T>> {
+    let mut rng = augment_rng(datum, ops.seed);
+    let mut copies: Vec<DatumT<T>> = vec![];
+
+    for _ in 0..ops.copies {
+        let mut aug = datum.clone();
+
+        if rng.gen::<f32>() < ops.mirror_prob {
+            aug = node_datum_mirror(&aug);
+        }
+
+        if rng.gen::<f32>() < ops.reverse_prob {
+            aug = node_datum_reverse(&aug);
+        }
+
+        let gain = gen_between(&mut rng, ops.gain_range);
+        let contrast = gen_between(&mut rng, ops.contrast_range);
+        aug = node_datum_gain(&aug, gain, contrast);
+
+        if ops.speckle_sigma > 0.0 {
+            aug = node_datum_speckle(&aug, ops.speckle_sigma, &mut rng);
+        }
+
+        let (sx, sy) = (ops.max_shift.0.saturating_abs(), ops.max_shift.1.saturating_abs());
+        let dx = rng.gen_range(-sx..=sx);
+        let dy = rng.gen_range(-sy..=sy);
+        aug = node_datum_shift(&aug, dx, dy);
+        aug = node_datum_box_jitter(&aug, ops.box_jitter, &mut rng);
+        copies.push(aug);
+    }
+
+    copies
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A small datum with a square in the mask and a gradient in the raw volume.
+    fn test_datum(width: u32, height: u32, depth: usize) -> DatumT {
+        let mut raw = ImageVolume(vec![]);
+        let mut mask = ImageVolume(vec![]);
+
+        for d in 0..depth {
+            raw.0.push(GrayImage::from_fn(width, height, |x, _| Luma([(x * 4 + d as u32) as u8])));
+            mask.0.push(GrayImage::from_fn(width, height, |x, y| {
+                if (4..8).contains(&x) && (2..6).contains(&y) { Luma([3]) } else { Luma([0]) }
+            }));
+        }
+
+        DatumT {
+            raw,
+            mask,
+            origin: None,
+            extents: (0, 0, width, height),
+            channels: vec![],
+        }
+    }
+
+    #[test]
+    fn test_mirror_reverse() {
+        let datum = test_datum(16, 8, 3);
+        let mirrored = node_datum_mirror(&datum);
+        assert_eq!(mirrored.raw.0[0].get_pixel(0, 0)[0], datum.raw.0[0].get_pixel(15, 0)[0]);
+        assert_eq!(mirrored.mask.0[0].get_pixel(15 - 4, 2)[0], 3);
+
+        let twice = node_datum_mirror(&mirrored);
+        assert_eq!(twice.raw.0[1].as_raw(), datum.raw.0[1].as_raw());
+
+        let reversed = node_datum_reverse(&datum);
+        assert_eq!(reversed.raw.0[0].as_raw(), datum.raw.0[2].as_raw());
+
+        // The extents follow the mirror - a datum 1 beam in on the left ends up 1 beam in on the right.
+        let mut datum = test_datum(2, 8, 1);
+        datum.origin = Some(crate::test_util::test_origin());
+        datum.extents = (1, 0, 2, 8);
+        assert_eq!(node_datum_mirror(&datum).extents, (1, 0, 2, 8));
+        datum.extents = (0, 0, 2, 8);
+        assert_eq!(node_datum_mirror(&datum).extents, (2, 0, 2, 8));
+    }
+
+    #[test]
+    fn test_box_jitter() {
+        // Two boxes, far enough apart that jittering can't join them.
+        let mut datum = test_datum(32, 16, 8);
+
+        for frame in datum.mask.0.iter_mut() {
+            for (x, y) in [(24, 8), (25, 8), (24, 9), (25, 9)] {
+                frame.put_pixel(x, y, Luma([5]));
+            }
+        }
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let jittered = node_datum_box_jitter(&datum, 2, &mut rng);
+
+        for frame in &jittered.mask.0 {
+            assert_eq!(mask_boxes(frame).len(), 2);
+
+            // Each box keeps its own class, and stays near where it was.
+            for (x, y, p) in frame.enumerate_pixels() {
+                match p.0[0] {
+                    0 => {}
+                    3 => assert!((2..10).contains(&x) && y < 8),
+                    5 => assert!((22..28).contains(&x) && (6..12).contains(&y)),
+                    v => panic!("unexpected class {}", v),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_shift_sector() {
+        // A mask at half the resolution of the raw volume, as in pipeline_sector.
+        let mut datum = test_datum(16, 8, 2);
+        datum.mask = ImageVolume(
+            datum.mask.0.iter().map(|m| image::imageops::resize(m, 8, 4, image::imageops::FilterType::Nearest)).collect(),
+        );
+
+        let shifted = node_datum_shift(&datum, 1, 0);
+        assert_eq!(shifted.raw.0[0].get_pixel(2, 0)[0], datum.raw.0[0].get_pixel(0, 0)[0]);
+        assert_eq!(shifted.raw.0[0].get_pixel(0, 0)[0], 0);
+        assert_eq!(shifted.mask.0[0].get_pixel(3, 1)[0], datum.mask.0[0].get_pixel(2, 1)[0]);
+    }
+
+    #[test]
+    fn test_augment_seeded() {
+        let datum = test_datum(32, 16, 4);
+        let ops = AugmentOps {
+            copies: 3,
+            seed: 42,
+            ..Default::default()
+        };
+
+        let a = node_datum_augment(&datum, &ops);
+        let b = node_datum_augment(&datum, &ops);
+        assert_eq!(a.len(), 3);
+
+        for (da, db) in a.iter().zip(b.iter()) {
+            assert_eq!(da.raw.0[0].as_raw(), db.raw.0[0].as_raw());
+            assert_eq!(da.mask.0[3].as_raw(), db.mask.0[3].as_raw());
+            // The class value must survive the augmentation.
+            assert!(da.mask.0.iter().all(|m| m.pixels().all(|p| p[0] == 0 || p[0] == 3)));
+        }
+
+        // Ranges given the wrong way round are swapped rather than panicking.
+        let ops = AugmentOps {
+            copies: 2,
+            gain_range: (1.2, 0.8),
+            contrast_range: (1.0, 1.0),
+            max_shift: (-4, -2),
+            ..Default::default()
+        };
+        assert_eq!(node_datum_augment(&datum, &ops).len(), 2);
+    }
+}
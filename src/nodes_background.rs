@@ -0,0 +1,345 @@
+//! Node functions that estimate the background of a volume as an ImageT and remove it again.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   nodes_background.rs - background estimation and removal nodes.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::image::{ImageVolume, VolumePixel};
+use crate::ptypes::{ImageT, VolumeT};
+use image::imageops::{crop_imm, resize, FilterType};
+use image::{ImageBuffer, Luma};
+use std::fmt;
+use std::str::FromStr;
+
+/// How the background of a volume is estimated.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackgroundMethod {
+    /// The per-pixel median over all the frames.
+    Median,
+    /// The per-pixel mean over all the frames.
+    Mean,
+    /// A streaming estimate of the given percentile (0 to 1). Doesn't need every frame in memory.
+    RunningPercentile(f32),
+}
+
+impl FromStr for BackgroundMethod {
+    type Err = String;
+
+    /// Parse a method from the command line - median, mean or percentile:<0 to 1>.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "median" => Ok(BackgroundMethod::Median),
+            "mean" => Ok(BackgroundMethod::Mean),
+            "percentile" => Ok(BackgroundMethod::RunningPercentile(0.5)),
+            _ => match s.strip_prefix("percentile:") {
+                Some(p) => {
+                    let p: f32 = p.parse().map_err(|_| format!("Bad percentile in {}", s))?;
+                    if !(0.0..=1.0).contains(&p) {
+                        return Err(format!("Percentile must be between 0 and 1 in {}", s));
+                    }
+                    Ok(BackgroundMethod::RunningPercentile(p))
+                }
+                None => Err(format!("Unknown background method {}", s)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for BackgroundMethod {
+    /// The method as it is given on the command line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackgroundMethod::Median => write!(f, "median"),
+            BackgroundMethod::Mean => write!(f, "mean"),
+            BackgroundMethod::RunningPercentile(p) => write!(f, "percentile:{}", p),
+        }
+    }
+}
+
+/// A running percentile over a sequence of frames. Each pixel estimate moves up or down a
+/// little with each new frame, so only the current estimate needs to be kept.
+pub struct RunningPercentile {
+    percentile: f32,
+    estimate: Vec<f32>,
+    width: u32,
+    height: u32,
+    count: u32,
+    /// The size of one u8 step in the units of the frames.
+    scale: f32,
+}
+
+impl RunningPercentile {
+    /// Create a new, empty, RunningPercentile.
+    ///
+    /// * `percentile` - the percentile to estimate, between 0 and 1.
+    pub fn new(percentile: f32) -> RunningPercentile {
+        RunningPercentile {
+            percentile,
+            estimate: vec![],
+            width: 0,
+            height: 0,
+            count: 0,
+            scale: 1.0,
+        }
+    }
+
+    /// Add a frame to the estimate. All frames must be the same size as the first.
+    ///
+    /// * `frame` - the next frame.
+    pub fn add<T: VolumePixel>(&mut self, frame: &ImageBuffer<Luma<T>, Vec<T>>) {
+        if self.count == 0 {
+            self.width = frame.width();
+            self.height = frame.height();
+            self.estimate = frame.pixels().map(|p| p.0[0].into()).collect();
+            self.count = 1;
+
+            // The steps below are in u8 units, so stretch them to the range of the pixels. f32
+            // frames have no fixed range, so the spread of the first frame stands in for it.
+            let range: f32 = if T::NAME == "f32" {
+                let low = self.estimate.iter().copied().fold(f32::MAX, f32::min);
+                let high = self.estimate.iter().copied().fold(f32::MIN, f32::max);
+                if high > low { high - low } else { 1.0 }
+            } else {
+                T::DEFAULT_MAX_VALUE.into()
+            };
+            self.scale = range / 255.0;
+            return;
+        }
+
+        assert!(frame.width() == self.width && frame.height() == self.height);
+        self.count += 1;
+
+        // Large steps to begin with, settling down as more frames arrive.
+        let step = (16.0 / (self.count as f32).sqrt()).max(0.5) * self.scale;
+
+        for (est, pixel) in self.estimate.iter_mut().zip(frame.pixels()) {
+            let v: f32 = pixel.0[0].into();
+
+            if v > *est {
+                *est += step * self.percentile;
+            } else if v < *est {
+                *est -= step * (1.0 - self.percentile);
+            }
+        }
+    }
+
+    /// How many frames have gone into this estimate so far.
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Return the current estimate as an image, of any of the volume pixel types.
+    pub fn image<T: VolumePixel>(&self) -> ImageBuffer<Luma<T>, Vec<T>> {
+        let data: Vec<T> = self.estimate.iter().map(|v| T::from_f32(*v)).collect();
+        ImageBuffer::from_vec(self.width, self.height, data).unwrap()
+    }
+}
+
+/// Compute the background of a stack of frames.
+///
+/// * `frames` - the frames, all the same size.
+/// * `method` - how to compute the background.
+pub fn background_from_frames<T: VolumePixel>(frames: &[ImageBuffer<Luma<T>, Vec<T>>], method: &BackgroundMethod) -> ImageBuffer<Luma<T>, Vec<T>> {
+    assert!(!frames.is_empty());
+    let width = frames[0].width();
+    let height = frames[0].height();
+
+    match method {
+        BackgroundMethod::Mean => {
+            let mut total: Vec<f64> = vec![0.0; (width * height) as usize];
+
+            for frame in frames {
+                for (t, p) in total.iter_mut().zip(frame.pixels()) {
+                    *t += Into::<f32>::into(p.0[0]) as f64;
+                }
+            }
+
+            let data = total.iter().map(|t| T::from_f32((*t / frames.len() as f64) as f32)).collect();
+            ImageBuffer::from_vec(width, height, data).unwrap()
+        }
+        BackgroundMethod::Median => {
+            let mut column: Vec<T> = vec![T::DEFAULT_MIN_VALUE; frames.len()];
+            let mut bg = ImageBuffer::from_pixel(width, height, Luma([T::DEFAULT_MIN_VALUE]));
+
+            for y in 0..height {
+                for x in 0..width {
+                    for (c, frame) in column.iter_mut().zip(frames) {
+                        *c = frame.get_pixel(x, y).0[0];
+                    }
+
+                    let mid = column.len() / 2;
+                    // f32 frames can hold NaN, which total_cmp sorts above everything rather than panicking.
+                    let (_, median, _) = column.select_nth_unstable_by(mid, |a, b| {
+                        let (a, b): (f32, f32) = ((*a).into(), (*b).into());
+                        a.total_cmp(&b)
+                    });
+                    bg.put_pixel(x, y, Luma([*median]));
+                }
+            }
+
+            bg
+        }
+        BackgroundMethod::RunningPercentile(p) => {
+            let mut running = RunningPercentile::new(*p);
+
+            for frame in frames {
+                running.add(frame);
+            }
+
+            running.image()
+        }
+    }
+}
+
+/// Compute the background ImageT for a VolumeT.
+///
+/// * `volume` - the VolumeT to find the background of.
+/// * `method` - how to compute the background.
+pub fn node_volume_background<T: VolumePixel>(volume: &VolumeT<T>, method: &BackgroundMethod) -> ImageT<T> {
+    ImageT {
+        image: background_from_frames(&volume.volume.0, method),
+        extents: volume.extents,
+        origin: volume.origin.clone(),
+    }
+}
+
+/// Fit a background image to a frame size. Backgrounds of the same width are in the same
+/// range bins as the frame, so are cropped from the top left (as node_group_to_volume does),
+/// or padded with zeros below if the frame is taller. Anything else is resized.
+///
+/// * `background` - the background ImageT.
+/// * `width` - the frame width we need.
+/// * `height` - the frame height we need.
+pub fn fit_background<T: VolumePixel>(background: &ImageT<T>, width: u32, height: u32) -> ImageBuffer<Luma<T>, Vec<T>> {
+    let bg = &background.image;
+
+    if bg.width() == width && bg.height() == height {
+        bg.clone()
+    } else if bg.width() == width && bg.height() > height {
+        crop_imm(bg, 0, 0, width, height).to_image()
+    } else if bg.width() == width {
+        let mut padded = ImageBuffer::from_pixel(width, height, Luma([T::DEFAULT_MIN_VALUE]));
+        image::imageops::replace(&mut padded, bg, 0, 0);
+        padded
+    } else {
+        resize(bg, width, height, FilterType::Triangle)
+    }
+}
+
+/// Subtract a background from every frame in a volume, clamping at zero.
+///
+/// * `volume` - the VolumeT to remove the background from.
+/// * `background` - the background ImageT.
+pub fn node_volume_subtract_background<T: VolumePixel>(volume: &VolumeT<T>, background: &ImageT<T>) -> VolumeT<T> {
+    let mut new_vol = ImageVolume(vec![]);
+
+    for frame in &volume.volume.0 {
+        let bg = fit_background(background, frame.width(), frame.height());
+        let mut nframe = frame.clone();
+
+        for (p, b) in nframe.pixels_mut().zip(bg.pixels()) {
+            let (v, b): (f32, f32) = (p.0[0].into(), b.0[0].into());
+            p.0[0] = T::from_f32((v - b).max(0.0));
+        }
+
+        new_vol.0.push(nframe);
+    }
+
+    VolumeT {
+        volume: new_vol,
+        extents: volume.extents,
+        origin: volume.origin.clone(),
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GrayImage;
+
+    fn test_volume(values: &[u8]) -> VolumeT {
+        let frames = values.iter().map(|v| GrayImage::from_pixel(4, 3, Luma([*v]))).collect();
+        VolumeT {
+            volume: ImageVolume(frames),
+            extents: (0, 0, 4, 3),
+            origin: None,
+        }
+    }
+
+    #[test]
+    fn test_background_methods() {
+        let vol = test_volume(&[10, 200, 12, 11, 13]);
+        let median = node_volume_background(&vol, &BackgroundMethod::Median);
+        assert_eq!(median.image.get_pixel(1, 1)[0], 12);
+
+        let mean = node_volume_background(&vol, &BackgroundMethod::Mean);
+        assert_eq!(mean.image.get_pixel(3, 2)[0], 49);
+
+        let vol_long = test_volume(&[20; 64]);
+        let running = node_volume_background(&vol_long, &BackgroundMethod::RunningPercentile(0.5));
+        assert_eq!(running.image.get_pixel(0, 0)[0], 20);
+
+        // The running steps follow the range of the pixels, so wider and float frames settle too.
+        let mut wide = RunningPercentile::new(0.5);
+        let mut float = RunningPercentile::new(0.5);
+        wide.add(&ImageBuffer::from_pixel(2, 2, Luma([0u16])));
+        float.add(&ImageBuffer::from_pixel(2, 2, Luma([0.0f32])));
+
+        for _ in 0..63 {
+            wide.add(&ImageBuffer::from_pixel(2, 2, Luma([10000u16])));
+            float.add(&ImageBuffer::from_pixel(2, 2, Luma([0.4f32])));
+        }
+
+        assert!((wide.image::<u16>().get_pixel(0, 0)[0] as i32 - 10000).abs() < 300);
+        assert!((float.image::<f32>().get_pixel(0, 0)[0] - 0.4).abs() < 0.01);
+
+        // Wider pixels keep their range.
+        let frames: Vec<ImageBuffer<Luma<u16>, Vec<u16>>> = [1000u16, 40000, 1200].iter().map(|v| ImageBuffer::from_pixel(2, 2, Luma([*v]))).collect();
+        assert_eq!(background_from_frames(&frames, &BackgroundMethod::Median).get_pixel(0, 0)[0], 1200);
+        assert_eq!(background_from_frames(&frames, &BackgroundMethod::Mean).get_pixel(0, 0)[0], 14067);
+
+        // A NaN in a float frame doesn't stop the median.
+        let frames: Vec<ImageBuffer<Luma<f32>, Vec<f32>>> = [0.5f32, f32::NAN, 0.2].iter().map(|v| ImageBuffer::from_pixel(2, 2, Luma([*v]))).collect();
+        assert_eq!(background_from_frames(&frames, &BackgroundMethod::Median).get_pixel(0, 0)[0], 0.5);
+
+        assert_eq!("percentile:0.9".parse::<BackgroundMethod>(), Ok(BackgroundMethod::RunningPercentile(0.9)));
+        assert!("percentile:2".parse::<BackgroundMethod>().is_err());
+    }
+
+    #[test]
+    fn test_subtract_clamps() {
+        let vol = test_volume(&[10, 200, 12]);
+        let bg = ImageT {
+            image: GrayImage::from_pixel(4, 5, Luma([12])),
+            extents: (0, 0, 4, 5),
+            origin: None,
+        };
+
+        let removed = node_volume_subtract_background(&vol, &bg);
+        assert_eq!(removed.volume.0[0].get_pixel(0, 0)[0], 0);
+        assert_eq!(removed.volume.0[1].get_pixel(0, 0)[0], 188);
+        assert_eq!(removed.volume.0[2].dimensions(), (4, 3));
+
+        // A background shorter than the frame isn't stretched - the rows below it are left alone.
+        let short = ImageT {
+            image: GrayImage::from_pixel(4, 2, Luma([12])),
+            extents: (0, 0, 4, 2),
+            origin: None,
+        };
+
+        let removed = node_volume_subtract_background(&vol, &short);
+        assert_eq!(removed.volume.0[1].get_pixel(0, 1)[0], 188);
+        assert_eq!(removed.volume.0[1].get_pixel(0, 2)[0], 200);
+        assert_eq!(BackgroundMethod::RunningPercentile(0.9).to_string(), "percentile:0.9");
+    }
+}
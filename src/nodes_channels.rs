@@ -0,0 +1,235 @@
+//! Node functions that derive extra input channels from a raw volume - frame differences,
+//! background removed and normalised - and attach them to a DatumT.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   nodes_channels.rs - extra channel nodes.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::image::{ImageVolume, VolumePixel};
+use crate::nodes::node_datum_add_channel;
+use crate::nodes_background::{node_volume_background, node_volume_subtract_background, BackgroundMethod};
+use crate::ptypes::{DatumT, VolumeT};
+use image::{ImageBuffer, Luma};
+use std::fmt;
+use std::str::FromStr;
+
+/// The kinds of extra channel we can derive from the raw volume.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelKind {
+    /// The absolute difference between each frame and the one before it.
+    Difference,
+    /// The raw volume with its own background removed.
+    BackgroundRemoved,
+    /// The raw volume stretched to fill the full range of its pixel type.
+    Normalised,
+}
+
+impl FromStr for ChannelKind {
+    type Err = String;
+
+    /// Parse a channel kind from the command line - diff, bgsub or norm.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "diff" => Ok(ChannelKind::Difference),
+            "bgsub" => Ok(ChannelKind::BackgroundRemoved),
+            "norm" => Ok(ChannelKind::Normalised),
+            _ => Err(format!("Unknown channel {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ChannelKind {
+    /// The channel name, as recorded in the dataset metadata.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChannelKind::Difference => write!(f, "diff"),
+            ChannelKind::BackgroundRemoved => write!(f, "bgsub"),
+            ChannelKind::Normalised => write!(f, "norm"),
+        }
+    }
+}
+
+/// Parse a comma separated list of channel kinds, such as "diff,bgsub". An empty string
+/// gives no channels.
+///
+/// * `list` - the list from the command line.
+pub fn parse_channels(list: &str) -> Result<Vec<ChannelKind>, String> {
+    list.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<ChannelKind>())
+        .collect()
+}
+
+/// The absolute difference between each frame and the previous one. The first frame has
+/// nothing before it so is all zeros.
+///
+/// * `volume` - the VolumeT to difference.
+pub fn node_volume_difference<T: VolumePixel>(volume: &VolumeT<T>) -> VolumeT<T> {
+    let mut new_vol = ImageVolume(vec![]);
+
+    for (idx, frame) in volume.volume.0.iter().enumerate() {
+        if idx == 0 {
+            new_vol.0.push(ImageBuffer::from_pixel(frame.width(), frame.height(), Luma([T::DEFAULT_MIN_VALUE])));
+            continue;
+        }
+
+        let prev = &volume.volume.0[idx - 1];
+        let mut nframe = frame.clone();
+
+        for (p, q) in nframe.pixels_mut().zip(prev.pixels()) {
+            p.0[0] = T::from_f32((p.0[0].into() - q.0[0].into()).abs());
+        }
+
+        new_vol.0.push(nframe);
+    }
+
+    VolumeT {
+        volume: new_vol,
+        extents: volume.extents,
+        origin: volume.origin.clone(),
+    }
+}
+
+/// Stretch a volume so the smallest value over all frames becomes 0 and the largest the top
+/// of the pixel type - 255 for u8, 65535 for u16 and 1 for f32.
+///
+/// * `volume` - the VolumeT to normalise.
+pub fn node_volume_normalise<T: VolumePixel>(volume: &VolumeT<T>) -> VolumeT<T> {
+    let mut low = f32::MAX;
+    let mut high = f32::MIN;
+
+    for frame in &volume.volume.0 {
+        for p in frame.pixels() {
+            low = low.min(p.0[0].into());
+            high = high.max(p.0[0].into());
+        }
+    }
+
+    let mut new_vol = volume.volume.clone();
+
+    if high > low {
+        let scale = T::DEFAULT_MAX_VALUE.into() / (high - low);
+
+        for frame in new_vol.0.iter_mut() {
+            for p in frame.pixels_mut() {
+                p.0[0] = T::from_f32((p.0[0].into() - low) * scale);
+            }
+        }
+    }
+
+    VolumeT {
+        volume: new_vol,
+        extents: volume.extents,
+        origin: volume.origin.clone(),
+    }
+}
+
+/// Derive a single channel from a volume.
+///
+/// * `volume` - the raw VolumeT.
+/// * `kind` - the channel to make.
+/// * `method` - how to estimate the background, for the background removed channel. None if
+///   the raw volume already has its background removed, so the channel is the raw volume as is.
+pub fn node_volume_channel<T: VolumePixel>(volume: &VolumeT<T>, kind: ChannelKind, method: Option<&BackgroundMethod>) -> VolumeT<T> {
+    match kind {
+        ChannelKind::Difference => node_volume_difference(volume),
+        ChannelKind::BackgroundRemoved => match method {
+            Some(method) => {
+                let background = node_volume_background(volume, method);
+                node_volume_subtract_background(volume, &background)
+            }
+            None => VolumeT {
+                volume: volume.volume.clone(),
+                extents: volume.extents,
+                origin: volume.origin.clone(),
+            },
+        },
+        ChannelKind::Normalised => node_volume_normalise(volume),
+    }
+}
+
+/// Derive the channels from the raw volume of a DatumT and add them, in order. Any
+/// channels the datum already has are kept.
+///
+/// * `datum` - the DatumT to add channels to.
+/// * `kinds` - the channels to add.
+/// * `method` - how to estimate the background, for the background removed channel. None if
+///   the raw volume already has its background removed.
+pub fn node_datum_channels<T: VolumePixel>(datum: &DatumT<T>, kinds: &[ChannelKind], method: Option<&BackgroundMethod>) -> DatumT<T> {
+    let raw = VolumeT {
+        volume: datum.raw.clone(),
+        extents: datum.extents,
+        origin: datum.origin.clone(),
+    };
+
+    let mut new_datum = datum.clone();
+
+    for kind in kinds {
+        let channel = node_volume_channel(&raw, *kind, method);
+        new_datum = node_datum_add_channel(&new_datum, &kind.to_string(), &channel);
+    }
+
+    new_datum
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GrayImage;
+
+    #[test]
+    fn test_channels() {
+        let frames = [10u8, 30, 20].iter().map(|v| GrayImage::from_pixel(4, 3, Luma([*v]))).collect();
+        let raw = VolumeT {
+            volume: ImageVolume(frames),
+            extents: (0, 0, 4, 3),
+            origin: None,
+        };
+        let mask = VolumeT {
+            volume: ImageVolume(vec![GrayImage::new(4, 3); 3]),
+            extents: (0, 0, 4, 3),
+            origin: None,
+        };
+
+        let diff = node_volume_difference(&raw);
+        assert_eq!(diff.volume.0[0].get_pixel(0, 0)[0], 0);
+        assert_eq!(diff.volume.0[1].get_pixel(0, 0)[0], 20);
+        assert_eq!(diff.volume.0[2].get_pixel(0, 0)[0], 10);
+
+        let norm = node_volume_normalise(&raw);
+        assert_eq!(norm.volume.0[0].get_pixel(0, 0)[0], 0);
+        assert_eq!(norm.volume.0[1].get_pixel(0, 0)[0], 255);
+
+        // Wider pixels stretch to the top of their own range.
+        let wide = VolumeT {
+            volume: ImageVolume(vec![ImageBuffer::from_pixel(4, 3, Luma([1000u16])), ImageBuffer::from_pixel(4, 3, Luma([3000u16]))]),
+            extents: (0, 0, 4, 3),
+            origin: None,
+        };
+        assert_eq!(node_volume_normalise(&wide).volume.0[1].get_pixel(0, 0)[0], 65535);
+        assert_eq!(node_volume_difference(&wide).volume.0[1].get_pixel(0, 0)[0], 2000);
+
+        let kinds = parse_channels("diff, bgsub").unwrap();
+        assert!(parse_channels("diff,foo").is_err());
+        assert!(parse_channels("").unwrap().is_empty());
+
+        let datum = node_datum_channels(&DatumT::new(&raw, &mask), &kinds, Some(&BackgroundMethod::Median));
+        assert_eq!(datum.channel_names(), vec!["raw", "diff", "bgsub"]);
+        assert_eq!(datum.channels[1].volume.0[1].get_pixel(0, 0)[0], 10);
+
+        // A raw volume that already has its background removed isn't subtracted from twice.
+        let datum = node_datum_channels(&DatumT::new(&raw, &mask), &kinds, None);
+        assert_eq!(datum.channels[1].volume.0, raw.volume.0);
+    }
+}
@@ -0,0 +1,403 @@
+//! Node functions that convert volumes and datums between pixel types - u8, u16 and f32 -
+//! normalising them on the way if asked.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   nodes_convert.rs - pixel type conversion nodes.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::image::{ImageVolume, VolumePixel};
+use crate::ptypes::{ChannelT, DatumT, SlicedDatumT, VolumeT};
+use image::{ImageBuffer, Primitive};
+use std::fmt;
+use std::str::FromStr;
+
+/// The pixel types volumes can be written out as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelType {
+    U8,
+    U16,
+    F32,
+}
+
+impl FromStr for PixelType {
+    type Err = String;
+
+    /// Parse a pixel type from the command line - u8, u16 or f32.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "u8" => Ok(PixelType::U8),
+            "u16" => Ok(PixelType::U16),
+            "f32" => Ok(PixelType::F32),
+            _ => Err(format!("Unknown pixel type {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for PixelType {
+    /// The pixel type, as recorded in the dataset metadata.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PixelType::U8 => write!(f, "u8"),
+            PixelType::U16 => write!(f, "u16"),
+            PixelType::F32 => write!(f, "f32"),
+        }
+    }
+}
+
+/// How to normalise a volume as it is converted. Each volume is normalised on its own, and the
+/// slices of a SlicedDatumT share the statistics of the whole group.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Normalise {
+    /// Keep the values as they are, clamped to the range of the new type.
+    None,
+    /// Scale so the largest value the old type holds becomes the largest the new type holds,
+    /// or 1 for f32. f32 volumes have no fixed top, so their largest value is used instead.
+    Max,
+    /// Stretch so the smallest value in the volume becomes 0 and the largest the largest the
+    /// new type holds, or 1 for f32.
+    MinMax,
+    /// Zero mean and unit standard deviation. Only makes sense for f32.
+    Standard,
+}
+
+impl FromStr for Normalise {
+    type Err = String;
+
+    /// Parse a normalisation from the command line - none, max, minmax or standard.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Normalise::None),
+            "max" => Ok(Normalise::Max),
+            "minmax" => Ok(Normalise::MinMax),
+            "standard" => Ok(Normalise::Standard),
+            _ => Err(format!("Unknown normalisation {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Normalise {
+    /// The normalisation, as recorded in the dataset metadata.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Normalise::None => write!(f, "none"),
+            Normalise::Max => write!(f, "max"),
+            Normalise::MinMax => write!(f, "minmax"),
+            Normalise::Standard => write!(f, "standard"),
+        }
+    }
+}
+
+/// The pixel type the raw volumes and channels are written as, and how they are normalised.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PixelOps {
+    pub pixel_type: PixelType,
+    pub normalise: Normalise,
+}
+
+impl Default for PixelOps {
+    /// u8 as it comes - the pipeline's own type.
+    fn default() -> Self {
+        PixelOps {
+            pixel_type: PixelType::U8,
+            normalise: Normalise::None,
+        }
+    }
+}
+
+/// The offset and spread that take the values of a set of volumes to the normalised range,
+/// as (value - offset) / spread. The statistics are taken over all the volumes together.
+///
+/// * `volumes` - the ImageVolumes to normalise.
+/// * `normalise` - the normalisation.
+fn normalise_params<S: VolumePixel>(volumes: &[&ImageVolume<S>], normalise: Normalise) -> (f32, f32) {
+    let values = || volumes.iter().flat_map(|v| v.0.iter()).flat_map(|f| f.as_raw().iter().map(|v| (*v).into()));
+    let spread = |s: f32| if s > 0.0 { s } else { 1.0 };
+
+    match normalise {
+        Normalise::None => (0.0, 1.0),
+        Normalise::Max if S::NAME == "f32" => (0.0, spread(values().fold(f32::MIN, f32::max))),
+        Normalise::Max => (0.0, S::DEFAULT_MAX_VALUE.into()),
+        Normalise::MinMax => {
+            let low = values().fold(f32::MAX, f32::min);
+            let high = values().fold(f32::MIN, f32::max);
+            (low, if high > low { high - low } else { 1.0 })
+        }
+        Normalise::Standard => {
+            let count = values().count().max(1) as f64;
+            let mean = values().map(|v| v as f64).sum::<f64>() / count;
+            let var = values().map(|v| (v as f64 - mean) * (v as f64 - mean)).sum::<f64>() / count;
+            (mean as f32, spread(var.sqrt() as f32))
+        }
+    }
+}
+
+/// Convert an ImageVolume to another pixel type, normalising it first with the statistics
+/// of the volume itself.
+///
+/// * `volume` - the ImageVolume to convert.
+/// * `normalise` - the normalisation.
+pub fn convert_volume<S: VolumePixel, T: VolumePixel>(volume: &ImageVolume<S>, normalise: Normalise) -> ImageVolume<T> {
+    convert_volume_with(volume, normalise, normalise_params(&[volume], normalise))
+}
+
+/// Convert an ImageVolume to another pixel type, normalising it with statistics already found.
+///
+/// * `volume` - the ImageVolume to convert.
+/// * `normalise` - the normalisation.
+/// * `params` - the offset and spread from normalise_params.
+fn convert_volume_with<S: VolumePixel, T: VolumePixel>(volume: &ImageVolume<S>, normalise: Normalise, params: (f32, f32)) -> ImageVolume<T> {
+    let (offset, spread) = params;
+    // Normalised values run from 0 to 1, so stretch them to fill an integer type.
+    let range: f32 = if normalise == Normalise::None { 1.0 } else { <T as Primitive>::DEFAULT_MAX_VALUE.into() };
+
+    ImageVolume(
+        volume
+            .0
+            .iter()
+            .map(|frame| {
+                let pixels: Vec<T> = frame.as_raw().iter().map(|v| T::from_f32(((*v).into() - offset) / spread * range)).collect();
+                ImageBuffer::from_raw(frame.width(), frame.height(), pixels).unwrap()
+            })
+            .collect(),
+    )
+}
+
+/// Convert a VolumeT to another pixel type, normalising it first.
+///
+/// * `volume` - the VolumeT to convert.
+/// * `normalise` - the normalisation.
+pub fn node_volume_convert<S: VolumePixel, T: VolumePixel>(volume: &VolumeT<S>, normalise: Normalise) -> VolumeT<T> {
+    VolumeT {
+        volume: convert_volume(&volume.volume, normalise),
+        extents: volume.extents,
+        origin: volume.origin.clone(),
+    }
+}
+
+/// Convert the raw volume and channels of a DatumT to another pixel type, normalising each
+/// on its own. The mask is left as it is.
+///
+/// * `datum` - the DatumT to convert.
+/// * `normalise` - the normalisation.
+pub fn node_datum_convert<S: VolumePixel, T: VolumePixel>(datum: &DatumT<S>, normalise: Normalise) -> DatumT<T> {
+    DatumT {
+        raw: convert_volume(&datum.raw, normalise),
+        mask: datum.mask.clone(),
+        origin: datum.origin.clone(),
+        extents: datum.extents,
+        channels: datum
+            .channels
+            .iter()
+            .map(|c| ChannelT {
+                name: c.name.clone(),
+                volume: convert_volume(&c.volume, normalise),
+            })
+            .collect(),
+    }
+}
+
+/// The normalisation statistics of a group, found once and shared by everything written from
+/// it - every patch, slice and augmented copy - so none of them is scaled on its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NormParams {
+    pub normalise: Normalise,
+    /// The offset and spread of the raw volume.
+    pub raw: (f32, f32),
+    /// The offset and spread of each channel, in order.
+    pub channels: Vec<(f32, f32)>,
+}
+
+/// Find the statistics over a set of datums together - for the raw volumes, then for each
+/// channel.
+///
+/// * `datums` - the DatumTs, all with the same channels.
+/// * `normalise` - the normalisation.
+fn norm_params<S: VolumePixel>(datums: &[&DatumT<S>], normalise: Normalise) -> NormParams {
+    let raws: Vec<&ImageVolume<S>> = datums.iter().map(|d| &d.raw).collect();
+    let num_channels = datums.first().map_or(0, |d| d.channels.len());
+
+    NormParams {
+        normalise,
+        raw: normalise_params(&raws, normalise),
+        channels: (0..num_channels)
+            .map(|idx| {
+                let volumes: Vec<&ImageVolume<S>> = datums.iter().map(|d| &d.channels[idx].volume).collect();
+                normalise_params(&volumes, normalise)
+            })
+            .collect(),
+    }
+}
+
+/// Find the normalisation statistics of a whole DatumT, before it is cut into patches and
+/// sliced.
+///
+/// * `datum` - the DatumT, with its channels.
+/// * `normalise` - the normalisation.
+pub fn node_datum_norm_params<S: VolumePixel>(datum: &DatumT<S>, normalise: Normalise) -> NormParams {
+    norm_params(&[datum], normalise)
+}
+
+/// Find the normalisation statistics over all the slices of a SlicedDatumT.
+///
+/// * `sliced` - the SlicedDatumT.
+/// * `normalise` - the normalisation.
+pub fn node_sliced_norm_params<S: VolumePixel>(sliced: &SlicedDatumT<S>, normalise: Normalise) -> NormParams {
+    let datums: Vec<&DatumT<S>> = sliced.slices.iter().collect();
+    norm_params(&datums, normalise)
+}
+
+/// Convert every slice of a SlicedDatumT to another pixel type. The statistics are found
+/// once over all the slices - for the raw volumes, then for each channel - so every slice of
+/// a group is scaled the same way. The masks are left as they are.
+///
+/// * `sliced` - the SlicedDatumT to convert.
+/// * `normalise` - the normalisation.
+pub fn node_sliced_convert<S: VolumePixel, T: VolumePixel>(sliced: &SlicedDatumT<S>, normalise: Normalise) -> SlicedDatumT<T> {
+    node_sliced_convert_with(sliced, &node_sliced_norm_params(sliced, normalise))
+}
+
+/// Convert every slice of a SlicedDatumT to another pixel type with statistics already found,
+/// usually for the whole group the slices came from. A channel the statistics don't cover
+/// is normalised on its own. The masks are left as they are.
+///
+/// * `sliced` - the SlicedDatumT to convert.
+/// * `params` - the NormParams from node_datum_norm_params or node_sliced_norm_params.
+pub fn node_sliced_convert_with<S: VolumePixel, T: VolumePixel>(sliced: &SlicedDatumT<S>, params: &NormParams) -> SlicedDatumT<T> {
+    let normalise = params.normalise;
+
+    SlicedDatumT {
+        slices: sliced
+            .slices
+            .iter()
+            .map(|d| DatumT {
+                raw: convert_volume_with(&d.raw, normalise, params.raw),
+                mask: d.mask.clone(),
+                origin: d.origin.clone(),
+                extents: d.extents,
+                channels: d
+                    .channels
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, c)| ChannelT {
+                        name: c.name.clone(),
+                        volume: match params.channels.get(idx) {
+                            Some(channel_params) => convert_volume_with(&c.volume, normalise, *channel_params),
+                            None => convert_volume(&c.volume, normalise),
+                        },
+                    })
+                    .collect(),
+            })
+            .collect(),
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    #[test]
+    fn test_convert() {
+        let frames = [10u8, 30, 255].iter().map(|v| GrayImage::from_pixel(2, 2, Luma([*v]))).collect();
+        let raw = VolumeT {
+            volume: ImageVolume(frames),
+            extents: (1, 2, 2, 2),
+            origin: None,
+        };
+        let pixel = |v: &ImageVolume<f32>, idx: usize| v.0[idx].get_pixel(0, 0)[0];
+
+        // No normalisation keeps the values.
+        let plain: VolumeT<f32> = node_volume_convert(&raw, Normalise::None);
+        assert_eq!(plain.extents, (1, 2, 2, 2));
+        assert_eq!(pixel(&plain.volume, 1), 30.0);
+
+        let max: VolumeT<f32> = node_volume_convert(&raw, Normalise::Max);
+        assert_eq!(pixel(&max.volume, 2), 1.0);
+
+        let minmax: VolumeT<f32> = node_volume_convert(&raw, Normalise::MinMax);
+        assert_eq!(pixel(&minmax.volume, 0), 0.0);
+        assert_eq!(pixel(&minmax.volume, 2), 1.0);
+
+        let standard: VolumeT<f32> = node_volume_convert(&raw, Normalise::Standard);
+        let mean: f32 = (0..3).map(|i| pixel(&standard.volume, i)).sum::<f32>() / 3.0;
+        assert!(mean.abs() < 1e-5);
+
+        // Integer types are filled by max and minmax, and clamped otherwise.
+        let wide: VolumeT<u16> = node_volume_convert(&raw, Normalise::Max);
+        assert_eq!(wide.volume.0[2].get_pixel(0, 0)[0], 65535);
+        let same: VolumeT<u16> = node_volume_convert(&raw, Normalise::None);
+        assert_eq!(same.volume.0[0].get_pixel(0, 0)[0], 10);
+        let narrow: VolumeT = node_volume_convert(&node_volume_convert::<u8, f32>(&raw, Normalise::None), Normalise::MinMax);
+        assert_eq!(narrow.volume.0[2].get_pixel(0, 0)[0], 255);
+
+        // The mask of a datum is left alone.
+        let datum: DatumT<f32> = node_datum_convert(&DatumT::new(&raw, &raw), Normalise::Max);
+        assert_eq!(datum.mask.0[1].get_pixel(0, 0)[0], 30);
+        assert_eq!(datum.raw.0[2].get_pixel(0, 0)[0], 1.0);
+
+        // f32 volumes have no fixed top, so max uses their largest value.
+        let top: VolumeT<f32> = node_volume_convert(&plain, Normalise::Max);
+        assert_eq!(pixel(&top.volume, 2), 1.0);
+
+        assert_eq!("f32".parse::<PixelType>().unwrap(), PixelType::F32);
+        assert!("u32".parse::<PixelType>().is_err());
+        assert_eq!("minmax".parse::<Normalise>().unwrap().to_string(), "minmax");
+    }
+
+    #[test]
+    fn test_sliced_convert() {
+        // Two slices of one group with very different ranges share the group's statistics.
+        let slice = |low: u8, high: u8| {
+            let raw = VolumeT {
+                volume: ImageVolume(vec![GrayImage::from_pixel(2, 2, Luma([low])), GrayImage::from_pixel(2, 2, Luma([high]))]),
+                extents: (0, 0, 2, 2),
+                origin: None,
+            };
+            DatumT::new(&raw, &raw)
+        };
+        let sliced = SlicedDatumT { slices: vec![slice(10, 20), slice(20, 110)] };
+
+        let minmax: SlicedDatumT<f32> = node_sliced_convert(&sliced, Normalise::MinMax);
+        assert_eq!(minmax.slices[0].raw.0[0].get_pixel(0, 0)[0], 0.0);
+        assert_eq!(minmax.slices[0].raw.0[1].get_pixel(0, 0)[0], 0.1);
+        assert_eq!(minmax.slices[1].raw.0[0].get_pixel(0, 0)[0], 0.1);
+        assert_eq!(minmax.slices[1].raw.0[1].get_pixel(0, 0)[0], 1.0);
+
+        // The same value in both slices comes out the same.
+        let standard: SlicedDatumT<f32> = node_sliced_convert(&sliced, Normalise::Standard);
+        assert_eq!(standard.slices[0].raw.0[1].get_pixel(0, 0)[0], standard.slices[1].raw.0[0].get_pixel(0, 0)[0]);
+        assert_eq!(standard.slices[1].mask.0[1].get_pixel(0, 0)[0], 110);
+    }
+
+    #[test]
+    fn test_group_params() {
+        // A patch of a group is scaled by the group, not stretched to its own range.
+        let volume = |values: [u8; 2]| VolumeT {
+            volume: ImageVolume(values.iter().map(|v| GrayImage::from_pixel(2, 2, Luma([*v]))).collect()),
+            extents: (0, 0, 2, 2),
+            origin: None,
+        };
+        let group = DatumT::new(&volume([0, 200]), &volume([0, 0]));
+        let params = node_datum_norm_params(&group, Normalise::MinMax);
+        assert_eq!(params.raw, (0.0, 200.0));
+
+        let patch = SlicedDatumT { slices: vec![DatumT::new(&volume([50, 100]), &volume([0, 0]))] };
+        let scaled: SlicedDatumT<f32> = node_sliced_convert_with(&patch, &params);
+        assert_eq!(scaled.slices[0].raw.0[0].get_pixel(0, 0)[0], 0.25);
+        assert_eq!(scaled.slices[0].raw.0[1].get_pixel(0, 0)[0], 0.5);
+
+        // On its own the patch would fill the range.
+        let own: SlicedDatumT<f32> = node_sliced_convert(&patch, Normalise::MinMax);
+        assert_eq!(own.slices[0].raw.0[1].get_pixel(0, 0)[0], 1.0);
+    }
+}
@@ -0,0 +1,219 @@
+//! Node functions that rescale the range axis (y) of volumes, tracks and groups so every
+//! pixel row covers the same distance, whatever range the sonar was set to.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   nodes_range.rs - range normalisation nodes.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::bbs::FrameBoxRaw;
+use crate::image::{ImageSize, ImageVolume, VolumePixel};
+use crate::models::Images;
+use crate::ptypes::{GroupT, OriginT, TrackRawT, VolumeT};
+use image::imageops::{replace, resize, FilterType};
+use image::{ImageBuffer, Luma};
+
+/// The scale for the range axis of each image - how many new rows each original row becomes.
+/// The full height of an original image covers the range of the sonar at that time.
+///
+/// * `images` - the images, giving the range of each frame.
+/// * `img_height` - the height of the original images, before any crop.
+/// * `metres_per_pixel` - the distance each new row should cover.
+pub fn range_scales(images: &[Images], img_height: u32, metres_per_pixel: f32) -> Vec<f32> {
+    images
+        .iter()
+        .map(|i| (i.range as f32 / img_height as f32) / metres_per_pixel)
+        .collect()
+}
+
+/// The height of the rescaled frames - tall enough for the frame with the longest range.
+///
+/// * `scales` - the scale of each frame, from range_scales.
+/// * `height` - the height of the frames before rescaling.
+fn range_height(scales: &[f32], height: u32) -> u32 {
+    scales
+        .iter()
+        .map(|s| (height as f32 * s).round() as u32)
+        .max()
+        .unwrap_or(height)
+        .max(1)
+}
+
+/// Update an origin to match the rescaled range axis.
+fn range_origin(origin: &OriginT, scales: &[f32], metres_per_pixel: f32) -> OriginT {
+    let mut new_origin = origin.clone();
+    new_origin.img_size = ImageSize {
+        width: origin.img_size.width,
+        height: range_height(scales, origin.img_size.height),
+    };
+    new_origin.crop_size = ImageSize {
+        width: origin.crop_size.width,
+        height: range_height(scales, origin.crop_size.height),
+    };
+    new_origin.metres_per_pixel = Some(metres_per_pixel);
+    new_origin
+}
+
+/// Rescale the range axis of each frame in a volume to a fixed metres per pixel. Frames are
+/// rescaled separately, so groups where the range changes part way through line up. Frames
+/// with a shorter range are padded with zeros at the far end.
+///
+/// * `volume` - the VolumeT to rescale, with one frame per image.
+/// * `images` - the images the volume was made from.
+/// * `metres_per_pixel` - the distance each row should cover.
+/// * `filter` - the filter to use. Nearest for masks.
+pub fn node_volume_range_normalise<T: VolumePixel>(
+    volume: &VolumeT<T>,
+    images: &[Images],
+    metres_per_pixel: f32,
+    filter: FilterType,
+) -> VolumeT<T> {
+    assert!(volume.volume.0.len() == images.len());
+
+    // Without an origin we assume the frames are the full, uncropped, images.
+    let img_height = match &volume.origin {
+        Some(o) => o.img_size.height,
+        None => volume.volume.0.first().map_or(1, |f| f.height()),
+    };
+
+    let scales = range_scales(images, img_height, metres_per_pixel);
+    let mut new_vol = ImageVolume(vec![]);
+
+    for (frame, scale) in volume.volume.0.iter().zip(scales.iter()) {
+        let height = range_height(&scales, frame.height());
+        let nh = ((frame.height() as f32 * scale).round() as u32).max(1);
+        let mut nframe = ImageBuffer::from_pixel(frame.width(), height, Luma([T::DEFAULT_MIN_VALUE]));
+        replace(&mut nframe, &resize(frame, frame.width(), nh, filter), 0, 0);
+        new_vol.0.push(nframe);
+    }
+
+    let origin = volume.origin.as_ref().map(|o| range_origin(o, &scales, metres_per_pixel));
+    let (ex, ey, ew, eh) = volume.extents;
+
+    VolumeT {
+        volume: new_vol,
+        extents: (ex, ey, ew, range_height(&scales, eh)),
+        origin,
+    }
+}
+
+/// Rescale the range axis of a TrackRawT to a fixed metres per pixel, frame by frame, to
+/// match node_volume_range_normalise. Boxes on frames past the end of the images have no
+/// range to scale by, so are dropped.
+///
+/// * `track` - the TrackRawT to rescale, with frames indexing into images.
+/// * `images` - the images of the group.
+/// * `metres_per_pixel` - the distance each row should cover.
+pub fn node_trackraw_range_normalise(track: &TrackRawT, images: &[Images], metres_per_pixel: f32) -> TrackRawT {
+    assert!(track.boxes.is_empty() || !images.is_empty());
+
+    // A track without an origin has no image size to scale from.
+    let img_height = match &track.origin {
+        Some(o) => o.img_size.height,
+        None => return TrackRawT::new(track.boxes.clone(), None),
+    };
+
+    let scales = range_scales(images, img_height, metres_per_pixel);
+
+    let boxes = track
+        .boxes
+        .iter()
+        .filter_map(|b| {
+            let scale = scales.get(b.frame as usize)?;
+            let mut bbox = b.bbox;
+            bbox.y_min = (bbox.y_min as f32 * scale).round() as i32;
+            bbox.y_max = (bbox.y_max as f32 * scale).round() as i32;
+            Some(FrameBoxRaw { frame: b.frame, bbox })
+        })
+        .collect();
+
+    TrackRawT {
+        boxes,
+        origin: track.origin.as_ref().map(|o| range_origin(o, &scales, metres_per_pixel)),
+    }
+}
+
+/// Update the origin of a GroupT to the sizes a range normalised volume will have, so the
+/// masks built from the group line up with the rescaled data.
+///
+/// * `group` - the GroupT.
+/// * `metres_per_pixel` - the distance each row should cover.
+pub fn node_group_range_normalise(group: &GroupT, metres_per_pixel: f32) -> GroupT {
+    let scales = range_scales(&group.images, group.origin.img_size.height, metres_per_pixel);
+
+    GroupT {
+        origin: range_origin(&group.origin, &scales, metres_per_pixel),
+        images: group.images.clone(),
+        points: group.points.clone(),
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbs::RawBox;
+    use crate::test_util::test_origin;
+    use image::GrayImage;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_range_normalise() {
+        // The range doubles half way through the group.
+        let images: Vec<Images> = [50.0, 100.0]
+            .iter()
+            .map(|r| Images {
+                filename: String::from("test.fits"),
+                uid: Uuid::nil(),
+                hastrack: true,
+                glf: String::from("test.glf"),
+                time: Utc::now(),
+                sonarid: 854,
+                range: *r,
+            })
+            .collect();
+
+        let volume = VolumeT {
+            volume: ImageVolume(vec![GrayImage::from_pixel(4, 100, Luma([9])); 2]),
+            extents: (0, 0, 4, 100),
+            origin: None,
+        };
+
+        // 100 rows over 50m is 0.5m per row, so 1m per row halves the first frame.
+        let scaled = node_volume_range_normalise(&volume, &images, 1.0, FilterType::Nearest);
+        assert_eq!(scaled.volume.0[0].dimensions(), (4, 100));
+        assert_eq!(scaled.volume.0[0].get_pixel(0, 49)[0], 9);
+        assert_eq!(scaled.volume.0[0].get_pixel(0, 50)[0], 0);
+        assert_eq!(scaled.volume.0[1].get_pixel(0, 99)[0], 9);
+        assert_eq!(scaled.extents.3, 100);
+
+        let track = TrackRawT {
+            boxes: vec![
+                FrameBoxRaw { frame: 0, bbox: RawBox { x_min: 0, y_min: 20, x_max: 2, y_max: 40 } },
+                FrameBoxRaw { frame: 1, bbox: RawBox { x_min: 0, y_min: 20, x_max: 2, y_max: 40 } },
+                FrameBoxRaw { frame: 2, bbox: RawBox { x_min: 0, y_min: 20, x_max: 2, y_max: 40 } },
+            ],
+            origin: Some(test_origin()),
+        };
+
+        // At 0.5m per row the first frame stays put and the second doubles.
+        let scaled_track = node_trackraw_range_normalise(&track, &images, 0.5);
+        assert_eq!(scaled_track.boxes[0].bbox.y_min, 20);
+        assert_eq!(scaled_track.boxes[1].bbox.y_max, 80);
+        // The box past the last image is dropped rather than indexing off the end.
+        assert_eq!(scaled_track.boxes.len(), 2);
+
+        let origin = scaled_track.origin.unwrap();
+        assert_eq!(origin.crop_size.height, 160);
+        assert_eq!(origin.metres_per_pixel, Some(0.5));
+    }
+}
@@ -0,0 +1,305 @@
+//! Node functions that resample volumes, tracks and groups onto a fixed time base, using
+//! the time each image was recorded rather than its position in the group.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   nodes_time.rs - temporal resampling nodes.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::bbs::{FrameBoxRaw, RawBox};
+use crate::image::{ImageVolume, VolumePixel};
+use crate::models::Images;
+use crate::ptypes::{GroupT, TrackRawT, VolumeT};
+use image::{ImageBuffer, Luma};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// How to move from the recorded ping rate to a fixed time base.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resample {
+    /// A fixed rate in Hz, taking the nearest recorded frame.
+    Nearest(f32),
+    /// A fixed rate in Hz, blending the two recorded frames either side.
+    Linear(f32),
+    /// Keep every nth recorded frame, ignoring the times.
+    Stride(usize),
+}
+
+impl FromStr for Resample {
+    type Err = String;
+
+    /// Parse a time base from the command line - nearest:<hz>, linear:<hz> or stride:<n>.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s.split_once(':').ok_or(format!("Resample {} needs a value, such as nearest:10", s))?;
+
+        match kind {
+            "nearest" | "linear" => {
+                let hz: f32 = value.parse().map_err(|_| format!("Bad rate in {}", s))?;
+
+                if hz <= 0.0 {
+                    return Err(format!("Rate must be above 0 in {}", s));
+                }
+
+                if kind == "nearest" {
+                    Ok(Resample::Nearest(hz))
+                } else {
+                    Ok(Resample::Linear(hz))
+                }
+            }
+            "stride" => match value.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(Resample::Stride(n)),
+                _ => Err(format!("Bad stride in {}", s)),
+            },
+            _ => Err(format!("Unknown resample method {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Resample {
+    /// The time base, as recorded in the dataset metadata. Parses back with from_str.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resample::Nearest(hz) => write!(f, "nearest:{}", hz),
+            Resample::Linear(hz) => write!(f, "linear:{}", hz),
+            Resample::Stride(n) => write!(f, "stride:{}", n),
+        }
+    }
+}
+
+/// One frame of the new time base - the recorded frames before and after it, and how far
+/// between the two it falls (0 is on the first, 1 on the second).
+type Sample = (usize, usize, f32);
+
+/// The time of each image in seconds, from the first image.
+///
+/// * `images` - the recorded images, in time order.
+pub fn image_seconds(images: &[Images]) -> Vec<f64> {
+    match images.first() {
+        Some(first) => images
+            .iter()
+            .map(|i| (i.time - first.time).num_microseconds().unwrap_or(0) as f64 / 1e6)
+            .collect(),
+        None => vec![],
+    }
+}
+
+/// Work out where each frame of the new time base falls amongst the recorded frames.
+///
+/// * `images` - the recorded images, in time order.
+/// * `resample` - the new time base.
+pub fn resample_plan(images: &[Images], resample: &Resample) -> Vec<Sample> {
+    if images.is_empty() {
+        return vec![];
+    }
+
+    let hz = match resample {
+        Resample::Stride(n) => return (0..images.len()).step_by(*n).map(|i| (i, i, 0.0)).collect(),
+        Resample::Nearest(hz) | Resample::Linear(hz) => *hz as f64,
+    };
+
+    let times = image_seconds(images);
+    let end = *times.last().unwrap();
+    let mut plan: Vec<Sample> = vec![];
+    let mut idx: usize = 0;
+    let mut step: usize = 0;
+
+    loop {
+        let t = step as f64 / hz;
+
+        if t > end {
+            break;
+        }
+
+        while idx + 1 < times.len() && times[idx + 1] <= t {
+            idx += 1;
+        }
+
+        if idx + 1 >= times.len() {
+            plan.push((idx, idx, 0.0));
+        } else {
+            let gap = times[idx + 1] - times[idx];
+            let w = if gap > 0.0 { ((t - times[idx]) / gap) as f32 } else { 0.0 };
+
+            match resample {
+                Resample::Nearest(_) if w >= 0.5 => plan.push((idx + 1, idx + 1, 0.0)),
+                Resample::Nearest(_) => plan.push((idx, idx, 0.0)),
+                _ => plan.push((idx, idx + 1, w)),
+            }
+        }
+
+        step += 1;
+    }
+
+    plan
+}
+
+/// The closest recorded frame to a sample.
+fn nearest(sample: &Sample) -> usize {
+    if sample.2 < 0.5 {
+        sample.0
+    } else {
+        sample.1
+    }
+}
+
+/// Blend two frames together, weighting the second by w.
+fn blend<T: VolumePixel>(a: &ImageBuffer<Luma<T>, Vec<T>>, b: &ImageBuffer<Luma<T>, Vec<T>>, w: f32) -> ImageBuffer<Luma<T>, Vec<T>> {
+    let mut frame = a.clone();
+
+    for (p, q) in frame.pixels_mut().zip(b.pixels()) {
+        p.0[0] = T::from_f32(p.0[0].into() * (1.0 - w) + q.0[0].into() * w);
+    }
+
+    frame
+}
+
+/// Resample a VolumeT onto a new time base. The volume must have one frame per image.
+///
+/// * `volume` - the VolumeT to resample.
+/// * `images` - the images the volume was made from, giving the time of each frame.
+/// * `resample` - the new time base.
+pub fn node_volume_resample<T: VolumePixel>(volume: &VolumeT<T>, images: &[Images], resample: &Resample) -> VolumeT<T> {
+    assert!(volume.volume.0.len() == images.len());
+    let mut new_vol = ImageVolume(vec![]);
+
+    for sample in resample_plan(images, resample) {
+        let (i0, i1, w) = sample;
+
+        if i0 == i1 || w <= 0.0 {
+            new_vol.0.push(volume.volume.0[i0].clone());
+        } else {
+            new_vol.0.push(blend(&volume.volume.0[i0], &volume.volume.0[i1], w));
+        }
+    }
+
+    let mut origin = volume.origin.clone();
+
+    if let Some(o) = origin.as_mut() {
+        o.time_base = Some(*resample);
+    }
+
+    VolumeT {
+        volume: new_vol,
+        extents: volume.extents,
+        origin,
+    }
+}
+
+/// Resample a TrackRawT onto a new time base. Linear resampling blends the boxes either side
+/// when both exist, otherwise the nearest box is used.
+///
+/// * `track` - the TrackRawT to resample, with frames indexing into images.
+/// * `images` - the images of the group, giving the time of each frame.
+/// * `resample` - the new time base.
+pub fn node_trackraw_resample(track: &TrackRawT, images: &[Images], resample: &Resample) -> TrackRawT {
+    let by_frame: HashMap<u32, RawBox> = track.boxes.iter().map(|b| (b.frame, b.bbox)).collect();
+    let mut boxes: Vec<FrameBoxRaw> = vec![];
+
+    for (fidx, sample) in resample_plan(images, resample).iter().enumerate() {
+        let (i0, i1, w) = *sample;
+        let before = by_frame.get(&(i0 as u32));
+        let after = by_frame.get(&(i1 as u32));
+
+        let bbox = match (before, after) {
+            (Some(a), Some(b)) if i0 != i1 && w > 0.0 => {
+                let lerp = |p: i32, q: i32| (p as f32 * (1.0 - w) + q as f32 * w).round() as i32;
+                Some(RawBox {
+                    x_min: lerp(a.x_min, b.x_min),
+                    y_min: lerp(a.y_min, b.y_min),
+                    x_max: lerp(a.x_max, b.x_max),
+                    y_max: lerp(a.y_max, b.y_max),
+                })
+            }
+            _ => by_frame.get(&(nearest(sample) as u32)).copied(),
+        };
+
+        if let Some(bbox) = bbox {
+            boxes.push(FrameBoxRaw { frame: fidx as u32, bbox });
+        }
+    }
+
+    let mut origin = track.origin.clone();
+
+    if let Some(o) = origin.as_mut() {
+        o.time_base = Some(*resample);
+    }
+
+    TrackRawT { boxes, origin }
+}
+
+/// Resample the images and points of a GroupT onto a new time base, taking the nearest
+/// recorded image for each frame. Used so the nodes that build volumes from a group
+/// (such as the masks) have the same number of frames as the resampled volume.
+///
+/// * `group` - the GroupT to resample.
+/// * `resample` - the new time base.
+pub fn node_group_resample(group: &GroupT, resample: &Resample) -> GroupT {
+    let plan = resample_plan(&group.images, resample);
+    let mut origin = group.origin.clone();
+    origin.time_base = Some(*resample);
+
+    GroupT {
+        origin,
+        images: plan.iter().map(|s| group.images[nearest(s)].clone()).collect(),
+        points: plan.iter().map(|s| group.points[nearest(s)].clone()).collect(),
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_images;
+    use image::GrayImage;
+
+    #[test]
+    fn test_resample() {
+        // Pings speed up half way through.
+        let images = test_images(&[0, 200, 400, 500, 600]);
+        assert_eq!("linear:10".parse::<Resample>(), Ok(Resample::Linear(10.0)));
+        assert!("stride:0".parse::<Resample>().is_err());
+        assert_eq!(Resample::Stride(2).to_string(), "stride:2");
+
+        let plan = resample_plan(&images, &Resample::Linear(10.0));
+        assert_eq!(plan.len(), 7);
+        assert_eq!(plan[1], (0, 1, 0.5));
+        assert_eq!(plan[5], (3, 4, 0.0));
+
+        let nearest = resample_plan(&images, &Resample::Nearest(5.0));
+        assert_eq!(nearest, vec![(0, 0, 0.0), (1, 1, 0.0), (2, 2, 0.0), (4, 4, 0.0)]);
+        assert_eq!(resample_plan(&images, &Resample::Stride(2)).len(), 3);
+
+        let frames = [0u8, 100, 200, 250, 150].iter().map(|v| GrayImage::from_pixel(2, 2, Luma([*v]))).collect();
+        let volume = VolumeT {
+            volume: ImageVolume(frames),
+            extents: (0, 0, 2, 2),
+            origin: None,
+        };
+
+        let resampled = node_volume_resample(&volume, &images, &Resample::Linear(10.0));
+        assert_eq!(resampled.volume.0.len(), 7);
+        assert_eq!(resampled.volume.0[1].get_pixel(0, 0)[0], 50);
+
+        let track = TrackRawT {
+            boxes: vec![
+                FrameBoxRaw { frame: 0, bbox: RawBox { x_min: 0, y_min: 0, x_max: 10, y_max: 10 } },
+                FrameBoxRaw { frame: 1, bbox: RawBox { x_min: 10, y_min: 10, x_max: 20, y_max: 20 } },
+            ],
+            origin: None,
+        };
+
+        let track_rs = node_trackraw_resample(&track, &images, &Resample::Linear(10.0));
+        assert_eq!(track_rs.boxes.len(), 3);
+        assert_eq!(track_rs.boxes[1].bbox.x_min, 5);
+        assert_eq!(track_rs.boxes[2].frame, 2);
+    }
+}
@@ -17,12 +17,39 @@ extern crate nalgebra as na;
 
 use crate::bbs::{points_to_bb, FrameBoxRaw, RawCoords};
 use crate::image::ImageSize;
-use crate::track::{interpolate_track_raw, smooth_track};
+use crate::kalman::{kalman_smooth_track, KalmanOps};
+use crate::models::Images;
+use crate::nodes_time::image_seconds;
+use crate::track::{interpolate_track_raw, interpolate_track_timed, smooth_track, split_track_gaps, Interpolation};
 use crate::{
     files::read_bearing_table,
     ptypes::{GroupT, TrackRawT},
     track::overlap_track_raw,
 };
+use std::str::FromStr;
+
+
+/// What to do with a track that has a gap longer than the maximum allowed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GapPolicy {
+    /// Split the track at the gap and interpolate each piece on its own.
+    Split,
+    /// Reject the whole group.
+    Reject,
+}
+
+impl FromStr for GapPolicy {
+    type Err = String;
+
+    /// Parse a gap policy from the command line - split or reject.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "split" => Ok(GapPolicy::Split),
+            "reject" => Ok(GapPolicy::Reject),
+            _ => Err(format!("Unknown gap policy {}", s)),
+        }
+    }
+}
 
 
 /// Convert a GroupT object to a TrackRawT object
@@ -72,6 +99,57 @@ pub fn node_trackraw_interpolate(track: &TrackRawT) -> TrackRawT {
 }
 
 
+/// Interpolate a TrackRawT object over the times the images were recorded. Gaps longer than
+/// max_gap are never bridged - the track is either split into pieces or rejected. Returns
+/// None if the track is rejected, otherwise the pieces in frame order.
+///
+/// * `track` - the TrackRawT object to interpolate, with frames indexing into images.
+/// * `images` - the images of the group, giving the time of each frame.
+/// * `method` - the interpolation to use.
+/// * `max_gap` - the longest gap to bridge, in seconds. None bridges any gap.
+/// * `policy` - what to do with longer gaps.
+pub fn node_trackraw_interpolate_timed(
+    track: &TrackRawT,
+    images: &[Images],
+    method: Interpolation,
+    max_gap: Option<f32>,
+    policy: GapPolicy,
+) -> Option<Vec<TrackRawT>> {
+    let times: Vec<f32> = image_seconds(images).iter().map(|t| *t as f32).collect();
+    let pieces = match max_gap {
+        Some(gap) => split_track_gaps(&track.boxes, &times, gap),
+        None => vec![track.boxes.clone()],
+    };
+
+    if pieces.len() > 1 && policy == GapPolicy::Reject {
+        return None;
+    }
+
+    Some(
+        pieces
+            .iter()
+            .map(|piece| match &track.origin {
+                Some(origin) => TrackRawT::new(
+                    interpolate_track_timed(piece, &times, &origin.img_size, method),
+                    Some(origin.clone()),
+                ),
+                None => TrackRawT::new(piece.clone(), None),
+            })
+            .collect(),
+    )
+}
+
+
+/// Join the pieces of a split track back into a single TrackRawT, leaving the gaps empty.
+///
+/// * `tracks` - the pieces, all from the same group.
+pub fn node_trackraw_merge(tracks: &[TrackRawT]) -> TrackRawT {
+    let boxes: Vec<FrameBoxRaw> = tracks.iter().flat_map(|t| t.boxes.clone()).collect();
+    let origin = tracks.first().and_then(|t| t.origin.clone());
+    TrackRawT::new(boxes, origin)
+}
+
+
 /// Make sure all points in the track overlap per frame.
 ///
 /// * `track` - the TrackRawT object to modify.
@@ -109,4 +187,21 @@ pub fn node_track_kalman(track: &TrackRawT) -> TrackRawT {
         boxes: track.boxes.clone(),
         origin: torigin
     }
-}
\ No newline at end of file
+}
+
+
+/// Smooth this track with a tunable Kalman filter, using the time between images as the time
+/// step. An alternative to node_track_kalman that can also run a backward smoothing pass.
+///
+/// * `track` - the TrackRawT object to smooth, with frames indexing into images.
+/// * `images` - the images of the group, giving the time of each frame.
+/// * `ops` - the noise and output options.
+pub fn node_track_smooth(track: &TrackRawT, images: &[Images], ops: &KalmanOps) -> TrackRawT {
+    match &track.origin {
+        Some(origin) => {
+            let times: Vec<f32> = image_seconds(images).iter().map(|t| *t as f32).collect();
+            TrackRawT::new(kalman_smooth_track(&track.boxes, &times, &origin.img_size, ops), Some(origin.clone()))
+        }
+        None => TrackRawT::new(track.boxes.clone(), None),
+    }
+}
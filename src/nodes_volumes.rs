@@ -16,20 +16,23 @@
 extern crate nalgebra as na;
 
 use crate::bbs::FrameBoxRaw;
+use crate::cache::FrameCache;
+use crate::frame_source::FrameSource;
+use crate::nodes_augment::augment_rng;
 
 use crate::ptypes::Dimensions;
 use crate::{
-    image::read_fits,
-    image::ImageVolume,
-    ptypes::{DatumT, GroupT, TrackRawT, VolumeT},
+    image::{ImageVolume, VolumePixel},
+    ptypes::{DatumT, GroupT, OriginT, TrackRawT, VolumeT},
 };
-use image::imageops::crop;
+use image::imageops::{crop, crop_imm, replace};
 use image::imageops::resize;
 use image::imageops::FilterType;
 use image::{GrayImage, ImageBuffer, Luma};
 
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
 use rand::prelude::*;
 
 
@@ -38,7 +41,7 @@ use rand::prelude::*;
 /// 
 /// * `vol` - the volume to trim down.
 /// * `track` - the TrackRawT to trim the volume to.
-pub fn node_volume_trim(vol: &VolumeT, track: &TrackRawT) -> (VolumeT, TrackRawT) {
+pub fn node_volume_trim<T: VolumePixel>(vol: &VolumeT<T>, track: &TrackRawT) -> (VolumeT<T>, TrackRawT) {
     let mut first_frame = u32::max_value();
     let mut last_frame = 0;
 
@@ -52,7 +55,7 @@ pub fn node_volume_trim(vol: &VolumeT, track: &TrackRawT) -> (VolumeT, TrackRawT
         }
     }
 
-    let mut new_vol: ImageVolume = ImageVolume {
+    let mut new_vol: ImageVolume<T> = ImageVolume {
         0: vec![]
     };
     let mut new_boxes: Vec<FrameBoxRaw> = vec![];
@@ -82,42 +85,169 @@ pub fn node_volume_trim(vol: &VolumeT, track: &TrackRawT) -> (VolumeT, TrackRawT
 }
 
 
-/// Convert a GroupT to an image VolumeT
-/// 
-/// * `group` - the GroupT to convert.
-/// * `image_path_cache` - the cache of FITS paths.
-pub fn node_group_to_volume(
-    group: &GroupT,
-    image_path_cache: &HashMap<String, PathBuf>,
-) -> Option<VolumeT> {
-    //! Given a GroupT, get all the images and output a VolumeT
+/// What to do with frames that don't match the crop size of the group. Sonar images of the
+/// same sonar are occasionally a few pixel rows short.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FramePolicy {
+    /// Pad short frames with zeros and crop long ones. Each frame keeps all of its own rows.
+    Pad,
+    /// Crop every frame to the region all the frames in the group share, padding the rest with
+    /// zeros, so the valid region doesn't change over time.
+    Crop,
+    /// Crop every frame to the crop size, resizing any frame that is short of it to fit.
+    Resample,
+    /// Reject the whole group if any frame is shorter than the crop height.
+    Reject,
+}
+
+impl FromStr for FramePolicy {
+    type Err = String;
+
+    /// Parse a policy from the command line - pad, crop, resample or reject.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pad" => Ok(FramePolicy::Pad),
+            "crop" => Ok(FramePolicy::Crop),
+            "resample" => Ok(FramePolicy::Resample),
+            "reject" => Ok(FramePolicy::Reject),
+            _ => Err(format!("Unknown frame policy {}", s)),
+        }
+    }
+}
+
+/// Fit a single frame to the crop size, returning the fitted frame and its ignore mask,
+/// which is 1 wherever there was no data in the original frame.
+///
+/// * `frame` - the frame as read from the FITS file.
+/// * `width` - the width to fit to.
+/// * `height` - the height to fit to.
+/// * `valid` - the most of the frame to keep (width, height). Ignored when resampling.
+/// * `policy` - how to fit the frame.
+fn fit_frame<T: VolumePixel>(
+    frame: &ImageBuffer<Luma<T>, Vec<T>>,
+    width: u32,
+    height: u32,
+    valid: (u32, u32),
+    policy: FramePolicy,
+) -> (ImageBuffer<Luma<T>, Vec<T>>, GrayImage) {
+    if policy == FramePolicy::Resample {
+        // Crop first, so only frames short of the crop size are stretched to fill it.
+        let cropped = crop_imm(frame, 0, 0, width.min(frame.width()), height.min(frame.height())).to_image();
+        let fitted = if cropped.dimensions() == (width, height) {
+            cropped
+        } else {
+            resize(&cropped, width, height, FilterType::Triangle)
+        };
+
+        return (fitted, GrayImage::from_pixel(width, height, Luma([0])));
+    }
+
+    let vw = valid.0.min(frame.width()).min(width);
+    let vh = valid.1.min(frame.height()).min(height);
+    let mut fitted = ImageBuffer::from_pixel(width, height, Luma([T::from_f32(0.0)]));
+    let mut ignore = GrayImage::from_pixel(width, height, Luma([1]));
+    replace(&mut fitted, &crop_imm(frame, 0, 0, vw, vh).to_image(), 0, 0);
+    replace(&mut ignore, &GrayImage::from_pixel(vw, vh, Luma([0])), 0, 0);
+    (fitted, ignore)
+}
+
+/// Fit the frames of a group to the crop size with the given policy, giving the image
+/// VolumeT and the ignore mask VolumeT.
+///
+/// * `frames` - the frames of the group, as read.
+/// * `origin` - the origin of the group.
+/// * `policy` - what to do with frames that don't match the crop size.
+fn frames_to_volumes<T: VolumePixel, F: Deref<Target = ImageBuffer<Luma<T>, Vec<T>>>>(
+    frames: &[F],
+    origin: &OriginT,
+    policy: FramePolicy,
+) -> (VolumeT<T>, VolumeT) {
+    let width = origin.crop_size.width; // Origin img_sizes are already cropped!
+    let height = origin.crop_size.height;
+
+    let valid = match policy {
+        FramePolicy::Crop => (
+            frames.iter().map(|f| f.width()).min().unwrap_or(width),
+            frames.iter().map(|f| f.height()).min().unwrap_or(height),
+        ),
+        _ => (width, height),
+    };
+
     let mut final_img = ImageVolume(vec![]);
-    let num_frames = group.images.len();
-    let bp: Luma<u8> = Luma([0]);
+    let mut final_ignore = ImageVolume(vec![]);
 
-    for _i in 0..num_frames {
-        final_img.0.push(GrayImage::from_pixel(
-            group.origin.crop_size.width as u32, // Origin img_sizes are already cropped!
-            group.origin.crop_size.height as u32,
-            bp,
-        ));
+    for frame in frames {
+        let (fitted, ignore) = fit_frame(frame, width, height, valid, policy);
+        final_img.0.push(fitted);
+        final_ignore.0.push(ignore);
     }
 
-    for _i in 0..num_frames {
-        let image = &group.images[_i as usize];
-        let image_path = image_path_cache.get(&image.filename).unwrap();
-        let mut img_data: ImageBuffer<Luma<u8>, Vec<u8>> = read_fits(&image_path).unwrap();
+    (
+        VolumeT::new(final_img, Option::Some(origin.clone())),
+        VolumeT::new(final_ignore, Option::Some(origin.clone())),
+    )
+}
+
+/// Convert a GroupT to an image VolumeT and an ignore mask VolumeT, fitting each frame to
+/// the crop size with the given policy. The ignore mask is 1 wherever a frame was padded.
+/// Returns None if the policy rejects the group or a frame can't be read.
+///
+/// * `group` - the GroupT to convert.
+/// * `frame_source` - decodes the frame for each image, from the FITS files or the GLFs.
+/// * `frame_cache` - the cache of decoded frames.
+/// * `policy` - what to do with frames that don't match the crop size.
+pub fn node_group_to_volume_sized(
+    group: &GroupT,
+    frame_source: &dyn FrameSource,
+    frame_cache: &FrameCache,
+    policy: FramePolicy,
+) -> Option<(VolumeT, VolumeT)> {
+    node_group_to_volume_as(group, frame_source, frame_cache, policy)
+}
+
+/// Convert a GroupT to an image VolumeT of any pixel type and an ignore mask VolumeT, as
+/// node_group_to_volume_sized does. Each frame is converted from the bit depth it was
+/// recorded with, applying BZERO and BSCALE, so 16 bit and float sonar data keeps its range.
+///
+/// * `group` - the GroupT to convert.
+/// * `frame_source` - decodes the frame for each image, from the FITS files or the GLFs.
+/// * `frame_cache` - the cache of decoded frames, of the same pixel type.
+/// * `policy` - what to do with frames that don't match the crop size.
+pub fn node_group_to_volume_as<T: VolumePixel>(
+    group: &GroupT,
+    frame_source: &dyn FrameSource,
+    frame_cache: &FrameCache<T>,
+    policy: FramePolicy,
+) -> Option<(VolumeT<T>, VolumeT)> {
+    let width = group.origin.crop_size.width; // Origin img_sizes are already cropped!
+    let height = group.origin.crop_size.height;
+    let mut frames: Vec<Arc<ImageBuffer<Luma<T>, Vec<T>>>> = vec![];
+
+    for image in &group.images {
+        let img_data = frame_cache.frame_from(frame_source, image, Some((width, height)))?;
 
         // Crop to height to remove all the variability in the images. Reject if any are too small
-        if img_data.height() < group.origin.crop_size.height {
-            return None
+        if policy == FramePolicy::Reject && img_data.height() < height {
+            return None;
         }
 
-        img_data = crop(&mut img_data, 0, 0, group.origin.crop_size.width, group.origin.crop_size.height).to_image();
-        final_img.0[_i] = img_data;
+        frames.push(img_data);
     }
 
-    Some(VolumeT::new(final_img, Option::Some(group.origin.clone())))
+    Some(frames_to_volumes(&frames, &group.origin, policy))
+}
+
+/// Convert a GroupT to an image VolumeT, rejecting the group if any frame is shorter than
+/// the crop height.
+/// 
+/// * `group` - the GroupT to convert.
+/// * `frame_source` - decodes the frame for each image, from the FITS files or the GLFs.
+pub fn node_group_to_volume(
+    group: &GroupT,
+    frame_source: &dyn FrameSource,
+) -> Option<VolumeT> {
+    //! Given a GroupT, get all the images and output a VolumeT
+    node_group_to_volume_sized(group, frame_source, &FrameCache::new(0, None), FramePolicy::Reject).map(|(volume, _)| volume)
 }
 
 
@@ -126,7 +256,7 @@ pub fn node_group_to_volume(
 /// * `volume` - the VolumeT to resize.
 /// * `width` - the width to resize to. Height is calculate so the ratio is maintained.
 /// * `filter` - which filter to use (Lanczos3 or similar).
-pub fn node_volume_resize(volume: &VolumeT, width: u32, filter: FilterType) -> VolumeT {
+pub fn node_volume_resize<T: VolumePixel>(volume: &VolumeT<T>, width: u32, filter: FilterType) -> VolumeT<T> {
     // Resize, starting with the extents this volume goes over.
     let ratio = volume.extents.2 as f32 / width as f32;
     let nx = (volume.extents.0 as f32 * ratio) as u32;
@@ -159,7 +289,7 @@ pub fn node_volume_resize(volume: &VolumeT, width: u32, filter: FilterType) -> V
 /// * `y` - starting y position for the crop.
 /// * `width` - the width to crop to.
 /// * `height` - the height to crop to.
-pub fn node_volume_crop(volume: &VolumeT, x: u32, y: u32, width: u32, height: u32) -> VolumeT {
+pub fn node_volume_crop<T: VolumePixel>(volume: &VolumeT<T>, x: u32, y: u32, width: u32, height: u32) -> VolumeT<T> {
     // Extend the extents as the extents must always reflect the origin.
     // So a crop of a crop still has extents relative to the original image.
     let nx = volume.extents.0 + x;
@@ -185,19 +315,28 @@ pub fn node_volume_crop(volume: &VolumeT, x: u32, y: u32, width: u32, height: u3
 /// 
 /// * `volume` - the VolumeT to crop.
 /// * `sector_size` - the size of the sectors we want (e.g 64 pixels).
-pub fn node_volume_crop_sector(volume: &VolumeT, sector_size: u32) -> VolumeT {
+pub fn node_volume_crop_sector<T: VolumePixel>(volume: &VolumeT<T>, sector_size: u32) -> VolumeT<T> {
+    node_volume_crop_sectors(volume, sector_size, sector_size)
+}
+
+/// Crop a volume in width and height to whole sectors, where the sectors need not be square.
+/// 
+/// * `volume` - the VolumeT to crop.
+/// * `sector_width` - the width of the sectors, in bearing (e.g 32 pixels).
+/// * `sector_height` - the height of the sectors, in range (e.g 64 pixels).
+pub fn node_volume_crop_sectors<T: VolumePixel>(volume: &VolumeT<T>, sector_width: u32, sector_height: u32) -> VolumeT<T> {
     // Crop to the nearest power of two.
     let nx = volume.extents.0;
     let ny = volume.extents.1;
 
     let mut width = 0;
-    while width + sector_size <= volume.width() as u32 {
-        width = width + sector_size;
+    while width + sector_width <= volume.width() as u32 {
+        width = width + sector_width;
     } 
 
     let mut height = 0;
-    while height + sector_size <= volume.height() as u32 {
-        height = height + sector_size;
+    while height + sector_height <= volume.height() as u32 {
+        height = height + sector_height;
     }
     
     let nw = width as u32;
@@ -269,77 +408,181 @@ pub fn node_volume_split(volume: &VolumeT) -> Vec<VolumeT> {
 }
 
 
-/// Split a volume into smaller, overlapping volumes with random placement.
-/// 
-/// * `volume_base` - the Image VolumeT to split.
-/// * `volume_mask` - the corresponding Track/ Mask VolumeT to split.
-/// * `split_size` - The dimension of these square volumes.
-pub fn node_volume_split_random(volume_base: &VolumeT, volume_mask: &VolumeT, split_size: i32) -> Vec<DatumT> {
-    // Split a volume into smaller volumes, but with a fixed size, randomly.
-    // We choose via a poisson sampling.
-    let mut new_vols: Vec<DatumT> = vec![];
-    
-    // Between 0 and 1
-    let bw = volume_base.volume.0[0].width() as i32 - split_size;
-    let bh = volume_base.volume.0[0].height() as i32 - split_size;
-
-    let mut rng = rand::thread_rng();
-   
-    let mut pp: Vec<[f64; 2]> = vec![];
-
-    for _ in 0..128 {
-        let x: f64 = rng.gen();
-        let y: f64 = rng.gen();
-        pp.push([x, y]);
-    }
+/// The options for the balanced patch sampler.
+#[derive(Clone)]
+pub struct PatchOps {
+    /// How many patches to cut from each datum. 0 means no patches.
+    pub count: usize,
+    /// The width and height of the square patches, in mask pixels.
+    pub size: u32,
+    /// The fraction of patches centred on a mask pixel. The rest are centred on the background.
+    pub positive_ratio: f32,
+    /// The smallest fraction of a positive patch that must be covered by the mask.
+    pub min_mask_fraction: f32,
+    /// The smallest distance between the centres of any two patches, in mask pixels.
+    pub min_spacing: f32,
+    /// The seed for the random number generator. Combined with the group huid so each datum is repeatable.
+    pub seed: u64,
+}
 
-    for p in pp {
-        let mut nx: i32 = (p[0] * bw as f64) as i32;
-        let mut ny: i32 = (p[1] * bh as f64) as i32;
-       
-        if  nx + split_size >= volume_base.extents.2 as i32 {
-            nx = nx - (nx + split_size - volume_base.extents.2 as i32);
+impl Default for PatchOps {
+    fn default() -> PatchOps {
+        PatchOps {
+            count: 128,
+            size: 128,
+            positive_ratio: 0.5,
+            min_mask_fraction: 0.0,
+            min_spacing: 0.0,
+            seed: 0,
         }
+    }
+}
 
-        if  ny + split_size >= volume_base.extents.3 as i32 {
-            ny = ny - (ny + split_size - volume_base.extents.3 as i32);
-        }
+/// Choose the top left corners of the patches to cut from a datum, in mask pixels. Patch
+/// centres are drawn from the mask pixels (over all frames) or the background, in the ratio
+/// asked for, and any centre closer than min_spacing to one already chosen is thrown away.
+/// Fewer than count positions come back if there isn't room for them all.
+///
+/// * `datum` - the DatumT to cut the patches from.
+/// * `ops` - the PatchOps.
+pub fn patch_positions<T: VolumePixel>(datum: &DatumT<T>, ops: &PatchOps) -> Vec<(u32, u32)> {
+    let Some(first) = datum.mask.0.first() else {
+        return vec![];
+    };
 
-        //println!("Box {},{} {},{}", nx, ny, nx + split_size, ny + split_size);
+    let (mw, mh) = first.dimensions();
 
-        // Now cut the volumes
-        let mut new_base = VolumeT {
-            origin: volume_base.origin.clone(),
-            extents: (nx as u32, ny as u32, split_size as u32, split_size as u32),
-            volume: ImageVolume(vec![]),
-        };
-    
-        for frame in &volume_base.volume.0 {
-            let mut fcopy = frame.clone();
-            let img_data = crop(&mut fcopy, nx as u32, ny as u32, split_size as u32, split_size as u32);
-            new_base.volume.0.push(img_data.to_image());
+    if ops.size == 0 || ops.size > mw || ops.size > mh {
+        return vec![];
+    }
+
+    // Squash the mask over time, then build an integral image so the mask fraction of any patch is quick to find.
+    let mut positives: Vec<(u32, u32)> = vec![];
+    let mut negatives: Vec<(u32, u32)> = vec![];
+    let mut integral: Vec<u32> = vec![0; ((mw + 1) * (mh + 1)) as usize];
+    let stride = (mw + 1) as usize;
+
+    for y in 0..mh {
+        for x in 0..mw {
+            let hit = datum.mask.0.iter().any(|f| f.get_pixel(x, y).0[0] > 0);
+
+            if hit {
+                positives.push((x, y));
+            } else {
+                negatives.push((x, y));
+            }
+
+            let (ix, iy) = (x as usize + 1, y as usize + 1);
+            integral[iy * stride + ix] =
+                hit as u32 + integral[(iy - 1) * stride + ix] + integral[iy * stride + ix - 1] - integral[(iy - 1) * stride + ix - 1];
         }
+    }
 
-        let mut new_mask = VolumeT {
-            origin: volume_mask.origin.clone(),
-            extents: (nx as u32, ny as u32, split_size as u32, split_size as u32),
-            volume: ImageVolume(vec![]),
-        };
-    
-        for frame in &volume_mask.volume.0 {
-            let mut fcopy = frame.clone();
-            let img_data = crop(&mut fcopy, nx as u32, ny as u32, split_size as u32, split_size as u32);
-            new_mask.volume.0.push(img_data.to_image());
+    let mask_fraction = |x: u32, y: u32| {
+        let (x0, y0, x1, y1) = (x as usize, y as usize, (x + ops.size) as usize, (y + ops.size) as usize);
+        let total = integral[y1 * stride + x1] + integral[y0 * stride + x0] - integral[y0 * stride + x1] - integral[y1 * stride + x0];
+        total as f32 / (ops.size * ops.size) as f32
+    };
+
+    let mut rng = augment_rng(datum, ops.seed);
+    let num_positive = (ops.count as f32 * ops.positive_ratio.clamp(0.0, 1.0)).round() as usize;
+    let mut centres: Vec<(f32, f32)> = vec![];
+    let mut chosen: Vec<(u32, u32)> = vec![];
+
+    for (pool, wanted, positive) in [(&positives, num_positive, true), (&negatives, ops.count - num_positive, false)] {
+        let mut found = 0;
+        let mut tries = 0;
+
+        while found < wanted && tries < wanted * 32 && !pool.is_empty() {
+            tries += 1;
+            let (cx, cy) = pool[rng.gen_range(0..pool.len())];
+            let x = cx.saturating_sub(ops.size / 2).min(mw - ops.size);
+            let y = cy.saturating_sub(ops.size / 2).min(mh - ops.size);
+
+            if positive && mask_fraction(x, y) < ops.min_mask_fraction.max(f32::EPSILON) {
+                continue;
+            }
+
+            let centre = (cx as f32, cy as f32);
+            let too_close = centres
+                .iter()
+                .any(|c| (c.0 - centre.0).hypot(c.1 - centre.1) < ops.min_spacing);
+
+            if too_close {
+                continue;
+            }
+
+            centres.push(centre);
+            chosen.push((x, y));
+            found += 1;
         }
+    }
+
+    chosen
+}
 
-        assert!(new_base.volume.0[0].width() == new_mask.volume.0[0].width());
-        assert!(new_base.volume.0[0].height() == new_mask.volume.0[0].height());
-        let d = DatumT::new(&new_base, &new_mask);
+/// Cut the same box out of every frame of a volume.
+///
+/// * `volume` - the ImageVolume to cut from.
+/// * `b` - the box to cut (left, top, width, height).
+fn cut_volume<T: VolumePixel>(volume: &ImageVolume<T>, b: (u32, u32, u32, u32)) -> ImageVolume<T> {
+    ImageVolume(volume.0.iter().map(|f| crop_imm(f, b.0, b.1, b.2, b.3).to_image()).collect())
+}
 
-        new_vols.push(d);
+/// Cut a single square patch out of a datum. The position and size are in mask pixels and
+/// are scaled up for the raw volume and channels, so sectored masks stay aligned with their data.
+///
+/// * `datum` - the DatumT to cut from.
+/// * `position` - the top left corner of the patch, in mask pixels.
+/// * `size` - the width and height of the patch, in mask pixels.
+pub fn node_datum_patch<T: VolumePixel>(datum: &DatumT<T>, position: (u32, u32), size: u32) -> DatumT<T> {
+    let rx = datum.raw.0[0].width() as f32 / datum.mask.0[0].width() as f32;
+    let ry = datum.raw.0[0].height() as f32 / datum.mask.0[0].height() as f32;
+    let (x, y) = position;
+    let raw_box = (
+        (x as f32 * rx) as u32,
+        (y as f32 * ry) as u32,
+        (size as f32 * rx) as u32,
+        (size as f32 * ry) as u32,
+    );
+
+    let mut patch = datum.clone();
+    patch.raw = cut_volume(&datum.raw, raw_box);
+    patch.mask = cut_volume(&datum.mask, (x, y, size, size));
+    patch.extents = (x, y, size, size);
+
+    for channel in patch.channels.iter_mut() {
+        channel.volume = cut_volume(&channel.volume, raw_box);
     }
 
-    new_vols
+    patch
+}
+
+/// Cut a datum into balanced, spaced out, random patches.
+///
+/// * `datum` - the DatumT to cut from.
+/// * `ops` - the PatchOps.
+pub fn node_datum_patches<T: VolumePixel>(datum: &DatumT<T>, ops: &PatchOps) -> Vec<DatumT<T>> {
+    patch_positions(datum, ops)
+        .iter()
+        .map(|p| node_datum_patch(datum, *p, ops.size))
+        .collect()
+}
+
+/// Split a volume into smaller, overlapping volumes with random placement, using the
+/// default PatchOps - 128 patches, half of them on the mask.
+/// 
+/// * `volume_base` - the Image VolumeT to split.
+/// * `volume_mask` - the corresponding Track/ Mask VolumeT to split.
+/// * `split_size` - The dimension of these square volumes.
+pub fn node_volume_split_random(volume_base: &VolumeT, volume_mask: &VolumeT, split_size: i32) -> Vec<DatumT> {
+    let ops = PatchOps {
+        size: split_size as u32,
+        min_spacing: split_size as f32 / 4.0,
+        ..Default::default()
+    };
+
+    node_datum_patches(&DatumT::new(volume_base, volume_mask), &ops)
 }
 
 
@@ -386,91 +629,300 @@ pub fn node_trackraw_to_volume(track: &TrackRawT, group: &GroupT) -> VolumeT {
 }
 
 
-/// Split a volume into smaller, overlapping volumes with random placement.
-/// 
-/// * `track` - the TrackRawT to convert
-/// * `group` - the corresponding GroupT object.
-/// * `sector_size` - The dimension of the square sector.
-pub fn node_trackraw_to_sectors(track: &TrackRawT, group: &GroupT, sector_size: u32) -> VolumeT {
-    // Convert a track to our image mask but sectored - so more pixelated in effect.
-    // We don't pixelate in the time dimension though.
-    // We look at the base and try to match that as best as we can.
-    // sector_size is related to the original image size as that is the space the tracks are in.
-    // Base image height is the height of all our images before any rescaling but before the intitial crop
-    let mut final_mask = ImageVolume(vec![]);
+/// How each sector of a sectored mask is labelled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SectorLabel {
+    /// The class id of the group, for sectors at or above the minimum occupancy.
+    Class,
+    /// The fraction of the sector covered by the track, scaled to 0 to 255.
+    Occupancy,
+}
 
-    // We use a value equal to the original class of this group
-    // TODO - we should check this corigin unwrap!
-    let corigin = track.origin.clone();
-    let bp: Luma<u8> = Luma([0]);
-    let wp: Luma<u8> = Luma([corigin.unwrap().classid]);
+impl FromStr for SectorLabel {
+    type Err = String;
 
-    let iwidth = group.origin.crop_size.width as u32;
-    let iheight = group.origin.crop_size.height as u32;
+    /// Parse a sector label from the command line - class or occupancy.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "class" => Ok(SectorLabel::Class),
+            "occupancy" => Ok(SectorLabel::Occupancy),
+            _ => Err(format!("Unknown sector label {}", s)),
+        }
+    }
+}
 
-    // Scale down and move the extents if necessary. Cropping the matching node seems alright
-    let nums_width = iwidth / sector_size;
-    let nums_height = iheight / sector_size;
-    let new_extents = (0, 0, nums_width * sector_size, nums_height * sector_size);
+/// The options for building sectored masks.
+#[derive(Clone)]
+pub struct SectorOps {
+    /// The sector sizes to build masks for, as (bearing / x, range / y) in pixels.
+    pub sizes: Vec<(u32, u32)>,
+    /// Sectors covered by less than this fraction of track are left empty. 0 keeps any overlap at all.
+    pub min_occupancy: f32,
+    /// How to label the sectors.
+    pub label: SectorLabel,
+}
 
-    for _i in 0..group.images.len() {
-        let mask = GrayImage::from_pixel(
-            nums_width as u32,
-            nums_height as u32,
-            bp,
-        );
-        final_mask.0.push(mask);
-    }
-    
-    // TODO - could probably just do this directly without intervening sectors
-    let mut sectors : Vec<Vec<Vec<u8>>> = vec![];
-    
-    for _d in 0..group.images.len() {
-        let mut sector : Vec<Vec<u8>> = vec![];
-        
-        for _ in 0..nums_height {
-            let mut row : Vec<u8> = vec![];
+/// Parse a comma separated list of sector sizes, such as "16x16,64x32", as (bearing, range).
+/// An empty string gives no sizes.
+///
+/// * `list` - the list from the command line.
+pub fn parse_sector_sizes(list: &str) -> Result<Vec<(u32, u32)>, String> {
+    list.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.split_once('x').map(|(w, h)| (w.parse::<u32>(), h.parse::<u32>())) {
+            Some((Ok(w), Ok(h))) if w > 0 && h > 0 => Ok((w, h)),
+            _ => Err(format!("Bad sector size {}", s)),
+        })
+        .collect()
+}
 
-            for _ in 0..nums_width {
-                row.push(0);
-            }
+/// Convert a TrackRawT to one sectored mask VolumeT per sector size, with a single pass over
+/// the track. The sectors start at the top left, so any pixels beyond the last whole sector
+/// are dropped, as node_volume_crop_sectors does for the data.
+///
+/// * `track` - the TrackRawT to convert.
+/// * `group` - the corresponding GroupT object.
+/// * `ops` - the SectorOps.
+pub fn node_trackraw_to_sectors_multi(track: &TrackRawT, group: &GroupT, ops: &SectorOps) -> Vec<VolumeT> {
+    // Sector sizes are in the original image space, as that is the space the tracks are in.
+    let iwidth = group.origin.crop_size.width;
+    let iheight = group.origin.crop_size.height;
+    let num_frames = group.images.len();
+    let grids: Vec<(u32, u32)> = ops.sizes.iter().map(|(sw, sh)| (iwidth / sw, iheight / sh)).collect();
 
-            sector.push(row);
+    // Count the track pixels in each sector, for each size. Overlapping boxes are only counted once.
+    let mut counts: Vec<Vec<Vec<u32>>> = grids
+        .iter()
+        .map(|(nw, nh)| vec![vec![0; (nw * nh) as usize]; num_frames])
+        .collect();
+    let mut covered: Vec<bool> = vec![false; (iwidth * iheight) as usize];
+
+    let mut by_frame: Vec<Vec<&FrameBoxRaw>> = vec![vec![]; num_frames];
+
+    for b in &track.boxes {
+        if (b.frame as usize) < num_frames {
+            by_frame[b.frame as usize].push(b);
         }
-        sectors.push(sector);
     }
 
-    for i in 0..track.boxes.len() {
-        let d = track.boxes[i].frame as usize;
-        let tbox = track.boxes[i].bbox;
-
-        for y in tbox.y_min..tbox.y_max {
-            for x in tbox.x_min..tbox.x_max {
-                // Convert to sector. Bit wasteful as we can go step size, not every pixel.
-                let sx= ((x as f32 / iwidth as f32) * nums_width as f32).floor() as usize;
-                let sy= ((y as f32 / iheight as f32) * nums_height as f32).floor() as usize;
-                if sy < nums_height as usize && sx < nums_width as usize {
-                    sectors[d][sy][sx] = 1;
+    for (frame, boxes) in by_frame.into_iter().enumerate() {
+        if boxes.is_empty() {
+            continue;
+        }
+
+        covered.iter_mut().for_each(|c| *c = false);
+
+        for b in boxes {
+            for y in b.bbox.y_min.max(0)..b.bbox.y_max.min(iheight as i32) {
+                for x in b.bbox.x_min.max(0)..b.bbox.x_max.min(iwidth as i32) {
+                    let idx = y as usize * iwidth as usize + x as usize;
+
+                    if covered[idx] {
+                        continue;
+                    }
+
+                    covered[idx] = true;
+
+                    for (g, ((sw, sh), (nw, nh))) in ops.sizes.iter().zip(grids.iter()).enumerate() {
+                        let sx = x as u32 / sw;
+                        let sy = y as u32 / sh;
+
+                        if sx < *nw && sy < *nh {
+                            counts[g][frame][(sy * nw + sx) as usize] += 1;
+                        }
+                    }
                 }
             }
         }
     }
 
-    // Now we have a sector map, lets translate that to our pixelated image.
-    // TODO - might be a better way of doing this more simply? Nearest neighbour resize?
-    for d in 0..group.images.len() {
-        let mask: &mut ImageBuffer<Luma<u8>, Vec<u8>> = &mut final_mask.0[d];
+    let classid = track.origin.as_ref().map_or(group.origin.classid, |o| o.classid);
+    let mut volumes: Vec<VolumeT> = vec![];
 
-        for y in 0..nums_height as usize {
-            for x in 0..nums_width as usize {
-                if sectors[d][y][x] == 1 {
-                    mask.put_pixel(x as u32, y as u32, wp);
+    for (g, ((sw, sh), (nw, nh))) in ops.sizes.iter().zip(grids.iter()).enumerate() {
+        let area = (sw * sh) as f32;
+        let mut final_mask = ImageVolume(vec![]);
+
+        for frame_counts in &counts[g] {
+            let mut mask = GrayImage::from_pixel(*nw, *nh, Luma([0]));
+
+            for (idx, count) in frame_counts.iter().enumerate() {
+                let fraction = *count as f32 / area;
+
+                if *count == 0 || fraction < ops.min_occupancy {
+                    continue;
                 }
+
+                let value = match ops.label {
+                    SectorLabel::Class => classid,
+                    SectorLabel::Occupancy => (fraction * 255.0).round().max(1.0) as u8,
+                };
+
+                mask.put_pixel(idx as u32 % nw, idx as u32 / nw, Luma([value]));
             }
+
+            final_mask.0.push(mask);
         }
+
+        let mut fvol = VolumeT::new(final_mask, Option::Some(group.origin.clone()));
+        fvol.extents = (0, 0, nw * sw, nh * sh);
+        volumes.push(fvol);
+    }
+
+    volumes
+}
+
+/// Convert a TrackRawT to a sectored mask VolumeT. A sector is set to the class id if any
+/// track pixel falls in it.
+/// 
+/// * `track` - the TrackRawT to convert
+/// * `group` - the corresponding GroupT object.
+/// * `sector_size` - The dimension of the square sector.
+pub fn node_trackraw_to_sectors(track: &TrackRawT, group: &GroupT, sector_size: u32) -> VolumeT {
+    let ops = SectorOps {
+        sizes: vec![(sector_size, sector_size)],
+        min_occupancy: 0.0,
+        label: SectorLabel::Class,
+    };
+
+    node_trackraw_to_sectors_multi(track, group, &ops).remove(0)
+}
+
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbs::RawBox;
+    use crate::image::ImageSize;
+
+    #[test]
+    fn test_fit_frame() {
+        // A frame two rows short of the crop height.
+        let frame = GrayImage::from_pixel(4, 6, Luma([5]));
+
+        let (padded, ignore) = fit_frame(&frame, 4, 8, (4, 8), FramePolicy::Pad);
+        assert_eq!(padded.dimensions(), (4, 8));
+        assert_eq!(padded.get_pixel(3, 5)[0], 5);
+        assert_eq!(padded.get_pixel(3, 6)[0], 0);
+        assert_eq!(ignore.get_pixel(3, 5)[0], 0);
+        assert_eq!(ignore.get_pixel(3, 6)[0], 1);
+
+        let (cropped, ignore) = fit_frame(&frame, 4, 8, (4, 3), FramePolicy::Crop);
+        assert_eq!(cropped.get_pixel(0, 3)[0], 0);
+        assert_eq!(ignore.get_pixel(0, 2)[0], 0);
+        assert_eq!(ignore.get_pixel(0, 3)[0], 1);
+
+        let (resized, ignore) = fit_frame(&frame, 4, 8, (4, 8), FramePolicy::Resample);
+        assert_eq!(resized.get_pixel(0, 7)[0], 5);
+        assert_eq!(ignore.get_pixel(0, 7)[0], 0);
+
+        // Frames longer than the crop are cropped, not squashed.
+        let mut long = GrayImage::from_pixel(4, 12, Luma([5]));
+        long.put_pixel(0, 10, Luma([200]));
+        let (resampled, _) = fit_frame(&long, 4, 8, (4, 8), FramePolicy::Resample);
+        assert_eq!(resampled.dimensions(), (4, 8));
+        assert!(resampled.pixels().all(|p| p[0] == 5));
+
+        // Wider pixels are fitted the same way.
+        let wide = ImageBuffer::<Luma<u16>, Vec<u16>>::from_pixel(4, 6, Luma([4000]));
+        let (padded, ignore) = fit_frame(&wide, 4, 8, (4, 8), FramePolicy::Pad);
+        assert_eq!(padded.get_pixel(3, 5)[0], 4000);
+        assert_eq!(padded.get_pixel(3, 6)[0], 0);
+        assert_eq!(ignore.get_pixel(3, 6)[0], 1);
+
+        assert_eq!("pad".parse::<FramePolicy>(), Ok(FramePolicy::Pad));
+        assert!("stretch".parse::<FramePolicy>().is_err());
     }
-   
-    let mut fvol = VolumeT::new(final_mask, Option::Some(group.origin.clone()));
-    fvol.extents = new_extents;
-    fvol
-}
\ No newline at end of file
+
+    #[test]
+    fn test_patches() {
+        let raw = VolumeT {
+            volume: ImageVolume(vec![GrayImage::from_pixel(64, 64, Luma([3])); 2]),
+            extents: (0, 0, 64, 64),
+            origin: None,
+        };
+
+        // A small object, at half the resolution of the raw data.
+        let mut mask_frame = GrayImage::from_pixel(32, 32, Luma([0]));
+        for x in 20..24 {
+            for y in 4..8 {
+                mask_frame.put_pixel(x, y, Luma([1]));
+            }
+        }
+
+        let mask = VolumeT {
+            volume: ImageVolume(vec![mask_frame; 2]),
+            extents: (0, 0, 32, 32),
+            origin: None,
+        };
+
+        let datum = DatumT::new(&raw, &mask);
+        let ops = PatchOps {
+            count: 8,
+            size: 8,
+            positive_ratio: 0.5,
+            min_mask_fraction: 0.1,
+            min_spacing: 2.0,
+            seed: 7,
+        };
+
+        let patches = node_datum_patches(&datum, &ops);
+        assert!(patches.len() >= 4);
+        assert_eq!(patches[0].raw.0[0].dimensions(), (16, 16));
+        assert_eq!(patches[0].mask.0[0].dimensions(), (8, 8));
+
+        // The first half are positive, with enough of the mask in them.
+        for patch in &patches[0..4] {
+            let hits = patch.mask.0[0].pixels().filter(|p| p.0[0] > 0).count();
+            assert!(hits as f32 / 64.0 >= 0.1);
+        }
+
+        // The same seed gives the same patches.
+        assert_eq!(patch_positions(&datum, &ops), patch_positions(&datum, &ops));
+    }
+
+    #[test]
+    fn test_soft_sectors() {
+        let mut origin = crate::test_util::test_origin();
+        origin.crop_size = ImageSize { width: 8, height: 8 };
+        let group = GroupT {
+            origin: origin.clone(),
+            images: vec![crate::test_util::test_images(&[0])[0].clone()],
+            points: vec![vec![]],
+        };
+
+        // A box filling the top left 4x4 sector, and an overlapping one reaching 2 pixels into the next.
+        let track = TrackRawT {
+            boxes: vec![
+                FrameBoxRaw { frame: 0, bbox: RawBox { x_min: 0, y_min: 0, x_max: 4, y_max: 4 } },
+                FrameBoxRaw { frame: 0, bbox: RawBox { x_min: 2, y_min: 0, x_max: 5, y_max: 2 } },
+            ],
+            origin: Some(origin),
+        };
+
+        let ops = SectorOps {
+            sizes: vec![(4, 4), (2, 8)],
+            min_occupancy: 0.2,
+            label: SectorLabel::Occupancy,
+        };
+
+        let volumes = node_trackraw_to_sectors_multi(&track, &group, &ops);
+        assert_eq!(volumes.len(), 2);
+        assert_eq!(volumes[0].volume.0[0].dimensions(), (2, 2));
+        assert_eq!(volumes[0].volume.0[0].get_pixel(0, 0)[0], 255);
+        // 2 of 16 pixels is below the minimum occupancy.
+        assert_eq!(volumes[0].volume.0[0].get_pixel(1, 0)[0], 0);
+        assert_eq!(volumes[1].volume.0[0].dimensions(), (4, 1));
+        assert_eq!(volumes[1].volume.0[0].get_pixel(1, 0)[0], 128);
+        assert_eq!(volumes[1].volume.0[0].get_pixel(2, 0)[0], 0);
+        assert_eq!(volumes[1].extents, (0, 0, 8, 8));
+
+        assert_eq!(parse_sector_sizes("16x16, 64x32"), Ok(vec![(16, 16), (64, 32)]));
+        assert!(parse_sector_sizes("16").is_err());
+
+        let hard = node_trackraw_to_sectors(&track, &group, 4);
+        assert_eq!(hard.volume.0[0].get_pixel(1, 0)[0], 1);
+    }
+}
@@ -15,6 +15,18 @@
  *
 */
 
+use crate::filter::GroupFilter;
+use crate::fits_index::IndexMode;
+use crate::kalman::KalmanOps;
+use crate::metrics::TrackRules;
+use crate::nodes_background::BackgroundMethod;
+use crate::nodes_channels::ChannelKind;
+use crate::nodes_convert::PixelOps;
+use crate::nodes_time::Resample;
+use crate::nodes_tracks::GapPolicy;
+use crate::nodes_volumes::{FramePolicy, SectorLabel};
+use crate::pgdf::PointsMode;
+use crate::track::Interpolation;
 use std::path::PathBuf;
 
 pub struct MovesOps {
@@ -30,20 +42,103 @@ pub struct MovesOps {
     pub dbpass: String,
     /// Name of the database
     pub dbname: String,
+    /// Path to a snapshot to read instead of the database, if given
+    pub snapshot_path: Option<PathBuf>,
     /// Path to the FITS files
     pub fits_path: PathBuf,
+    /// Path to the index of the FITS files
+    pub index_path: PathBuf,
+    /// What to do with an existing FITS index
+    pub index_mode: IndexMode,
+    /// How many decoded frames to keep in memory
+    pub frame_cache_size: usize,
+    /// Where to keep decoded frames between runs, if anywhere
+    pub frame_cache_path: Option<PathBuf>,
+    /// Path to the GLF recordings, read for frames without a FITS, if given
+    pub glf_path: Option<PathBuf>,
+    /// How many GLFs to keep open
+    pub glf_cache_size: usize,
+    /// Where the points come from - the database, the PGDFs, or the database checked against the PGDFs
+    pub points_mode: PointsMode,
+    /// Path to the PGDFs, needed unless the points come from the database alone
+    pub pgdf_path: Option<PathBuf>,
+    /// Where to keep the volumes of each group between runs, if anywhere
+    pub stage_cache_path: Option<PathBuf>,
     /// Path where the dataset is saved
     pub out_path: PathBuf,
     /// The number of frames / history time window length
     pub num_frames: u32,
     /// Number of threads to run in parallel
     pub num_threads: u32,
-    /// Path to an optional SQLFilter file
+    /// The filter selecting the groups, if any
+    pub group_filter: Option<GroupFilter>,
+    /// Path to an optional SQLFilter file, for selections a filter can't express
     pub sqlfilter: Option<PathBuf>,
     /// If we are sectoring, what size the sectors?
     pub sector_size: u32,
     // Of the original images, what is the minimum height they should all be set to
     pub crop_height: u32,
-    // The rejection rate for the track rejection function. 
-    pub reject_rate: f32,
+    // How to interpolate the track between the frames that have boxes.
+    pub interpolation: Interpolation,
+    // The longest gap in a track to interpolate over, in seconds. None bridges any gap.
+    pub max_gap: Option<f32>,
+    // What to do with a track that has a longer gap.
+    pub gap_policy: GapPolicy,
+    // The tunable Kalman smoother. None uses the original forward only filter.
+    pub kalman: Option<KalmanOps>,
+    // Track rejection rules, in metres and seconds.
+    pub track_rules: TrackRules,
+    // The border of each mask frame to ignore when checking the mask isn't empty.
+    pub mask_border: u32,
+    // A mask must have more pixels set than this, inside the border, to be kept.
+    pub mask_min_pixels: u32,
+    // How many augmented copies of each training datum to write. 0 means none.
+    pub augment_copies: u32,
+    // The seed for the augmentation random number generator.
+    pub augment_seed: u64,
+    // How to remove the background from the raw volumes, if at all.
+    pub background: Option<BackgroundMethod>,
+    // Keep the raw volume and write the background removed one as an extra output.
+    pub background_extra: bool,
+    // How to build the long term static clutter maps, if at all.
+    pub clutter: Option<BackgroundMethod>,
+    // Where the static clutter maps are cached.
+    pub clutter_path: PathBuf,
+    // The most frames used to build each clutter map.
+    pub clutter_frames: usize,
+    // Clutter above this value, in the units of the volumes, is exported as a static clutter
+    // mask. 0 means no mask.
+    pub clutter_threshold: f32,
+    // The extra channels to add after the raw volume, in order.
+    pub channels: Vec<ChannelKind>,
+    // The pixel type the raw volume and channels are written as, and how they are normalised.
+    pub pixel_ops: PixelOps,
+    // The fixed time base to resample the frames to. None keeps the recorded ping rate.
+    pub resample: Option<Resample>,
+    // The distance each pixel row should cover. None keeps the recorded range scale.
+    pub metres_per_pixel: Option<f32>,
+    // What to do with frames that don't match the crop size.
+    pub frame_policy: FramePolicy,
+    // How many balanced random patches to cut from each datum. 0 writes the whole datum.
+    pub patch_count: usize,
+    // The width and height of the patches, in mask pixels.
+    pub patch_size: u32,
+    // The fraction of patches centred on the mask.
+    pub patch_positive: f32,
+    // The smallest fraction of a positive patch covered by the mask.
+    pub patch_min_mask: f32,
+    // The smallest distance between patch centres, in mask pixels.
+    pub patch_spacing: f32,
+    // The seed for the patch sampler.
+    pub patch_seed: u64,
+    // The height of the sectors in range. sector_size is the width in bearing. 0 means square.
+    pub sector_range_size: u32,
+    // Extra sector sizes (bearing, range) to write masks for, alongside the main one.
+    pub sector_extra_sizes: Vec<(u32, u32)>,
+    // Sectors covered by less than this fraction of track are left empty.
+    pub sector_min_occupancy: f32,
+    // How the sectors are labelled.
+    pub sector_label: SectorLabel,
+    // Write the occupancy masks as f32 fractions.
+    pub sector_float: bool,
 }
@@ -0,0 +1,643 @@
+//! Reading the PAMGuard binary detection files (PGDFs) the tracks were originally saved in,
+//! so the points of a group can be rebuilt or checked against the database.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   pgdf.rs - PAMGuard binary detection files.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::models::{Images, Points, TracksGroups};
+use chrono::{DateTime, Utc};
+use log::warn;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+/// Object identifiers of the PAMGuard binary store. Data objects have their own, positive ones.
+const FILE_HEADER: i32 = -1;
+const FILE_FOOTER: i32 = -2;
+const MODULE_HEADER: i32 = -3;
+const MODULE_FOOTER: i32 = -4;
+const DATAGRAM: i32 = -6;
+
+/// The flags saying which optional fields follow the time of a data object.
+const FLAG_TIMENANOS: i16 = 0x2;
+const FLAG_CHANNELMAP: i16 = 0x4;
+const FLAG_UID: i16 = 0x8;
+const FLAG_STARTSAMPLE: i16 = 0x10;
+const FLAG_SAMPLEDURATION: i16 = 0x20;
+const FLAG_FREQUENCYLIMITS: i16 = 0x40;
+const FLAG_MILLISDURATION: i16 = 0x80;
+const FLAG_TIMEDELAYSSECONDS: i16 = 0x100;
+const FLAG_SEQUENCEMAP: i16 = 0x400;
+const FLAG_NOISE: i16 = 0x800;
+const FLAG_SIGNAL: i16 = 0x1000;
+const FLAG_SIGNALEXCESS: i16 = 0x2000;
+
+/// The oldest file format with the flags above.
+const MIN_FILE_FORMAT: i32 = 3;
+
+/// Builds an invalid data error.
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Reads the big-endian fields PAMGuard writes, one after another.
+struct Fields<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        if self.pos + count > self.bytes.len() {
+            return Err(invalid(format!("PGDF truncated at byte {}", self.pos)));
+        }
+
+        let taken = &self.bytes[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(taken)
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// A Java modified UTF-8 string, prefixed with its length.
+    fn utf(&mut self) -> io::Result<String> {
+        let len = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).to_string())
+    }
+
+    fn time(&mut self) -> io::Result<DateTime<Utc>> {
+        let millis = self.i64()?;
+        DateTime::from_timestamp_millis(millis).ok_or(invalid(format!("PGDF time {} out of range", millis)))
+    }
+}
+
+/// One detected region of a track, as the Gemini tracker saved it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PgdfPoint {
+    pub time: DateTime<Utc>,
+    pub sonarid: i32,
+    pub minbearing: f32,
+    pub maxbearing: f32,
+    pub peakbearing: f32,
+    pub minrange: f32,
+    pub maxrange: f32,
+    pub peakrange: f32,
+    pub objsize: f32,
+    pub occupancy: f32,
+    pub maxvalue: f32,
+}
+
+/// A track from a PGDF. Its uid is the track_pam_id in tracks_groups.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PgdfTrack {
+    pub uid: i64,
+    pub time: DateTime<Utc>,
+    pub points: Vec<PgdfPoint>,
+}
+
+/// The tracks in a PGDF, with the module that wrote them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PgdfFile {
+    pub file_format: i32,
+    pub module_type: String,
+    pub module_name: String,
+    pub stream_name: String,
+    pub tracks: Vec<PgdfTrack>,
+}
+
+/// The regions of a track, from the data of its object.
+///
+/// Laid out as a count, then for each region its time, sonar, bearings and ranges (min, max
+/// and peak), size and occupancy, then the average, total and max values.
+///
+/// * `data` - the data of the track object.
+fn parse_track_data(data: &[u8]) -> io::Result<Vec<PgdfPoint>> {
+    let mut fields = Fields { bytes: data, pos: 0 };
+    let count = fields.i16()?.max(0) as usize;
+    let mut points = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let time = fields.time()?;
+        let sonarid = fields.i16()? as i32;
+        let minbearing = fields.f32()?;
+        let maxbearing = fields.f32()?;
+        let peakbearing = fields.f32()?;
+        let minrange = fields.f32()?;
+        let maxrange = fields.f32()?;
+        let peakrange = fields.f32()?;
+        let objsize = fields.f32()?;
+        let occupancy = fields.f32()?;
+        let _average = fields.u16()?;
+        let _total = fields.i32()?;
+        let maxvalue = fields.u16()? as f32;
+
+        points.push(PgdfPoint {
+            time,
+            sonarid,
+            minbearing,
+            maxbearing,
+            peakbearing,
+            minrange,
+            maxrange,
+            peakrange,
+            objsize,
+            occupancy,
+            maxvalue,
+        });
+    }
+
+    Ok(points)
+}
+
+/// Parse a whole PGDF.
+///
+/// * `bytes` - the contents of the file.
+pub fn parse_pgdf(bytes: &[u8]) -> io::Result<PgdfFile> {
+    let mut fields = Fields { bytes, pos: 0 };
+    let mut pgdf: Option<PgdfFile> = None;
+
+    while fields.pos < bytes.len() {
+        let start = fields.pos;
+        let length = fields.i32()?;
+        let identifier = fields.i32()?;
+
+        if length < 8 || start + length as usize > bytes.len() {
+            return Err(invalid(format!("PGDF object at byte {} has a bad length {}", start, length)));
+        }
+
+        let end = start + length as usize;
+
+        match identifier {
+            FILE_HEADER => {
+                let file_format = fields.i32()?;
+
+                if fields.take(12)? != b"PAMGUARDDATA" {
+                    return Err(invalid(String::from("Not a PAMGuard binary file")));
+                }
+
+                if file_format < MIN_FILE_FORMAT {
+                    return Err(invalid(format!("PGDF file format {} is too old", file_format)));
+                }
+
+                let _version = fields.utf()?;
+                let _branch = fields.utf()?;
+                let _data_date = fields.i64()?;
+                let _analysis_date = fields.i64()?;
+                let _start_sample = fields.i64()?;
+
+                pgdf = Some(PgdfFile {
+                    file_format,
+                    module_type: fields.utf()?,
+                    module_name: fields.utf()?,
+                    stream_name: fields.utf()?,
+                    tracks: vec![],
+                });
+            }
+            FILE_FOOTER => break,
+            MODULE_HEADER | MODULE_FOOTER | DATAGRAM => {}
+            _ => {
+                let file = pgdf.as_mut().ok_or(invalid(String::from("PGDF data before the file header")))?;
+                let time = fields.time()?;
+                let flags = fields.i16()?;
+                let mut uid = 0;
+
+                if flags & FLAG_TIMENANOS != 0 {
+                    fields.i64()?;
+                }
+                if flags & FLAG_CHANNELMAP != 0 {
+                    fields.i32()?;
+                }
+                if flags & FLAG_UID != 0 {
+                    uid = fields.i64()?;
+                }
+                if flags & FLAG_STARTSAMPLE != 0 {
+                    fields.i64()?;
+                }
+                if flags & FLAG_SAMPLEDURATION != 0 {
+                    fields.i32()?;
+                }
+                if flags & FLAG_FREQUENCYLIMITS != 0 {
+                    fields.take(8)?;
+                }
+                if flags & FLAG_MILLISDURATION != 0 {
+                    fields.f32()?;
+                }
+                if flags & FLAG_TIMEDELAYSSECONDS != 0 {
+                    let delays = fields.i16()?.max(0) as usize;
+                    fields.take(delays * 4)?;
+                }
+                if flags & FLAG_SEQUENCEMAP != 0 {
+                    fields.i32()?;
+                }
+                for flag in [FLAG_NOISE, FLAG_SIGNAL, FLAG_SIGNALEXCESS] {
+                    if flags & flag != 0 {
+                        fields.f32()?;
+                    }
+                }
+
+                let data_length = fields.i32()?.max(0) as usize;
+                let points = parse_track_data(fields.take(data_length)?)?;
+                file.tracks.push(PgdfTrack { uid, time, points });
+            }
+        }
+
+        // Anything not read, such as annotations, is skipped.
+        fields.pos = end;
+    }
+
+    pgdf.ok_or(invalid(String::from("PGDF has no file header")))
+}
+
+/// Read a PGDF from disk.
+///
+/// * `path` - the path to the PGDF.
+pub fn read_pgdf(path: &Path) -> io::Result<PgdfFile> {
+    parse_pgdf(&fs::read(path)?)
+}
+
+/// The points of a PGDF track, as they would be in the points table.
+///
+/// * `track` - the track from the PGDF.
+/// * `track_id` - the track_id the track has in the database.
+pub fn track_points(track: &PgdfTrack, track_id: Uuid) -> Vec<Points> {
+    track
+        .points
+        .iter()
+        .enumerate()
+        .map(|(idx, p)| Points {
+            time: p.time,
+            sonarid: p.sonarid,
+            minbearing: p.minbearing,
+            maxbearing: p.maxbearing,
+            minrange: p.minrange,
+            maxrange: p.maxrange,
+            track_id,
+            // Rebuilt points always get the same uid.
+            uid: Uuid::from_u64_pair(track.uid as u64, idx as u64),
+            peakbearing: p.peakbearing,
+            peakrange: p.peakrange,
+            maxvalue: p.maxvalue,
+            occupancy: p.occupancy,
+            objsize: p.objsize,
+        })
+        .collect()
+}
+
+/// Where the points of a group come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PointsMode {
+    /// The points table.
+    Db,
+    /// Rebuilt from the PGDFs of the tracks, ignoring the points table.
+    Pgdf,
+    /// The points table, checked against the PGDFs with any differences logged.
+    Check,
+}
+
+impl FromStr for PointsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "db" => Ok(PointsMode::Db),
+            "pgdf" => Ok(PointsMode::Pgdf),
+            "check" => Ok(PointsMode::Check),
+            _ => Err(format!("unknown points mode '{}' - expected db, pgdf or check", s)),
+        }
+    }
+}
+
+impl fmt::Display for PointsMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PointsMode::Db => write!(f, "db"),
+            PointsMode::Pgdf => write!(f, "pgdf"),
+            PointsMode::Check => write!(f, "check"),
+        }
+    }
+}
+
+/// How the points of a group in the database compare with those in the PGDFs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PointsCheck {
+    /// Points in both, with the same bounds.
+    pub matched: usize,
+    /// Points in both, with different bounds.
+    pub differ: usize,
+    /// Points only in the database.
+    pub only_db: usize,
+    /// Points only in the PGDFs.
+    pub only_pgdf: usize,
+}
+
+impl PointsCheck {
+    /// Whether the database and the PGDFs agree.
+    pub fn consistent(&self) -> bool {
+        self.differ == 0 && self.only_db == 0 && self.only_pgdf == 0
+    }
+}
+
+impl fmt::Display for PointsCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} matched, {} differ, {} only in the database, {} only in the PGDFs",
+            self.matched, self.differ, self.only_db, self.only_pgdf
+        )
+    }
+}
+
+/// Compare the points of each image from the database with those rebuilt from the PGDFs.
+/// Points are paired by track, and differ if any bound is more than the tolerance apart.
+///
+/// * `db` - the points of each image from the database.
+/// * `pgdf` - the points of each image from the PGDFs.
+/// * `tolerance` - how far apart the bearings and ranges may be.
+pub fn check_points(db: &[Vec<Points>], pgdf: &[Vec<Points>], tolerance: f32) -> PointsCheck {
+    let mut check = PointsCheck::default();
+    let empty = vec![];
+
+    for idx in 0..db.len().max(pgdf.len()) {
+        let db_points = db.get(idx).unwrap_or(&empty);
+        let mut pgdf_points: Vec<&Points> = pgdf.get(idx).unwrap_or(&empty).iter().collect();
+
+        for point in db_points {
+            match pgdf_points.iter().position(|p| p.track_id == point.track_id) {
+                Some(pos) => {
+                    let other = pgdf_points.remove(pos);
+                    let close = [
+                        (point.minbearing, other.minbearing),
+                        (point.maxbearing, other.maxbearing),
+                        (point.minrange, other.minrange),
+                        (point.maxrange, other.maxrange),
+                    ]
+                    .iter()
+                    .all(|(a, b)| (a - b).abs() <= tolerance);
+
+                    if close {
+                        check.matched += 1;
+                    } else {
+                        check.differ += 1;
+                    }
+                }
+                None => check.only_db += 1,
+            }
+        }
+
+        check.only_pgdf += pgdf_points.len();
+    }
+
+    check
+}
+
+/// Reads the tracks of groups from the PGDFs named in tracks_groups.binfile. Each PGDF is
+/// parsed once and kept, as the tracks of a group, and neighbouring groups, share them.
+pub struct PgdfSource {
+    /// The directory holding the PGDFs.
+    root: PathBuf,
+    /// Whether the points are rebuilt or checked.
+    pub mode: PointsMode,
+    /// The PGDFs read so far. None if they couldn't be.
+    files: Mutex<HashMap<PathBuf, Option<Arc<PgdfFile>>>>,
+    /// Every PGDF under the root, by name. Only walked if a PGDF isn't where it should be.
+    found: OnceLock<HashMap<String, PathBuf>>,
+}
+
+impl PgdfSource {
+    /// Create a new PgdfSource.
+    ///
+    /// * `root` - the directory holding the PGDFs.
+    /// * `mode` - whether the points are rebuilt or checked.
+    pub fn new(root: &Path, mode: PointsMode) -> PgdfSource {
+        PgdfSource {
+            root: root.to_path_buf(),
+            mode,
+            files: Mutex::new(HashMap::new()),
+            found: OnceLock::new(),
+        }
+    }
+
+    /// Find a PGDF - at its binfile path under the root, at the root, or anywhere below.
+    ///
+    /// * `binfile` - the binfile of the track.
+    pub fn pgdf_path(&self, binfile: &str) -> Option<PathBuf> {
+        let name = Path::new(binfile).file_name()?.to_str()?;
+
+        for path in [self.root.join(binfile.trim_start_matches('/')), self.root.join(name)] {
+            if path.is_file() {
+                return Some(path);
+            }
+        }
+
+        let found = self.found.get_or_init(|| {
+            WalkDir::new(&self.root)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .filter_map(|e| Some((e.file_name().to_str()?.to_string(), e.path().to_path_buf())))
+                .collect()
+        });
+
+        found.get(name).cloned()
+    }
+
+    /// Read a PGDF, or get it from those already read.
+    ///
+    /// * `binfile` - the binfile of the track.
+    fn pgdf(&self, binfile: &str) -> Option<Arc<PgdfFile>> {
+        let path = self.pgdf_path(binfile)?;
+
+        if let Some(file) = self.files.lock().unwrap().get(&path) {
+            return file.clone();
+        }
+
+        let file = match read_pgdf(&path) {
+            Ok(file) => Some(Arc::new(file)),
+            Err(e) => {
+                warn!("Failed to read PGDF {} - {}", path.display(), e);
+                None
+            }
+        };
+
+        self.files.lock().unwrap().insert(path, file.clone());
+        file
+    }
+
+    /// Rebuild the points of each image of a group from the PGDFs of its tracks. Points are
+    /// given to the image from the same sonar at the same time, as in the points table.
+    /// None if any track can't be found.
+    ///
+    /// * `images` - the images of the group on one sonar, in order.
+    /// * `tracks` - the tracks_groups rows of the group.
+    pub fn group_points(&self, images: &[Images], tracks: &[TracksGroups]) -> Option<Vec<Vec<Points>>> {
+        let mut points: Vec<Vec<Points>> = vec![vec![]; images.len()];
+
+        for track in tracks {
+            let file = self.pgdf(&track.binfile)?;
+            let pgdf_track = match file.tracks.iter().find(|t| t.uid == track.track_pam_id) {
+                Some(t) => t,
+                None => {
+                    warn!("Track {} is not in PGDF {}", track.track_pam_id, track.binfile);
+                    return None;
+                }
+            };
+
+            for point in track_points(pgdf_track, track.track_id) {
+                if let Some(idx) = images.iter().position(|i| i.sonarid == point.sonarid && i.time == point.time) {
+                    points[idx].push(point);
+                }
+            }
+        }
+
+        Some(points)
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_images;
+
+    fn utf(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as u16).to_be_bytes());
+        out.extend(s.as_bytes());
+    }
+
+    /// Wrap the body of an object with its length and identifier.
+    fn object(out: &mut Vec<u8>, identifier: i32, body: &[u8]) {
+        out.extend((body.len() as i32 + 8).to_be_bytes());
+        out.extend(identifier.to_be_bytes());
+        out.extend(body);
+    }
+
+    /// A PGDF holding one track per entry, each with a region at each time.
+    fn make_pgdf(tracks: &[(i64, Vec<DateTime<Utc>>)]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut header = vec![];
+        header.extend(6i32.to_be_bytes());
+        header.extend(b"PAMGUARDDATA");
+        utf(&mut header, "2.02");
+        utf(&mut header, "core");
+        header.extend([0u8; 24]);
+        utf(&mut header, "Gemini Threshold Detector");
+        utf(&mut header, "Gemini Tracks");
+        utf(&mut header, "Tracks");
+        header.extend(0i32.to_be_bytes());
+        object(&mut out, FILE_HEADER, &header);
+        object(&mut out, MODULE_HEADER, &[0u8; 8]);
+
+        for (uid, times) in tracks {
+            let mut data = vec![];
+            data.extend((times.len() as i16).to_be_bytes());
+
+            for (idx, time) in times.iter().enumerate() {
+                data.extend(time.timestamp_millis().to_be_bytes());
+                data.extend(854i16.to_be_bytes());
+                for v in [0.1, 0.2, 0.15, 10.0 + idx as f32, 12.0, 11.0, 1.5, 0.5f32] {
+                    data.extend(v.to_be_bytes());
+                }
+                data.extend(40u16.to_be_bytes());
+                data.extend(400i32.to_be_bytes());
+                data.extend(200u16.to_be_bytes());
+            }
+
+            let mut body = vec![];
+            body.extend(times[0].timestamp_millis().to_be_bytes());
+            body.extend((FLAG_UID | FLAG_CHANNELMAP | FLAG_MILLISDURATION).to_be_bytes());
+            body.extend(1i32.to_be_bytes());
+            body.extend(uid.to_be_bytes());
+            body.extend(2.5f32.to_be_bytes());
+            body.extend((data.len() as i32).to_be_bytes());
+            body.extend(data);
+            // An annotation the reader doesn't know about.
+            body.extend([7u8; 6]);
+            object(&mut out, 1, &body);
+        }
+
+        object(&mut out, MODULE_FOOTER, &[]);
+        object(&mut out, FILE_FOOTER, &[0u8; 16]);
+        out
+    }
+
+    #[test]
+    fn test_pgdf() {
+        let images = test_images(&[0, 100, 200, 300]);
+        let times: Vec<DateTime<Utc>> = images.iter().map(|i| i.time).collect();
+        let bytes = make_pgdf(&[(77, times[1..3].to_vec()), (78, times[2..4].to_vec())]);
+
+        let file = parse_pgdf(&bytes).unwrap();
+        assert_eq!(file.module_type, "Gemini Threshold Detector");
+        assert_eq!(file.tracks.len(), 2);
+        assert_eq!(file.tracks[0].uid, 77);
+        assert_eq!(file.tracks[1].points[1].time, times[3]);
+        assert_eq!(file.tracks[1].points[1].minrange, 11.0);
+        assert_eq!(file.tracks[1].points[1].maxvalue, 200.0);
+        assert!(parse_pgdf(&bytes[..bytes.len() - 30]).is_err());
+        assert!(parse_pgdf(b"not a pgdf at all").is_err());
+
+        // Rebuild a group's points from a PGDF under the root.
+        let root = std::env::temp_dir().join("crabseal_test_pgdf");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("20230528")).unwrap();
+        std::fs::write(root.join("20230528").join("tracks.pgdf"), &bytes).unwrap();
+
+        let track = |pam_id: i64| TracksGroups {
+            track_pam_id: pam_id,
+            group_id: Uuid::nil(),
+            binfile: String::from("/data/20230528/tracks.pgdf"),
+            track_id: Uuid::from_u64_pair(0, pam_id as u64),
+        };
+        let source = PgdfSource::new(&root, PointsMode::Pgdf);
+        let points = source.group_points(&images, &[track(77), track(78)]).unwrap();
+        let counts: Vec<usize> = points.iter().map(|p| p.len()).collect();
+        assert_eq!(counts, vec![0, 1, 2, 1]);
+        assert_eq!(points[3][0].track_id, track(78).track_id);
+        assert!(source.group_points(&images, &[track(79)]).is_none());
+
+        // Cross-check against slightly different database points.
+        let mut db = points.clone();
+        db[1][0].minrange += 0.001;
+        db[2][1].maxbearing += 1.0;
+        db[3].clear();
+        let check = check_points(&db, &points, 0.01);
+        assert_eq!(check, PointsCheck { matched: 2, differ: 1, only_db: 0, only_pgdf: 1 });
+        assert!(!check.consistent());
+        assert!(check_points(&points, &points, 0.0).consistent());
+        assert_eq!("check".parse::<PointsMode>().unwrap(), PointsMode::Check);
+    }
+}
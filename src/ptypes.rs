@@ -16,8 +16,9 @@ use crate::image::ImageSize;
 use crate::models::{Groups, Images, Points};
 use crate::bbs::{FrameBox, FrameBoxRaw};
 use crate::image::ImageVolume;
+use crate::nodes_time::Resample;
 use chrono::{DateTime, Utc};
-use image::{ImageBuffer, Luma};
+use image::{ImageBuffer, Luma, Primitive};
 use std::path::PathBuf;
 
 
@@ -37,6 +38,10 @@ pub struct OriginT {
     pub img_size: ImageSize,
     /// Actual size after the initial crop.
     pub crop_size: ImageSize,
+    /// The time base the frames were resampled to. None means one frame per recorded ping.
+    pub time_base: Option<Resample>,
+    /// The distance each pixel row covers, if the range axis has been normalised.
+    pub metres_per_pixel: Option<f32>,
 }
 
 /// A group and its associated images, points and sonarid. Generated from a DB Node. 
@@ -47,30 +52,31 @@ pub struct GroupT {
     pub points: Vec<Vec<Points>>,
 }
 
-/// A Volume - a stack of 2D images that represent the sonar over time.
-pub struct VolumeT {
+/// A Volume - a stack of 2D images that represent the sonar over time. The pixels are u8
+/// unless asked otherwise.
+pub struct VolumeT<T: Primitive = u8> {
     /// A stack of images
-    pub volume: ImageVolume,
+    pub volume: ImageVolume<T>,
     /// Does this volume cover all or some of the origin image size? (left, top, width, height).
     pub extents: (u32, u32, u32, u32),
     /// The origin of this object.
     pub origin:Option<OriginT>,
 }
 
-/// A Single image - often used for background removal
-pub struct ImageT {
-    pub image: ImageBuffer<Luma<u8>, Vec<u8>>,
+/// A Single image - often used for background removal. The pixels are u8 unless asked otherwise.
+pub struct ImageT<T: Primitive = u8> {
+    pub image: ImageBuffer<Luma<T>, Vec<T>>,
     pub extents: (u32, u32, u32, u32),  
     pub origin:Option<OriginT>,
 }
 
-impl VolumeT {
+impl<T: Primitive> VolumeT<T> {
     
     /// Create a new VolumeT
     /// 
     /// * `vol` - the ImageVolume that forms the VolumeT.
     /// * `origin` - an optional OriginT.
-    pub fn new (vol: ImageVolume, origin:Option<OriginT>) -> VolumeT {
+    pub fn new (vol: ImageVolume<T>, origin:Option<OriginT>) -> VolumeT<T> {
         let mut extents = (0, 0, 0, 0);
 
         if origin.is_some() {
@@ -93,7 +99,7 @@ pub trait Dimensions {
     fn depth(&self) -> usize;
 }
 
-impl Dimensions for VolumeT {
+impl<T: Primitive> Dimensions for VolumeT<T> {
     /// Return the width of this volume in pixels.
     fn width(&self) -> usize {
         if self.volume.0.len() > 0 {
@@ -151,23 +157,42 @@ pub struct BlankGroupT {
     pub time_end: DateTime<Utc>,
 }
 
-/// The final result that gets sent to one of the various sets.
+/// An extra, named, input channel that sits alongside the raw volume in a DatumT.
 #[derive(Clone)]
-pub struct DatumT {
-    pub raw: ImageVolume,
+pub struct ChannelT<T: Primitive = u8> {
+    /// The name of this channel, recorded in the dataset metadata.
+    pub name: String,
+    /// The channel data, the same size and depth as the raw volume.
+    pub volume: ImageVolume<T>,
+}
+
+/// The final result that gets sent to one of the various sets. The raw volume and channels
+/// are u8 unless asked otherwise; the mask is always u8.
+#[derive(Clone)]
+pub struct DatumT<T: Primitive = u8> {
+    pub raw: ImageVolume<T>,
     pub mask: ImageVolume,
     pub origin: Option<OriginT>,
-    pub extents: (u32, u32, u32, u32)
+    pub extents: (u32, u32, u32, u32),
+    /// Any extra input channels, following on from the raw volume (channel 0).
+    pub channels: Vec<ChannelT<T>>,
 }
 
 
-impl DatumT {
+impl<T: Primitive> DatumT<T> {
+
+    /// The names of all the input channels in this datum, starting with the raw volume.
+    pub fn channel_names(&self) -> Vec<String> {
+        let mut names = vec![String::from("raw")];
+        names.extend(self.channels.iter().map(|c| c.name.clone()));
+        names
+    }
     
     /// Create a new DatumT object.
     /// 
     /// * `raw` - The raw/base/image VolumeT.
     /// * `mask` - The mask/track VolumeT.
-    pub fn new (raw: &VolumeT, mask: &VolumeT ) -> DatumT{
+    pub fn new (raw: &VolumeT<T>, mask: &VolumeT ) -> DatumT<T>{
         if raw.origin.is_some() && mask.origin.is_some() {
             let raw_o = raw.origin.clone().unwrap();
             let mask_o = raw.origin.clone().unwrap();
@@ -186,13 +211,14 @@ impl DatumT {
             raw: raw.volume.clone(),
             mask: mask.volume.clone(),
             origin: raw.origin.clone(),
-            extents: mask.extents.clone()
+            extents: mask.extents.clone(),
+            channels: vec![],
         }
     }
 }
 
 /// A Datum that has been sliced into bits.
 #[derive(Clone)]
-pub struct SlicedDatumT {
-    pub slices : Vec<DatumT>
+pub struct SlicedDatumT<T: Primitive = u8> {
+    pub slices : Vec<DatumT<T>>
 }
@@ -0,0 +1,182 @@
+//! Finding the FITS file for an image. *SealHits* keeps its frames as
+//! fits/YYYY_MM_DD/<timestamp>_<sonar>.fits(.lz4), so the path can usually be worked out
+//! from the image and checked directly. The index, or a walk of the tree, is only needed
+//! for the frames that aren't where they should be.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   resolver.rs - FITS path resolution.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::files::parse_fits_name;
+use crate::fits_index::{Compression, FitsEntry, FitsIndex, IndexMode};
+use crate::models::Images;
+use chrono::NaiveDate;
+use log::info;
+use std::collections::HashMap;
+use std::fs::read_dir;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Something that can find the FITS files for images. Shared between the pipeline threads.
+pub trait FitsLookup: Sync {
+    /// The path to the FITS for an image, if it can be found.
+    ///
+    /// * `image` - the image.
+    fn lookup(&self, image: &Images) -> Option<PathBuf>;
+
+    /// The names (without any .lz4) and paths of every FITS recorded on a day.
+    ///
+    /// * `date` - the day.
+    fn day_files(&self, date: &NaiveDate) -> Vec<(String, PathBuf)>;
+}
+
+/// A map of FITS name, without the .lz4, to path, such as FitsIndex::paths gives.
+impl FitsLookup for HashMap<String, PathBuf> {
+    fn lookup(&self, image: &Images) -> Option<PathBuf> {
+        self.get(&image.filename).cloned()
+    }
+
+    fn day_files(&self, date: &NaiveDate) -> Vec<(String, PathBuf)> {
+        self.iter()
+            .filter(|(name, _)| parse_fits_name(name).is_some_and(|(time, _)| time.date() == *date))
+            .map(|(name, path)| (name.clone(), path.clone()))
+            .collect()
+    }
+}
+
+/// The name of the day directory a FITS is kept in.
+///
+/// * `date` - the day the frame was recorded.
+pub fn day_dir(date: &NaiveDate) -> String {
+    date.format("%Y_%m_%d").to_string()
+}
+
+/// Check the places a FITS should be in the *SealHits* layout, the .lz4 first as
+/// fits_in_path does. The day comes from the name, and failing that from the image time.
+///
+/// * `fits_path` - the path to the FITS files.
+/// * `fits_name` - the name of the FITS, without any .lz4.
+/// * `date` - the day the image was recorded, if known.
+pub fn layout_path(fits_path: &Path, fits_name: &str, date: Option<NaiveDate>) -> Option<PathBuf> {
+    let mut days: Vec<NaiveDate> = parse_fits_name(fits_name).map(|(time, _)| time.date()).into_iter().collect();
+    days.extend(date.filter(|d| !days.contains(d)));
+
+    days.iter()
+        .map(|d| fits_path.join(day_dir(d)))
+        .flat_map(|dir| [dir.join(format!("{}.lz4", fits_name)), dir.join(fits_name)])
+        .find(|p| p.is_file())
+}
+
+/// Finds FITS files from the *SealHits* layout, falling back to the index of the fits path
+/// for anything that isn't where it should be. The index is only opened on the first miss,
+/// so a tree that follows the layout is never walked.
+pub struct FitsResolver {
+    /// The path to the FITS files.
+    pub fits_path: PathBuf,
+    /// The path to the index of the FITS files.
+    pub index_path: PathBuf,
+    /// What to do with an existing index, when it is needed.
+    pub index_mode: IndexMode,
+    fallback: OnceLock<HashMap<String, PathBuf>>,
+}
+
+impl FitsResolver {
+    /// Create a new resolver. Nothing is read until the first lookup.
+    ///
+    /// * `fits_path` - the path to the FITS files.
+    /// * `index_path` - the path to the index of the FITS files.
+    /// * `index_mode` - what to do with an existing index, when it is needed.
+    pub fn new(fits_path: &Path, index_path: &Path, index_mode: IndexMode) -> FitsResolver {
+        FitsResolver {
+            fits_path: fits_path.to_path_buf(),
+            index_path: index_path.to_path_buf(),
+            index_mode,
+            fallback: OnceLock::new(),
+        }
+    }
+
+    /// The index of the fits path as a map, opening it the first time it's needed.
+    fn fallback(&self) -> &HashMap<String, PathBuf> {
+        self.fallback.get_or_init(|| {
+            info!("Falling back to the FITS index of {}", self.fits_path.display());
+            FitsIndex::open(&self.fits_path, &self.index_path, self.index_mode).paths()
+        })
+    }
+}
+
+impl FitsLookup for FitsResolver {
+    fn lookup(&self, image: &Images) -> Option<PathBuf> {
+        layout_path(&self.fits_path, &image.filename, Some(image.time.date_naive()))
+            .or_else(|| self.fallback().get(&image.filename).cloned())
+    }
+
+    fn day_files(&self, date: &NaiveDate) -> Vec<(String, PathBuf)> {
+        let dir = match read_dir(self.fits_path.join(day_dir(date))) {
+            Ok(d) => d,
+            Err(_) => return self.fallback().day_files(date),
+        };
+
+        // Keep the .lz4 where both copies are in the directory.
+        let mut files: HashMap<String, FitsEntry> = HashMap::new();
+
+        for entry in dir.flatten().filter_map(|e| FitsEntry::from_path(&e.path())) {
+            if !files.contains_key(&entry.name) || entry.compression == Compression::Lz4 {
+                files.insert(entry.name.clone(), entry);
+            }
+        }
+
+        files.into_values().map(|e| (e.name, e.path)).collect()
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_images;
+    use std::fs;
+
+    #[test]
+    fn test_resolver() {
+        let root = std::env::temp_dir().join("crabseal_test_resolver");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("2023_05_28")).unwrap();
+        fs::create_dir_all(root.join("misfiled")).unwrap();
+
+        let mut images = test_images(&[0, 1000]);
+        images[0].filename = String::from("2023_05_28_22_52_42_130_854.fits");
+        images[1].filename = String::from("2023_05_28_22_52_43_130_854.fits");
+        fs::write(root.join("2023_05_28").join("2023_05_28_22_52_42_130_854.fits"), "plain").unwrap();
+        fs::write(root.join("2023_05_28").join("2023_05_28_22_52_42_130_854.fits.lz4"), "lz4").unwrap();
+        fs::write(root.join("misfiled").join("2023_05_28_22_52_43_130_854.fits"), "plain").unwrap();
+
+        // Where the layout is followed, no index is needed.
+        let resolver = FitsResolver::new(&root, &root.join("crabseal.index"), IndexMode::Use);
+        let found = resolver.lookup(&images[0]).unwrap();
+        assert_eq!(found, root.join("2023_05_28").join("2023_05_28_22_52_42_130_854.fits.lz4"));
+        assert!(resolver.fallback.get().is_none());
+        assert!(!root.join("crabseal.index").exists());
+
+        let day = NaiveDate::from_ymd_opt(2023, 5, 28).unwrap();
+        assert_eq!(resolver.day_files(&day), vec![(images[0].filename.clone(), found)]);
+
+        // A misfiled frame is found through the index.
+        let misfiled = resolver.lookup(&images[1]).unwrap();
+        assert_eq!(misfiled, root.join("misfiled").join("2023_05_28_22_52_43_130_854.fits"));
+        assert!(root.join("crabseal.index").exists());
+
+        // A map of paths finds the same.
+        let paths = FitsIndex::build(&root).paths();
+        assert_eq!(paths.lookup(&images[1]), Some(misfiled));
+        assert_eq!(paths.day_files(&day).len(), 2);
+    }
+}
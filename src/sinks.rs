@@ -14,6 +14,8 @@
  *   
  */
 
+use crate::image::{ImageVolume, VolumePixel};
+use crate::nodes_convert::{node_sliced_convert_with, NormParams, PixelType};
 use crate::ptypes::VolumeT;
 
 use crate::ptypes::{DatumT, SlicedDatumT};
@@ -32,7 +34,7 @@ use std::{
 /// 
 /// * `datum` - the DatumT to save.
 /// * `outpath` - The path to save the PNGs.
-pub fn sink_to_png(datum: &DatumT, out_path: &PathBuf) {
+pub fn sink_to_png<T: VolumePixel>(datum: &DatumT<T>, out_path: &PathBuf) {
     //! Save PNGs out to disk by squashing the volume.
     let bp: Rgb<f32> = Rgb([0.1, 0.2, 0.0]);
     let bq: Rgb<u8> = Rgb([0, 0, 0]);
@@ -54,7 +56,7 @@ pub fn sink_to_png(datum: &DatumT, out_path: &PathBuf) {
             let mut x = 0;
             for og_pixel in row {
                 let mut new_pixel = tee_raw.get_pixel(x, y).clone();
-                new_pixel.0[2] += og_pixel.0[0] as f32;
+                new_pixel.0[2] += og_pixel.0[0].into();
                 tee_raw.put_pixel(x, y, new_pixel);
                 x += 1;
             }
@@ -98,7 +100,7 @@ pub fn sink_to_png(datum: &DatumT, out_path: &PathBuf) {
 
             for og_pixel in row {
                 let mut new_pixel = final_mask.get_pixel(x, y).clone();
-                new_pixel.0[0] = (og_pixel[0] + new_pixel.0[0]).min(1);
+                new_pixel.0[0] = og_pixel[0].saturating_add(new_pixel.0[0]).min(1);
                 final_mask.put_pixel(x, y, new_pixel);
                 x += 1;
             }
@@ -131,102 +133,307 @@ pub fn sink_to_png(datum: &DatumT, out_path: &PathBuf) {
 }
 
 
-/// Save a sliced datum as a series of NPZ files for numpy.
+/// Build the common part of the NPZ filenames for a slice - huid, slice index, extents,
+/// sonar id and suffix.
+///
+/// * `datum` - the DatumT slice.
+/// * `sidx` - the index of this slice.
+/// * `suffix` - a common suffix to all the files.
+fn npz_stem<T: VolumePixel>(datum: &DatumT<T>, sidx: usize, suffix: &str) -> String {
+    // TODO - assuming an origin here.
+    let origin = datum.origin.as_ref().unwrap();
+    let (ex, ey, ew, eh) = datum.extents;
+
+    origin.group.huid.to_string()
+        + "_"
+        + &format!("{:02}", sidx)
+        + "_"
+        + &format!("{:02}", ex)
+        + "-"
+        + &format!("{:02}", ey)
+        + "-"
+        + &format!("{:02}", ex + ew)
+        + "-"
+        + &format!("{:02}", ey + eh)
+        + "_"
+        + &format!("{}", origin.sonar_id)
+        + "_"
+        + suffix
+}
+
+/// Write a single ImageVolume to an NPZ file as a [depth, height, width] array of its own
+/// pixel type.
+///
+/// * `volume` - the ImageVolume to write.
+/// * `path` - the full path of the file.
+fn write_npz<T: VolumePixel>(volume: ImageVolume<T>, path: &Path) {
+    let file: io::BufWriter<File> = io::BufWriter::new(File::create(path).unwrap());
+    let shape = [
+        volume.0.len() as u64,
+        volume.0[0].height() as u64,
+        volume.0[0].width() as u64,
+    ];
+    let mut writer = npyz::WriteOptions::new()
+        .default_dtype()
+        .shape(&shape)
+        .writer(file)
+        .begin_nd()
+        .unwrap();
+
+    writer.extend(volume).unwrap();
+    writer.finish().unwrap();
+}
+
+/// Write the raw volume and extra channels of a DatumT to an NPZ file as a
+/// [depth, channels, height, width] array of its own pixel type. Channel 0 is the raw volume,
+/// so a datum with no extra channels has one.
+///
+/// * `datum` - the DatumT to write.
+/// * `path` - the full path of the file.
+fn write_npz_channels<T: VolumePixel>(datum: &DatumT<T>, path: &Path) {
+    let file: io::BufWriter<File> = io::BufWriter::new(File::create(path).unwrap());
+    let shape = [
+        datum.raw.0.len() as u64,
+        datum.channels.len() as u64 + 1,
+        datum.raw.0[0].height() as u64,
+        datum.raw.0[0].width() as u64,
+    ];
+    let mut writer = npyz::WriteOptions::new()
+        .default_dtype()
+        .shape(&shape)
+        .writer(file)
+        .begin_nd()
+        .unwrap();
+
+    for (fidx, frame) in datum.raw.0.iter().enumerate() {
+        writer.extend(frame.as_raw().iter().copied()).unwrap();
+
+        for channel in &datum.channels {
+            writer.extend(channel.volume.0[fidx].as_raw().iter().copied()).unwrap();
+        }
+    }
+
+    writer.finish().unwrap();
+}
+
+/// Write an occupancy ImageVolume (0 to 255) to an NPZ file as a [depth, height, width] f32
+/// array of fractions, 0 to 1.
+///
+/// * `volume` - the ImageVolume to write.
+/// * `path` - the full path of the file.
+fn write_npz_fraction(volume: &ImageVolume, path: &Path) {
+    let file: io::BufWriter<File> = io::BufWriter::new(File::create(path).unwrap());
+    let shape = [
+        volume.0.len() as u64,
+        volume.0[0].height() as u64,
+        volume.0[0].width() as u64,
+    ];
+    let mut writer = npyz::WriteOptions::new()
+        .default_dtype()
+        .shape(&shape)
+        .writer(file)
+        .begin_nd()
+        .unwrap();
+
+    for frame in &volume.0 {
+        writer.extend(frame.as_raw().iter().map(|v| *v as f32 / 255.0)).unwrap();
+    }
+
+    writer.finish().unwrap();
+}
+
+/// Save a sliced datum as a series of NPZ files for numpy. The base file is always a
+/// [depth, channels, height, width] array, with the raw volume as channel 0, in the pixel
+/// type of the datum. The mask is a [depth, height, width] u8 array.
 /// 
 /// * `sliced` - the SlicedDatumT to save.
 /// * `out_path` - the path to save the NPZ files.
 /// * `suffix` - a common suffix to all the files.
-pub fn sink_to_npz(sliced: SlicedDatumT, out_path: &PathBuf, suffix: &str) {
+pub fn sink_to_npz<T: VolumePixel>(sliced: SlicedDatumT<T>, out_path: &PathBuf, suffix: &str) {
     // Take the datum ownership and send it to npz files
+    if !std::path::Path::new(&out_path).exists() {
+        std::fs::create_dir(out_path).unwrap();
+    }
 
-    for sidx in 0..sliced.slices.len() {
-        let datum = sliced.slices[sidx].clone(); // TODO - Using a lot of clones around here :/
-        let origin = &datum.origin;
+    for (sidx, datum) in sliced.slices.into_iter().enumerate() {
+        // Save out the final image to a subdir 'images' of the dataset
+        let stem = npz_stem(&datum, sidx, suffix);
 
-        // TODO - assuming an origin here. Also the clone seems unnecessary
-        let fname = origin.clone().unwrap().group.huid;
-        let sonar_id = origin.clone().unwrap().sonar_id;
+        write_npz_channels(&datum, &out_path.join(stem.clone() + "_base.npz"));
+        write_npz(datum.mask, &out_path.join(stem + "_mask.npz"));
+    }
+}
 
-        let ex = datum.extents.0;
-        let ey = datum.extents.1;
-        let ew = datum.extents.2;
-        let eh = datum.extents.3;
 
-        // Save out the final image to a subdir 'images' of the dataset
-        let gname = fname.to_string()
-            + "_"
-            + &format!("{:02}", sidx)
-            + "_"
-            + &format!("{:02}", ex)
-            + "-"
-            + &format!("{:02}", ey)
-            + "-"
-            + &format!("{:02}", ex + ew)
-            + "-"
-            + &format!("{:02}", ey + eh)
-            + "_"
-            + &format!("{}", sonar_id)
-            + "_"
-            + suffix
-            + "_base.npz";
-        let mname = fname.to_string()
-            + "_"
-            + &format!("{:02}", sidx)
-            + "_"
-            + &format!("{:02}", ex)
-            + "-"
-            + &format!("{:02}", ey)
-            + "-"
-            + &format!("{:02}", ex + ew)
-            + "-"
-            + &format!("{:02}", ey + eh)
-            + "_"
-            + &format!("{}", sonar_id)
-            + "_"
-            + suffix
-            + "_mask.npz";
-
-        if !std::path::Path::new(&out_path).exists() {
-            std::fs::create_dir(&out_path).unwrap();
+/// Save a sliced datum as a series of NPZ files for numpy, as sink_to_npz does, but with the
+/// mask written as f32 occupancy fractions. The mask should come from the occupancy sector labels.
+/// 
+/// * `sliced` - the SlicedDatumT to save.
+/// * `out_path` - the path to save the NPZ files.
+/// * `suffix` - a common suffix to all the files.
+pub fn sink_to_npz_occupancy<T: VolumePixel>(sliced: SlicedDatumT<T>, out_path: &PathBuf, suffix: &str) {
+    if !std::path::Path::new(&out_path).exists() {
+        std::fs::create_dir(out_path).unwrap();
+    }
+
+    for (sidx, datum) in sliced.slices.into_iter().enumerate() {
+        let stem = npz_stem(&datum, sidx, suffix);
+        write_npz_fraction(&datum.mask, &out_path.join(stem.clone() + "_mask.npz"));
+        write_npz_channels(&datum, &out_path.join(stem + "_base.npz"));
+    }
+}
+
+
+/// Convert a sliced datum to a pixel type and pass it to the sink for that type. The
+/// normalisation statistics are found beforehand, for the whole group, so every piece and
+/// slice written from it is scaled the same way.
+///
+/// * `sliced` - the SlicedDatumT to convert.
+/// * `pixel_type` - the pixel type to write.
+/// * `params` - the normalisation statistics of the group.
+/// * `sink_u8` - the sink for u8 slices.
+/// * `sink_u16` - the sink for u16 slices.
+/// * `sink_f32` - the sink for f32 slices.
+fn sink_as<S: VolumePixel>(
+    sliced: SlicedDatumT<S>,
+    pixel_type: PixelType,
+    params: &NormParams,
+    sink_u8: impl FnOnce(SlicedDatumT),
+    sink_u16: impl FnOnce(SlicedDatumT<u16>),
+    sink_f32: impl FnOnce(SlicedDatumT<f32>),
+) {
+    match pixel_type {
+        PixelType::U8 => sink_u8(node_sliced_convert_with(&sliced, params)),
+        PixelType::U16 => sink_u16(node_sliced_convert_with(&sliced, params)),
+        PixelType::F32 => sink_f32(node_sliced_convert_with(&sliced, params)),
+    }
+}
+
+/// Save a sliced datum as sink_to_npz does, converting the raw volume and channels of each
+/// slice to the pixel type first.
+///
+/// * `sliced` - the SlicedDatumT to save.
+/// * `out_path` - the path to save the NPZ files.
+/// * `suffix` - a common suffix to all the files.
+/// * `pixel_type` - the pixel type to write.
+/// * `params` - the normalisation statistics of the group.
+pub fn sink_to_npz_as<S: VolumePixel>(sliced: SlicedDatumT<S>, out_path: &PathBuf, suffix: &str, pixel_type: PixelType, params: &NormParams) {
+    sink_as(
+        sliced,
+        pixel_type,
+        params,
+        |s| sink_to_npz(s, out_path, suffix),
+        |s| sink_to_npz(s, out_path, suffix),
+        |s| sink_to_npz(s, out_path, suffix),
+    )
+}
+
+/// Save a sliced datum as sink_to_npz_occupancy does, converting the raw volume and channels
+/// of each slice to the pixel type first.
+///
+/// * `sliced` - the SlicedDatumT to save.
+/// * `out_path` - the path to save the NPZ files.
+/// * `suffix` - a common suffix to all the files.
+/// * `pixel_type` - the pixel type to write.
+/// * `params` - the normalisation statistics of the group.
+pub fn sink_to_npz_occupancy_as<S: VolumePixel>(sliced: SlicedDatumT<S>, out_path: &PathBuf, suffix: &str, pixel_type: PixelType, params: &NormParams) {
+    sink_as(
+        sliced,
+        pixel_type,
+        params,
+        |s| sink_to_npz_occupancy(s, out_path, suffix),
+        |s| sink_to_npz_occupancy(s, out_path, suffix),
+        |s| sink_to_npz_occupancy(s, out_path, suffix),
+    )
+}
+
+/// Save the raw volumes of a sliced datum as sink_to_npz_extra does, converting them to the
+/// pixel type first.
+///
+/// * `sliced` - the SlicedDatumT to save.
+/// * `out_path` - the path to save the NPZ files.
+/// * `suffix` - the same suffix passed to sink_to_npz_as.
+/// * `name` - the name of this extra output, replacing 'base' in the filename.
+/// * `pixel_type` - the pixel type to write.
+/// * `params` - the normalisation statistics of the group.
+pub fn sink_to_npz_extra_as<S: VolumePixel>(sliced: SlicedDatumT<S>, out_path: &PathBuf, suffix: &str, name: &str, pixel_type: PixelType, params: &NormParams) {
+    sink_as(
+        sliced,
+        pixel_type,
+        params,
+        |s| sink_to_npz_extra(s, out_path, suffix, name),
+        |s| sink_to_npz_extra(s, out_path, suffix, name),
+        |s| sink_to_npz_extra(s, out_path, suffix, name),
+    )
+}
+
+
+/// Record a value in the dataset metadata file - a 'key,value' CSV at the top of the dataset.
+/// Existing keys are replaced, so this is safe to call each time a pipeline runs.
+/// 
+/// * `out_path` - the path to the top of the dataset.
+/// * `key` - the name of the value, such as 'channels'.
+/// * `value` - the value itself. Lists are separated with ';'.
+pub fn sink_to_metadata(out_path: &Path, key: &str, value: &str) -> io::Result<()> {
+    let meta_path = out_path.join("metadata.csv");
+    let mut entries: Vec<(String, String)> = vec![];
+
+    if meta_path.exists() {
+        for line in std::fs::read_to_string(&meta_path)?.lines() {
+            if let Some((k, v)) = line.split_once(',') {
+                if k != key {
+                    entries.push((k.to_string(), v.to_string()));
+                }
+            }
         }
+    }
+
+    entries.push((key.to_string(), value.to_string()));
+    let mut file = File::create(&meta_path)?;
+
+    for (k, v) in entries {
+        writeln!(file, "{},{}", k, v)?;
+    }
+
+    Ok(())
+}
+
+
+/// Save only the raw volumes of a sliced datum, alongside the files written by sink_to_npz.
+/// Used for extra outputs such as the background removed volume. The slices must be cut
+/// the same way as those passed to sink_to_npz so the names match.
+/// 
+/// * `sliced` - the SlicedDatumT to save.
+/// * `out_path` - the path to save the NPZ files.
+/// * `suffix` - the same suffix passed to sink_to_npz.
+/// * `name` - the name of this extra output, replacing 'base' in the filename.
+pub fn sink_to_npz_extra<T: VolumePixel>(sliced: SlicedDatumT<T>, out_path: &PathBuf, suffix: &str, name: &str) {
+    if !std::path::Path::new(&out_path).exists() {
+        std::fs::create_dir(out_path).unwrap();
+    }
 
-        let fstr = out_path.join(&gname);
-        let fpath: &Path = Path::new(&fstr);
-
-        let mstr = out_path.join(&mname);
-        let mpath: &Path = Path::new(&mstr);
-
-        // Write out the volume (3D image / Video etc) as a numpy NPZ file for use in Python
-        let file_image: io::BufWriter<File> = io::BufWriter::new(File::create(fpath).unwrap());
-        let shape = [
-            datum.raw.0.len() as u64,
-            datum.raw.0[0].height() as u64,
-            datum.raw.0[0].width() as u64,
-        ];
-        let mut writer = npyz::WriteOptions::new()
-            .default_dtype()
-            .shape(&shape)
-            .writer(file_image)
-            .begin_nd()
-            .unwrap();
-
-        writer.extend(datum.raw.into_iter()).unwrap();
-        writer.finish().unwrap();
-
-        let file_mask: io::BufWriter<File> = io::BufWriter::new(File::create(mpath).unwrap());
-        let shape = [
-            datum.mask.0.len() as u64,
-            datum.mask.0[0].height() as u64,
-            datum.mask.0[0].width() as u64,
-        ];
-        let mut writer = npyz::WriteOptions::new()
-            .default_dtype()
-            .shape(&shape)
-            .writer(file_mask)
-            .begin_nd()
-            .unwrap();
-        writer.extend(datum.mask.into_iter()).unwrap();
-        writer.finish().unwrap();
+    for (sidx, datum) in sliced.slices.into_iter().enumerate() {
+        let stem = npz_stem(&datum, sidx, suffix);
+        write_npz(datum.raw, &out_path.join(stem + "_" + name + ".npz"));
+    }
+}
+
+
+/// Save only the raw volumes of a sliced datum as f32 occupancy fractions, alongside the files
+/// written by sink_to_npz_occupancy. Used for the masks at extra sector sizes.
+/// 
+/// * `sliced` - the SlicedDatumT to save.
+/// * `out_path` - the path to save the NPZ files.
+/// * `suffix` - the same suffix passed to sink_to_npz_occupancy.
+/// * `name` - the name of this extra output, replacing 'base' in the filename.
+pub fn sink_to_npz_extra_occupancy(sliced: SlicedDatumT, out_path: &PathBuf, suffix: &str, name: &str) {
+    if !std::path::Path::new(&out_path).exists() {
+        std::fs::create_dir(out_path).unwrap();
+    }
+
+    for (sidx, datum) in sliced.slices.into_iter().enumerate() {
+        let stem = npz_stem(&datum, sidx, suffix);
+        write_npz_fraction(&datum.raw, &out_path.join(stem + "_" + name + ".npz"));
     }
 }
 
@@ -236,7 +443,7 @@ pub fn sink_to_npz(sliced: SlicedDatumT, out_path: &PathBuf, suffix: &str) {
 /// * `volume` - the VolumeT to save.
 /// * `out_path` - the path to save the NPZ files.
 /// * `suffix` - a common suffix to all the files.
-pub fn sink_to_npz_volume(volume: VolumeT, out_path: &PathBuf, suffix: &str) {
+pub fn sink_to_npz_volume<T: VolumePixel>(volume: VolumeT<T>, out_path: &PathBuf, suffix: &str) {
     //! Sink a volume to an NPZ
     let origin = &volume.origin;
 
@@ -290,11 +497,34 @@ pub fn sink_to_npz_volume(volume: VolumeT, out_path: &PathBuf, suffix: &str) {
 }
 
 
+/// Empty rejected.csv at the root of the dataset. Called once at the start of a run, as
+/// sink_to_rejected appends, so a rerun doesn't count the same groups twice.
+///
+/// * `out_path` - the root of the dataset.
+pub fn sink_rejected_reset(out_path: &Path) -> io::Result<()> {
+    File::create(out_path.join("rejected.csv")).map(|_| ())
+}
+
+/// Record a rejected group, and why, in rejected.csv at the root of the dataset.
+///
+/// * `out_path` - the root of the dataset.
+/// * `huid` - the huid of the rejected group.
+/// * `reason` - why the group was rejected.
+pub fn sink_to_rejected(out_path: &Path, huid: &str, reason: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(out_path.join("rejected.csv"))?;
+
+    writeln!(file, "{},{}", huid, reason)
+}
+
+
 /// Save a datum to a text file.
 /// 
 /// * `datum` - the DatumT to save.
 /// * `out_path` - the path to the text file to save this DatumT to.
-pub fn sink_to_txt(datum: &DatumT, out_path: &PathBuf) {
+pub fn sink_to_txt<T: VolumePixel>(datum: &DatumT<T>, out_path: &PathBuf) {
     //! Record the HUID to a text file
     let mut file = OpenOptions::new()
         .read(true)
@@ -306,3 +536,100 @@ pub fn sink_to_txt(datum: &DatumT, out_path: &PathBuf) {
     let line = datum.origin.clone().unwrap().group.huid;
     write!(file, "{}\n", line).unwrap();
 }
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata() {
+        let out_path = std::env::temp_dir().join("crabseal_test_metadata");
+        std::fs::create_dir_all(&out_path).unwrap();
+        let _ = std::fs::remove_file(out_path.join("metadata.csv"));
+
+        sink_to_metadata(&out_path, "channels", "raw;diff").unwrap();
+        sink_to_metadata(&out_path, "window", "16").unwrap();
+        sink_to_metadata(&out_path, "channels", "raw;bgsub").unwrap();
+
+        let meta = std::fs::read_to_string(out_path.join("metadata.csv")).unwrap();
+        assert_eq!(meta, "window,16\nchannels,raw;bgsub\n");
+    }
+
+    #[test]
+    fn test_rejected_reset() {
+        let out_path = std::env::temp_dir().join("crabseal_test_rejected_reset");
+        std::fs::create_dir_all(&out_path).unwrap();
+
+        // A rerun starts from an empty file rather than adding to the last run.
+        for _ in 0..2 {
+            sink_rejected_reset(&out_path).unwrap();
+            sink_to_rejected(&out_path, "abc", "gap").unwrap();
+        }
+
+        let rejected = std::fs::read_to_string(out_path.join("rejected.csv")).unwrap();
+        assert_eq!(rejected, "abc,gap\n");
+    }
+
+    #[test]
+    fn test_npz_pixel_types() {
+        use crate::dataset::read_npz;
+        use crate::test_util::test_origin;
+        use crate::nodes_convert::{node_sliced_norm_params, Normalise};
+
+        let out_path = std::env::temp_dir().join("crabseal_test_npz_pixel_types");
+        let _ = std::fs::remove_dir_all(&out_path);
+        let raw = VolumeT::new(ImageVolume(vec![GrayImage::from_pixel(2, 2, Luma([51])); 2]), Some(test_origin()));
+        let sliced = SlicedDatumT { slices: vec![DatumT::new(&raw, &raw)] };
+        let stem = npz_stem(&sliced.slices[0], 0, "");
+
+        // u16 keeps its values, so read back as they were written.
+        let params = node_sliced_norm_params(&sliced, Normalise::Max);
+        sink_to_npz_as(sliced.clone(), &out_path, "", PixelType::U16, &params);
+        let base = read_npz(&out_path.join(stem.clone() + "_base.npz")).unwrap();
+        assert!(!base.fraction);
+        assert_eq!(base.values[0], 51.0 * 257.0);
+
+        sink_to_npz_as(sliced, &out_path, "", PixelType::F32, &params);
+        let base = read_npz(&out_path.join(stem.clone() + "_base.npz")).unwrap();
+        assert!(base.fraction);
+        assert_eq!(base.shape, vec![2, 1, 2, 2]);
+        assert!((base.values[0] - 0.2).abs() < 1e-6);
+
+        // The mask stays u8.
+        let mask = read_npz(&out_path.join(stem + "_mask.npz")).unwrap();
+        assert!(!mask.fraction);
+        assert_eq!(mask.values[0], 51.0);
+    }
+
+    #[test]
+    fn test_npz_extra_masks() {
+        use crate::dataset::read_npz;
+        use crate::test_util::test_origin;
+        use crate::nodes_convert::{node_sliced_norm_params, Normalise};
+
+        let out_path = std::env::temp_dir().join("crabseal_test_npz_extra_masks");
+        let _ = std::fs::remove_dir_all(&out_path);
+
+        // A clutter mask with three classes, which minmax would otherwise stretch to 0, 0.5 and 1.
+        let mut classes = GrayImage::new(3, 1);
+        classes.put_pixel(1, 0, Luma([1]));
+        classes.put_pixel(2, 0, Luma([2]));
+        let mask = VolumeT::new(ImageVolume(vec![classes; 2]), Some(test_origin()));
+        let sliced = SlicedDatumT { slices: vec![DatumT::new(&mask, &mask)] };
+        let stem = npz_stem(&sliced.slices[0], 0, "");
+
+        // Data extras take the pixel options, the masks are written as they are.
+        let params = node_sliced_norm_params(&sliced, Normalise::MinMax);
+        sink_to_npz_extra_as(sliced.clone(), &out_path, "", "bgsub", PixelType::F32, &params);
+        sink_to_npz_extra(sliced, &out_path, "", "clutter");
+
+        let bgsub = read_npz(&out_path.join(stem.clone() + "_bgsub.npz")).unwrap();
+        assert!(bgsub.fraction);
+        assert!((bgsub.values[1] - 0.5).abs() < 1e-6);
+
+        let clutter = read_npz(&out_path.join(stem + "_clutter.npz")).unwrap();
+        assert!(!clutter.fraction);
+        assert_eq!(&clutter.values[0..3], &[0.0, 1.0, 2.0]);
+    }
+}
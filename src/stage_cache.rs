@@ -0,0 +1,280 @@
+//! A cache of the intermediate results of the pipeline for each group. Reading, cropping,
+//! cleaning and resizing the frames of a group is by far the slowest part of a run, but
+//! depends only on the nodes upstream of it. Results are stored under a hash of those
+//! upstream parameters, so a rerun that only changes the slicing or the outputs reuses them.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   stage_cache.rs - cache of intermediate group volumes and tracks.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::bbs::{FrameBoxRaw, RawBox};
+use crate::dataset::read_npz;
+use crate::image::{ImageVolume, VolumePixel};
+use crate::ptypes::{OriginT, TrackRawT, VolumeT};
+use image::ImageBuffer;
+use npyz::WriterBuilder;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// The file listing the volumes of a group, written last so a half written group is ignored.
+const VOLUMES_FILE: &str = "volumes.csv";
+/// The file listing the extra masks of a group, kept as u8 whatever the pixel type of the volumes.
+const MASKS_FILE: &str = "masks.csv";
+/// The file holding the track of a group.
+const TRACK_FILE: &str = "track.csv";
+
+/// A stable 64 bit FNV-1a hash of the upstream parameters, as 16 hex digits. The standard
+/// library hasher may change between releases, which would throw the cache away.
+///
+/// * `params` - a description of every parameter upstream of the cached stage.
+pub fn stage_key(params: &str) -> String {
+    let hash = params.bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    format!("{:016x}", hash)
+}
+
+/// Intermediate results for each group, stored under the hash of the upstream parameters.
+pub struct StageCache {
+    /// The directory for this set of parameters.
+    pub path: PathBuf,
+}
+
+impl StageCache {
+    /// Open the cache for a set of upstream parameters, creating its directory. The
+    /// parameters are written alongside, so the directories can be told apart.
+    ///
+    /// * `root` - the directory holding the caches for every set of parameters.
+    /// * `params` - a description of every parameter upstream of the cached stage.
+    pub fn new(root: &Path, params: &str) -> io::Result<StageCache> {
+        let path = root.join(stage_key(params));
+        fs::create_dir_all(&path)?;
+        fs::write(path.join("params.txt"), params)?;
+        Ok(StageCache { path })
+    }
+
+    /// The directory for a group.
+    fn group_path(&self, huid: &str, sonar_id: i32) -> PathBuf {
+        self.path.join(format!("{}_{}", huid, sonar_id))
+    }
+
+    /// True if the volumes for a group are in the cache.
+    ///
+    /// * `huid` - the huid of the group.
+    /// * `sonar_id` - the sonar the group came from.
+    pub fn contains(&self, huid: &str, sonar_id: i32) -> bool {
+        self.group_path(huid, sonar_id).join(VOLUMES_FILE).exists()
+    }
+
+    /// Store the volumes of a group, in order, at their own pixel type. Every volume must have
+    /// at least one frame. Store the masks first, so a group with volumes has its masks too.
+    ///
+    /// * `huid` - the huid of the group.
+    /// * `sonar_id` - the sonar the group came from.
+    /// * `volumes` - the name and VolumeT of each volume.
+    pub fn put_volumes<T: VolumePixel>(&self, huid: &str, sonar_id: i32, volumes: &[(&str, &VolumeT<T>)]) -> io::Result<()> {
+        self.put_listing(huid, sonar_id, VOLUMES_FILE, volumes)
+    }
+
+    /// Store the extra masks of a group, such as the ignore and clutter masks, in order.
+    ///
+    /// * `huid` - the huid of the group.
+    /// * `sonar_id` - the sonar the group came from.
+    /// * `masks` - the name and VolumeT of each mask.
+    pub fn put_masks(&self, huid: &str, sonar_id: i32, masks: &[(&str, &VolumeT)]) -> io::Result<()> {
+        self.put_listing(huid, sonar_id, MASKS_FILE, masks)
+    }
+
+    /// Get the volumes of a group back, in the order they were stored, as the pixel type they
+    /// were stored with. Returns None if they aren't in the cache or can't be read.
+    ///
+    /// * `huid` - the huid of the group.
+    /// * `sonar_id` - the sonar the group came from.
+    /// * `origin` - the origin to give the volumes, as it isn't stored.
+    pub fn get_volumes<T: VolumePixel>(&self, huid: &str, sonar_id: i32, origin: &OriginT) -> Option<Vec<(String, VolumeT<T>)>> {
+        self.get_listing(huid, sonar_id, VOLUMES_FILE, origin)
+    }
+
+    /// Get the extra masks of a group back, in the order they were stored. Returns None if
+    /// they aren't in the cache or can't be read.
+    ///
+    /// * `huid` - the huid of the group.
+    /// * `sonar_id` - the sonar the group came from.
+    /// * `origin` - the origin to give the masks, as it isn't stored.
+    pub fn get_masks(&self, huid: &str, sonar_id: i32, origin: &OriginT) -> Option<Vec<(String, VolumeT)>> {
+        self.get_listing(huid, sonar_id, MASKS_FILE, origin)
+    }
+
+    /// Store a set of volumes of a group, one NPY file each, listed in order in the listing file.
+    fn put_listing<T: VolumePixel>(&self, huid: &str, sonar_id: i32, listing_file: &str, volumes: &[(&str, &VolumeT<T>)]) -> io::Result<()> {
+        let group_path = self.group_path(huid, sonar_id);
+        fs::create_dir_all(&group_path)?;
+        let mut listing = String::new();
+
+        for (name, volume) in volumes {
+            let frames = &volume.volume.0;
+            let first = frames.first().ok_or(io::Error::new(io::ErrorKind::InvalidInput, "empty volume"))?;
+            let shape = [frames.len() as u64, first.height() as u64, first.width() as u64];
+            let file = BufWriter::new(File::create(group_path.join(format!("{}.npy", name)))?);
+            let mut writer = npyz::WriteOptions::new().default_dtype().shape(&shape).writer(file).begin_nd()?;
+            writer.extend(frames.iter().flat_map(|f| f.as_raw().iter().copied()))?;
+            writer.finish()?;
+
+            let (x, y, w, h) = volume.extents;
+            listing += &format!("{},{},{},{},{}\n", name, x, y, w, h);
+        }
+
+        fs::write(group_path.join(listing_file), listing)
+    }
+
+    /// Get a set of volumes of a group back from its listing file.
+    fn get_listing<T: VolumePixel>(&self, huid: &str, sonar_id: i32, listing_file: &str, origin: &OriginT) -> Option<Vec<(String, VolumeT<T>)>> {
+        let group_path = self.group_path(huid, sonar_id);
+        let listing = fs::read_to_string(group_path.join(listing_file)).ok()?;
+        let mut volumes: Vec<(String, VolumeT<T>)> = vec![];
+
+        for line in listing.lines() {
+            let tokens: Vec<&str> = line.split(',').collect();
+
+            if tokens.len() != 5 {
+                return None;
+            }
+
+            let ext: Vec<u32> = tokens[1..].iter().map(|t| t.parse::<u32>()).collect::<Result<_, _>>().ok()?;
+            let array = read_npz(&group_path.join(format!("{}.npy", tokens[0]))).ok()?;
+
+            if array.shape.len() != 3 {
+                return None;
+            }
+
+            let (height, width) = (array.shape[1] as u32, array.shape[2] as u32);
+            let size = (height * width) as usize;
+            let frames: Vec<ImageBuffer<_, Vec<T>>> = array
+                .values
+                .chunks(size.max(1))
+                .map(|c| ImageBuffer::from_raw(width, height, c.iter().map(|v| T::from_f32(*v)).collect()))
+                .collect::<Option<_>>()?;
+
+            volumes.push((
+                tokens[0].to_string(),
+                VolumeT {
+                    volume: ImageVolume(frames),
+                    extents: (ext[0], ext[1], ext[2], ext[3]),
+                    origin: Some(origin.clone()),
+                },
+            ));
+        }
+
+        Some(volumes)
+    }
+
+    /// Store the track of a group.
+    ///
+    /// * `huid` - the huid of the group.
+    /// * `sonar_id` - the sonar the group came from.
+    /// * `track` - the TrackRawT.
+    pub fn put_track(&self, huid: &str, sonar_id: i32, track: &TrackRawT) -> io::Result<()> {
+        let group_path = self.group_path(huid, sonar_id);
+        fs::create_dir_all(&group_path)?;
+        let mut file = BufWriter::new(File::create(group_path.join(TRACK_FILE))?);
+
+        for b in &track.boxes {
+            writeln!(file, "{},{},{},{},{}", b.frame, b.bbox.x_min, b.bbox.y_min, b.bbox.x_max, b.bbox.y_max)?;
+        }
+
+        file.flush()
+    }
+
+    /// Get the track of a group back. Returns None if it isn't in the cache.
+    ///
+    /// * `huid` - the huid of the group.
+    /// * `sonar_id` - the sonar the group came from.
+    /// * `origin` - the origin to give the track, as it isn't stored.
+    pub fn get_track(&self, huid: &str, sonar_id: i32, origin: &OriginT) -> Option<TrackRawT> {
+        let file = File::open(self.group_path(huid, sonar_id).join(TRACK_FILE)).ok()?;
+        let mut boxes: Vec<FrameBoxRaw> = vec![];
+
+        for line in BufReader::new(file).lines() {
+            let line = line.ok()?;
+            let v: Vec<i32> = line.split(',').map(|t| t.parse::<i32>()).collect::<Result<_, _>>().ok()?;
+
+            if v.len() != 5 {
+                return None;
+            }
+
+            boxes.push(FrameBoxRaw {
+                frame: v[0] as u32,
+                bbox: RawBox { x_min: v[1], y_min: v[2], x_max: v[3], y_max: v[4] },
+            });
+        }
+
+        Some(TrackRawT::new(boxes, Some(origin.clone())))
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_origin;
+    use image::{GrayImage, Luma};
+
+    #[test]
+    fn test_stage_cache() {
+        let root = std::env::temp_dir().join("crabseal_test_stage_cache");
+        let _ = fs::remove_dir_all(&root);
+        let origin = test_origin();
+
+        // The key only changes with the parameters.
+        assert_eq!(stage_key("width=512"), stage_key("width=512"));
+        assert_ne!(stage_key("width=512"), stage_key("width=256"));
+
+        let cache = StageCache::new(&root, "width=512").unwrap();
+        assert!(!cache.contains("huid", 854));
+        assert!(cache.get_volumes::<u8>("huid", 854, &origin).is_none());
+
+        let mut frame = GrayImage::from_pixel(4, 3, Luma([2]));
+        frame.put_pixel(3, 2, Luma([200]));
+        let data = VolumeT { volume: ImageVolume(vec![frame.clone(), frame]), extents: (1, 2, 4, 3), origin: None };
+        let ignore = VolumeT { volume: ImageVolume(vec![GrayImage::new(4, 3); 2]), extents: (0, 0, 4, 3), origin: None };
+        cache.put_masks("huid", 854, &[("ignore", &ignore)]).unwrap();
+        cache.put_volumes("huid", 854, &[("data", &data)]).unwrap();
+        assert!(cache.contains("huid", 854));
+        assert!(!cache.contains("huid", 853));
+
+        let volumes = cache.get_volumes::<u8>("huid", 854, &origin).unwrap();
+        assert_eq!(volumes.len(), 1);
+        assert_eq!(volumes[0].0, "data");
+        assert_eq!(volumes[0].1.extents, (1, 2, 4, 3));
+        assert_eq!(volumes[0].1.volume.0, data.volume.0);
+        let masks = cache.get_masks("huid", 854, &origin).unwrap();
+        assert_eq!(masks.len(), 1);
+        assert_eq!(masks[0].0, "ignore");
+        assert!(masks[0].1.origin.is_some());
+
+        // Wider pixels come back as they went in.
+        let wide = VolumeT { volume: ImageVolume(vec![ImageBuffer::from_pixel(4, 3, Luma([40000u16]))]), extents: (0, 0, 4, 3), origin: None };
+        cache.put_volumes("wide", 854, &[("data", &wide)]).unwrap();
+        assert!(cache.get_masks("wide", 854, &origin).is_none());
+        let volumes = cache.get_volumes::<u16>("wide", 854, &origin).unwrap();
+        assert_eq!(volumes[0].1.volume.0, wide.volume.0);
+
+        let boxes = vec![FrameBoxRaw { frame: 3, bbox: RawBox { x_min: 1, y_min: 2, x_max: 5, y_max: 9 } }];
+        cache.put_track("huid", 854, &TrackRawT::new(boxes, None)).unwrap();
+        let track = cache.get_track("huid", 854, &origin).unwrap();
+        assert_eq!(track.boxes.len(), 1);
+        assert_eq!((track.boxes[0].frame, track.boxes[0].bbox.y_max), (3, 9));
+
+        // Different parameters don't see the same results.
+        let other = StageCache::new(&root, "width=256").unwrap();
+        assert!(!other.contains("huid", 854));
+    }
+}
@@ -0,0 +1,393 @@
+//! Statistics about a generated dataset - classes per split, track lengths, mask coverage,
+//! sonar balance and rejected groups - written as Markdown, JSON and histogram PNGs.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   stats.rs - dataset statistics.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::dataset::{dataset_files, read_npz, read_rejected, read_set, DatasetFile, SPLITS};
+use imageproc::drawing::draw_filled_rect_mut;
+// imageproc is built against its own version of image, so draw with the one it re-exports.
+use imageproc::image::{ImageResult, Rgb, RgbImage};
+use imageproc::rect::Rect;
+use rayon::prelude::*;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// The number of bins in the histograms.
+pub const HIST_BINS: usize = 20;
+
+/// The statistics for one split.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SplitStats {
+    /// The name of the split.
+    pub name: String,
+    /// The groups listed in the set file.
+    pub groups: usize,
+    /// The datums (mask files) written, including augmented copies.
+    pub datums: usize,
+    /// How many of the datums are augmented copies.
+    pub augmented: usize,
+    /// The frames over all datums.
+    pub frames: usize,
+    /// The datums containing each class.
+    pub class_datums: BTreeMap<u32, usize>,
+    /// The groups containing each class.
+    pub class_groups: BTreeMap<u32, usize>,
+    /// The datums from each sonar.
+    pub sonars: BTreeMap<i32, usize>,
+}
+
+/// The statistics for a whole dataset.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DatasetStats {
+    /// The statistics per split, in the order train, test, val.
+    pub splits: Vec<SplitStats>,
+    /// The number of frames with any mask set, per datum.
+    pub track_lengths: Vec<usize>,
+    /// The fraction of each frame covered by the mask, over every frame of every datum.
+    pub coverage: Vec<f32>,
+    /// The number of rejected groups.
+    pub rejected: usize,
+    /// How many groups were rejected for each reason.
+    pub reasons: BTreeMap<String, usize>,
+    /// Mask files that could not be read.
+    pub unreadable: Vec<PathBuf>,
+}
+
+/// What we need from each mask file.
+struct MaskSummary {
+    classes: BTreeSet<u32>,
+    frames: usize,
+    coverage: Vec<f32>,
+}
+
+/// Summarise one mask file - the classes in it and the coverage of each frame. Occupancy
+/// masks hold fractions rather than classes, so count as class 1.
+fn summarise_mask(file: &DatasetFile) -> Option<MaskSummary> {
+    let array = read_npz(&file.path).ok()?;
+
+    if array.shape.len() != 3 {
+        return None;
+    }
+
+    let frame_size = (array.shape[1] * array.shape[2]) as usize;
+    let mut classes: BTreeSet<u32> = BTreeSet::new();
+    let mut coverage: Vec<f32> = vec![];
+
+    for frame in array.values.chunks(frame_size.max(1)) {
+        let mut set = 0;
+
+        for v in frame {
+            if *v > 0.0 {
+                set += 1;
+                classes.insert(if array.fraction { 1 } else { *v as u32 });
+            }
+        }
+
+        coverage.push(set as f32 / frame_size as f32);
+    }
+
+    Some(MaskSummary {
+        classes,
+        frames: array.shape[0] as usize,
+        coverage,
+    })
+}
+
+/// Work out the statistics of a generated dataset from its NPZ files, set text files and
+/// list of rejected groups.
+///
+/// * `root` - the root of the dataset.
+pub fn dataset_stats(root: &Path) -> DatasetStats {
+    let (files, _) = dataset_files(root);
+    let masks: Vec<&DatasetFile> = files.iter().filter(|f| f.kind == "mask").collect();
+    let summaries: Vec<Option<MaskSummary>> = masks.par_iter().map(|f| summarise_mask(f)).collect();
+    let mut stats = DatasetStats::default();
+
+    for split in SPLITS {
+        let mut split_stats = SplitStats {
+            name: split.to_string(),
+            groups: read_set(root, split).len(),
+            ..Default::default()
+        };
+        let mut class_groups: BTreeMap<u32, BTreeSet<&str>> = BTreeMap::new();
+
+        for (file, summary) in masks.iter().zip(summaries.iter()) {
+            if file.split != split {
+                continue;
+            }
+
+            let summary = match summary {
+                Some(s) => s,
+                None => {
+                    stats.unreadable.push(file.path.clone());
+                    continue;
+                }
+            };
+
+            split_stats.datums += 1;
+            split_stats.frames += summary.frames;
+            *split_stats.sonars.entry(file.sonar_id).or_insert(0) += 1;
+
+            if file.suffix.starts_with("aug") {
+                split_stats.augmented += 1;
+            }
+
+            for class in &summary.classes {
+                *split_stats.class_datums.entry(*class).or_insert(0) += 1;
+                class_groups.entry(*class).or_default().insert(&file.huid);
+            }
+
+            stats.track_lengths.push(summary.coverage.iter().filter(|c| **c > 0.0).count());
+            stats.coverage.extend(summary.coverage.iter());
+        }
+
+        split_stats.class_groups = class_groups.iter().map(|(c, g)| (*c, g.len())).collect();
+        stats.splits.push(split_stats);
+    }
+
+    let rejected = read_rejected(root);
+    stats.rejected = rejected.len();
+
+    for (_, reasons) in rejected {
+        for reason in reasons {
+            *stats.reasons.entry(reason).or_insert(0) += 1;
+        }
+    }
+
+    stats
+}
+
+/// Count values into equal width bins between low and high. Values outside go in the end bins.
+///
+/// * `values` - the values to count.
+/// * `bins` - the number of bins.
+/// * `low` - the bottom of the first bin.
+/// * `high` - the top of the last bin.
+pub fn histogram(values: &[f32], bins: usize, low: f32, high: f32) -> Vec<usize> {
+    let mut counts = vec![0; bins];
+    let width = (high - low) / bins as f32;
+
+    for v in values {
+        let bin = if width > 0.0 { ((v - low) / width).floor().max(0.0) as usize } else { 0 };
+        counts[bin.min(bins - 1)] += 1;
+    }
+
+    counts
+}
+
+/// Draw a histogram as a simple bar chart PNG.
+///
+/// * `counts` - the count in each bin.
+/// * `path` - where to save the PNG.
+pub fn histogram_png(counts: &[usize], path: &Path) -> ImageResult<()> {
+    let (bar, height) = (16u32, 200u32);
+    let mut img = RgbImage::from_pixel(bar * counts.len().max(1) as u32, height, Rgb([255, 255, 255]));
+    let most = *counts.iter().max().unwrap_or(&0);
+
+    for (idx, count) in counts.iter().enumerate() {
+        if *count == 0 {
+            continue;
+        }
+
+        let h = ((*count as f32 / most as f32) * (height - 1) as f32).ceil().max(1.0) as u32;
+        let rect = Rect::at((idx as u32 * bar + 1) as i32, (height - h) as i32).of_size(bar - 2, h);
+        draw_filled_rect_mut(&mut img, rect, Rgb([40, 90, 160]));
+    }
+
+    img.save(path)
+}
+
+/// Join a map as "key: value" pairs for the Markdown tables.
+fn md_map<K: std::fmt::Display>(map: &BTreeMap<K, usize>) -> String {
+    let pairs: Vec<String> = map.iter().map(|(k, v)| format!("{}: {}", k, v)).collect();
+    pairs.join(", ")
+}
+
+/// A map as a JSON object. JSON keys are strings, so numeric keys are written as text.
+fn json_map<K: std::fmt::Display>(map: &BTreeMap<K, usize>) -> Value {
+    Value::Object(map.iter().map(|(k, v)| (k.to_string(), json!(v))).collect::<Map<String, Value>>())
+}
+
+impl DatasetStats {
+    /// The track length histogram - frames with any mask, per datum.
+    pub fn track_length_histogram(&self) -> Vec<usize> {
+        let longest = self.track_lengths.iter().max().copied().unwrap_or(0);
+        let lengths: Vec<f32> = self.track_lengths.iter().map(|l| *l as f32).collect();
+        histogram(&lengths, HIST_BINS, 0.0, (longest + 1) as f32)
+    }
+
+    /// The mask coverage histogram - fraction of each frame covered, from 0 to 1.
+    pub fn coverage_histogram(&self) -> Vec<usize> {
+        histogram(&self.coverage, HIST_BINS, 0.0, 1.0)
+    }
+
+    /// The report as Markdown.
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::from("# Dataset statistics\n\n## Splits\n\n");
+        md += "| Split | Groups | Datums | Augmented | Frames | Datums per class | Groups per class | Datums per sonar |\n";
+        md += "|---|---|---|---|---|---|---|---|\n";
+
+        for s in &self.splits {
+            let _ = writeln!(
+                md,
+                "| {} | {} | {} | {} | {} | {} | {} | {} |",
+                s.name,
+                s.groups,
+                s.datums,
+                s.augmented,
+                s.frames,
+                md_map(&s.class_datums),
+                md_map(&s.class_groups),
+                md_map(&s.sonars)
+            );
+        }
+
+        let frames = self.coverage.len().max(1) as f32;
+        let empty = self.coverage.iter().filter(|c| **c == 0.0).count();
+        let mean_length = self.track_lengths.iter().sum::<usize>() as f32 / self.track_lengths.len().max(1) as f32;
+        let _ = write!(
+            md,
+            "\n## Tracks\n\nMean track length {:.2} frames, longest {}. See *track_lengths.png*.\n\n",
+            mean_length,
+            self.track_lengths.iter().max().unwrap_or(&0)
+        );
+        let _ = write!(
+            md,
+            "## Mask coverage\n\nMean coverage {:.4} of each frame, {:.1}% of frames have no mask. See *coverage.png*.\n\n",
+            self.coverage.iter().sum::<f32>() / frames,
+            empty as f32 / frames * 100.0
+        );
+        let _ = write!(md, "## Rejected\n\n{} groups rejected.\n\n", self.rejected);
+
+        for (reason, count) in &self.reasons {
+            let _ = writeln!(md, "* {} - {}", reason, count);
+        }
+
+        if !self.unreadable.is_empty() {
+            md += "\n## Unreadable\n\n";
+
+            for path in &self.unreadable {
+                let _ = writeln!(md, "* {}", path.display());
+            }
+        }
+
+        md
+    }
+
+    /// The report as JSON.
+    pub fn to_json(&self) -> String {
+        let splits: Vec<Value> = self
+            .splits
+            .iter()
+            .map(|s| {
+                json!({
+                    "name": s.name,
+                    "groups": s.groups,
+                    "datums": s.datums,
+                    "augmented": s.augmented,
+                    "frames": s.frames,
+                    "class_datums": json_map(&s.class_datums),
+                    "class_groups": json_map(&s.class_groups),
+                    "sonars": json_map(&s.sonars),
+                })
+            })
+            .collect();
+        let unreadable: Vec<String> = self.unreadable.iter().map(|p| p.display().to_string()).collect();
+        let report = json!({
+            "splits": splits,
+            "track_length_histogram": self.track_length_histogram(),
+            "coverage_histogram": self.coverage_histogram(),
+            "rejected": self.rejected,
+            "reasons": json_map(&self.reasons),
+            "unreadable": unreadable,
+        });
+
+        serde_json::to_string_pretty(&report).unwrap() + "\n"
+    }
+
+    /// Write the Markdown and JSON reports and the histogram PNGs.
+    ///
+    /// * `out_path` - the directory to write to.
+    pub fn write(&self, out_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(out_path.join("stats.md"), self.to_markdown())?;
+        std::fs::write(out_path.join("stats.json"), self.to_json())?;
+        histogram_png(&self.track_length_histogram(), &out_path.join("track_lengths.png"))?;
+        histogram_png(&self.coverage_histogram(), &out_path.join("coverage.png"))?;
+        Ok(())
+    }
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ImageVolume;
+    use crate::test_util::test_origin;
+    use crate::ptypes::{DatumT, SlicedDatumT, VolumeT};
+    use crate::sinks::{sink_to_npz, sink_to_rejected};
+    use image::{GrayImage, Luma};
+
+    #[test]
+    fn test_stats() {
+        let root = std::env::temp_dir().join("crabseal_test_stats");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("images")).unwrap();
+
+        // Two frames of 4x4, the first with a quarter of the mask set to class 1.
+        let mut first = GrayImage::new(4, 4);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            first.put_pixel(x, y, Luma([1]));
+        }
+        let mask = VolumeT {
+            volume: ImageVolume(vec![first, GrayImage::new(4, 4)]),
+            extents: (0, 0, 4, 4),
+            origin: Some(test_origin()),
+        };
+        let raw = VolumeT {
+            volume: ImageVolume(vec![GrayImage::from_pixel(4, 4, Luma([9])); 2]),
+            extents: (0, 0, 4, 4),
+            origin: Some(test_origin()),
+        };
+        let datum = DatumT::new(&raw, &mask);
+        sink_to_npz(SlicedDatumT { slices: vec![datum.clone()] }, &root.join("images").join("train"), "");
+        sink_to_npz(SlicedDatumT { slices: vec![datum] }, &root.join("images").join("train"), "aug00");
+        std::fs::write(root.join("set_train.txt"), "test\n").unwrap();
+        sink_to_rejected(&root, "other", "too_fast;gap").unwrap();
+        sink_to_rejected(&root, "quoted", "a \"quoted\\\" reason").unwrap();
+
+        let stats = dataset_stats(&root);
+        let train = &stats.splits[0];
+        assert_eq!(train.groups, 1);
+        assert_eq!(train.datums, 2);
+        assert_eq!(train.augmented, 1);
+        assert_eq!(train.frames, 4);
+        assert_eq!(train.class_datums.get(&1), Some(&2));
+        assert_eq!(train.class_groups.get(&1), Some(&1));
+        assert_eq!(train.sonars.get(&854), Some(&2));
+        assert_eq!(stats.track_lengths, vec![1, 1]);
+        assert_eq!(stats.coverage, vec![0.25, 0.0, 0.25, 0.0]);
+        assert_eq!(stats.rejected, 2);
+        assert_eq!(stats.reasons.get("gap"), Some(&1));
+
+        assert_eq!(histogram(&[0.0, 0.26, 1.0, 2.0], 4, 0.0, 1.0), vec![1, 1, 0, 2]);
+        // Reasons holding quotes and backslashes still make valid JSON.
+        let report: Value = serde_json::from_str(&stats.to_json()).unwrap();
+        assert_eq!(report["splits"][0]["class_datums"]["1"], 2);
+        assert_eq!(report["reasons"]["a \"quoted\\\" reason"], 1);
+        stats.write(&root).unwrap();
+        assert!(root.join("coverage.png").exists());
+    }
+}
@@ -0,0 +1,538 @@
+//! Synthetic sonar data - frames of speckle with bright blobs moving through the sonar fan
+//! along known trajectories, and the groups, images, points and tracks that describe them.
+//! Written out as FITS files in the *SealHits* layout and a snapshot, so the pipelines can be
+//! run end to end without the test data or a database, and used as pretraining data.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   synthetic.rs - synthetic sonar data.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::constants::MAX_ANGLE;
+use crate::datasource::{export_snapshot, SnapshotSource};
+use crate::files::read_bearing_table;
+use crate::fits::write_fits_image;
+use crate::models::{Groups, Images, Points, TracksGroups};
+use crate::resolver::day_dir;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use image::{GrayImage, Luma};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fs;
+use std::io;
+use std::path::Path;
+use uuid::{Builder, Uuid};
+
+/// Blobs are kept this far inside the edges of the fan, as a fraction of the range and angle.
+const FAN_MARGIN: f32 = 0.15;
+
+/// What to generate.
+#[derive(Clone, Debug)]
+pub struct SyntheticOps {
+    /// The seed for the random number generator. The same seed gives the same data.
+    pub seed: u64,
+    /// How many groups.
+    pub groups: usize,
+    /// How many frames in each group.
+    pub frames: usize,
+    /// The time between frames, in milliseconds.
+    pub interval_ms: i64,
+    /// The sonar the frames come from.
+    pub sonar_id: i32,
+    /// The number of beams - the width of each frame in pixels.
+    pub beams: u32,
+    /// The number of range bins - the height of each frame in pixels.
+    pub height: u32,
+    /// The range of the sonar in metres.
+    pub range: f32,
+    /// How many blobs move through each group.
+    pub blobs: usize,
+    /// The size of each blob in metres.
+    pub blob_size: f32,
+    /// The fastest a blob moves, in metres per second.
+    pub speed: f32,
+    /// The mean brightness of the speckle.
+    pub speckle: f32,
+    /// The brightness of the centre of a blob, above the speckle.
+    pub brightness: f32,
+    /// The code of every group.
+    pub code: String,
+    /// Whether the FITS files are LZ4 compressed.
+    pub lz4: bool,
+    /// The time of the first frame of the first group.
+    pub start: DateTime<Utc>,
+}
+
+impl Default for SyntheticOps {
+    /// Frames the size of the real sonar, with one seal sized blob in each group.
+    fn default() -> Self {
+        SyntheticOps {
+            seed: 0,
+            groups: 10,
+            frames: 32,
+            interval_ms: 250,
+            sonar_id: 854,
+            beams: 512,
+            height: 1632,
+            range: 55.0,
+            blobs: 1,
+            blob_size: 1.5,
+            speed: 1.5,
+            speckle: 20.0,
+            brightness: 180.0,
+            code: String::from("seal"),
+            lz4: true,
+            start: Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
+        }
+    }
+}
+
+/// A group of synthetic frames and the records that describe it.
+pub struct SyntheticGroup {
+    pub group: Groups,
+    /// The images, in time order.
+    pub images: Vec<Images>,
+    /// The frame of each image.
+    pub frames: Vec<GrayImage>,
+    /// The points of each image - one for each blob.
+    pub points: Vec<Vec<Points>>,
+    /// A track for each blob.
+    pub tracks: Vec<TracksGroups>,
+}
+
+/// A uuid from the random number generator, so the same seed gives the same uuids.
+fn random_uuid(rng: &mut StdRng) -> Uuid {
+    Builder::from_random_bytes(rng.gen()).into_uuid()
+}
+
+/// The bearing in radians and the distance in metres of a point in the fan, given in metres
+/// across the fan and along its centre line.
+///
+/// * `across` - metres across the fan from its centre line, positive to the right.
+/// * `along` - metres along the centre line from the sonar.
+pub fn to_bearing_distance(across: f32, along: f32) -> (f32, f32) {
+    (across.atan2(along), across.hypot(along))
+}
+
+/// Whether a point, in metres across and along the fan, is well inside it.
+///
+/// * `ops` - the SyntheticOps.
+/// * `across` - metres across the fan.
+/// * `along` - metres along the fan.
+fn inside_fan(ops: &SyntheticOps, across: f32, along: f32) -> bool {
+    let (bearing, distance) = to_bearing_distance(across, along);
+    bearing.abs() <= MAX_ANGLE.to_radians() * (1.0 - FAN_MARGIN)
+        && distance >= ops.range * FAN_MARGIN
+        && distance <= ops.range * (1.0 - FAN_MARGIN)
+}
+
+/// The path of a blob, in metres across and along the fan at each frame. Blobs move in a
+/// straight line, bouncing back off the edges of the fan.
+///
+/// * `ops` - the SyntheticOps.
+/// * `rng` - the random number generator to draw from.
+pub fn blob_trajectory(ops: &SyntheticOps, rng: &mut StdRng) -> Vec<(f32, f32)> {
+    let max_bearing = MAX_ANGLE.to_radians() * (1.0 - FAN_MARGIN) * 0.8;
+    let bearing: f32 = rng.gen_range(-max_bearing..max_bearing);
+    let distance: f32 = rng.gen_range(ops.range * 0.3..ops.range * 0.7);
+    let heading: f32 = rng.gen_range(0.0..std::f32::consts::TAU);
+    let speed: f32 = rng.gen_range(ops.speed * 0.25..=ops.speed);
+    let step = ops.interval_ms as f32 / 1000.0;
+    let mut position = (bearing.sin() * distance, bearing.cos() * distance);
+    let mut velocity = (heading.sin() * speed * step, heading.cos() * speed * step);
+    let mut path = vec![];
+
+    for _ in 0..ops.frames {
+        path.push(position);
+        let next = (position.0 + velocity.0, position.1 + velocity.1);
+
+        if inside_fan(ops, next.0, next.1) {
+            position = next;
+        } else {
+            velocity = (-velocity.0, -velocity.1);
+        }
+    }
+
+    path
+}
+
+/// Draw a frame of speckle with a blob at each position. Frames are raw, as the sonar records
+/// them and *SealHits* stores them - a column for each beam, at the bearings in the bearing
+/// table, and a row for each range bin, nearest the sonar first.
+///
+/// * `ops` - the SyntheticOps.
+/// * `blobs` - the position of each blob, in metres across and along the fan.
+/// * `btable` - the bearing table.
+/// * `rng` - the random number generator to draw the speckle from.
+pub fn synth_frame(ops: &SyntheticOps, blobs: &[(f32, f32)], btable: &[f32], rng: &mut StdRng) -> GrayImage {
+    let sigma = ops.blob_size / 2.0;
+    let mut frame = GrayImage::new(ops.beams, ops.height);
+
+    for (x, y, pixel) in frame.enumerate_pixels_mut() {
+        let beam = (x as usize * btable.len() / ops.beams as usize).min(btable.len() - 1);
+        let distance = (y as f32 + 0.5) / ops.height as f32 * ops.range;
+        let across = btable[beam].sin() * distance;
+        let along = btable[beam].cos() * distance;
+
+        let blob: f32 = blobs
+            .iter()
+            .map(|(bx, by)| {
+                let d2 = (across - bx).powi(2) + (along - by).powi(2);
+                ops.brightness * (-d2 / (2.0 * sigma * sigma)).exp()
+            })
+            .sum();
+
+        // Speckle is exponentially distributed about its mean. Blobs get a little of it too.
+        let noise: f32 = -(1.0 - rng.gen::<f32>()).ln();
+        let value = ops.speckle * noise + blob * (0.75 + 0.25 * noise);
+        *pixel = Luma([value.clamp(0.0, 255.0) as u8]);
+    }
+
+    frame
+}
+
+/// The point for a blob, as PAMGuard would record it.
+///
+/// * `ops` - the SyntheticOps.
+/// * `position` - the blob, in metres across and along the fan.
+/// * `time` - the time of the frame.
+/// * `track_id` - the track of the blob.
+/// * `uid` - the uid of the point.
+fn blob_point(ops: &SyntheticOps, position: (f32, f32), time: DateTime<Utc>, track_id: Uuid, uid: Uuid) -> Points {
+    let (bearing, distance) = to_bearing_distance(position.0, position.1);
+    let half = ops.blob_size / 2.0;
+    let half_angle = (half / distance).atan();
+
+    Points {
+        time,
+        sonarid: ops.sonar_id,
+        // The points table holds the larger bearing as the min - see points_to_bb.
+        minbearing: bearing + half_angle,
+        maxbearing: bearing - half_angle,
+        minrange: distance - half,
+        maxrange: distance + half,
+        track_id,
+        uid,
+        peakbearing: bearing,
+        peakrange: distance,
+        maxvalue: ops.speckle + ops.brightness,
+        occupancy: 1.0,
+        objsize: ops.blob_size,
+    }
+}
+
+/// The name of the FITS for a frame, as *SealHits* names them.
+///
+/// * `time` - the time of the frame.
+/// * `sonar_id` - the sonar.
+pub fn synth_fits_name(time: &DateTime<Utc>, sonar_id: i32) -> String {
+    format!("{}_{}.fits", time.format("%Y_%m_%d_%H_%M_%S_%3f"), sonar_id)
+}
+
+/// Generate one group. Each group has its own random number generator, so is the same
+/// whichever others are generated.
+///
+/// * `ops` - the SyntheticOps.
+/// * `index` - which group this is.
+/// * `btable` - the bearing table.
+pub fn synth_group(ops: &SyntheticOps, index: usize, btable: &[f32]) -> SyntheticGroup {
+    let mut rng = StdRng::seed_from_u64(ops.seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ index as u64);
+    // A minute between each group.
+    let span = ops.interval_ms * ops.frames as i64 + 60_000;
+    let start = ops.start + Duration::milliseconds(span * index as i64);
+    let times: Vec<DateTime<Utc>> = (0..ops.frames).map(|f| start + Duration::milliseconds(ops.interval_ms * f as i64)).collect();
+    let group_id = random_uuid(&mut rng);
+
+    let trajectories: Vec<Vec<(f32, f32)>> = (0..ops.blobs).map(|_| blob_trajectory(ops, &mut rng)).collect();
+    let tracks: Vec<TracksGroups> = (0..ops.blobs)
+        .map(|b| TracksGroups {
+            track_pam_id: (index * ops.blobs + b) as i64,
+            group_id,
+            binfile: String::new(),
+            track_id: random_uuid(&mut rng),
+        })
+        .collect();
+
+    let mut images = vec![];
+    let mut frames = vec![];
+    let mut points = vec![];
+
+    for (f, time) in times.iter().enumerate() {
+        let positions: Vec<(f32, f32)> = trajectories.iter().map(|t| t[f]).collect();
+        frames.push(synth_frame(ops, &positions, btable, &mut rng));
+
+        points.push(
+            positions
+                .iter()
+                .zip(tracks.iter())
+                .map(|(p, t)| {
+                    let uid = random_uuid(&mut rng);
+                    blob_point(ops, *p, *time, t.track_id, uid)
+                })
+                .collect(),
+        );
+
+        images.push(Images {
+            filename: synth_fits_name(time, ops.sonar_id),
+            uid: random_uuid(&mut rng),
+            hastrack: ops.blobs > 0,
+            glf: String::new(),
+            time: *time,
+            sonarid: ops.sonar_id,
+            range: ops.range as f64,
+        });
+    }
+
+    let group = Groups {
+        gid: index as i64,
+        timestart: times[0],
+        interact: false,
+        mammal: if ops.code == "seal" { ops.blobs as i32 } else { 0 },
+        fish: if ops.code == "fish" { ops.blobs as i32 } else { 0 },
+        bird: if ops.code == "bird" { ops.blobs as i32 } else { 0 },
+        sqlite: String::from("synthetic"),
+        uid: group_id,
+        code: ops.code.clone(),
+        comment: Some(format!("synthetic, seed {}", ops.seed)),
+        timeend: times[times.len() - 1],
+        sqliteid: index as i64,
+        split: 0,
+        huid: format!("synthetic_{}_{:04}", ops.seed, index),
+    };
+
+    SyntheticGroup {
+        group,
+        images,
+        frames,
+        points,
+        tracks,
+    }
+}
+
+/// Generate every group, writing the frames as FITS files under *fits* in the *SealHits*
+/// layout and the records as a snapshot in *snapshot*. Groups are written one at a time, so
+/// only the frames of one are held in memory. Returns the snapshot.
+///
+/// * `ops` - the SyntheticOps.
+/// * `out_path` - the directory to write to.
+pub fn write_synthetic(ops: &SyntheticOps, out_path: &Path) -> io::Result<SnapshotSource> {
+    let btable = read_bearing_table()?;
+    let fits_path = out_path.join("fits");
+    let mut groups = vec![];
+    let mut images = vec![];
+    let mut points = vec![];
+    let mut tracks = vec![];
+
+    for index in 0..ops.groups {
+        let synth = synth_group(ops, index, &btable);
+
+        for (image, frame) in synth.images.iter().zip(synth.frames.iter()) {
+            let day_path = fits_path.join(day_dir(&image.time.date_naive()));
+            fs::create_dir_all(&day_path)?;
+            let name = if ops.lz4 { format!("{}.lz4", image.filename) } else { image.filename.clone() };
+            write_fits_image(&day_path.join(name), frame)?;
+        }
+
+        let uid = synth.group.uid;
+        groups.push(synth.group);
+        images.extend(synth.images.into_iter().map(|i| (uid, i)));
+        points.extend(synth.points.into_iter().flatten().map(|p| (uid, p)));
+        tracks.extend(synth.tracks);
+    }
+
+    let snapshot = SnapshotSource::from_rows(groups.clone(), images, points, tracks);
+    export_snapshot(&snapshot, &groups, &[ops.sonar_id], &out_path.join("snapshot"))?;
+    Ok(snapshot)
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbs::{points_to_bb, RawCoords};
+    use crate::cache::FrameCache;
+    use crate::dataset::read_npz;
+    use crate::datasource::DataSource;
+    use crate::files::{builtin_bearing_table, create_image_dirs};
+    use crate::fits_index::IndexMode;
+    use crate::generators::GeneratorGroups;
+    use crate::image::ImageSize;
+    use crate::metrics::TrackRules;
+    use crate::nodes::{node_combine_datum_mask, node_reject_on_mask, node_reject_on_track_metrics, node_slice_datum_overlap};
+    use crate::nodes_convert::{node_datum_norm_params, Normalise, PixelType};
+    use crate::nodes_tracks::{
+        node_group_to_trackraw, node_track_kalman, node_trackraw_interpolate_timed, node_trackraw_merge,
+        node_trackraw_overlap, GapPolicy,
+    };
+    use crate::nodes_volumes::{
+        node_group_to_volume, node_group_to_volume_sized, node_trackraw_to_volume, node_volume_resize, FramePolicy,
+    };
+    use crate::ptypes::{GroupT, TrackRawT};
+    use crate::resolver::FitsResolver;
+    use crate::sinks::{sink_rejected_reset, sink_to_metadata, sink_to_npz_as, sink_to_png, sink_to_txt};
+    use crate::track::Interpolation;
+    use crate::validate::validate_dataset;
+    use image::imageops::FilterType::{Lanczos3, Nearest};
+    use std::collections::HashMap;
+
+    /// A little synthetic data, small enough to run through everything quickly.
+    fn test_ops(seed: u64) -> SyntheticOps {
+        SyntheticOps {
+            seed,
+            groups: 2,
+            frames: 12,
+            height: 96,
+            range: 20.0,
+            blob_size: 3.0,
+            speckle: 10.0,
+            ..SyntheticOps::default()
+        }
+    }
+
+    /// Write the synthetic data and read the groups back through the generator, from the
+    /// snapshot and FITS files alone.
+    ///
+    /// * `ops` - the SyntheticOps.
+    /// * `out_path` - the directory to write to.
+    fn synthetic_groups(ops: &SyntheticOps, out_path: &Path) -> (Vec<GroupT>, FitsResolver) {
+        let _ = std::fs::remove_dir_all(out_path);
+        write_synthetic(ops, out_path).unwrap();
+
+        let snapshot = SnapshotSource::new(&out_path.join("snapshot")).unwrap();
+        assert_eq!(snapshot.groups().len(), ops.groups);
+        let fits_path = out_path.join("fits");
+        let resolver = FitsResolver::new(&fits_path, &fits_path.join("crabseal.index"), IndexMode::Use);
+        let code_to_id = HashMap::from([(String::from("seal"), 1u8)]);
+        let generator = GeneratorGroups::new(
+            &snapshot,
+            &vec![854],
+            &resolver,
+            &FrameCache::<u8>::new(0, None),
+            None,
+            8,
+            0,
+            ops.height,
+            None,
+            &None,
+            1,
+            &code_to_id,
+        );
+
+        (generator.collect(), resolver)
+    }
+
+    #[test]
+    fn test_synthetic() {
+        let ops = test_ops(3);
+
+        // The same seed gives the same group.
+        let btable = builtin_bearing_table();
+        let first = synth_group(&ops, 1, &btable);
+        let again = synth_group(&ops, 1, &btable);
+        assert_eq!(first.group.uid, again.group.uid);
+        assert_eq!(first.frames[5], again.frames[5]);
+        assert_ne!(first.group.uid, synth_group(&ops, 0, &btable).group.uid);
+        assert_eq!(first.frames[0].dimensions(), (512, 96));
+
+        let (groups, resolver) = synthetic_groups(&ops, &std::env::temp_dir().join("crabseal_test_synthetic"));
+        assert_eq!(groups.len(), 2);
+
+        for group in groups {
+            assert_eq!(group.points.len(), 12);
+            assert!(group.points.iter().all(|p| p.len() == 1));
+            let volume = node_group_to_volume(&group, &resolver).unwrap();
+            assert_eq!(volume.volume.0.len(), 12);
+
+            // The blob is where its points say it is, once they're converted as the pipelines do.
+            let frame = &volume.volume.0[4];
+            let size = ImageSize { width: frame.width(), height: frame.height() };
+            let bbox = points_to_bb(&group.points[4], ops.range).to_raw(&size, &btable);
+            let mean = |x0: i32, y0: i32, x1: i32, y1: i32| {
+                let values: Vec<f32> = (y0..=y1)
+                    .flat_map(|y| (x0..=x1).map(move |x| (x, y)))
+                    .map(|(x, y)| frame.get_pixel(x as u32, y as u32)[0] as f32)
+                    .collect();
+                values.iter().sum::<f32>() / values.len() as f32
+            };
+            let inside = mean(bbox.x_min, bbox.y_min, bbox.x_max, bbox.y_max);
+            assert!(inside > ops.speckle * 4.0, "blob mean {} at {:?}", inside, (bbox.x_min, bbox.y_min));
+        }
+    }
+
+    #[test]
+    fn test_synthetic_pipeline() {
+        // Tracks to masks, volumes to datums, and datums to a dataset on disk, as the pipeline does.
+        let ops = test_ops(5);
+        let out_path = std::env::temp_dir().join("crabseal_test_synthetic_pipeline");
+        let (groups, resolver) = synthetic_groups(&ops, &out_path.join("synthetic"));
+        assert_eq!(groups.len(), 2);
+
+        let root = out_path.join("dataset");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        create_image_dirs(&root.to_str().unwrap().to_string());
+        let train = root.join("images").join("train");
+        std::fs::write(root.join("code_to_class.csv"), "seal,1\n").unwrap();
+        sink_rejected_reset(&root).unwrap();
+        sink_to_metadata(&root, "num_frames", "8").unwrap();
+        sink_to_metadata(&root, "labels", "class").unwrap();
+
+        for group in &groups {
+            let track = node_group_to_trackraw(group);
+            let pieces =
+                node_trackraw_interpolate_timed(&track, &group.images, Interpolation::Frame, None, GapPolicy::Split).unwrap();
+            let smoothed: Vec<TrackRawT> = pieces.iter().map(|p| node_track_kalman(&node_trackraw_overlap(p))).collect();
+            let reasons = node_reject_on_track_metrics(&smoothed, &group.images, &TrackRules::default(), &builtin_bearing_table());
+            assert!(reasons.is_empty());
+            let track = node_trackraw_merge(&smoothed.iter().map(node_trackraw_overlap).collect::<Vec<_>>());
+
+            let (data, _) = node_group_to_volume_sized(group, &resolver, &FrameCache::new(0, None), FramePolicy::Reject).unwrap();
+            let data = node_volume_resize(&data, 128, Lanczos3);
+            let mask = node_volume_resize(&node_trackraw_to_volume(&track, group), 128, Nearest);
+            let datum = node_combine_datum_mask(&data, &mask);
+            assert!(!node_reject_on_mask(&datum, 0, 0));
+
+            sink_to_png(&datum, &train);
+            sink_to_txt(&datum, &root.join("set_train.txt"));
+            let params = node_datum_norm_params(&datum, Normalise::None);
+            sink_to_npz_as(node_slice_datum_overlap(&datum, 8).unwrap(), &train, "", PixelType::U8, &params);
+        }
+
+        let report = validate_dataset(&root, None);
+        assert!(report.is_valid(), "{}", report);
+        assert!(report.datums >= 2);
+        assert_eq!(std::fs::read_to_string(root.join("set_train.txt")).unwrap().lines().count(), 2);
+
+        for entry in std::fs::read_dir(&train).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap().to_string();
+
+            let Some(stem) = name.strip_suffix("_base.npz") else {
+                continue;
+            };
+
+            let base = read_npz(&path).unwrap();
+            let mask = read_npz(&train.join(format!("{}_mask.npz", stem))).unwrap();
+            assert_eq!(base.shape.len(), 4);
+            assert_eq!(base.shape[..2], [8, 1]);
+            assert_eq!(base.shape[2..], mask.shape[1..]);
+            assert_eq!(mask.shape[0], 8);
+
+            // The mask holds the class of the group, and marks the blob - brighter than the speckle around it.
+            assert!(mask.values.iter().all(|v| *v == 0.0 || *v == 1.0));
+            let inside: Vec<f32> = base.values.iter().zip(&mask.values).filter(|(_, m)| **m > 0.0).map(|(b, _)| *b).collect();
+            let outside: Vec<f32> = base.values.iter().zip(&mask.values).filter(|(_, m)| **m == 0.0).map(|(b, _)| *b).collect();
+            assert!(!inside.is_empty());
+            let mean = |v: &[f32]| v.iter().sum::<f32>() / v.len() as f32;
+            assert!(mean(&inside) > mean(&outside) * 2.0, "{} inside, {} outside", mean(&inside), mean(&outside));
+        }
+    }
+}
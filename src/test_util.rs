@@ -0,0 +1,67 @@
+//! Fixtures shared by the tests of several modules.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   test_util.rs - shared test fixtures.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::image::ImageSize;
+use crate::models::{Groups, Images};
+use crate::ptypes::OriginT;
+use chrono::{Duration, TimeZone, Utc};
+use uuid::Uuid;
+
+/// The origin of a small test group on sonar 854, with 4 by 100 images cropped to 4 by 80.
+pub fn test_origin() -> OriginT {
+    OriginT {
+        group: Groups {
+            gid: 0,
+            timestart: Utc::now(),
+            interact: false,
+            mammal: 1,
+            fish: 0,
+            bird: 0,
+            sqlite: String::from("test.sqlite3"),
+            uid: Uuid::nil(),
+            code: String::from("seal"),
+            comment: None,
+            timeend: Utc::now(),
+            sqliteid: 0,
+            split: 0,
+            huid: String::from("test"),
+        },
+        sonar_id: 854,
+        classid: 1,
+        img_size: ImageSize { width: 4, height: 100 },
+        crop_size: ImageSize { width: 4, height: 80 },
+        time_base: None,
+        metres_per_pixel: None,
+    }
+}
+
+/// Images on sonar 854 recorded at the given offsets, in milliseconds.
+///
+/// * `offsets` - the time of each image after the first, in milliseconds.
+pub fn test_images(offsets: &[i64]) -> Vec<Images> {
+    let start = Utc.with_ymd_and_hms(2023, 5, 28, 22, 52, 42).unwrap();
+    offsets
+        .iter()
+        .map(|ms| Images {
+            filename: String::from("test.fits"),
+            uid: Uuid::nil(),
+            hastrack: true,
+            glf: String::from("test.glf"),
+            time: start + Duration::milliseconds(*ms),
+            sonarid: 854,
+            range: 55.0,
+        })
+        .collect()
+}
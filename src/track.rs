@@ -16,9 +16,10 @@
  */
 
 use crate::{bbs::{distance_rawbox, overlap_rawbox, Expand, FrameBox, FrameBoxRaw, RawBox, XYBox}, image::ImageSize};
-use enterpolation::{linear::Linear, Generator};
+use enterpolation::{bspline::BSpline, linear::Linear, Generator};
 use similari::utils::bbox::{BoundingBox, Universal2DBox};
 use similari::utils::kalman::kalman_2d_box::Universal2DBoxKalmanFilter;
+use std::str::FromStr;
 
 
 /// A Three dimensional bounding box.
@@ -213,6 +214,171 @@ pub fn interpolate_track_raw(frames: &Vec<FrameBoxRaw>, img_size: &ImageSize) ->
 }
 
 
+/// How the boxes of a track are interpolated between the frames that have them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    /// Straight lines against the frame index, ignoring the image times.
+    Frame,
+    /// Straight lines against the image times.
+    Linear,
+    /// A Catmull-Rom spline through the boxes, against the image times.
+    CatmullRom,
+    /// A cubic B-spline using the boxes as control points, against the image times.
+    /// Smoother than the others but doesn't pass through every box.
+    BSpline,
+}
+
+impl FromStr for Interpolation {
+    type Err = String;
+
+    /// Parse an interpolation method from the command line - frame, linear, catmull or bspline.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "frame" => Ok(Interpolation::Frame),
+            "linear" => Ok(Interpolation::Linear),
+            "catmull" => Ok(Interpolation::CatmullRom),
+            "bspline" => Ok(Interpolation::BSpline),
+            _ => Err(format!("Unknown interpolation {}", s)),
+        }
+    }
+}
+
+/// Split a track wherever the time between two neighbouring boxes is more than max_gap.
+///
+/// * `frames` - a vector of FrameBoxRaw to split.
+/// * `times` - the time of each frame, in seconds.
+/// * `max_gap` - the longest gap to bridge, in seconds.
+pub fn split_track_gaps(frames: &[FrameBoxRaw], times: &[f32], max_gap: f32) -> Vec<Vec<FrameBoxRaw>> {
+    let mut sorted = frames.to_vec();
+    sorted.sort_by_key(|f| f.frame);
+    let mut pieces: Vec<Vec<FrameBoxRaw>> = vec![];
+
+    for frame in sorted {
+        match pieces.last_mut() {
+            Some(piece) if times[frame.frame as usize] - times[piece.last().unwrap().frame as usize] <= max_gap => {
+                piece.push(frame)
+            }
+            _ => pieces.push(vec![frame]),
+        }
+    }
+
+    pieces
+}
+
+/// Sample a curve through values at knots, at each of the given times.
+fn sample_curve(values: &[f32], knots: &[f32], at: &[f32], method: Interpolation) -> Vec<f32> {
+    let n = values.len();
+
+    match method {
+        Interpolation::CatmullRom => {
+            // The tangent at each knot comes from its neighbours, one sided at the ends.
+            let tangent = |k: usize| {
+                let (a, b) = (k.saturating_sub(1), (k + 1).min(n - 1));
+                (values[b] - values[a]) / (knots[b] - knots[a])
+            };
+
+            at.iter()
+                .map(|t| {
+                    let k = knots.iter().rposition(|kt| kt <= t).unwrap_or(0).min(n - 2);
+                    let h = knots[k + 1] - knots[k];
+                    let s = ((t - knots[k]) / h).clamp(0.0, 1.0);
+                    let (s2, s3) = (s * s, s * s * s);
+                    (2.0 * s3 - 3.0 * s2 + 1.0) * values[k]
+                        + (s3 - 2.0 * s2 + s) * h * tangent(k)
+                        + (-2.0 * s3 + 3.0 * s2) * values[k + 1]
+                        + (s3 - s2) * h * tangent(k + 1)
+                })
+                .collect()
+        }
+        Interpolation::BSpline if n > 2 => {
+            // A clamped spline, with the inner knots averaged from the times so each box
+            // pulls on the curve near its own time. The end knots are repeated for us.
+            let degree = 3.min(n - 1);
+            let mut spline_knots: Vec<f32> = vec![knots[0]];
+
+            for j in 1..n - degree {
+                spline_knots.push(knots[j..j + degree].iter().sum::<f32>() / degree as f32);
+            }
+
+            spline_knots.push(knots[n - 1]);
+
+            let spline = BSpline::builder()
+                .clamped()
+                .elements(values.to_vec())
+                .knots(spline_knots)
+                .dynamic()
+                .build()
+                .unwrap();
+
+            at.iter().map(|t| spline.gen(*t)).collect()
+        }
+        _ => {
+            let lin = Linear::builder().elements(values.to_vec()).knots(knots.to_vec()).build().unwrap();
+            at.iter().map(|t| lin.gen(*t)).collect()
+        }
+    }
+}
+
+/// Given a track with missing frames, interpolate the boxes over the time each frame was
+/// recorded, rather than the frame index, so uneven ping rates don't bend the path.
+///
+/// * `frames` - a vector of FrameBoxRaw to interpolate.
+/// * `times` - the time of each frame, in seconds.
+/// * `img_size` - the size of image we are working within.
+/// * `method` - the interpolation to use.
+pub fn interpolate_track_timed(
+    frames: &Vec<FrameBoxRaw>,
+    times: &[f32],
+    img_size: &ImageSize,
+    method: Interpolation,
+) -> Vec<FrameBoxRaw> {
+    let (boxes, frame_numbers) = one_frame_one_box(frames, img_size);
+
+    if frame_numbers.len() < 2 {
+        return frames.clone();
+    }
+
+    if method == Interpolation::Frame {
+        return interpolate_track_raw(frames, img_size);
+    }
+
+    // Knots must increase, so nudge any images recorded at the same time.
+    let mut knots: Vec<f32> = vec![];
+
+    for f in &frame_numbers {
+        let t = times[*f as usize];
+        knots.push(knots.last().map_or(t, |k: &f32| t.max(k + 1e-4)));
+    }
+
+    let minf = frame_numbers[0];
+    let maxf = *frame_numbers.last().unwrap();
+    let at: Vec<f32> = (minf..maxf + 1).map(|f| times[f as usize].clamp(knots[0], *knots.last().unwrap())).collect();
+    let boxes: Vec<RawBox> = boxes.into_iter().flatten().collect();
+
+    let x_min = sample_curve(&boxes.iter().map(|b| b.x_min as f32).collect::<Vec<f32>>(), &knots, &at, method);
+    let y_min = sample_curve(&boxes.iter().map(|b| b.y_min as f32).collect::<Vec<f32>>(), &knots, &at, method);
+    let x_max = sample_curve(&boxes.iter().map(|b| b.x_max as f32).collect::<Vec<f32>>(), &knots, &at, method);
+    let y_max = sample_curve(&boxes.iter().map(|b| b.y_max as f32).collect::<Vec<f32>>(), &knots, &at, method);
+
+    (0..at.len())
+        .map(|i| {
+            // Splines can overshoot, so keep the corners in order and inside the image.
+            let (x0, x1) = (x_min[i].round() as i32, x_max[i].round() as i32);
+            let (y0, y1) = (y_min[i].round() as i32, y_max[i].round() as i32);
+
+            FrameBoxRaw {
+                frame: minf + i as u32,
+                bbox: RawBox {
+                    x_min: x0.min(x1).max(0),
+                    y_min: y0.min(y1).max(0),
+                    x_max: x0.max(x1).min(img_size.width as i32 - 1),
+                    y_max: y0.max(y1).min(img_size.height as i32 - 1),
+                },
+            }
+        })
+        .collect()
+}
+
 /// Given a track with missing frames, interpolate the track up to the num_frames amount
 /// or the maximum available frames - whichever is smaller. Each frame is expanded by esize.
 /// 
@@ -337,7 +503,7 @@ pub fn interpolate_track(frames: &Vec<FrameBox>, img_size: &ImageSize, esize: i3
 /// 
 /// * `frames` - a vector of FrameBoxRaw to modify.
 /// * `img_size` - the size of image we are working within.
-fn one_frame_one_box(frames: &Vec<FrameBoxRaw>, _img_size: &ImageSize) -> (Vec<Option<RawBox>>, Vec<u32>){
+pub(crate) fn one_frame_one_box(frames: &Vec<FrameBoxRaw>, _img_size: &ImageSize) -> (Vec<Option<RawBox>>, Vec<u32>){
     let mut box_by_frame: Vec<Vec<RawBox>> = vec![];
     let mut box_by_frame_final: Vec<Option<RawBox>> = vec![];
     let mut frame_numbers: Vec<u32> = vec![];
@@ -652,4 +818,41 @@ mod tests {
         assert!(overlap_rawbox(&new_frames[0].bbox, &new_frames[1].bbox))
 
     }
+
+    #[test]
+    fn test_interp_track_timed() {
+        // The third image comes soon after the second, then there is a long pause.
+        let times = [0.0, 0.1, 0.2, 1.0, 3.0];
+        let img_size = ImageSize { width: 400, height: 400 };
+        let frames: Vec<FrameBoxRaw> = [(0, 0), (1, 10), (3, 100)]
+            .iter()
+            .map(|(f, x)| FrameBoxRaw { frame: *f, bbox: RawBox { x_min: *x, y_min: 0, x_max: x + 10, y_max: 10 } })
+            .collect();
+
+        assert_eq!("catmull".parse::<Interpolation>(), Ok(Interpolation::CatmullRom));
+        assert!("cubic".parse::<Interpolation>().is_err());
+
+        // By frame index the missing box is half way, by time it is much nearer the second.
+        let by_frame = interpolate_track_timed(&frames, &times, &img_size, Interpolation::Frame);
+        assert_eq!(by_frame[2].bbox.x_min, 55);
+        let by_time = interpolate_track_timed(&frames, &times, &img_size, Interpolation::Linear);
+        assert_eq!(by_time.len(), 4);
+        assert_eq!(by_time[2].bbox.x_min, 20);
+
+        // Catmull-Rom passes through the boxes, the B-spline only through the ends.
+        let catmull = interpolate_track_timed(&frames, &times, &img_size, Interpolation::CatmullRom);
+        assert_eq!(catmull[1].bbox.x_min, 10);
+        assert!(catmull[2].bbox.x_min > 10 && catmull[2].bbox.x_min < 100);
+        let bspline = interpolate_track_timed(&frames, &times, &img_size, Interpolation::BSpline);
+        assert_eq!(bspline[0].bbox.x_min, 0);
+        assert_eq!(bspline[3].bbox.x_max, 110);
+        let mut longer = frames.clone();
+        longer.push(FrameBoxRaw { frame: 4, bbox: RawBox { x_min: 200, y_min: 0, x_max: 210, y_max: 10 } });
+        assert_eq!(interpolate_track_timed(&longer, &times, &img_size, Interpolation::BSpline).len(), 5);
+
+        let pieces = split_track_gaps(&frames, &times, 0.5);
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(pieces[1][0].frame, 3);
+        assert_eq!(split_track_gaps(&frames, &times, 1.0).len(), 1);
+    }
 }
@@ -0,0 +1,298 @@
+//! Checks on a generated dataset before it goes anywhere near training - raw and mask files
+//! pair up with matching shapes, mask values are real classes, the splits don't share
+//! groups, every datum has the right number of frames and every file can be read.
+/**
+ *     /\
+ *    ( /   @ @    ()
+ *     \  __| |__  /
+ *      -/   "   \-
+ *     /-|       |-\
+ *    / /-\     /-\ \
+ *     / /-`---'-\ \
+ *      /         \ CRABSEAL
+ *
+ *   validate.rs - dataset validation.
+ *   Author - bjb8@st-andrews.ac.uk
+ *
+ */
+use crate::dataset::{dataset_files, read_metadata, read_npz, read_set, DatasetFile, NpzArray, SPLITS};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The outcome of validating a dataset. Any errors mean the dataset shouldn't be used.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ValidationReport {
+    /// The number of files checked.
+    pub files: usize,
+    /// The number of datums (base and mask pairs) checked.
+    pub datums: usize,
+    /// Problems that make the dataset unusable.
+    pub errors: Vec<String>,
+    /// Things worth knowing that don't stop the dataset being used.
+    pub warnings: Vec<String>,
+}
+
+impl ValidationReport {
+    /// True if no errors were found.
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Checked {} files making {} datums.", self.files, self.datums)?;
+
+        for w in &self.warnings {
+            writeln!(f, "WARNING: {}", w)?;
+        }
+
+        for e in &self.errors {
+            writeln!(f, "ERROR: {}", e)?;
+        }
+
+        if self.is_valid() {
+            writeln!(f, "Dataset is valid.")
+        } else {
+            writeln!(f, "Dataset is NOT valid - {} errors.", self.errors.len())
+        }
+    }
+}
+
+/// The values a mask may hold, from the labels recorded in the metadata and the class map.
+enum MaskValues {
+    /// 0 or one of the class ids.
+    Classes(BTreeSet<u32>),
+    /// Any u8 - the occupancy labels.
+    Occupancy,
+    /// f32 fractions from 0 to 1.
+    Fraction,
+    /// No class map was found, so the values aren't checked.
+    Unknown,
+}
+
+/// Check the values of a mask.
+fn check_mask_values(file: &DatasetFile, mask: &NpzArray, allowed: &MaskValues, errors: &mut Vec<String>) {
+    match allowed {
+        MaskValues::Classes(classes) => {
+            let found: BTreeSet<u32> = mask.values.iter().map(|v| *v as u32).collect();
+            let bad: Vec<String> = found.difference(classes).map(|c| c.to_string()).collect();
+
+            if mask.fraction || !bad.is_empty() {
+                errors.push(format!("{} has values outside the class map: {}", file.path.display(), bad.join(",")));
+            }
+        }
+        MaskValues::Fraction => {
+            if !mask.fraction || mask.values.iter().any(|v| !(0.0..=1.0).contains(v)) {
+                errors.push(format!("{} should hold fractions from 0 to 1", file.path.display()));
+            }
+        }
+        MaskValues::Occupancy => {
+            if mask.fraction {
+                errors.push(format!("{} should hold u8 occupancy", file.path.display()));
+            }
+        }
+        MaskValues::Unknown => {}
+    }
+}
+
+/// Validate a generated dataset.
+///
+/// * `root` - the root of the dataset.
+/// * `num_frames` - the frames every datum should have. None uses the number recorded in
+///   the metadata, or failing that only checks each split is consistent.
+pub fn validate_dataset(root: &Path, num_frames: Option<usize>) -> ValidationReport {
+    let mut report = ValidationReport::default();
+    let (files, unknown) = dataset_files(root);
+    report.files = files.len() + unknown.len();
+
+    for path in &unknown {
+        report.errors.push(format!("{} is not a dataset file", path.display()));
+    }
+
+    // The mask values allowed, from the labels the dataset was written with.
+    let allowed = match read_metadata(root, "labels").as_deref() {
+        Some("fraction") => MaskValues::Fraction,
+        Some("occupancy") => MaskValues::Occupancy,
+        _ => {
+            let map_path = [root.join("code_to_class.csv"), PathBuf::from("code_to_class.csv")]
+                .into_iter()
+                .find(|p| p.exists());
+
+            // Read the ids directly, as every line of the class map (the first included) is a class.
+            match map_path.and_then(|p| std::fs::read_to_string(p).ok()) {
+                Some(map) => MaskValues::Classes(
+                    map.lines()
+                        .filter_map(|l| l.split(',').nth(1)?.trim().parse::<u32>().ok())
+                        .chain([0])
+                        .collect(),
+                ),
+                None => {
+                    report.warnings.push(String::from("No class map found, so mask values are not checked"));
+                    MaskValues::Unknown
+                }
+            }
+        }
+    };
+
+    let num_frames = num_frames.or(read_metadata(root, "num_frames").and_then(|n| n.parse().ok()));
+
+    // Read every file once, in parallel.
+    let arrays: Vec<Result<NpzArray, String>> =
+        files.par_iter().map(|f| read_npz(&f.path).map_err(|e| e.to_string())).collect();
+    let mut datums: BTreeMap<(String, String), BTreeMap<String, usize>> = BTreeMap::new();
+
+    for (idx, (file, array)) in files.iter().zip(arrays.iter()).enumerate() {
+        if let Err(e) = array {
+            report.errors.push(format!("{} can't be read: {}", file.path.display(), e));
+        }
+
+        datums.entry((file.split.clone(), file.stem())).or_default().insert(file.kind.clone(), idx);
+    }
+
+    // Pair up the files of each datum and check their shapes.
+    let mut depths: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+
+    for ((split, stem), kinds) in &datums {
+        let (base, mask) = match (kinds.get("base"), kinds.get("mask")) {
+            (Some(b), Some(m)) => (*b, *m),
+            (None, _) => {
+                report.errors.push(format!("{}/{} has no base file", split, stem));
+                continue;
+            }
+            (_, None) => {
+                report.errors.push(format!("{}/{} has no mask file", split, stem));
+                continue;
+            }
+        };
+
+        report.datums += 1;
+
+        let (raw, mask_array) = match (&arrays[base], &arrays[mask]) {
+            (Ok(r), Ok(m)) => (r, m),
+            _ => continue,
+        };
+
+        // The raw volume is [depth, channels, height, width]. Sector masks are smaller than the
+        // raw volume, but never bigger.
+        let raw_hw = &raw.shape[raw.shape.len().saturating_sub(2)..];
+
+        if mask_array.shape.len() != 3
+            || raw.shape.len() != 4
+            || raw.shape[0] != mask_array.shape[0]
+            || mask_array.shape[1] > raw_hw[0]
+            || mask_array.shape[2] > raw_hw[1]
+        {
+            report.errors.push(format!(
+                "{}/{} has mismatched shapes, base {:?} and mask {:?}",
+                split, stem, raw.shape, mask_array.shape
+            ));
+            continue;
+        }
+
+        check_mask_values(&files[mask], mask_array, &allowed, &mut report.errors);
+        depths.entry(split.clone()).or_default().insert(mask_array.shape[0]);
+
+        // Extra outputs must have the same number of frames.
+        for (kind, idx) in kinds.iter().filter(|(k, _)| *k != "base" && *k != "mask") {
+            if let Ok(extra) = &arrays[*idx] {
+                if extra.shape.first() != mask_array.shape.first() {
+                    report.errors.push(format!("{}/{} has a {} file with {:?} frames", split, stem, kind, extra.shape.first()));
+                }
+            }
+        }
+    }
+
+    // Every datum in a split should have the same number of frames.
+    for (split, split_depths) in &depths {
+        let bad: Vec<&u64> = match num_frames {
+            Some(n) => split_depths.iter().filter(|d| **d as usize != n).collect(),
+            None if split_depths.len() > 1 => split_depths.iter().collect(),
+            None => vec![],
+        };
+
+        if !bad.is_empty() {
+            report.errors.push(format!("{} has datums with {:?} frames, expected {:?}", split, bad, num_frames));
+        }
+    }
+
+    // The set files and the files on disk should agree, and no group should be in two splits.
+    let mut seen: BTreeMap<String, &str> = BTreeMap::new();
+
+    for split in SPLITS {
+        let listed: BTreeSet<String> = read_set(root, split).into_iter().collect();
+        let on_disk: BTreeSet<&str> = files.iter().filter(|f| f.split == split).map(|f| f.huid.as_str()).collect();
+
+        for huid in &listed {
+            if let Some(other) = seen.insert(huid.clone(), split) {
+                report.errors.push(format!("{} is in both {} and {}", huid, other, split));
+            }
+
+            if !on_disk.contains(huid.as_str()) {
+                report.errors.push(format!("{} is listed in set_{}.txt but has no files", huid, split));
+            }
+        }
+
+        for huid in on_disk {
+            if !listed.contains(huid) {
+                report.errors.push(format!("{} has files in {} but is not in set_{}.txt", huid, split, split));
+            }
+        }
+    }
+
+    report
+}
+
+// *** TESTS ***
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::ImageVolume;
+    use crate::test_util::test_origin;
+    use crate::ptypes::{DatumT, SlicedDatumT, VolumeT};
+    use crate::sinks::{sink_to_metadata, sink_to_npz};
+    use image::{GrayImage, Luma};
+
+    #[test]
+    fn test_validate() {
+        let root = std::env::temp_dir().join("crabseal_test_validate");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("images")).unwrap();
+        std::fs::write(root.join("code_to_class.csv"), "seal,1\n").unwrap();
+        sink_to_metadata(&root, "num_frames", "2").unwrap();
+
+        let volume = |v: u8| VolumeT {
+            volume: ImageVolume(vec![GrayImage::from_pixel(4, 4, Luma([v])); 2]),
+            extents: (0, 0, 4, 4),
+            origin: Some(test_origin()),
+        };
+        let datum = DatumT::new(&volume(9), &volume(1));
+        sink_to_npz(SlicedDatumT { slices: vec![datum.clone()] }, &root.join("images").join("train"), "");
+        std::fs::write(root.join("set_train.txt"), "test\n").unwrap();
+        // The PNG previews sit alongside the NPZ files, and aren't part of the dataset.
+        std::fs::write(root.join("images").join("train").join("test00-00-04-04_base.png"), "").unwrap();
+
+        let report = validate_dataset(&root, None);
+        assert!(report.is_valid(), "{}", report);
+        assert_eq!(report.datums, 1);
+
+        // The same group in the test split, with a class that isn't in the map.
+        let bad = DatumT::new(&volume(9), &volume(7));
+        sink_to_npz(SlicedDatumT { slices: vec![bad] }, &root.join("images").join("test"), "");
+        std::fs::write(root.join("set_test.txt"), "test\nmissing\n").unwrap();
+        std::fs::write(root.join("images").join("test").join("stray.npz"), "").unwrap();
+        std::fs::remove_file(root.join("images").join("train").join("test_00_00-00-04-04_854__mask.npz")).unwrap();
+
+        let report = validate_dataset(&root, Some(2));
+        assert!(!report.is_valid());
+        let errors = report.errors.join("\n");
+        assert!(errors.contains("has no mask file"));
+        assert!(errors.contains("stray.npz is not a dataset file"));
+        assert!(errors.contains("outside the class map: 7"));
+        assert!(errors.contains("test is in both train and test"));
+        assert!(errors.contains("missing is listed in set_test.txt but has no files"));
+        assert!(validate_dataset(&root, Some(3)).errors.iter().any(|e| e.contains("expected Some(3)")));
+    }
+}